@@ -1227,6 +1227,7 @@ impl ActiveThread {
                     tools: vec![],
                     stop: vec![],
                     temperature: None,
+                    tool_choice: None,
                 };
 
                 Some(default_model.model.count_tokens(request, cx))
@@ -2223,7 +2224,7 @@ impl ActiveThread {
             .expanded_thinking_segments
             .get(&(message_id, ix))
             .copied()
-            .unwrap_or_default();
+            .unwrap_or_else(|| !AssistantSettings::get_global(cx).collapse_reasoning_by_default);
 
         let editor_bg = cx.theme().colors().panel_background;
 
@@ -2283,12 +2284,8 @@ impl ActiveThread {
                                                 .closed_icon(IconName::ChevronDown)
                                                 .on_click(cx.listener({
                                                     move |this, _event, _window, _cx| {
-                                                        let is_open = this
-                                                            .expanded_thinking_segments
-                                                            .entry((message_id, ix))
-                                                            .or_insert(false);
-
-                                                        *is_open = !*is_open;
+                                                        this.expanded_thinking_segments
+                                                            .insert((message_id, ix), !is_open);
                                                     }
                                                 })),
                                         ),
@@ -2406,12 +2403,8 @@ impl ActiveThread {
                                         .closed_icon(IconName::ChevronDown)
                                         .on_click(cx.listener({
                                             move |this, _event, _window, _cx| {
-                                                let is_open = this
-                                                    .expanded_thinking_segments
-                                                    .entry((message_id, ix))
-                                                    .or_insert(false);
-
-                                                *is_open = !*is_open;
+                                                this.expanded_thinking_segments
+                                                    .insert((message_id, ix), !is_open);
                                             }
                                         })),
                                 ),
@@ -210,6 +210,8 @@ impl AssistantConfiguration {
 
     fn render_command_permission(&mut self, cx: &mut Context<Self>) -> impl IntoElement {
         let always_allow_tool_actions = AssistantSettings::get_global(cx).always_allow_tool_actions;
+        let collapse_reasoning_by_default =
+            AssistantSettings::get_global(cx).collapse_reasoning_by_default;
 
         const HEADING: &str = "Allow running editing tools without asking for confirmation";
 
@@ -251,6 +253,38 @@ impl AssistantConfiguration {
                         }),
                     ),
             )
+            .child(
+                h_flex()
+                    .gap_4()
+                    .justify_between()
+                    .flex_wrap()
+                    .child(
+                        v_flex()
+                            .gap_0p5()
+                            .max_w_5_6()
+                            .child(Label::new("Collapse reasoning by default"))
+                            .child(Label::new("When enabled, a model's thought process starts out collapsed so its answer comes first.").color(Color::Muted)),
+                    )
+                    .child(
+                        Switch::new(
+                            "collapse-reasoning-by-default-switch",
+                            collapse_reasoning_by_default.into(),
+                        )
+                        .on_click({
+                            let fs = self.fs.clone();
+                            move |state, _window, cx| {
+                                let collapse = state == &ToggleState::Selected;
+                                update_settings_file::<AssistantSettings>(
+                                    fs.clone(),
+                                    cx,
+                                    move |settings, _| {
+                                        settings.set_collapse_reasoning_by_default(collapse);
+                                    },
+                                );
+                            }
+                        }),
+                    ),
+            )
     }
 
     fn render_context_servers_section(&mut self, cx: &mut Context<Self>) -> impl IntoElement {
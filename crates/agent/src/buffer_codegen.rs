@@ -431,6 +431,7 @@ impl CodegenAlternative {
             stop: Vec::new(),
             temperature: None,
             messages: vec![request_message],
+            tool_choice: None,
         })
     }
 
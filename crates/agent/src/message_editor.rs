@@ -398,6 +398,7 @@ impl MessageEditor {
 
         let editor_bg_color = cx.theme().colors().editor_background;
         let is_generating = thread.is_generating();
+        let is_completion_queued = thread.is_completion_queued();
         let focus_handle = self.editor.focus_handle(cx);
 
         let is_model_selected = self.is_model_selected(cx);
@@ -527,6 +528,29 @@ impl MessageEditor {
                             .child(
                                 h_flex()
                                     .gap_1()
+                                    .when(is_completion_queued, |this| {
+                                        this.child(
+                                            Icon::new(IconName::ArrowCircle)
+                                                .size(IconSize::XSmall)
+                                                .color(Color::Muted)
+                                                .with_animation(
+                                                    "queued-arrow-circle",
+                                                    Animation::new(Duration::from_secs(2)).repeat(),
+                                                    |icon, delta| {
+                                                        icon.transform(
+                                                            gpui::Transformation::rotate(
+                                                                gpui::percentage(delta),
+                                                            ),
+                                                        )
+                                                    },
+                                                ),
+                                        )
+                                        .child(
+                                            Label::new("Waiting for a free slot…")
+                                                .size(LabelSize::XSmall)
+                                                .color(Color::Muted),
+                                        )
+                                    })
                                     .when(!incompatible_tools.is_empty(), |this| {
                                         this.child(
                                             IconButton::new(
@@ -1019,6 +1043,7 @@ impl MessageEditor {
                     tools: vec![],
                     stop: vec![],
                     temperature: None,
+                    tool_choice: None,
                 };
 
                 Some(default_model.model.count_tokens(request, cx))
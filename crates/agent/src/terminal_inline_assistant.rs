@@ -267,6 +267,7 @@ impl TerminalInlineAssistant {
             tools: Vec::new(),
             stop: Vec::new(),
             temperature: None,
+            tool_choice: None,
         })
     }
 
@@ -15,8 +15,8 @@ use futures::{FutureExt, StreamExt as _};
 use git::repository::DiffType;
 use gpui::{App, AppContext, Context, Entity, EventEmitter, SharedString, Task, WeakEntity};
 use language_model::{
-    ConfiguredModel, LanguageModel, LanguageModelCompletionEvent, LanguageModelId,
-    LanguageModelKnownError, LanguageModelRegistry, LanguageModelRequest,
+    CompletionRequestStatus, ConfiguredModel, LanguageModel, LanguageModelCompletionEvent,
+    LanguageModelId, LanguageModelKnownError, LanguageModelRegistry, LanguageModelRequest,
     LanguageModelRequestMessage, LanguageModelRequestTool, LanguageModelToolResult,
     LanguageModelToolUseId, MaxMonthlySpendReachedError, MessageContent,
     ModelRequestLimitReachedError, PaymentRequiredError, RequestUsage, Role, StopReason,
@@ -531,6 +531,13 @@ impl Thread {
         !self.pending_completions.is_empty() || !self.all_tools_finished()
     }
 
+    /// Returns whether a pending completion is waiting for the provider to free up a slot.
+    pub fn is_completion_queued(&self) -> bool {
+        self.pending_completions
+            .iter()
+            .any(|completion| completion.queued)
+    }
+
     pub fn tools(&self) -> &Entity<ToolWorkingSet> {
         &self.tools
     }
@@ -990,6 +997,7 @@ impl Thread {
             tools: Vec::new(),
             stop: Vec::new(),
             temperature: None,
+            tool_choice: None,
         };
 
         if let Some(project_context) = self.project_context.borrow().as_ref() {
@@ -1087,6 +1095,7 @@ impl Thread {
             tools: Vec::new(),
             stop: Vec::new(),
             temperature: None,
+            tool_choice: None,
         };
 
         for message in &self.messages {
@@ -1218,7 +1227,19 @@ impl Thread {
                             LanguageModelCompletionEvent::Stop(reason) => {
                                 stop_reason = reason;
                             }
-                            LanguageModelCompletionEvent::UsageUpdate(token_usage) => {
+                            LanguageModelCompletionEvent::ToolUseStart { .. }
+                            | LanguageModelCompletionEvent::SafetyRatings(_) => {}
+                            LanguageModelCompletionEvent::StatusUpdate(status) => {
+                                if let Some(completion) = thread
+                                    .pending_completions
+                                    .iter_mut()
+                                    .find(|completion| completion.id == pending_completion_id)
+                                {
+                                    completion.queued = status == CompletionRequestStatus::Queued;
+                                }
+                            }
+                            LanguageModelCompletionEvent::UsageUpdate(token_usage)
+                            | LanguageModelCompletionEvent::PartialUsageUpdate(token_usage) => {
                                 thread.update_token_usage_at_last_message(token_usage);
                                 thread.cumulative_token_usage = thread.cumulative_token_usage
                                     + token_usage
@@ -1334,6 +1355,7 @@ impl Thread {
                             }
                             StopReason::EndTurn => {}
                             StopReason::MaxTokens => {}
+                            StopReason::Cancelled => {}
                         },
                         Err(error) => {
                             if error.is::<PaymentRequiredError>() {
@@ -1410,6 +1432,7 @@ impl Thread {
 
         self.pending_completions.push(PendingCompletion {
             id: pending_completion_id,
+            queued: false,
             _task: task,
         });
     }
@@ -2203,6 +2226,7 @@ impl EventEmitter<ThreadEvent> for Thread {}
 
 struct PendingCompletion {
     id: usize,
+    queued: bool,
     _task: Task<()>,
 }
 
@@ -2984,6 +2984,7 @@ impl CodegenAlternative {
             tools: Vec::new(),
             stop: Vec::new(),
             temperature: None,
+            tool_choice: None,
         })
     }
 
@@ -2427,7 +2427,11 @@ impl AssistantContext {
                                         );
                                     }
                                     LanguageModelCompletionEvent::ToolUse(_) => {}
+                                    LanguageModelCompletionEvent::ToolUseStart { .. } => {}
+                                    LanguageModelCompletionEvent::SafetyRatings(_) => {}
                                     LanguageModelCompletionEvent::UsageUpdate(_) => {}
+                                    LanguageModelCompletionEvent::PartialUsageUpdate(_) => {}
+                                    LanguageModelCompletionEvent::StatusUpdate(_) => {}
                                 }
                             });
 
@@ -2520,6 +2524,7 @@ impl AssistantContext {
                             StopReason::ToolUse => {}
                             StopReason::EndTurn => {}
                             StopReason::MaxTokens => {}
+                            StopReason::Cancelled => {}
                         }
                     }
                 })
@@ -2561,6 +2566,7 @@ impl AssistantContext {
             tools: Vec::new(),
             stop: Vec::new(),
             temperature: None,
+            tool_choice: None,
         };
         for message in self.messages(cx) {
             if message.status != MessageStatus::Done {
@@ -87,6 +87,7 @@ pub struct AssistantSettings {
     pub profiles: IndexMap<AgentProfileId, AgentProfile>,
     pub always_allow_tool_actions: bool,
     pub notify_when_agent_waiting: NotifyWhenAgentWaiting,
+    pub collapse_reasoning_by_default: bool,
 }
 
 impl AssistantSettings {
@@ -209,6 +210,7 @@ impl AssistantSettingsContent {
                     profiles: None,
                     always_allow_tool_actions: None,
                     notify_when_agent_waiting: None,
+                    collapse_reasoning_by_default: None,
                 },
                 VersionedAssistantSettingsContent::V2(ref settings) => settings.clone(),
             },
@@ -236,6 +238,7 @@ impl AssistantSettingsContent {
                 profiles: None,
                 always_allow_tool_actions: None,
                 notify_when_agent_waiting: None,
+                collapse_reasoning_by_default: None,
             },
         }
     }
@@ -379,6 +382,16 @@ impl AssistantSettingsContent {
         }
     }
 
+    pub fn set_collapse_reasoning_by_default(&mut self, collapse: bool) {
+        let AssistantSettingsContent::Versioned(boxed) = self else {
+            return;
+        };
+
+        if let VersionedAssistantSettingsContent::V2(ref mut settings) = **boxed {
+            settings.collapse_reasoning_by_default = Some(collapse);
+        }
+    }
+
     pub fn set_profile(&mut self, profile_id: AgentProfileId) {
         let AssistantSettingsContent::Versioned(boxed) = self else {
             return;
@@ -457,6 +470,7 @@ impl Default for VersionedAssistantSettingsContent {
             profiles: None,
             always_allow_tool_actions: None,
             notify_when_agent_waiting: None,
+            collapse_reasoning_by_default: None,
         })
     }
 }
@@ -512,6 +526,10 @@ pub struct AssistantSettingsContentV2 {
     ///
     /// Default: "primary_screen"
     notify_when_agent_waiting: Option<NotifyWhenAgentWaiting>,
+    /// Whether a model's reasoning starts out collapsed in the thread, so answers come first.
+    ///
+    /// Default: true
+    collapse_reasoning_by_default: Option<bool>,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize, JsonSchema, PartialEq)]
@@ -674,6 +692,10 @@ impl Settings for AssistantSettings {
                 &mut settings.notify_when_agent_waiting,
                 value.notify_when_agent_waiting,
             );
+            merge(
+                &mut settings.collapse_reasoning_by_default,
+                value.collapse_reasoning_by_default,
+            );
             merge(&mut settings.default_profile, value.default_profile);
 
             if let Some(profiles) = value.profiles {
@@ -771,6 +793,7 @@ mod tests {
                             profiles: None,
                             always_allow_tool_actions: None,
                             notify_when_agent_waiting: None,
+                            collapse_reasoning_by_default: None,
                         }),
                     ))
                 },
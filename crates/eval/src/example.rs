@@ -410,6 +410,9 @@ impl Example {
                                 Ok(StopReason::MaxTokens) => {
                                     return Err(anyhow!("Exceeded maximum tokens"));
                                 }
+                                Ok(StopReason::Cancelled) => {
+                                    return Err(anyhow!("Completion was cancelled"));
+                                }
                                 Ok(StopReason::ToolUse) => {
                                     if std::env::var("ZED_EVAL_DEBUG").is_ok() {
                                         println!("{}StopReason: Tool use", log_prefix);
@@ -568,6 +571,7 @@ impl Example {
             temperature: None,
             tools: Vec::new(),
             stop: Vec::new(),
+            tool_choice: None,
         };
 
         let diff_response = send_language_model_request(model, request, cx).await?;
@@ -614,6 +618,7 @@ impl Example {
                 temperature: None,
                 tools: Vec::new(),
                 stop: Vec::new(),
+                tool_choice: None,
             };
 
             let thread_response = send_language_model_request(model, request, cx).await?;
@@ -1004,6 +1009,10 @@ fn response_events_to_markdown(
             }
             Ok(
                 LanguageModelCompletionEvent::UsageUpdate(_)
+                | LanguageModelCompletionEvent::PartialUsageUpdate(_)
+                | LanguageModelCompletionEvent::ToolUseStart { .. }
+                | LanguageModelCompletionEvent::SafetyRatings(_)
+                | LanguageModelCompletionEvent::StatusUpdate(_)
                 | LanguageModelCompletionEvent::StartMessage { .. },
             ) => {}
             Err(error) => {
@@ -112,6 +112,8 @@ pub trait Fs: Send + Sync {
     async fn atomic_write(&self, path: PathBuf, text: String) -> Result<()>;
     async fn save(&self, path: &Path, text: &Rope, line_ending: LineEnding) -> Result<()>;
     async fn write(&self, path: &Path, content: &[u8]) -> Result<()>;
+    /// Appends `content` to the file at `path`, creating the file if it doesn't exist.
+    async fn append(&self, path: &Path, content: &[u8]) -> Result<()>;
     async fn canonicalize(&self, path: &Path) -> Result<PathBuf>;
     async fn is_file(&self, path: &Path) -> bool;
     async fn is_dir(&self, path: &Path) -> bool;
@@ -576,6 +578,17 @@ impl Fs for RealFs {
         Ok(())
     }
 
+    async fn append(&self, path: &Path, content: &[u8]) -> Result<()> {
+        let mut file = smol::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .await?;
+        file.write_all(content).await?;
+        file.flush().await?;
+        Ok(())
+    }
+
     async fn canonicalize(&self, path: &Path) -> Result<PathBuf> {
         Ok(smol::fs::canonicalize(path).await?)
     }
@@ -2124,6 +2137,19 @@ impl Fs for FakeFs {
         Ok(())
     }
 
+    async fn append(&self, path: &Path, content: &[u8]) -> Result<()> {
+        self.simulate_random_delay().await;
+        let path = normalize_path(path);
+        let mut new_content = if self.is_file(&path).await {
+            self.load_internal(&path).await?
+        } else {
+            Vec::new()
+        };
+        new_content.extend_from_slice(content);
+        self.write_file_internal(path, new_content, false)?;
+        Ok(())
+    }
+
     async fn canonicalize(&self, path: &Path) -> Result<PathBuf> {
         let path = normalize_path(path);
         self.simulate_random_delay().await;
@@ -2888,4 +2914,19 @@ mod tests {
             "B"
         );
     }
+
+    #[gpui::test]
+    async fn test_append(executor: BackgroundExecutor) {
+        let temp_dir = TempDir::new().unwrap();
+        let path = temp_dir.path().join("file.log");
+        let real_fs = RealFs::new(None, executor.clone());
+        let fake_fs = FakeFs::new(executor);
+        fake_fs.create_dir(temp_dir.path()).await.unwrap();
+
+        for fs in [&real_fs as &dyn Fs, fake_fs.as_ref()] {
+            fs.append(&path, b"first\n").await.unwrap();
+            fs.append(&path, b"second\n").await.unwrap();
+            assert_eq!(fs.load(&path).await.unwrap(), "first\nsecond\n");
+        }
+    }
 }
@@ -1754,6 +1754,7 @@ impl GitPanel {
                     tools: Vec::new(),
                     stop: Vec::new(),
                     temperature: None,
+                    tool_choice: None,
                 };
 
                 let stream = model.stream_completion_text(request, &cx);
@@ -70,10 +70,58 @@ pub enum LanguageModelCompletionEvent {
         signature: Option<String>,
     },
     ToolUse(LanguageModelToolUse),
+    /// A tool call whose name is known but whose input is still streaming. The `ToolUse` with
+    /// the same id follows once the input is complete.
+    ToolUseStart {
+        id: LanguageModelToolUseId,
+        name: Arc<str>,
+    },
     StartMessage {
         message_id: String,
     },
     UsageUpdate(TokenUsage),
+    /// The usage so far of a response that is still streaming, for providers that report it
+    /// incrementally. A `UsageUpdate` with the final tally follows when the provider sends one.
+    PartialUsageUpdate(TokenUsage),
+    StatusUpdate(CompletionRequestStatus),
+    /// How the upstream rated the response against its safety categories, for providers that
+    /// report it. Informational only: a blocked response still ends with its own stop or error.
+    SafetyRatings(Vec<LanguageModelSafetyRating>),
+}
+
+/// An upstream's assessment of a response against one of its safety categories.
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+pub struct LanguageModelSafetyRating {
+    pub category: String,
+    /// The likelihood the upstream reported, such as "NEGLIGIBLE" or "HIGH".
+    pub probability: String,
+    /// Whether this category caused the response to be blocked.
+    pub blocked: bool,
+}
+
+/// The progress of a completion request.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
+pub enum CompletionRequestStatus {
+    /// The request is waiting for a free slot in the provider's rate limiter.
+    Queued,
+    /// The request has been sent to the provider.
+    Started,
+    /// The model is responding and has generated roughly `output_tokens` of the `max_tokens` the
+    /// request allows. This is an estimate, and models often stop well before the cap.
+    Generating { output_tokens: u32, max_tokens: u32 },
+}
+
+impl CompletionRequestStatus {
+    /// The estimated fraction of the response generated so far, between 0 and 1.
+    pub fn progress(&self) -> Option<f32> {
+        match self {
+            CompletionRequestStatus::Generating {
+                output_tokens,
+                max_tokens,
+            } if *max_tokens > 0 => Some((*output_tokens as f32 / *max_tokens as f32).min(1.)),
+            _ => None,
+        }
+    }
 }
 
 /// Indicates the format used to define the input schema for a language model tool.
@@ -91,6 +139,8 @@ pub enum StopReason {
     EndTurn,
     MaxTokens,
     ToolUse,
+    /// The completion was cancelled on purpose, e.g. by the user, rather than failing.
+    Cancelled,
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -310,7 +360,13 @@ pub trait LanguageModel: Send + Sync {
                                 Ok(LanguageModelCompletionEvent::Thinking { .. }) => None,
                                 Ok(LanguageModelCompletionEvent::Stop(_)) => None,
                                 Ok(LanguageModelCompletionEvent::ToolUse(_)) => None,
-                                Ok(LanguageModelCompletionEvent::UsageUpdate(token_usage)) => {
+                                Ok(LanguageModelCompletionEvent::ToolUseStart { .. }) => None,
+                                Ok(LanguageModelCompletionEvent::StatusUpdate(_)) => None,
+                                Ok(LanguageModelCompletionEvent::SafetyRatings(_)) => None,
+                                Ok(
+                                    LanguageModelCompletionEvent::UsageUpdate(token_usage)
+                                    | LanguageModelCompletionEvent::PartialUsageUpdate(token_usage),
+                                ) => {
                                     *last_token_usage.lock() = token_usage;
                                     None
                                 }
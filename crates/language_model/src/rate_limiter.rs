@@ -1,5 +1,6 @@
 use anyhow::Result;
 use futures::Stream;
+use gpui::{App, Global};
 use smol::lock::{Semaphore, SemaphoreGuardArc};
 use std::{
     future::Future,
@@ -31,6 +32,41 @@ where
     }
 }
 
+/// A cap on concurrent requests shared by every provider that opts into it, on top of each
+/// provider's own [`RateLimiter`].
+#[derive(Clone, Default)]
+pub struct GlobalRateLimiter {
+    limit: Option<usize>,
+    limiter: Option<RateLimiter>,
+}
+
+impl Global for GlobalRateLimiter {}
+
+impl GlobalRateLimiter {
+    /// The shared limiter, or `None` when concurrency is unlimited.
+    pub fn global(cx: &App) -> Option<RateLimiter> {
+        cx.try_global::<Self>()
+            .and_then(|this| this.limiter.clone())
+    }
+
+    /// Caps concurrent requests at `limit`, or lifts the cap when `None` or zero, since a
+    /// limiter with no slots would hold every request forever. Requests already holding a slot
+    /// keep it.
+    pub fn set_limit(limit: Option<usize>, cx: &mut App) {
+        let limit = limit.filter(|limit| *limit > 0);
+        if cx
+            .try_global::<Self>()
+            .is_some_and(|this| this.limit == limit)
+        {
+            return;
+        }
+        cx.set_global(Self {
+            limit,
+            limiter: limit.map(RateLimiter::new),
+        });
+    }
+}
+
 impl RateLimiter {
     pub fn new(limit: usize) -> Self {
         Self {
@@ -38,6 +74,11 @@ impl RateLimiter {
         }
     }
 
+    /// Returns whether every slot is currently taken, meaning a new request would have to wait.
+    pub fn is_saturated(&self) -> bool {
+        self.semaphore.try_acquire_arc().is_none()
+    }
+
     pub fn run<'a, Fut, T>(&self, future: Fut) -> impl 'a + Future<Output = Result<T>>
     where
         Fut: 'a + Future<Output = Result<T>>,
@@ -98,3 +139,42 @@ impl RateLimiter {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use gpui::TestAppContext;
+
+    #[gpui::test]
+    fn test_zero_limit_is_unlimited(cx: &mut TestAppContext) {
+        cx.update(|cx| {
+            GlobalRateLimiter::set_limit(Some(0), cx);
+            assert!(GlobalRateLimiter::global(cx).is_none());
+        });
+    }
+
+    #[gpui::test]
+    async fn test_changing_the_limit_replaces_the_limiter(cx: &mut TestAppContext) {
+        cx.update(|cx| GlobalRateLimiter::set_limit(Some(1), cx));
+        let limiter = cx.update(|cx| GlobalRateLimiter::global(cx)).unwrap();
+        let _slot = limiter
+            .stream(async { Ok(futures::stream::empty::<()>()) })
+            .await
+            .unwrap();
+        assert!(limiter.is_saturated());
+
+        cx.update(|cx| GlobalRateLimiter::set_limit(Some(1), cx));
+        let unchanged = cx.update(|cx| GlobalRateLimiter::global(cx)).unwrap();
+        assert!(unchanged.is_saturated());
+
+        cx.update(|cx| GlobalRateLimiter::set_limit(Some(2), cx));
+        let raised = cx.update(|cx| GlobalRateLimiter::global(cx)).unwrap();
+        assert!(!raised.is_saturated());
+        assert!(limiter.is_saturated());
+
+        cx.update(|cx| {
+            GlobalRateLimiter::set_limit(None, cx);
+            assert!(GlobalRateLimiter::global(cx).is_none());
+        });
+    }
+}
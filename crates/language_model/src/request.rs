@@ -9,6 +9,7 @@ use gpui::{
     point, px, size,
 };
 use image::{DynamicImage, ImageDecoder, codecs::png::PngEncoder, imageops::resize};
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 use util::ResultExt;
 
@@ -248,6 +249,18 @@ pub struct LanguageModelRequestTool {
     pub input_schema: serde_json::Value,
 }
 
+/// Whether the model must, may or must not call one of the request's tools.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum LanguageModelToolChoice {
+    /// The model decides whether to call a tool.
+    Auto,
+    /// The model must call at least one tool.
+    Required,
+    /// The model must answer without calling tools.
+    None,
+}
+
 #[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq)]
 pub struct LanguageModelRequest {
     pub thread_id: Option<String>,
@@ -256,6 +269,8 @@ pub struct LanguageModelRequest {
     pub tools: Vec<LanguageModelRequestTool>,
     pub stop: Vec<String>,
     pub temperature: Option<f32>,
+    /// Left to the provider's default when unset.
+    pub tool_choice: Option<LanguageModelToolChoice>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Eq, PartialEq)]
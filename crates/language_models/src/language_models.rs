@@ -23,10 +23,10 @@ use crate::provider::open_ai::OpenAiLanguageModelProvider;
 pub use crate::settings::*;
 
 pub fn init(user_store: Entity<UserStore>, client: Arc<Client>, fs: Arc<dyn Fs>, cx: &mut App) {
-    crate::settings::init(fs, cx);
+    crate::settings::init(fs.clone(), cx);
     let registry = LanguageModelRegistry::global(cx);
     registry.update(cx, |registry, cx| {
-        register_language_model_providers(registry, user_store, client, cx);
+        register_language_model_providers(registry, user_store, client, fs, cx);
     });
 }
 
@@ -34,6 +34,7 @@ fn register_language_model_providers(
     registry: &mut LanguageModelRegistry,
     user_store: Entity<UserStore>,
     client: Arc<Client>,
+    fs: Arc<dyn Fs>,
     cx: &mut Context<LanguageModelRegistry>,
 ) {
     use feature_flags::FeatureFlagAppExt;
@@ -72,7 +73,7 @@ fn register_language_model_providers(
     );
     registry.register_provider(CopilotChatLanguageModelProvider::new(cx), cx);
     registry.register_provider(
-        OpenRouterLanguageModelProvider::new(client.http_client(), cx),
+        OpenRouterLanguageModelProvider::new(client.http_client(), fs, cx),
         cx,
     );
 
@@ -1,471 +1,4816 @@
-use anyhow::{anyhow, Context as _, Result};
+use ::settings::{Settings, SettingsStore, update_settings_file};
+use anyhow::{Context as _, Result, anyhow};
+use chrono::{DateTime, Local};
+use collections::{BTreeMap, HashMap, HashSet};
 use credentials_provider::CredentialsProvider;
-use editor::{Editor, EditorElement};
-use gpui::{Context, Entity, FontStyle, Subscription, Task, TextStyle, WhiteSpace};
+use fs::{Fs, RemoveOptions};
+use futures::{
+    FutureExt, StreamExt,
+    channel::mpsc,
+    future::{AbortRegistration, Abortable, BoxFuture, Either},
+    stream::BoxStream,
+};
+use gpui::{AnyView, App, AsyncApp, BackgroundExecutor, Context, Entity, Subscription, Task};
 use http_client::HttpClient;
 use language_model::{
-    AuthenticateError, LanguageModel, LanguageModelId, LanguageModelProvider,
-    LanguageModelProviderId, LanguageModelProviderName, LanguageModelProviderState, RateLimiter,
+    AuthenticateError, CompletionRequestStatus, GlobalRateLimiter, LanguageModel,
+    LanguageModelCompletionEvent, LanguageModelId, LanguageModelKnownError, LanguageModelName,
+    LanguageModelProvider, LanguageModelProviderId, LanguageModelProviderName,
+    LanguageModelProviderState, LanguageModelRequest, RateLimiter, Role, StopReason,
 };
-use settings::{Settings, SettingsStore};
-use theme::ThemeSettings;
-use ui::{prelude::*, List, Render};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant};
+use ui::{IconName, prelude::*};
 use util::ResultExt;
+use workspace::notifications::{
+    NotificationId, show_app_notification, simple_message_notification::MessageNotification,
+};
+
+use crate::AllLanguageModelSettings;
+
+mod configuration_view;
+mod events;
+mod model_switcher;
+mod request;
+mod settings;
+mod transcript;
 
-use crate::{ui::InstructionListItem, AllLanguageModelSettings};
+use self::settings::CompletionTimeouts;
+use configuration_view::ConfigurationView;
+use events::{
+    InFlightUsageRecords, PostProcessor, coalesce_text_events, end_after_tool_calls,
+    post_process_text, report_progress, report_usage_telemetry,
+};
+use request::{
+    estimate_request_tokens, provider_preferences, request_metadata, screen_prompt,
+    supports_assistant_prefill, with_assistant_prefill,
+};
+use transcript::{StreamTranscript, TranscriptWriter, default_transcript_path};
+
+pub(crate) use self::settings::upgrade_available_models;
+pub use self::settings::{
+    AvailableModel, MaxPrice, ModelAlias, ModelIdFormat, ModelParameters, OpenRouterSettings,
+    PostProcess, RateLimitBehavior, ReasoningMode, SystemRole,
+};
+pub use events::{
+    map_choices_to_language_model_completion_events, map_to_language_model_completion_events,
+};
+pub use model_switcher::SwitchModel;
+pub use request::{into_open_router, into_open_router_tool};
 
 const PROVIDER_ID: &str = "openrouter";
 const PROVIDER_NAME: &str = "OpenRouter";
+const OPENROUTER_API_KEY_VAR: &str = "OPENROUTER_API_KEY";
+const MIN_MODEL_ID_SIMILARITY: f64 = 0.85;
 
-#[derive(Default, Clone, Debug, PartialEq)]
-pub struct OpenRouterSettings {
-    pub api_url: String,
-    pub available_models: Vec<AvailableModel>,
-    pub needs_setting_migration: bool,
-}
+/// Models offered when nothing is enabled and badged as recommended in the model switcher, chosen
+/// for solid tool use across price points.
+const RECOMMENDED_MODEL_IDS: &[&str] = &[
+    "anthropic/claude-3.7-sonnet",
+    "openai/gpt-4.1",
+    "google/gemini-2.5-pro-preview",
+    "deepseek/deepseek-chat-v3-0324",
+];
+/// How many times a completion is retried after a transient failure before it produced output.
+const MAX_COMPLETION_RETRIES: usize = 2;
+/// How many times a single response may be resumed after its connection drops.
+const MAX_STREAM_RESUMES: usize = 2;
+const MAX_CONCURRENT_REQUESTS_PER_MODEL: usize = 4;
+
+const COMPLETION_RETRY_DELAY: Duration = Duration::from_secs(1);
+/// How old a cached catalog can get before the configuration view suggests refreshing it.
+const CATALOG_STALE_AFTER_HOURS: i64 = 24;
+const DRY_RUN_RESPONSE: &str = "Dry run: the request was logged and not sent to OpenRouter.";
 
-#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
-pub struct AvailableModel {
-    pub name: String,
-    pub display_name: Option<String>,
-    pub max_tokens: usize,
-    pub max_output_tokens: Option<u32>,
-    pub max_completion_tokens: Option<u32>,
+/// The outcome of checking whether a model can be served with the current key and routing.
+#[derive(Clone, Debug, PartialEq)]
+enum ModelProbe {
+    Pending,
+    Available,
+    Unavailable(SharedString),
 }
 
 pub struct OpenRouterLanguageModelProvider {
     http_client: Arc<dyn HttpClient>,
-    state: gpui::Entity<State>,
+    state: Entity<State>,
+    request_limiters: RequestLimiters,
+}
+/// Request limiters keyed by model id, shared by every instance of a model.
+///
+/// A limiter is only created once its model sends a request, so enabling hundreds of models
+/// doesn't allocate one for each up front.
+#[derive(Clone, Default)]
+struct RequestLimiters(Arc<Mutex<HashMap<String, RateLimiter>>>);
+impl RequestLimiters {
+    fn for_model(&self, model_id: &str) -> RateLimiter {
+        self.0
+            .lock()
+            .entry(model_id.to_string())
+            .or_insert_with(|| RateLimiter::new(MAX_CONCURRENT_REQUESTS_PER_MODEL))
+            .clone()
+    }
 }
-
 pub struct State {
     api_key: Option<String>,
     api_key_from_env: bool,
+    /// Set when validation found the stored API key revoked, until a new one is entered.
+    api_key_revoked: bool,
+    http_client: Arc<dyn HttpClient>,
+    fs: Arc<dyn Fs>,
+    catalog: Option<Vec<open_router::Model>>,
+    catalog_fetched_at: Option<DateTime<Local>>,
+    /// The last-used model's catalog entry, loaded from disk at startup so the model can be used
+    /// before the catalog loads. Only consulted while the catalog is missing.
+    warm_model: Option<open_router::Model>,
+    /// Why the last catalog fetch failed, cleared once a fetch succeeds.
+    catalog_error: Option<SharedString>,
+    credits: Option<open_router::Credits>,
+    low_credit_alert_shown: bool,
+    /// Whether low-credit mode was restricting models when the balance was last checked, so the
+    /// user is warned once when it takes effect.
+    low_credit_mode_engaged: bool,
+    enabled_models: Vec<AvailableModel>,
+    /// Configured model ids missing from the catalog, paired with the closest catalog id.
+    model_id_suggestions: Vec<(String, String)>,
+    /// Offer every catalog model instead of only the enabled, tool-capable ones. Not persisted.
+    show_all_models: bool,
+    fetch_catalog_task: Option<Task<Result<()>>>,
+    fetch_credits_task: Option<Task<Result<()>>>,
+    refresh_catalog_task: Option<Task<()>>,
+    /// Set once exact token counting has failed, so later counts go straight to the estimator
+    /// instead of retrying (and logging) on every keystroke. Shared with background counts.
+    exact_token_count_unavailable: Arc<AtomicBool>,
+    /// Availability checks the user has run from the configuration view, keyed by model id.
+    model_probes: HashMap<String, ModelProbe>,
+    /// The most recent completion failure, kept so it can be copied into a bug report. Shared
+    /// with in-flight completions, which record failures off the main thread.
+    last_failure: Arc<Mutex<Option<CompletionFailure>>>,
+    /// The parameters the most recent completion was sent with, as described by
+    /// [`diagnostic_request_params`]. Only recorded while `show_request_params` is enabled.
+    last_request_params: Arc<Mutex<Option<serde_json::Value>>>,
+    /// Whether this session has written to the stream transcript yet. Held while appending, so
+    /// concurrent completions don't interleave their entries.
+    transcript_started: Arc<futures::lock::Mutex<bool>>,
+    /// Asks for the credit balance to be refreshed. Completions send on this once finished, since
+    /// they can't update the state from a background thread.
+    refresh_credits_tx: mpsc::UnboundedSender<()>,
+    _refresh_credits_task: Task<()>,
+    /// Usage of completions still streaming, reported when Zed quits so it isn't lost.
+    in_flight_usage: InFlightUsageRecords,
+    /// Whether the last completion to finish came without usage, as from gateways that never
+    /// report it.
+    usage_unavailable: Arc<AtomicBool>,
+    _quit_subscription: Subscription,
     _subscription: Subscription,
 }
-
-const OPENROUTER_API_KEY_VAR: &str = "OPENROUTER_API_KEY";
-
 impl State {
     fn is_authenticated(&self) -> bool {
         self.api_key.is_some()
     }
 
-    fn reset_api_key(&self, cx: &mut Context<Self>) -> Task<Result<()>> {
-        let credentials_provider = <dyn CredentialsProvider>::global(cx);
-        let api_url = AllLanguageModelSettings::get_global(cx)
+    fn catalog_entry(&self, id: &str) -> Option<&open_router::Model> {
+        match self.catalog.as_ref() {
+            Some(catalog) => catalog.iter().find(|model| model.id() == id),
+            None => self.warm_model.as_ref().filter(|model| model.id() == id),
+        }
+    }
+
+    /// Remembers `id` as the last-used model, caching its catalog entry for the next startup.
+    fn remember_last_used_model(&mut self, id: &str, cx: &mut Context<Self>) {
+        if !AllLanguageModelSettings::get_global(cx)
             .openrouter
-            .api_url
-            .clone();
+            .cache_last_used_model
+            || self
+                .warm_model
+                .as_ref()
+                .is_some_and(|model| model.id() == id)
+        {
+            return;
+        }
+        let Some(entry) = self.catalog_entry(id).cloned() else {
+            return;
+        };
+        self.warm_model = Some(entry);
+        self.write_warm_model_cache(cx);
+    }
+
+    /// Checks the cached last-used model against a freshly fetched catalog, updating the cache
+    /// when the model's metadata changed and dropping it when the model is no longer listed.
+    fn validate_warm_model(&mut self, cx: &mut Context<Self>) {
+        let Some(warm_model) = self.warm_model.as_ref() else {
+            return;
+        };
+        let fresh = self
+            .catalog
+            .iter()
+            .flatten()
+            .find(|model| model.id() == warm_model.id())
+            .cloned();
+        if fresh.as_ref() == Some(warm_model) {
+            return;
+        }
+        if fresh.is_none() {
+            log::info!(
+                "Dropping the cached OpenRouter model {}, which the catalog no longer lists",
+                warm_model.id()
+            );
+        }
+        self.warm_model = fresh;
+        self.write_warm_model_cache(cx);
+    }
+
+    /// Reads the cached last-used model, which stands in for its catalog entry until the
+    /// catalog loads. A model picked or a catalog loaded in the meantime takes precedence.
+    fn load_warm_model_cache(&mut self, cx: &mut Context<Self>) {
+        let settings = &AllLanguageModelSettings::get_global(cx).openrouter;
+        if !settings.cache_last_used_model {
+            return;
+        }
+        let api_url = settings.api_url.clone();
+        let fs = self.fs.clone();
         cx.spawn(async move |this, cx| {
-            credentials_provider
-                .delete_credentials(&api_url, &cx)
-                .await
-                .log_err();
+            let Ok(cache) = fs.load(&warm_model_cache_path()).await else {
+                return Ok(());
+            };
+            let cache = serde_json::from_str::<CachedWarmModel>(&cache)?;
+            // Catalogs differ between gateways, so an entry from another API URL doesn't apply.
+            if cache.api_url != api_url {
+                return Ok(());
+            }
             this.update(cx, |this, cx| {
-                this.api_key = None;
-                this.api_key_from_env = false;
-                cx.notify();
+                if this.warm_model.is_none() && this.catalog.is_none() {
+                    this.warm_model = Some(cache.model);
+                    this.update_enabled_models(cx);
+                    cx.notify();
+                }
             })
         })
+        .detach_and_log_err(cx);
     }
 
-    fn set_api_key(&mut self, api_key: String, cx: &mut Context<Self>) -> Task<Result<()>> {
-        let credentials_provider = <dyn CredentialsProvider>::global(cx);
+    fn write_warm_model_cache(&self, cx: &mut Context<Self>) {
+        let cache = self.warm_model.clone().map(|model| CachedWarmModel {
+            api_url: AllLanguageModelSettings::get_global(cx)
+                .openrouter
+                .api_url
+                .clone(),
+            model,
+        });
+        let fs = self.fs.clone();
+        cx.background_spawn(async move {
+            let path = warm_model_cache_path();
+            let Some(cache) = cache else {
+                return fs
+                    .remove_file(
+                        &path,
+                        RemoveOptions {
+                            ignore_if_not_exists: true,
+                            ..Default::default()
+                        },
+                    )
+                    .await;
+            };
+            if let Some(dir) = path.parent() {
+                fs.create_dir(dir).await?;
+            }
+            fs.atomic_write(path, serde_json::to_string(&cache)?).await
+        })
+        .detach_and_log_err(cx);
+    }
+
+    /// Whether `id` is one of [`RECOMMENDED_MODEL_IDS`] and still offered by the live catalog, so
+    /// retired recommendations stop being suggested.
+    fn is_recommended(&self, id: &str) -> bool {
+        RECOMMENDED_MODEL_IDS.contains(&id) && self.catalog_entry(id).is_some()
+    }
+
+    /// The configured aliases whose chains are not empty, each as the model its first entry
+    /// describes, renamed to the alias.
+    /// Aliases whose name collides with an enabled model or an earlier alias are left out, so
+    /// every offered model has a distinct id.
+    fn alias_models(&self, cx: &App) -> Vec<(ModelAlias, AvailableModel)> {
+        let mut ids = self
+            .enabled_models
+            .iter()
+            .map(|model| model.name.as_str())
+            .collect::<HashSet<_>>();
+        AllLanguageModelSettings::get_global(cx)
+            .openrouter
+            .model_aliases
+            .iter()
+            .filter(|alias| ids.insert(&alias.name))
+            .filter_map(|alias| {
+                let head = alias.models.first()?;
+                let mut model = self
+                    .enabled_models
+                    .iter()
+                    .find(|model| &model.name == head)
+                    .cloned()
+                    .or_else(|| self.catalog_entry(head).map(AvailableModel::from))?;
+                model.display_name = Some(
+                    alias
+                        .display_name
+                        .clone()
+                        .unwrap_or_else(|| alias.name.clone()),
+                );
+                Some((alias.clone(), model))
+            })
+            .collect()
+    }
+
+    /// The models offered in the model picker.
+    ///
+    /// By default these are the enabled models that support tools, so the agent can use any of
+    /// them. When `show_all_models` is set, the whole catalog is offered, keeping the user's
+    /// configuration for models they have enabled.
+    fn visible_models(&self) -> Vec<AvailableModel> {
+        if self.show_all_models {
+            self.catalog
+                .iter()
+                .flatten()
+                .map(|entry| {
+                    self.enabled_models
+                        .iter()
+                        .find(|model| model.name == entry.id())
+                        .cloned()
+                        .unwrap_or_else(|| AvailableModel::from(entry))
+                })
+                .collect()
+        } else {
+            self.enabled_models
+                .iter()
+                .filter(|model| {
+                    self.catalog_entry(&model.name)
+                        .is_some_and(|entry| entry.supports_tools())
+                })
+                .cloned()
+                .collect()
+        }
+    }
+
+    fn set_show_all_models(&mut self, show_all_models: bool, cx: &mut Context<Self>) {
+        self.show_all_models = show_all_models;
+        cx.notify();
+    }
+
+    fn update_enabled_models(&mut self, cx: &App) {
+        let settings = &AllLanguageModelSettings::get_global(cx).openrouter;
+        self.enabled_models = match self.catalog.as_ref() {
+            Some(catalog) => dedup_by_id(
+                settings
+                    .available_models
+                    .iter()
+                    .filter(|model| catalog.iter().any(|entry| entry.id() == model.name))
+                    .cloned()
+                    .collect(),
+                |model| &model.name,
+                "model",
+            ),
+            // Until the catalog loads, only the cached last-used model is known to exist.
+            None => self
+                .warm_model
+                .iter()
+                .filter_map(|warm_model| {
+                    settings
+                        .available_models
+                        .iter()
+                        .find(|model| model.name == warm_model.id())
+                        .cloned()
+                })
+                .collect(),
+        };
+        let mut ids = self
+            .enabled_models
+            .iter()
+            .map(|model| model.name.as_str())
+            .collect::<HashSet<_>>();
+        for alias in &settings.model_aliases {
+            if !ids.insert(&alias.name) {
+                log::warn!(
+                    "Ignoring OpenRouter model alias \"{}\", which reuses the id of another model",
+                    alias.name
+                );
+            }
+        }
+
+        let suggestions = match self.catalog.as_ref() {
+            Some(catalog) => settings
+                .available_models
+                .iter()
+                .filter(|model| catalog.iter().all(|entry| entry.id() != model.name))
+                .filter_map(|model| {
+                    let suggestion = suggest_model_id(&model.name, catalog)?;
+                    Some((model.name.clone(), suggestion.to_string()))
+                })
+                .collect(),
+            None => Vec::new(),
+        };
+        for (id, suggestion) in &suggestions {
+            if !self
+                .model_id_suggestions
+                .contains(&(id.clone(), suggestion.clone()))
+            {
+                log::warn!("OpenRouter model \"{id}\" not found. Did you mean \"{suggestion}\"?");
+            }
+        }
+        self.model_id_suggestions = suggestions;
+    }
+
+    fn fetch_catalog(&mut self, cx: &mut Context<Self>) -> Task<Result<()>> {
+        let http_client = self.http_client.clone();
         let api_url = AllLanguageModelSettings::get_global(cx)
             .openrouter
             .api_url
             .clone();
         cx.spawn(async move |this, cx| {
-            credentials_provider
-                .write_credentials(&api_url, "Bearer", api_key.as_bytes(), &cx)
-                .await
-                .log_err();
+            let models = open_router::list_models(http_client.as_ref(), &api_url).await;
+
             this.update(cx, |this, cx| {
-                this.api_key = Some(api_key);
+                let mut models = match models {
+                    Ok(models) => models,
+                    Err(error) => {
+                        this.catalog_error = Some(error.to_string().into());
+                        cx.notify();
+                        return Err(error);
+                    }
+                };
+                let mut models = dedup_by_id(models, open_router::Model::id, "catalog entry");
+                models.sort_by(|a, b| a.id.cmp(&b.id));
+                if let Some(previous) = this.catalog.as_deref() {
+                    let changes = CatalogChanges::between(previous, &models);
+                    if !changes.is_empty() {
+                        log::info!("OpenRouter catalog changed: {changes:?}");
+                        this.notify_catalog_changes(&changes, cx);
+                        this.enable_new_models(&changes.added, &models, cx);
+                    }
+                }
+                this.catalog = Some(models);
+                this.catalog_fetched_at = Some(Local::now());
+                this.catalog_error = None;
+                this.write_catalog_cache(api_url, cx);
+                this.validate_warm_model(cx);
+                this.update_enabled_models(cx);
                 cx.notify();
-            })
+                Ok(())
+            })?
         })
     }
 
-    fn authenticate(&self, cx: &mut Context<Self>) -> Task<Result<(), AuthenticateError>> {
-        if self.is_authenticated() {
-            return Task::ready(Ok(()));
+    /// Fetches the catalog if it isn't loaded yet, or with `auto_refresh_catalog` disabled, loads
+    /// the one cached by the last fetch instead.
+    fn ensure_catalog(&mut self, cx: &mut Context<Self>) {
+        if self.catalog.is_some() {
+            return;
+        }
+        if AllLanguageModelSettings::get_global(cx)
+            .openrouter
+            .auto_refresh_catalog
+        {
+            self.restart_fetch_catalog_task(cx);
+        } else {
+            self.load_catalog_cache(cx);
         }
+    }
 
-        let credentials_provider = <dyn CredentialsProvider>::global(cx);
+    fn load_catalog_cache(&mut self, cx: &mut Context<Self>) {
+        let fs = self.fs.clone();
         let api_url = AllLanguageModelSettings::get_global(cx)
             .openrouter
             .api_url
             .clone();
         cx.spawn(async move |this, cx| {
-            let (api_key, from_env) = std::env::var(OPENROUTER_API_KEY_VAR)
-                .map(|key| (key, true))
-                .or_else(async |_| {
-                    credentials_provider
-                        .read_credentials(&api_url, &cx)
-                        .await?
-                        .ok_or(AuthenticateError::CredentialsNotFound)
-                        .and_then(|(_, api_key)| {
-                            String::from_utf8(api_key)
-                                .map(|key| (key, false))
-                                .context("invalid {PROVIDER_NAME} API key")
-                        })
-                })?;
+            let cache = fs.load(&catalog_cache_path()).await?;
+            let cache = serde_json::from_str::<CachedCatalog>(&cache)?;
+            // Catalogs differ between gateways, so a cache from another API URL doesn't apply.
+            if cache.api_url != api_url {
+                return Ok(());
+            }
             this.update(cx, |this, cx| {
-                this.api_key = Some(api_key);
-                this.api_key_from_env = from_env;
-                cx.notify();
-            })?;
-
-            Ok(())
+                if this.catalog.is_none() {
+                    this.catalog = Some(cache.models);
+                    this.catalog_fetched_at = Some(cache.fetched_at);
+                    this.update_enabled_models(cx);
+                    cx.notify();
+                }
+            })
         })
+        .detach_and_log_err(cx);
     }
-}
 
-impl OpenRouterLanguageModelProvider {
-    pub fn new(http_client: Arc<dyn HttpClient>, cx: &mut App) -> Self {
-        let state = cx.new(|cx| State {
-            api_key: None,
-            api_key_from_env: false,
-            _subscription: cx.observe_global::<SettingsStore>(|_this: &mut State, cx| {
-                cx.notify();
-            }),
-        });
-
-        Self { http_client, state }
+    fn write_catalog_cache(&self, api_url: String, cx: &mut Context<Self>) {
+        let (Some(models), Some(fetched_at)) = (self.catalog.clone(), self.catalog_fetched_at)
+        else {
+            return;
+        };
+        let cache = CachedCatalog {
+            api_url,
+            fetched_at,
+            models,
+        };
+        let fs = self.fs.clone();
+        cx.background_spawn(async move {
+            let path = catalog_cache_path();
+            if let Some(dir) = path.parent() {
+                fs.create_dir(dir).await?;
+            }
+            fs.atomic_write(path, serde_json::to_string(&cache)?).await
+        })
+        .detach_and_log_err(cx);
     }
-}
 
-impl LanguageModelProviderState for OpenRouterLanguageModelProvider {
-    type ObservableEntity = State;
-
-    fn observable_entity(&self) -> Option<gpui::Entity<Self::ObservableEntity>> {
-        Some(self.state.clone())
+    /// Whether the catalog comes from a cache old enough that it may be missing recent models.
+    fn is_catalog_stale(&self, cx: &App) -> bool {
+        let auto_refresh = AllLanguageModelSettings::get_global(cx)
+            .openrouter
+            .auto_refresh_catalog;
+        !auto_refresh
+            && self.catalog_fetched_at.is_some_and(|fetched_at| {
+                Local::now() - fetched_at > chrono::Duration::hours(CATALOG_STALE_AFTER_HOURS)
+            })
     }
-}
 
-impl LanguageModelProvider for OpenRouterLanguageModelProvider {
-    fn id(&self) -> LanguageModelProviderId {
-        LanguageModelProviderId(PROVIDER_ID.into())
-    }
+    fn notify_catalog_changes(&self, changes: &CatalogChanges, cx: &mut Context<Self>) {
+        if !AllLanguageModelSettings::get_global(cx)
+            .openrouter
+            .notify_catalog_changes
+        {
+            return;
+        }
 
-    fn name(&self) -> LanguageModelProviderName {
-        LanguageModelProviderName(PROVIDER_NAME.into())
-    }
+        struct CatalogChangesNotification;
 
-    fn icon(&self) -> IconName {
-        IconName::AiOpenRouter
+        let message: SharedString = changes.summary().into();
+        show_app_notification(
+            NotificationId::unique::<CatalogChangesNotification>(),
+            cx,
+            move |cx| cx.new(|cx| MessageNotification::new(message.clone(), cx)),
+        );
     }
 
-    fn default_model(&self, cx: &ui::App) -> Option<std::sync::Arc<dyn LanguageModel>> {
-        // it's good to use openrouter_rs to get user's default model on openrouter settings page
-        // but currently, openrouter doesn't provide a way to get the user's default model
-        // so we'll just use the hard-coded default model
-        //
-        // let model = openrouter_rs::Model::default();
-        Some(Arc::new(OpenRouterLanguageModel {
-            id: LanguageModelId::from(model.id().to_string()),
-            // model,
-            state: self.state.clone(),
-            http_client: self.http_client.clone(),
-            request_limiter: RateLimiter::new(4),
-        }))
-    }
+    /// Adds models that a refresh found newly added to `available_models`, when
+    /// `auto_enable_new_models` is enabled.
+    fn enable_new_models(
+        &self,
+        added: &[String],
+        catalog: &[open_router::Model],
+        cx: &mut Context<Self>,
+    ) {
+        let settings = &AllLanguageModelSettings::get_global(cx).openrouter;
+        if !settings.auto_enable_new_models || added.is_empty() {
+            return;
+        }
+        let new_models = catalog
+            .iter()
+            .filter(|entry| added.iter().any(|id| id == entry.id()))
+            .filter(|entry| {
+                !settings
+                    .available_models
+                    .iter()
+                    .any(|model| model.name == entry.id())
+            })
+            .map(AvailableModel::from)
+            .collect::<Vec<_>>();
+        if new_models.is_empty() {
+            return;
+        }
 
-    fn provided_models(&self, cx: &ui::App) -> Vec<std::sync::Arc<dyn LanguageModel>> {
-        // get models from openrouter_rs
-        // but, openrouter offers too many models
-        // it's better to select a fixed range of models in ConfigurationView
-        // and models selected in ConfigurationView is the final provided models
-        // also, only use models which support tools
-        // TODO: add models selector in ConfigurationView
-        todo!()
+        log::info!("Enabling {} new OpenRouter models", new_models.len());
+        update_settings_file::<AllLanguageModelSettings>(
+            self.fs.clone(),
+            cx,
+            move |settings, _| {
+                settings
+                    .openrouter
+                    .get_or_insert_with(Default::default)
+                    .available_models
+                    .get_or_insert_with(Vec::new)
+                    .extend(new_models);
+            },
+        );
     }
 
-    fn is_authenticated(&self, cx: &ui::App) -> bool {
-        self.state.read(cx).is_authenticated()
+    fn restart_fetch_catalog_task(&mut self, cx: &mut Context<Self>) {
+        let task = self.fetch_catalog(cx);
+        self.fetch_catalog_task.replace(task);
     }
 
-    fn authenticate(&self, cx: &mut ui::App) -> gpui::Task<gpui::Result<(), AuthenticateError>> {
-        self.state.update(cx, |state, cx| state.authenticate(cx))
+    /// Fetches the catalog for browsing before an API key is added. Listing models doesn't need
+    /// a key, so this works while unauthenticated.
+    fn fetch_catalog_preview(&mut self, cx: &mut Context<Self>) {
+        if self.catalog.is_none() {
+            self.restart_fetch_catalog_task(cx);
+        }
     }
 
-    fn configuration_view(&self, window: &mut ui::Window, cx: &mut ui::App) -> gpui::AnyView {
-        cx.new(|cx| ConfigurationView::new(self.state.clone(), window, cx))
-            .into()
-    }
+    fn fetch_credits(&mut self, cx: &mut Context<Self>) -> Task<Result<()>> {
+        let http_client = self.http_client.clone();
+        let api_url = AllLanguageModelSettings::get_global(cx)
+            .openrouter
+            .api_url
+            .clone();
+        let Some(api_key) = self.api_key.clone() else {
+            return Task::ready(Err(anyhow!("Missing OpenRouter API Key")));
+        };
+        cx.spawn(async move |this, cx| {
+            let credits =
+                open_router::get_credits(http_client.as_ref(), &api_url, &api_key).await?;
 
-    fn reset_credentials(&self, cx: &mut ui::App) -> gpui::Task<gpui::Result<()>> {
-        self.state.update(cx, |state, cx| state.reset_api_key(cx))
+            this.update(cx, |this, cx| {
+                this.credits = Some(credits);
+                this.check_low_credit_alert(cx);
+                this.check_low_credit_mode(cx);
+                cx.notify();
+            })
+        })
     }
-}
 
-pub struct OpenRouterLanguageModel {
-    id: LanguageModelId,
-    // model: openrouter_rs::Model,
-    state: gpui::Entity<State>,
-    http_client: Arc<dyn HttpClient>,
-    request_limiter: RateLimiter,
-}
+    /// Notifies the user once when their balance drops below the configured threshold. The alert
+    /// re-arms after the balance recovers, so repeated completions don't show it again.
+    fn check_low_credit_alert(&mut self, cx: &mut Context<Self>) {
+        let settings = &AllLanguageModelSettings::get_global(cx).openrouter;
+        let (true, Some(threshold), Some(credits)) = (
+            settings.low_credit_alerts,
+            settings.low_credit_alert_threshold,
+            self.credits,
+        ) else {
+            return;
+        };
 
-impl LanguageModel for OpenRouterLanguageModel {
-    fn id(&self) -> language_model::LanguageModelId {
-        self.id.clone()
-    }
+        let remaining = credits.remaining();
+        if remaining >= threshold {
+            self.low_credit_alert_shown = false;
+            return;
+        }
+        if self.low_credit_alert_shown {
+            return;
+        }
+        self.low_credit_alert_shown = true;
 
-    fn name(&self) -> language_model::LanguageModelName {
-        // LanguageModelName::from(self.model.display_name().to_string())
-    }
+        struct LowCreditAlert;
 
-    fn provider_id(&self) -> LanguageModelProviderId {
-        LanguageModelProviderId(PROVIDER_ID.into())
+        let message: SharedString = format!(
+            "Your OpenRouter balance is ${remaining:.2}, below your alert threshold of ${threshold:.2}."
+        )
+        .into();
+        show_app_notification(NotificationId::unique::<LowCreditAlert>(), cx, move |cx| {
+            cx.new(|cx| {
+                MessageNotification::new(message.clone(), cx)
+                    .primary_message("Add Credits")
+                    .primary_on_click(|_, cx| cx.open_url("https://openrouter.ai/settings/credits"))
+            })
+        });
     }
 
-    fn provider_name(&self) -> LanguageModelProviderName {
-        LanguageModelProviderName(PROVIDER_NAME.into())
+    /// Whether only free models should be offered, because low-credit mode is on and the balance
+    /// is below the alert threshold.
+    fn low_credit_mode_active(&self, settings: &OpenRouterSettings) -> bool {
+        match (
+            settings.low_credit_mode,
+            settings.low_credit_alert_threshold,
+            self.credits,
+        ) {
+            (true, Some(threshold), Some(credits)) => credits.remaining() < threshold,
+            _ => false,
+        }
     }
 
-    fn telemetry_id(&self) -> String {
-        // format!("openrouter/{}", self.model.id())
+    fn is_free_model(&self, id: &str) -> bool {
+        self.catalog_entry(id).is_some_and(|entry| entry.is_free())
     }
 
-    fn max_token_count(&self) -> usize {
-        // self.model.max_token_count()
-    }
+    /// Warns the user when low-credit mode starts hiding paid models.
+    fn check_low_credit_mode(&mut self, cx: &mut Context<Self>) {
+        let settings = &AllLanguageModelSettings::get_global(cx).openrouter;
+        let active = self.low_credit_mode_active(settings);
+        if active == self.low_credit_mode_engaged {
+            return;
+        }
+        self.low_credit_mode_engaged = active;
+        let (true, Some(credits)) = (active, self.credits) else {
+            return;
+        };
 
-    fn max_output_tokens(&self) -> Option<u32> {
-        // self.model.max_output_tokens()
+        struct LowCreditMode;
+
+        let message: SharedString = format!(
+            "Your OpenRouter balance is ${:.2}, so only free models are offered. Paid models \
+            return once you add credits, or when `low_credit_mode` is turned off.",
+            credits.remaining()
+        )
+        .into();
+        show_app_notification(NotificationId::unique::<LowCreditMode>(), cx, move |cx| {
+            cx.new(|cx| {
+                MessageNotification::new(message.clone(), cx)
+                    .primary_message("Add Credits")
+                    .primary_on_click(|_, cx| cx.open_url("https://openrouter.ai/settings/credits"))
+            })
+        });
     }
 
-    fn count_tokens(
-        &self,
-        request: language_model::LanguageModelRequest,
-        cx: &ui::App,
-    ) -> futures::future::BoxFuture<'static, gpui::Result<usize>> {
-        // call openrouter_rs::get_generation() to count tokens
-        todo!()
+    fn restart_fetch_credits_task(&mut self, cx: &mut Context<Self>) {
+        let task = self.fetch_credits(cx);
+        self.fetch_credits_task.replace(task);
     }
 
-    fn stream_completion(
-        &self,
-        request: language_model::LanguageModelRequest,
-        cx: &gpui::AsyncApp,
-    ) -> futures::future::BoxFuture<
-        'static,
-        gpui::Result<
-            futures::stream::BoxStream<
-                'static,
-                gpui::Result<language_model::LanguageModelCompletionEvent>,
-            >,
-        >,
-    > {
-        todo!()
-    }
-
-    fn use_any_tool(
-        &self,
-        request: language_model::LanguageModelRequest,
-        name: String,
-        description: String,
-        schema: serde_json::Value,
-        cx: &gpui::AsyncApp,
-    ) -> futures::future::BoxFuture<
-        'static,
-        gpui::Result<futures::stream::BoxStream<'static, gpui::Result<String>>>,
-    > {
-        todo!()
-    }
-}
-
-struct ConfigurationView {
-    api_key_editor: Entity<Editor>,
-    state: gpui::Entity<State>,
-    load_credentials_task: Option<Task<()>>,
-}
-
-impl ConfigurationView {
-    fn new(state: Entity<State>, window: &mut Window, cx: &mut Context<Self>) -> Self {
-        let api_key_editor = cx.new(|cx| {
-            let mut editor = Editor::single_line(window, cx);
-            editor.set_placeholder_text(
-                "sk-or-v1-0000000000000000000000000000000000000000000000000000000000000000",
-                cx,
-            );
-            editor
-        });
+    /// Sends a 1-token completion to `model_id` to check that the current key and routing can
+    /// actually serve it, e.g. that it isn't region-restricted. This costs a tiny amount of credit,
+    /// so it only runs when the user asks for it.
+    fn probe_model(&mut self, model_id: String, cx: &mut Context<Self>) {
+        let Some(api_key) = self.api_key.clone() else {
+            return;
+        };
+        let settings = &AllLanguageModelSettings::get_global(cx).openrouter;
+        let api_url = settings.api_url.clone();
+        // A cached answer from a proxy wouldn't tell us anything about the model.
+        let options = open_router::RequestOptions {
+            idempotency_key: false,
+            ..settings.request_options()
+        };
+        let request = open_router::Request {
+            model: model_id.clone(),
+            models: Vec::new(),
+            messages: vec![open_router::RequestMessage::User {
+                content: "Hi".into(),
+            }],
+            stream: false,
+            max_tokens: Some(1),
+            stop: Vec::new(),
+            temperature: None,
+            top_p: None,
+            tools: Vec::new(),
+            user: None,
+            reasoning: None,
+            provider: provider_preferences(
+                settings,
+                self.enabled_models
+                    .iter()
+                    .find(|model| model.name == model_id)
+                    .map_or(&[][..], |model| model.only.as_slice()),
+            ),
+            preset: settings.preset.clone(),
+            metadata: request_metadata(&settings.request_metadata),
+            n: None,
+            tool_choice: None,
+        };
+        let http_client = self.http_client.clone();
 
-        cx.observe(&state, |_, _, cx| {
-            cx.notify();
+        self.model_probes
+            .insert(model_id.clone(), ModelProbe::Pending);
+        cx.notify();
+        cx.spawn(async move |this, cx| {
+            let result =
+                open_router::complete(http_client.as_ref(), &api_url, &api_key, request, options)
+                    .await;
+            this.update(cx, |this, cx| {
+                let probe = match result {
+                    Ok(_) => ModelProbe::Available,
+                    Err(error) => ModelProbe::Unavailable(error.to_string().into()),
+                };
+                this.model_probes.insert(model_id, probe);
+                cx.notify();
+            })
+            .ok();
         })
         .detach();
+    }
 
-        let load_credentials_task = Some(cx.spawn_in(window, {
-            let state = state.clone();
-            async move |this, cx| {
-                if let Some(task) = state
-                    .update(cx, |state, cx| state.authenticate(cx))
-                    .log_err()
-                {
-                    // We don't log an error, because "not signed in" is also an error.
-                    let _ = task.await;
+    /// Periodically refreshes the catalog so new models show up during long sessions.
+    fn restart_refresh_catalog_task(&mut self, cx: &mut Context<Self>) {
+        let settings = &AllLanguageModelSettings::get_global(cx).openrouter;
+        let interval_hours = settings
+            .catalog_refresh_interval_hours
+            .filter(|_| settings.auto_refresh_catalog);
+        self.refresh_catalog_task = interval_hours.filter(|hours| *hours > 0).map(|hours| {
+            let interval = Duration::from_secs(hours * 60 * 60);
+            cx.spawn(async move |this, cx| {
+                loop {
+                    cx.background_executor().timer(interval).await;
+                    if this
+                        .update(cx, |this, cx| this.restart_fetch_catalog_task(cx))
+                        .is_err()
+                    {
+                        break;
+                    }
                 }
+            })
+        });
+    }
 
-                this.update(cx, |this, cx| {
-                    this.load_credentials_task = None;
-                    cx.notify();
-                })
-                .log_err();
-            }
-        }));
-
-        Self {
-            api_key_editor,
-            state,
-            load_credentials_task,
-        }
+    /// Credentials are stored per API URL unless a `credentials_scope` is set, so switching
+    /// either requires authenticating again.
+    fn reauthenticate_for_api_url_change(&mut self, cx: &mut Context<Self>) {
+        self.api_key = None;
+        self.api_key_from_env = false;
+        self.catalog = None;
+        // The cached entry describes the old gateway's model.
+        self.warm_model = None;
+        self.fetch_catalog_task = None;
+        self.credits = None;
+        self.fetch_credits_task = None;
+        self.authenticate(cx).detach();
     }
 
-    fn save_api_key(&mut self, _: &menu::Confirm, window: &mut Window, cx: &mut Context<Self>) {
-        let api_key = self.api_key_editor.read(cx).text(cx);
-        if api_key.is_empty() {
-            return;
-        }
+    fn reset_api_key(&mut self, cx: &mut Context<Self>) -> Task<Result<()>> {
+        // Dropping the in-flight fetch cancels it, so it can't repopulate the catalog afterwards.
+        self.fetch_catalog_task = None;
+        self.catalog = None;
+        self.catalog_fetched_at = None;
+        self.catalog_error = None;
+        self.update_enabled_models(cx);
+        cx.notify();
 
-        let state = self.state.clone();
-        cx.spawn_in(window, async move |_, cx| {
-            state
-                .update(cx, |state, cx| state.set_api_key(api_key, cx))?
+        let credentials_provider = <dyn CredentialsProvider>::global(cx);
+        let credentials_key = AllLanguageModelSettings::get_global(cx)
+            .openrouter
+            .credentials_key()
+            .to_string();
+        cx.spawn(async move |this, cx| {
+            credentials_provider
+                .delete_credentials(&credentials_key, &cx)
                 .await
+                .log_err();
+            this.update(cx, |this, cx| {
+                this.api_key = None;
+                this.api_key_from_env = false;
+                this.credits = None;
+                this.fetch_credits_task = None;
+                cx.notify();
+            })
         })
-        .detach_and_log_err(cx);
-
-        cx.notify();
     }
 
-    fn reset_api_key(&mut self, window: &mut Window, cx: &mut Context<Self>) {
-        self.api_key_editor
-            .update(cx, |editor, cx| editor.set_text("", window, cx));
+    /// Reports the usage accumulated so far by completions that are still streaming. This only
+    /// queues telemetry events, so it can't hold up quitting.
+    fn flush_in_flight_usage(&mut self) {
+        for (_, record) in self.in_flight_usage.lock().drain() {
+            telemetry::event!(
+                "OpenRouter Completion Interrupted",
+                model = record.telemetry_id,
+                input_tokens = record.usage.input_tokens,
+                output_tokens = record.usage.output_tokens,
+            );
+        }
+    }
 
-        let state = self.state.clone();
-        cx.spawn_in(window, async move |_, cx| {
-            state.update(cx, |state, cx| state.reset_api_key(cx))?.await
+    /// Returns the provider to its first-run condition: the stored API key and the on-disk catalog
+    /// cache are deleted and everything fetched or remembered since startup is forgotten.
+    /// Settings are left alone.
+    fn reset_all(&mut self, cx: &mut Context<Self>) -> Task<Result<()>> {
+        self.warm_model = None;
+        self.write_warm_model_cache(cx);
+        self.low_credit_alert_shown = false;
+        self.low_credit_mode_engaged = false;
+        self.show_all_models = false;
+        self.model_probes.clear();
+        self.last_failure.lock().take();
+        self.last_request_params.lock().take();
+        self.exact_token_count_unavailable
+            .store(false, Ordering::Relaxed);
+        let fs = self.fs.clone();
+        let remove_catalog_cache = cx.background_spawn(async move {
+            fs.remove_file(
+                &catalog_cache_path(),
+                RemoveOptions {
+                    ignore_if_not_exists: true,
+                    ..Default::default()
+                },
+            )
+            .await
+        });
+        let reset_api_key = self.reset_api_key(cx);
+        cx.background_spawn(async move {
+            remove_catalog_cache.await?;
+            reset_api_key.await
         })
-        .detach_and_log_err(cx);
+    }
+
+    fn set_api_key(&mut self, api_key: String, cx: &mut Context<Self>) -> Task<Result<()>> {
+        let credentials_provider = <dyn CredentialsProvider>::global(cx);
+        let credentials_key = AllLanguageModelSettings::get_global(cx)
+            .openrouter
+            .credentials_key()
+            .to_string();
+        cx.spawn(async move |this, cx| {
+            credentials_provider
+                .write_credentials(&credentials_key, "Bearer", api_key.as_bytes(), &cx)
+                .await
+                .log_err();
+            this.update(cx, |this, cx| {
+                this.api_key = Some(api_key);
+                this.api_key_revoked = false;
+                this.ensure_catalog(cx);
+                this.restart_fetch_credits_task(cx);
+                cx.notify();
+            })
+        })
+    }
+
+    fn authenticate(&mut self, cx: &mut Context<Self>) -> Task<Result<(), AuthenticateError>> {
+        if self.is_authenticated() {
+            return Task::ready(Ok(()));
+        }
+
+        let credentials_provider = <dyn CredentialsProvider>::global(cx);
+        let settings = &AllLanguageModelSettings::get_global(cx).openrouter;
+        let api_url = settings.api_url.clone();
+        let credentials_key = settings.credentials_key().to_string();
+        let validate_api_key = settings.validate_api_key;
+        let http_client = self.http_client.clone();
+        cx.spawn(async move |this, cx| {
+            let (api_key, from_env) = if let Ok(api_key) = std::env::var(OPENROUTER_API_KEY_VAR) {
+                (api_key, true)
+            } else {
+                let (_, api_key) = credentials_provider
+                    .read_credentials(&credentials_key, &cx)
+                    .await?
+                    .ok_or(AuthenticateError::CredentialsNotFound)?;
+                (parse_stored_api_key(api_key)?, false)
+            };
+
+            if validate_api_key {
+                if let Err(error) =
+                    open_router::get_key_info(http_client.as_ref(), &api_url, &api_key).await
+                {
+                    let revoked = matches!(
+                        error.downcast_ref::<open_router::OpenRouterError>(),
+                        Some(open_router::OpenRouterError::Unauthorized(_))
+                    );
+                    if revoked {
+                        if !from_env {
+                            credentials_provider
+                                .delete_credentials(&credentials_key, &cx)
+                                .await
+                                .log_err();
+                        }
+                        this.update(cx, |this, cx| {
+                            this.api_key_revoked = true;
+                            cx.notify();
+                        })?;
+                        return Err(anyhow!(
+                            "The {PROVIDER_NAME} API key was revoked, please enter a new one"
+                        )
+                        .into());
+                    }
+                    // Being offline shouldn't lock anyone out, so only a rejection counts.
+                    log::warn!("Couldn't validate the OpenRouter API key: {error:#}");
+                }
+            }
+
+            this.update(cx, |this, cx| {
+                this.api_key = Some(api_key);
+                this.api_key_from_env = from_env;
+                this.ensure_catalog(cx);
+                this.restart_fetch_credits_task(cx);
+                cx.notify();
+            })?;
+
+            Ok(())
+        })
+    }
+}
+impl OpenRouterLanguageModelProvider {
+    pub fn new(http_client: Arc<dyn HttpClient>, fs: Arc<dyn Fs>, cx: &mut App) -> Self {
+        let state = cx.new(|cx| {
+            let (refresh_credits_tx, mut refresh_credits_rx) = mpsc::unbounded();
+            let mut state = State {
+                api_key: None,
+                api_key_from_env: false,
+                api_key_revoked: false,
+                http_client: http_client.clone(),
+                fs,
+                catalog: None,
+                catalog_fetched_at: None,
+                warm_model: None,
+                catalog_error: None,
+                credits: None,
+                low_credit_alert_shown: false,
+                low_credit_mode_engaged: false,
+                model_id_suggestions: Vec::new(),
+                show_all_models: false,
+                enabled_models: Vec::new(),
+                fetch_catalog_task: None,
+                fetch_credits_task: None,
+                refresh_catalog_task: None,
+                exact_token_count_unavailable: Arc::new(AtomicBool::new(false)),
+                model_probes: HashMap::default(),
+                last_failure: Arc::default(),
+                last_request_params: Arc::default(),
+                transcript_started: Arc::default(),
+                refresh_credits_tx,
+                in_flight_usage: InFlightUsageRecords::default(),
+                usage_unavailable: Arc::default(),
+                _quit_subscription: cx.on_app_quit(|this: &mut State, _| {
+                    this.flush_in_flight_usage();
+                    futures::future::ready(())
+                }),
+                _refresh_credits_task: cx.spawn(async move |this, cx| {
+                    while refresh_credits_rx.next().await.is_some() {
+                        if this
+                            .update(cx, |this, cx| this.restart_fetch_credits_task(cx))
+                            .is_err()
+                        {
+                            break;
+                        }
+                    }
+                }),
+                _subscription: cx.observe_global::<SettingsStore>({
+                    let mut settings = AllLanguageModelSettings::get_global(cx).openrouter.clone();
+                    move |this: &mut State, cx| {
+                        let new_settings = &AllLanguageModelSettings::get_global(cx).openrouter;
+                        if &settings != new_settings {
+                            let credentials_changed = settings.api_url != new_settings.api_url
+                                || settings.credentials_key() != new_settings.credentials_key();
+                            let refresh_interval_changed = settings.catalog_refresh_interval_hours
+                                != new_settings.catalog_refresh_interval_hours
+                                || settings.auto_refresh_catalog
+                                    != new_settings.auto_refresh_catalog;
+                            if settings.request_metadata != new_settings.request_metadata {
+                                let sent = request_metadata(&new_settings.request_metadata).len();
+                                let ignored = new_settings.request_metadata.len() - sent;
+                                if ignored > 0 {
+                                    log::warn!(
+                                        "Ignoring {ignored} OpenRouter request_metadata tags that \
+                                        exceed the limits on their number or size"
+                                    );
+                                }
+                            }
+                            settings = new_settings.clone();
+                            if credentials_changed {
+                                this.reauthenticate_for_api_url_change(cx);
+                            }
+                            if refresh_interval_changed {
+                                this.restart_refresh_catalog_task(cx);
+                            }
+                            this.update_enabled_models(cx);
+                            cx.notify();
+                        }
+                    }
+                }),
+            };
+            state.update_enabled_models(cx);
+            state.load_warm_model_cache(cx);
+            state.restart_refresh_catalog_task(cx);
+            state
+        });
+        model_switcher::register(state.downgrade(), cx);
+
+        Self {
+            http_client,
+            state,
+            request_limiters: RequestLimiters::default(),
+        }
+    }
+
+    /// Returns the models the user has enabled in their settings that are also present in the
+    /// cached OpenRouter catalog.
+    ///
+    /// This never fetches, and is empty until the catalog has been loaded.
+    pub fn enabled_models<'a>(&self, cx: &'a App) -> &'a [AvailableModel] {
+        &self.state.read(cx).enabled_models
+    }
+
+    /// Returns the offered model with the given id as its concrete type, for callers that need
+    /// OpenRouter-specific capabilities such as
+    /// [`OpenRouterLanguageModel::stream_completion_with_api_url`].
+    pub fn language_model(&self, id: &str, cx: &App) -> Option<OpenRouterLanguageModel> {
+        let state = self.state.read(cx);
+        if let Some(model) = state
+            .visible_models()
+            .into_iter()
+            .find(|model| model.name == id)
+        {
+            return Some(self.build_language_model(model, cx));
+        }
+        let (alias, model) = state
+            .alias_models(cx)
+            .into_iter()
+            .find(|(alias, _)| alias.name == id)?;
+        Some(self.build_alias_language_model(alias, model, cx))
+    }
+
+    fn create_language_model(&self, model: AvailableModel, cx: &App) -> Arc<dyn LanguageModel> {
+        Arc::new(self.build_language_model(model, cx))
+    }
+
+    /// Builds the model for `alias`, selected by the alias name and sent with its whole chain.
+    fn build_alias_language_model(
+        &self,
+        alias: ModelAlias,
+        model: AvailableModel,
+        cx: &App,
+    ) -> OpenRouterLanguageModel {
+        OpenRouterLanguageModel {
+            id: LanguageModelId::from(alias.name),
+            fallback_models: alias.models,
+            ..self.build_language_model(model, cx)
+        }
+    }
+
+    fn build_language_model(&self, model: AvailableModel, cx: &App) -> OpenRouterLanguageModel {
+        let catalog_entry = self.state.read(cx).catalog_entry(&model.name);
+        let supports_tools = catalog_entry.is_some_and(|entry| entry.supports_tools());
+        let supports_reasoning = catalog_entry.is_none_or(|entry| entry.supports_reasoning());
+        let max_output_tokens_ceiling = catalog_entry.and_then(|entry| entry.max_output_tokens());
+        let (input_modalities, output_modalities) = match catalog_entry {
+            Some(entry) => (entry.input_modalities(), entry.output_modalities()),
+            None => (
+                vec![open_router::Modality::Text],
+                vec![open_router::Modality::Text],
+            ),
+        };
+
+        OpenRouterLanguageModel {
+            id: LanguageModelId::from(model.name.clone()),
+            model,
+            fallback_models: Vec::new(),
+            supports_tools,
+            supports_reasoning,
+            model_id_format: AllLanguageModelSettings::get_global(cx)
+                .openrouter
+                .log_model_ids,
+            max_output_tokens_ceiling,
+            input_modalities,
+            output_modalities,
+            state: self.state.clone(),
+            http_client: self.http_client.clone(),
+            request_limiters: self.request_limiters.clone(),
+        }
+    }
+}
+impl LanguageModelProviderState for OpenRouterLanguageModelProvider {
+    type ObservableEntity = State;
+
+    fn observable_entity(&self) -> Option<Entity<Self::ObservableEntity>> {
+        Some(self.state.clone())
+    }
+}
+impl LanguageModelProvider for OpenRouterLanguageModelProvider {
+    fn id(&self) -> LanguageModelProviderId {
+        LanguageModelProviderId(PROVIDER_ID.into())
+    }
+
+    fn name(&self) -> LanguageModelProviderName {
+        LanguageModelProviderName(PROVIDER_NAME.into())
+    }
+
+    fn icon(&self) -> IconName {
+        IconName::AiOpenRouter
+    }
+
+    fn default_model(&self, cx: &App) -> Option<Arc<dyn LanguageModel>> {
+        // OpenRouter doesn't expose the user's default model, so use the first enabled one.
+        self.provided_models(cx).into_iter().next()
+    }
+
+    fn default_fast_model(&self, cx: &App) -> Option<Arc<dyn LanguageModel>> {
+        self.default_model(cx)
+    }
+
+    fn provided_models(&self, cx: &App) -> Vec<Arc<dyn LanguageModel>> {
+        let mut models = BTreeMap::default();
+        let state = self.state.read(cx);
+        let free_only =
+            state.low_credit_mode_active(&AllLanguageModelSettings::get_global(cx).openrouter);
+
+        for model in state.visible_models() {
+            if free_only && !state.is_free_model(&model.name) {
+                continue;
+            }
+            // Keep the first definition so the same id always resolves to the same model.
+            models.entry(model.name.clone()).or_insert(model);
+        }
+
+        let mut aliases = state.alias_models(cx);
+        if free_only {
+            aliases.retain(|(alias, _)| alias.models.iter().all(|id| state.is_free_model(id)));
+        }
+        models
+            .into_values()
+            .map(|model| self.create_language_model(model, cx))
+            .chain(aliases.into_iter().map(|(alias, model)| {
+                Arc::new(self.build_alias_language_model(alias, model, cx))
+                    as Arc<dyn LanguageModel>
+            }))
+            .collect()
+    }
+
+    fn is_authenticated(&self, cx: &App) -> bool {
+        self.state.read(cx).is_authenticated()
+    }
+
+    fn authenticate(&self, cx: &mut App) -> Task<Result<(), AuthenticateError>> {
+        self.state.update(cx, |state, cx| state.authenticate(cx))
+    }
+
+    fn configuration_view(&self, window: &mut Window, cx: &mut App) -> AnyView {
+        cx.new(|cx| ConfigurationView::new(self.state.clone(), window, cx))
+            .into()
+    }
+
+    fn reset_credentials(&self, cx: &mut App) -> Task<Result<()>> {
+        self.state.update(cx, |state, cx| state.reset_api_key(cx))
+    }
+}
+pub struct OpenRouterLanguageModel {
+    id: LanguageModelId,
+    model: AvailableModel,
+    /// For aliases, the chain of models OpenRouter tries in order.
+    fallback_models: Vec<String>,
+    supports_tools: bool,
+    /// Whether requests carry reasoning options. Only false for models the catalog lists
+    /// without reasoning support, since models missing from the catalog may well have it.
+    supports_reasoning: bool,
+    /// How the model's id appears in telemetry, from the `log_model_ids` setting.
+    model_id_format: ModelIdFormat,
+    max_output_tokens_ceiling: Option<u32>,
+    input_modalities: Vec<open_router::Modality>,
+    output_modalities: Vec<open_router::Modality>,
+    state: Entity<State>,
+    http_client: Arc<dyn HttpClient>,
+    request_limiters: RequestLimiters,
+}
+impl OpenRouterLanguageModel {
+    /// The kinds of input this model accepts, for gating affordances like image attachments.
+    pub fn input_modalities(&self) -> &[open_router::Modality] {
+        &self.input_modalities
+    }
+
+    pub fn output_modalities(&self) -> &[open_router::Modality] {
+        &self.output_modalities
+    }
+
+    pub fn accepts_images(&self) -> bool {
+        self.input_modalities
+            .contains(&open_router::Modality::Image)
+    }
+
+    fn request_limiter(&self) -> RateLimiter {
+        self.request_limiters.for_model(&self.model.name)
+    }
+
+    /// The output limit sent with a request: the model's configured limit, falling back to
+    /// `default_max_output_tokens` so ad-hoc prompts can't run to the model's full maximum.
+    fn request_max_output_tokens(&self, settings: &OpenRouterSettings) -> u32 {
+        let max_output_tokens = self
+            .model
+            .max_output_tokens
+            .unwrap_or(settings.default_max_output_tokens);
+        match self.max_output_tokens_ceiling {
+            Some(ceiling) => max_output_tokens.min(ceiling),
+            None => max_output_tokens,
+        }
+    }
+
+    /// How many tokens of input fit in the context window while leaving room for the reply.
+    ///
+    /// The reply's room is the output limit sent with requests, or `min_reserved_output_tokens`
+    /// when that is larger. Context trimming should budget against this rather than
+    /// [`LanguageModel::max_token_count`], since OpenRouter rejects requests whose prompt plus
+    /// `max_tokens` exceed the context window.
+    pub fn max_input_tokens(&self, cx: &App) -> usize {
+        let settings = &AllLanguageModelSettings::get_global(cx).openrouter;
+        let reserved_output = self
+            .request_max_output_tokens(settings)
+            .max(settings.min_reserved_output_tokens);
+        self.max_token_count()
+            .saturating_sub(reserved_output as usize)
+    }
+
+    /// Fails when `request`'s estimated size exceeds the context window, sparing a round-trip
+    /// that the server would reject anyway.
+    fn check_context_fits(&self, request: &LanguageModelRequest) -> Result<()> {
+        let estimated_tokens = estimate_request_tokens(&self.model.name, request);
+        let max_tokens = self.max_token_count();
+        if estimated_tokens <= max_tokens {
+            return Ok(());
+        }
+        Err(anyhow!(
+            LanguageModelKnownError::ContextWindowLimitExceeded {
+                tokens: estimated_tokens
+            }
+        ))
+        .with_context(|| {
+            format!(
+                "Prompt exceeds the context window of {}: an estimated {estimated_tokens} tokens, \
+                but the model accepts at most {max_tokens}",
+                self.model.name
+            )
+        })
+    }
+
+    /// Streams a completion like [`LanguageModel::stream_completion`] that ends as soon as
+    /// `cancellation` is aborted, e.g. because the user edited the prompt. Unlike dropping the
+    /// stream, this releases the request and its concurrency slot even while the caller still
+    /// holds on to the stream.
+    ///
+    /// A cancelled stream ends with [`StopReason::Cancelled`] rather than an error, so callers
+    /// can tell a deliberate abort from a failure.
+    pub fn stream_completion_with_cancellation(
+        &self,
+        request: LanguageModelRequest,
+        cancellation: AbortRegistration,
+        cx: &AsyncApp,
+    ) -> BoxFuture<'static, Result<BoxStream<'static, Result<LanguageModelCompletionEvent>>>> {
+        let completion = self.stream_completion_with_api_url(request, None, cx);
+        let mut cancelled = Abortable::new(futures::future::pending::<()>(), cancellation);
+        async move {
+            let events = match futures::future::select(&mut cancelled, completion).await {
+                Either::Left(_) => {
+                    return Ok(futures::stream::once(futures::future::ready(Ok(
+                        LanguageModelCompletionEvent::Stop(StopReason::Cancelled),
+                    )))
+                    .boxed());
+                }
+                Either::Right((events, _)) => events?,
+            };
+            Ok(until_cancelled(
+                events,
+                cancelled,
+                Ok(LanguageModelCompletionEvent::Stop(StopReason::Cancelled)),
+            ))
+        }
+        .boxed()
+    }
+
+    /// Streams a completion like [`LanguageModel::stream_completion`], but sends it to `api_url`
+    /// instead of the configured one when given, e.g. to compare two OpenRouter-compatible
+    /// gateways. The override applies to this request only.
+    pub fn stream_completion_with_api_url(
+        &self,
+        request: LanguageModelRequest,
+        api_url: Option<String>,
+        cx: &AsyncApp,
+    ) -> BoxFuture<'static, Result<BoxStream<'static, Result<LanguageModelCompletionEvent>>>> {
+        let Ok(settings) =
+            cx.update(|cx| AllLanguageModelSettings::get_global(cx).openrouter.clone())
+        else {
+            return futures::future::ready(Err(anyhow!("App state dropped"))).boxed();
+        };
+        cx.update(|cx| {
+            self.state.update(cx, |state, cx| {
+                state.remember_last_used_model(&self.model.name, cx)
+            })
+        })
+        .ok();
+
+        let (request, max_output_tokens) = match self.build_request(request, &settings) {
+            Ok(request) => request,
+            Err(error) => return futures::future::ready(Err(error)).boxed(),
+        };
+        let global_limiter = cx.update(|cx| GlobalRateLimiter::global(cx)).ok().flatten();
+        let queued = self.request_limiter().is_saturated()
+            || global_limiter
+                .as_ref()
+                .is_some_and(RateLimiter::is_saturated);
+        let completions = self.send_open_router_request(request, api_url, cx);
+        let future = if queued {
+            // Hand back a stream right away so the UI can explain the delay while the request
+            // waits for a free slot, rather than appearing frozen.
+            let events = futures::stream::once(completions).flat_map(|result| match result {
+                Ok(events) => futures::stream::iter([Ok(
+                    LanguageModelCompletionEvent::StatusUpdate(CompletionRequestStatus::Started),
+                )])
+                .chain(map_to_language_model_completion_events(events))
+                .boxed(),
+                Err(error) => futures::stream::iter([Err(error)]).boxed(),
+            });
+            let events = futures::stream::iter([Ok(LanguageModelCompletionEvent::StatusUpdate(
+                CompletionRequestStatus::Queued,
+            ))])
+            .chain(events);
+            futures::future::ready(Ok(events.boxed())).boxed()
+        } else {
+            async move { Ok(map_to_language_model_completion_events(completions.await?).boxed()) }
+                .boxed()
+        };
+        let future = match max_output_tokens.filter(|_| settings.report_progress) {
+            Some(max_tokens) => {
+                let model_id = self.model.name.clone();
+                future
+                    .map(move |result| {
+                        result.map(|events| report_progress(events, model_id, max_tokens))
+                    })
+                    .boxed()
+            }
+            None => future,
+        };
+        let post_processor = PostProcessor::new(
+            self.model
+                .post_process
+                .as_ref()
+                .unwrap_or(&settings.post_process),
+        );
+        let future = match post_processor {
+            Some(post_processor) => future
+                .map(move |result| result.map(|events| post_process_text(events, post_processor)))
+                .boxed(),
+            None => future,
+        };
+        let future = match settings.coalesce_window_ms.filter(|window| *window > 0) {
+            Some(window) => {
+                let executor = cx.background_executor().clone();
+                future
+                    .map(move |result| {
+                        result.map(|events| {
+                            coalesce_text_events(events, Duration::from_millis(window), executor)
+                        })
+                    })
+                    .boxed()
+            }
+            None => future,
+        };
+        self.track_completion(future, |event| event, &settings, cx)
+    }
+
+    /// Streams `n` alternative completions of `request` at once, tagging each event with the
+    /// index of the choice it belongs to. Models that can't return several choices have `n`
+    /// dropped with a warning, and stream a single choice at index 0.
+    ///
+    /// Usage is tracked as for a single completion, but choices skip progress reports,
+    /// `post_process` and `coalesce_window_ms`: those hold back or re-time text, while
+    /// [`map_choices_to_language_model_completion_events`] relies on each choice's events being
+    /// mapped as soon as they arrive to keep choices apart.
+    pub fn stream_completion_choices(
+        &self,
+        request: LanguageModelRequest,
+        n: u32,
+        cx: &AsyncApp,
+    ) -> BoxFuture<'static, Result<BoxStream<'static, Result<(u32, LanguageModelCompletionEvent)>>>>
+    {
+        let Ok((settings, supports_multiple_choices)) = cx.read_entity(&self.state, |state, cx| {
+            (
+                AllLanguageModelSettings::get_global(cx).openrouter.clone(),
+                state
+                    .catalog_entry(&self.model.name)
+                    .is_some_and(|entry| entry.supports_multiple_choices()),
+            )
+        }) else {
+            return futures::future::ready(Err(anyhow!("App state dropped"))).boxed();
+        };
+        cx.update(|cx| {
+            self.state.update(cx, |state, cx| {
+                state.remember_last_used_model(&self.model.name, cx)
+            })
+        })
+        .ok();
+
+        let (mut request, _) = match self.build_request(request, &settings) {
+            Ok(request) => request,
+            Err(error) => return futures::future::ready(Err(error)).boxed(),
+        };
+        if n > 1 {
+            if supports_multiple_choices {
+                request.n = Some(n);
+            } else {
+                log::warn!(
+                    "{} can't return multiple choices, requesting a single one instead of {n}",
+                    self.model.name
+                );
+            }
+        }
+
+        let completions = self.send_open_router_request(request, None, cx);
+        let future = async move {
+            Ok(map_choices_to_language_model_completion_events(completions.await?).boxed())
+        }
+        .boxed();
+        self.track_completion(future, |(_, event)| event, &settings, cx)
+    }
+
+    /// Converts `request` for OpenRouter, returning it along with the output limit it asks for.
+    /// Fails when the prompt can't fit the context window and `preflight_context_check` is on.
+    fn build_request(
+        &self,
+        request: LanguageModelRequest,
+        settings: &OpenRouterSettings,
+    ) -> Result<(open_router::Request, Option<u32>)> {
+        if settings.preflight_context_check {
+            self.check_context_fits(&request)?;
+        }
+
+        let max_output_tokens = (!settings.omit_max_tokens || self.model.requires_max_tokens())
+            .then(|| self.request_max_output_tokens(settings));
+        let mut request = into_open_router(request, &self.model, max_output_tokens, settings)?;
+        request.models = self.fallback_models.clone();
+        if !self.supports_reasoning {
+            request.reasoning = None;
+        }
+        Ok((request, max_output_tokens))
+    }
+
+    /// Reports usage telemetry once the completion ends and, with `low_credit_alerts` on,
+    /// refreshes the credit balance afterwards. `completion_event` picks the event out of each
+    /// item, so single and multi-choice streams are tracked alike.
+    fn track_completion<T: Send + 'static>(
+        &self,
+        completion: BoxFuture<'static, Result<BoxStream<'static, Result<T>>>>,
+        completion_event: fn(&T) -> &LanguageModelCompletionEvent,
+        settings: &OpenRouterSettings,
+        cx: &AsyncApp,
+    ) -> BoxFuture<'static, Result<BoxStream<'static, Result<T>>>> {
+        let telemetry_id = self.telemetry_id();
+        let Ok((in_flight_usage, usage_unavailable, refresh_credits_tx)) =
+            cx.read_entity(&self.state, |state, _| {
+                (
+                    state.in_flight_usage.clone(),
+                    state.usage_unavailable.clone(),
+                    state.refresh_credits_tx.clone(),
+                )
+            })
+        else {
+            return futures::future::ready(Err(anyhow!("App state dropped"))).boxed();
+        };
+        let low_credit_alerts = settings.low_credit_alerts;
+        completion
+            .map(move |result| {
+                result.map(|events| {
+                    let events = report_usage_telemetry(
+                        telemetry_id,
+                        in_flight_usage,
+                        usage_unavailable,
+                        events,
+                        completion_event,
+                    );
+                    if !low_credit_alerts {
+                        return events;
+                    }
+
+                    // Refresh the balance once the completion's cost has been deducted, so
+                    // low-credit alerts fire as soon as the threshold is crossed.
+                    let refresh_credits = futures::stream::once(async move {
+                        refresh_credits_tx.unbounded_send(()).ok();
+                    })
+                    .filter_map(|()| futures::future::ready(None));
+                    events.chain(refresh_credits).boxed()
+                })
+            })
+            .boxed()
+    }
+
+    fn send_open_router_request(
+        &self,
+        request: open_router::Request,
+        api_url: Option<String>,
+        cx: &AsyncApp,
+    ) -> BoxFuture<'static, Result<BoxStream<'static, Result<open_router::ResponseStreamEvent>>>>
+    {
+        let http_client = self.http_client.clone();
+        let Ok((
+            api_key,
+            api_url,
+            dry_run,
+            options,
+            timeouts,
+            last_failure,
+            retry_policy,
+            fallbacks,
+            resume_dropped_streams,
+            last_request_params,
+            global_limiter,
+            transcript,
+            stop_after_tool_calls,
+            model_id_format,
+            moderate_prompts,
+        )) = cx.read_entity(&self.state, |state, cx| {
+            let settings = &AllLanguageModelSettings::get_global(cx).openrouter;
+            let fallbacks = match settings.on_rate_limit {
+                RateLimitBehavior::FailoverToFallback => settings
+                    .fallback_models
+                    .iter()
+                    .filter(|model| **model != request.model)
+                    .cloned()
+                    .collect(),
+                RateLimitBehavior::Retry | RateLimitBehavior::Error => Vec::new(),
+            };
+            (
+                state.api_key.clone(),
+                api_url.unwrap_or_else(|| settings.api_url.clone()),
+                settings.dry_run,
+                settings.request_options(),
+                settings.timeouts(),
+                state.last_failure.clone(),
+                RetryPolicy::new(
+                    MAX_COMPLETION_RETRIES,
+                    settings.on_rate_limit,
+                    fallbacks.len(),
+                ),
+                fallbacks,
+                settings.resume_dropped_streams,
+                settings
+                    .show_request_params
+                    .then(|| state.last_request_params.clone()),
+                GlobalRateLimiter::global(cx),
+                settings.stream_transcript.then(|| StreamTranscript {
+                    fs: state.fs.clone(),
+                    path: settings
+                        .stream_transcript_path
+                        .clone()
+                        .unwrap_or_else(default_transcript_path),
+                    include_prompt: settings.stream_transcript_include_prompt,
+                    started: state.transcript_started.clone(),
+                }),
+                settings.stop_after_tool_calls,
+                settings.log_model_ids,
+                settings.moderate_prompts,
+            )
+        })
+        else {
+            return futures::future::ready(Err(anyhow!("App state dropped"))).boxed();
+        };
+
+        if let Some(last_request_params) = last_request_params {
+            last_request_params
+                .lock()
+                .replace(diagnostic_request_params(&request));
+        }
+
+        if dry_run {
+            return futures::future::ready(dry_run_completion(&api_url, request)).boxed();
+        }
+
+        let mut transcript = transcript.map(|transcript| {
+            TranscriptWriter::new(transcript, &request, cx.background_executor().clone())
+        });
+
+        let record_failure = {
+            let model_id = request.model.clone();
+            let request_params = diagnostic_request_params(&request);
+            let api_url = api_url.clone();
+            let api_key = api_key.clone();
+            move |error: &anyhow::Error, request_id: Option<String>| {
+                match &request_id {
+                    Some(request_id) => {
+                        log::error!("OpenRouter request {request_id} failed: {error:#}")
+                    }
+                    None => log::error!("OpenRouter request failed: {error:#}"),
+                }
+                let failure = CompletionFailure::new(
+                    &model_id,
+                    &api_url,
+                    request_params.clone(),
+                    request_id,
+                    error,
+                    api_key.as_deref(),
+                );
+                last_failure.lock().replace(failure);
+            }
+        };
+
+        let resume_request = (resume_dropped_streams
+            && request.stream
+            && supports_assistant_prefill(&request.model)
+            && request.n.is_none_or(|n| n <= 1))
+        .then(|| request.clone());
+        let pinned_providers = request
+            .provider
+            .as_ref()
+            .map(|provider| provider.only.clone())
+            .unwrap_or_default();
+        let executor = cx.background_executor().clone();
+        let request_sent_at = Arc::new(OnceLock::new());
+        let send = {
+            let executor = executor.clone();
+            let request_sent_at = request_sent_at.clone();
+            async move {
+                let api_key = api_key.ok_or_else(|| anyhow!("Missing OpenRouter API Key"))?;
+                if moderate_prompts {
+                    screen_prompt(http_client.as_ref(), &api_url, &api_key, &request).await?;
+                }
+                request_sent_at.set(executor.now()).ok();
+                let restart = {
+                    let http_client = http_client.clone();
+                    let api_url = api_url.clone();
+                    let api_key = api_key.clone();
+                    move |request| {
+                        let http_client = http_client.clone();
+                        let api_url = api_url.clone();
+                        let api_key = api_key.clone();
+                        async move {
+                            open_router::stream_completion(
+                                http_client.as_ref(),
+                                &api_url,
+                                &api_key,
+                                request,
+                                options,
+                            )
+                            .await
+                        }
+                        .boxed()
+                    }
+                };
+                let mut request = request;
+                let mut fallbacks = fallbacks.into_iter();
+                let start = move |attempt: Attempt| {
+                    if attempt == Attempt::Failover {
+                        if let Some(fallback) = fallbacks.next() {
+                            log::warn!(
+                                "OpenRouter rate-limited {}, failing over to {} as configured \
+                                by on_rate_limit",
+                                model_id_format.format(&request.model),
+                                model_id_format.format(&fallback),
+                            );
+                            request.model = fallback;
+                            request.models.clear();
+                        }
+                    }
+                    let http_client = http_client.clone();
+                    let api_url = api_url.clone();
+                    let api_key = api_key.clone();
+                    let request = request.clone();
+                    async move {
+                        if request.stream {
+                            open_router::stream_completion(
+                                http_client.as_ref(),
+                                &api_url,
+                                &api_key,
+                                request,
+                                options,
+                            )
+                            .await
+                        } else {
+                            let response = open_router::complete(
+                                http_client.as_ref(),
+                                &api_url,
+                                &api_key,
+                                request,
+                                options,
+                            )
+                            .await?;
+                            let event = open_router::ResponseStreamEvent::from(response);
+                            Ok(futures::stream::iter([Ok(event)]).boxed())
+                        }
+                    }
+                    .boxed()
+                };
+                let events = stream_with_retries(start, retry_policy, executor.clone());
+                let events = match timeouts.total {
+                    Some(total) => {
+                        match futures::future::select(events, executor.timer(total)).await {
+                            futures::future::Either::Left((events, _)) => events,
+                            futures::future::Either::Right(_) => Err(anyhow!(
+                                "OpenRouter request timed out after {} seconds",
+                                total.as_secs()
+                            )),
+                        }
+                    }
+                    None => events.await,
+                };
+                match resume_request {
+                    Some(request) => events.map(|events| {
+                        resume_dropped_stream(events, request, MAX_STREAM_RESUMES, restart)
+                    }),
+                    None => events,
+                }
+            }
+        };
+        // Take the model's own slot first, so a request waiting for it doesn't hold a global slot
+        // that another provider could be using.
+        let future = self.request_limiter().stream(async move {
+            match global_limiter {
+                Some(limiter) => Ok(limiter.stream(send).await?.boxed()),
+                None => send.await,
+            }
+        });
+
+        async move {
+            let events = match future.await {
+                Ok(events) => events.boxed(),
+                Err(error) => {
+                    let error = explain_pinned_provider_failure(error, &pinned_providers);
+                    record_failure(&error, None);
+                    if let Some(transcript) = &mut transcript {
+                        transcript.push_error(&error);
+                    }
+                    return Err(error);
+                }
+            };
+            let deadline = timeouts
+                .total
+                .zip(request_sent_at.get().copied())
+                .map(|(total, sent_at)| sent_at + total);
+            let events = if stop_after_tool_calls {
+                end_after_tool_calls(events)
+            } else {
+                events
+            };
+            let mut request_id = None;
+            Ok(with_stall_timeouts(events, timeouts, deadline, executor)
+                .inspect(move |event| match event {
+                    Ok(event) => {
+                        if let (None, Some(id)) = (&request_id, &event.id) {
+                            log::info!(
+                                "OpenRouter request {id} started for {}",
+                                model_id_format.format(&event.model)
+                            );
+                            request_id = Some(id.clone());
+                        }
+                        if let Some(transcript) = &mut transcript {
+                            transcript.push_event(event);
+                        }
+                    }
+                    Err(error) => {
+                        record_failure(error, request_id.clone());
+                        if let Some(transcript) = &mut transcript {
+                            transcript.push_error(error);
+                        }
+                    }
+                })
+                .boxed())
+        }
+        .boxed()
+    }
+}
+impl LanguageModel for OpenRouterLanguageModel {
+    fn id(&self) -> LanguageModelId {
+        self.id.clone()
+    }
+
+    fn name(&self) -> LanguageModelName {
+        LanguageModelName::from(
+            self.model
+                .display_name
+                .clone()
+                .unwrap_or_else(|| self.model.name.clone()),
+        )
+    }
+
+    fn provider_id(&self) -> LanguageModelProviderId {
+        LanguageModelProviderId(PROVIDER_ID.into())
+    }
+
+    fn provider_name(&self) -> LanguageModelProviderName {
+        LanguageModelProviderName(PROVIDER_NAME.into())
+    }
+
+    fn supports_tools(&self) -> bool {
+        self.supports_tools
+    }
+
+    /// Keeps the variant suffix, e.g. `:free` or `:nitro`, so usage of a variant isn't counted
+    /// as usage of the base model. Trimmed as configured by `log_model_ids`.
+    fn telemetry_id(&self) -> String {
+        format!(
+            "openrouter/{}",
+            self.model_id_format.format(&self.model.name)
+        )
+    }
+
+    fn max_token_count(&self) -> usize {
+        self.model.context_length()
+    }
+
+    fn max_output_tokens(&self) -> Option<u32> {
+        self.model.max_output_tokens
+    }
+
+    fn count_tokens(
+        &self,
+        request: LanguageModelRequest,
+        cx: &App,
+    ) -> BoxFuture<'static, Result<usize>> {
+        let model_id = self.model.name.clone();
+        let exact_token_count_unavailable =
+            self.state.read(cx).exact_token_count_unavailable.clone();
+        cx.background_spawn(async move {
+            // tiktoken only knows OpenAI's vocabularies, so other families are estimated.
+            if open_router::TokenizerFamily::for_model(&model_id)
+                != open_router::TokenizerFamily::Gpt
+                || exact_token_count_unavailable.load(Ordering::Relaxed)
+            {
+                return Ok(estimate_request_tokens(&model_id, &request));
+            }
+
+            let messages = request
+                .messages
+                .iter()
+                .map(|message| tiktoken_rs::ChatCompletionRequestMessage {
+                    role: match message.role {
+                        Role::User => "user".into(),
+                        Role::Assistant => "assistant".into(),
+                        Role::System => "system".into(),
+                    },
+                    content: Some(message.string_contents()),
+                    name: None,
+                    function_call: None,
+                })
+                .collect::<Vec<_>>();
+
+            tiktoken_rs::num_tokens_from_messages("gpt-4", &messages).or_else(|error| {
+                if !exact_token_count_unavailable.swap(true, Ordering::Relaxed) {
+                    log::warn!(
+                        "Exact OpenRouter token counting is unavailable, estimating instead: {error}"
+                    );
+                }
+                Ok(estimate_request_tokens(&model_id, &request))
+            })
+        })
+        .boxed()
+    }
+
+    fn stream_completion(
+        &self,
+        request: LanguageModelRequest,
+        cx: &AsyncApp,
+    ) -> BoxFuture<'static, Result<BoxStream<'static, Result<LanguageModelCompletionEvent>>>> {
+        self.stream_completion_with_api_url(request, None, cx)
+    }
+}
+/// Ends `events` with `cancelled_event` once `cancelled` resolves, dropping the underlying stream
+/// right away.
+fn until_cancelled<T, F>(
+    events: BoxStream<'static, T>,
+    cancelled: F,
+    cancelled_event: T,
+) -> BoxStream<'static, T>
+where
+    T: Send + 'static,
+    F: Future + Send + Unpin + 'static,
+{
+    futures::stream::unfold(
+        Some((events, cancelled, cancelled_event)),
+        |state| async move {
+            let (mut events, cancelled, cancelled_event) = state?;
+            match futures::future::select(cancelled, events.next()).await {
+                Either::Left(_) => Some((cancelled_event, None)),
+                Either::Right((None, _)) => None,
+                Either::Right((Some(event), cancelled)) => {
+                    Some((event, Some((events, cancelled, cancelled_event))))
+                }
+            }
+        },
+    )
+    .boxed()
+}
+/// Starts a completion with `start`, retrying transient failures up to `max_retries` times.
+///
+/// Retries only happen until the response produces output. Once any text, reasoning or tool-call
+/// fragment has been received the request is no longer retryable, because repeating it could make
+/// the model issue the same tool call twice; later failures are surfaced as errors instead.
+fn stream_with_retries<F>(
+    mut start: F,
+    mut policy: RetryPolicy,
+    executor: BackgroundExecutor,
+) -> BoxFuture<'static, Result<BoxStream<'static, Result<open_router::ResponseStreamEvent>>>>
+where
+    F: FnMut(
+            Attempt,
+        ) -> BoxFuture<
+            'static,
+            Result<BoxStream<'static, Result<open_router::ResponseStreamEvent>>>,
+        > + Send
+        + 'static,
+{
+    async move {
+        let mut attempt = Attempt::First;
+        let events = loop {
+            match start(attempt).await {
+                Ok(events) => break events,
+                Err(error) => match policy.next_attempt(&error) {
+                    Some(next_attempt) => {
+                        attempt = next_attempt;
+                        if attempt == Attempt::Retry {
+                            log::warn!("Retrying OpenRouter completion after error: {error}");
+                            executor.timer(COMPLETION_RETRY_DELAY).await;
+                        }
+                    }
+                    None => return Err(error),
+                },
+            }
+        };
+
+        struct RetryState<F> {
+            events: BoxStream<'static, Result<open_router::ResponseStreamEvent>>,
+            start: F,
+            policy: RetryPolicy,
+            received_output: bool,
+            executor: BackgroundExecutor,
+        }
+
+        let state = RetryState {
+            events,
+            start,
+            policy,
+            received_output: false,
+            executor,
+        };
+        Ok(futures::stream::unfold(Some(state), |state| async move {
+            let mut state = state?;
+            loop {
+                match state.events.next().await {
+                    Some(Ok(event)) => {
+                        state.received_output |= carries_output(&event);
+                        return Some((Ok(event), Some(state)));
+                    }
+                    Some(Err(error)) => {
+                        let next_attempt = if state.received_output {
+                            None
+                        } else {
+                            state.policy.next_attempt(&error)
+                        };
+                        let Some(attempt) = next_attempt else {
+                            return Some((Err(error), None));
+                        };
+                        if attempt == Attempt::Retry {
+                            log::warn!("Retrying OpenRouter completion after error: {error}");
+                            state.executor.timer(COMPLETION_RETRY_DELAY).await;
+                        }
+                        match (state.start)(attempt).await {
+                            Ok(events) => state.events = events,
+                            Err(error) => return Some((Err(error), None)),
+                        }
+                    }
+                    None => return None,
+                }
+            }
+        })
+        .boxed())
+    }
+    .boxed()
+}
+/// Points out that a model pinned with `only` wasn't routed elsewhere when its providers are down,
+/// so the failure doesn't look like an OpenRouter outage.
+fn explain_pinned_provider_failure(
+    error: anyhow::Error,
+    pinned_providers: &[String],
+) -> anyhow::Error {
+    let no_provider = matches!(
+        error.downcast_ref::<open_router::OpenRouterError>(),
+        Some(open_router::OpenRouterError::NoProviderAvailable(_))
+    );
+    if pinned_providers.is_empty() || !no_provider {
+        return error;
+    }
+    error.context(format!(
+        "This model is pinned to {} with `only`, which couldn't serve the request",
+        pinned_providers.join(", ")
+    ))
+}
+/// Why a completion is being started.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Attempt {
+    First,
+    /// Retrying the same model after a transient failure.
+    Retry,
+    /// Switching to the next fallback model after a rate limit.
+    Failover,
+}
+/// Decides whether and how a completion that failed before producing output is attempted again.
+#[derive(Clone, Copy, Debug)]
+struct RetryPolicy {
+    retries_left: usize,
+    on_rate_limit: RateLimitBehavior,
+    fallbacks_left: usize,
+}
+impl RetryPolicy {
+    fn new(max_retries: usize, on_rate_limit: RateLimitBehavior, fallbacks: usize) -> Self {
+        Self {
+            retries_left: max_retries,
+            on_rate_limit,
+            fallbacks_left: fallbacks,
+        }
+    }
+
+    fn next_attempt(&mut self, error: &anyhow::Error) -> Option<Attempt> {
+        if is_rate_limit(error) {
+            match self.on_rate_limit {
+                RateLimitBehavior::Retry => {}
+                RateLimitBehavior::Error => return None,
+                RateLimitBehavior::FailoverToFallback => {
+                    self.fallbacks_left = self.fallbacks_left.checked_sub(1)?;
+                    return Some(Attempt::Failover);
+                }
+            }
+        }
+        if !is_retryable(error) {
+            return None;
+        }
+        self.retries_left = self.retries_left.checked_sub(1)?;
+        Some(Attempt::Retry)
+    }
+}
+/// Whether `error` means the connection was lost, rather than that OpenRouter rejected the
+/// request.
+fn is_dropped_connection(error: &anyhow::Error) -> bool {
+    matches!(
+        error.downcast_ref::<open_router::OpenRouterError>(),
+        Some(open_router::OpenRouterError::Transport(_))
+    )
+}
+/// Continues `events` after its connection drops by sending `request` again with the text
+/// received so far as an assistant prefill, so the model picks up where it left off.
+///
+/// Gives up, surfacing the error, after `max_resumes` attempts or once the response has started
+/// a tool call or finished, since neither can be continued from a prefill.
+fn resume_dropped_stream<F>(
+    events: BoxStream<'static, Result<open_router::ResponseStreamEvent>>,
+    request: open_router::Request,
+    max_resumes: usize,
+    restart: F,
+) -> BoxStream<'static, Result<open_router::ResponseStreamEvent>>
+where
+    F: FnMut(
+            open_router::Request,
+        ) -> BoxFuture<
+            'static,
+            Result<BoxStream<'static, Result<open_router::ResponseStreamEvent>>>,
+        > + Send
+        + 'static,
+{
+    struct State<F> {
+        events: BoxStream<'static, Result<open_router::ResponseStreamEvent>>,
+        request: open_router::Request,
+        restart: F,
+        received: String,
+        resumable: bool,
+        resumes_left: usize,
+    }
+
+    let state = State {
+        events,
+        request,
+        restart,
+        received: String::new(),
+        resumable: true,
+        resumes_left: max_resumes,
+    };
+    futures::stream::unfold(state, |mut state| async move {
+        loop {
+            match state.events.next().await? {
+                Ok(event) => {
+                    if let Some(choice) = event.choices.first() {
+                        if let Some(content) = &choice.delta.content {
+                            state.received.push_str(content);
+                        }
+                        if choice.delta.tool_calls.is_some() || choice.finish_reason.is_some() {
+                            state.resumable = false;
+                        }
+                    }
+                    return Some((Ok(event), state));
+                }
+                Err(error)
+                    if state.resumable
+                        && state.resumes_left > 0
+                        && is_dropped_connection(&error) =>
+                {
+                    state.resumes_left -= 1;
+                    log::warn!(
+                        "OpenRouter stream dropped after {} bytes of text, resuming: {error:#}",
+                        state.received.len()
+                    );
+                    let request = with_assistant_prefill(state.request.clone(), &state.received);
+                    match (state.restart)(request).await {
+                        Ok(events) => state.events = events,
+                        Err(resume_error) => {
+                            log::error!("Failed to resume OpenRouter stream: {resume_error:#}");
+                            state.events = futures::stream::empty().boxed();
+                            return Some((Err(error), state));
+                        }
+                    }
+                }
+                Err(error) => return Some((Err(error), state)),
+            }
+        }
+    })
+    .boxed()
+}
+
+fn is_rate_limit(error: &anyhow::Error) -> bool {
+    matches!(
+        error.downcast_ref::<open_router::OpenRouterError>(),
+        Some(
+            open_router::OpenRouterError::RateLimited(_)
+                | open_router::OpenRouterError::RateLimitedMidStream(_)
+        )
+    )
+}
+/// A failed completion, described without any prompt content or credentials.
+struct CompletionFailure {
+    model_id: String,
+    /// The generation id of the failed response, if it got far enough to have one.
+    request_id: Option<String>,
+    api_url: String,
+    request_params: serde_json::Value,
+    status_code: Option<u16>,
+    error: String,
+    metadata: Option<String>,
+    failed_at: DateTime<Local>,
+}
+impl CompletionFailure {
+    fn new(
+        model_id: &str,
+        api_url: &str,
+        request_params: serde_json::Value,
+        request_id: Option<String>,
+        error: &anyhow::Error,
+        api_key: Option<&str>,
+    ) -> Self {
+        let open_router_error = error.downcast_ref::<open_router::OpenRouterError>();
+        let redact = |text: String| match api_key.filter(|api_key| !api_key.is_empty()) {
+            Some(api_key) => text.replace(api_key, "[redacted]"),
+            None => text,
+        };
+        Self {
+            model_id: model_id.to_string(),
+            request_id,
+            api_url: api_url.to_string(),
+            request_params,
+            status_code: open_router_error.and_then(|error| error.status_code()),
+            error: redact(format!("{error:#}")),
+            metadata: open_router_error
+                .and_then(|error| error.metadata())
+                .map(|metadata| redact(metadata.redacted())),
+            failed_at: Local::now(),
+        }
+    }
+
+    /// A block to paste into a bug report.
+    fn diagnostic(&self) -> String {
+        let status_code = self
+            .status_code
+            .map_or_else(|| "none".to_string(), |code| code.to_string());
+        let request_params = serde_json::to_string_pretty(&self.request_params).unwrap_or_default();
+        let mut diagnostic = format!(
+            "```\nOpenRouter completion failed at {}\nModel: {}\nAPI URL: {}\nStatus: {status_code}\nError: {}\n",
+            self.failed_at.to_rfc3339(),
+            self.model_id,
+            self.api_url,
+            self.error,
+        );
+        if let Some(request_id) = &self.request_id {
+            diagnostic.push_str(&format!("Request ID: {request_id}\n"));
+        }
+        if let Some(metadata) = &self.metadata {
+            diagnostic.push_str(&format!("Error metadata: {metadata}\n"));
+        }
+        diagnostic.push_str(&format!("Request parameters: {request_params}\n```"));
+        diagnostic
+    }
+}
+/// The parameters of `request` that help reproduce a failure, leaving out the prompt, stop
+/// sequences and user id.
+fn diagnostic_request_params(request: &open_router::Request) -> serde_json::Value {
+    let mut params = serde_json::to_value(request).unwrap_or_default();
+    if let Some(params) = params.as_object_mut() {
+        params.remove("messages");
+        params.remove("stop");
+        params.remove("user");
+        params.insert("message_count".into(), request.messages.len().into());
+        params.insert(
+            "tools".into(),
+            request
+                .tools
+                .iter()
+                .map(|tool| match tool {
+                    open_router::ToolDefinition::Function { function } => function.name.clone(),
+                })
+                .collect::<Vec<_>>()
+                .into(),
+        );
+    }
+    params
+}
+/// Summarizes the parameters recorded by [`diagnostic_request_params`] on one line, listing
+/// the ones most likely to differ between requests.
+fn request_params_summary(params: &serde_json::Value) -> String {
+    [
+        "model",
+        "temperature",
+        "top_p",
+        "max_tokens",
+        "provider",
+        "preset",
+    ]
+    .into_iter()
+    .filter_map(|key| {
+        let value = params.get(key).filter(|value| !value.is_null())?;
+        Some(match (key, value) {
+            ("model", serde_json::Value::String(model)) => model.clone(),
+            _ => format!("{key} {value}"),
+        })
+    })
+    .collect::<Vec<_>>()
+    .join(" · ")
+}
+/// Ends `events` with an error if no event arrives within `timeouts.inter_token`, or once
+/// `deadline` passes. The inner stream is dropped when either fires, releasing its rate limiter
+/// permit even if the caller keeps the returned stream around.
+fn with_stall_timeouts(
+    events: BoxStream<'static, Result<open_router::ResponseStreamEvent>>,
+    timeouts: CompletionTimeouts,
+    deadline: Option<Instant>,
+    executor: BackgroundExecutor,
+) -> BoxStream<'static, Result<open_router::ResponseStreamEvent>> {
+    if timeouts.inter_token.is_none() && deadline.is_none() {
+        return events;
+    }
+
+    futures::stream::unfold(Some(events), move |events| {
+        let executor = executor.clone();
+        async move {
+            let mut events = events?;
+            let until_deadline =
+                deadline.map(|deadline| deadline.saturating_duration_since(executor.now()));
+            let wait = match (timeouts.inter_token, until_deadline) {
+                (Some(inter_token), Some(until_deadline)) => inter_token.min(until_deadline),
+                (wait, None) | (None, wait) => wait?,
+            };
+
+            match futures::future::select(events.next(), executor.timer(wait)).await {
+                futures::future::Either::Left((event, _)) => Some((event?, Some(events))),
+                futures::future::Either::Right(_) => {
+                    let error = match timeouts.total {
+                        Some(total)
+                            if deadline.is_some_and(|deadline| executor.now() >= deadline) =>
+                        {
+                            anyhow!(
+                                "OpenRouter request timed out after {} seconds",
+                                total.as_secs()
+                            )
+                        }
+                        _ => anyhow!(
+                            "OpenRouter stopped sending tokens for {} seconds",
+                            wait.as_secs()
+                        ),
+                    };
+                    Some((Err(error), None))
+                }
+            }
+        }
+    })
+    .boxed()
+}
+
+/// Whether an error is likely to go away if the same request is sent again.
+fn is_retryable(error: &anyhow::Error) -> bool {
+    matches!(
+        error.downcast_ref::<open_router::OpenRouterError>(),
+        Some(
+            open_router::OpenRouterError::RateLimited(_)
+                | open_router::OpenRouterError::RateLimitedMidStream(_)
+                | open_router::OpenRouterError::NoProviderAvailable(_)
+                | open_router::OpenRouterError::Transport(_)
+        )
+    )
+}
+/// Whether an event delivers part of the model's response, as opposed to only metadata.
+fn carries_output(event: &open_router::ResponseStreamEvent) -> bool {
+    event.choices.iter().any(|choice| {
+        choice
+            .delta
+            .content
+            .as_ref()
+            .is_some_and(|content| !content.is_empty())
+            || choice
+                .delta
+                .reasoning
+                .as_ref()
+                .is_some_and(|reasoning| !reasoning.is_empty())
+            || choice
+                .delta
+                .tool_calls
+                .as_ref()
+                .is_some_and(|tool_calls| !tool_calls.is_empty())
+    })
+}
+/// Sends the same request to each of `models` at once, for comparing their responses.
+///
+/// Events from all models are merged into one stream as they arrive, each tagged with the id of
+/// the model that produced it. Every request still goes through its model's rate limiter, and a
+/// failure only ends that model's events; the others carry on.
+pub fn fan_out_completion(
+    models: &[OpenRouterLanguageModel],
+    request: LanguageModelRequest,
+    cx: &AsyncApp,
+) -> BoxStream<'static, (LanguageModelId, Result<LanguageModelCompletionEvent>)> {
+    let streams = models.iter().map(|model| {
+        let id = model.id.clone();
+        futures::stream::once(model.stream_completion_with_api_url(request.clone(), None, cx))
+            .flat_map(|result| match result {
+                Ok(events) => events,
+                Err(error) => futures::stream::iter([Err(error)]).boxed(),
+            })
+            .map(move |event| (id.clone(), event))
+            .boxed()
+    });
+    futures::stream::select_all(streams).boxed()
+}
+
+/// Describes what a catalog model can do, for labelling it in the model list.
+fn model_capabilities(entry: Option<&open_router::Model>) -> String {
+    let Some(entry) = entry else {
+        return "not in the OpenRouter catalog".into();
+    };
+    let mut capabilities = vec![format!("{} token context", entry.max_token_count())];
+    if entry.supports_tools() {
+        capabilities.push("tools".into());
+    }
+    if entry
+        .input_modalities()
+        .contains(&open_router::Modality::Image)
+    {
+        capabilities.push("images".into());
+    }
+    capabilities.join(", ")
+}
+/// The capabilities that distinguish models in the comparison table.
+fn comparison_capabilities(entry: Option<&open_router::Model>) -> String {
+    let Some(entry) = entry else {
+        return "—".into();
+    };
+    let mut capabilities = Vec::new();
+    if entry.supports_tools() {
+        capabilities.push("tools");
+    }
+    if entry
+        .input_modalities()
+        .contains(&open_router::Modality::Image)
+    {
+        capabilities.push("images");
+    }
+    if entry.supports_reasoning() {
+        capabilities.push("reasoning");
+    }
+    if capabilities.is_empty() {
+        "text only".into()
+    } else {
+        capabilities.join(", ")
+    }
+}
+/// Formats a catalog price, given in USD per token, as USD per million tokens.
+fn format_price_per_million(price_per_token: Option<&str>) -> String {
+    match price_per_token.and_then(|price| price.parse::<f64>().ok()) {
+        Some(price) if price == 0. => "free".into(),
+        Some(price) if price > 0. => format!("${:.2}", price * 1_000_000.),
+        _ => "—".into(),
+    }
+}
+
+/// Logs the request that would have been sent and answers with a fixed response.
+fn dry_run_completion(
+    api_url: &str,
+    request: open_router::Request,
+) -> Result<BoxStream<'static, Result<open_router::ResponseStreamEvent>>> {
+    let body = serde_json::to_string_pretty(&request)?;
+    log::info!(
+        "OpenRouter dry run, not sending:\nPOST {api_url}/chat/completions\nAuthorization: Bearer [redacted]\n{body}"
+    );
+
+    let event = open_router::ResponseStreamEvent {
+        id: None,
+        created: 0,
+        model: request.model,
+        choices: vec![open_router::ChoiceDelta {
+            index: 0,
+            delta: open_router::ResponseMessageDelta {
+                role: Some(open_router::Role::Assistant),
+                content: Some(DRY_RUN_RESPONSE.to_string()),
+                reasoning: None,
+                tool_calls: None,
+            },
+            finish_reason: Some("stop".to_string()),
+            safety_ratings: Vec::new(),
+        }],
+        usage: None,
+    };
+    Ok(futures::stream::iter([Ok(event)]).boxed())
+}
+/// The models added, removed and repriced between two catalog fetches, by id.
+#[derive(Debug, Default, PartialEq)]
+struct CatalogChanges {
+    added: Vec<String>,
+    removed: Vec<String>,
+    repriced: Vec<String>,
+}
+impl CatalogChanges {
+    fn between(previous: &[open_router::Model], current: &[open_router::Model]) -> Self {
+        let previous = previous
+            .iter()
+            .map(|model| (model.id(), model))
+            .collect::<HashMap<_, _>>();
+        let current_ids = current
+            .iter()
+            .map(|model| model.id())
+            .collect::<HashSet<_>>();
+
+        let mut changes = Self::default();
+        for model in current {
+            match previous.get(model.id()) {
+                None => changes.added.push(model.id.clone()),
+                Some(previous) if previous.pricing != model.pricing => {
+                    changes.repriced.push(model.id.clone())
+                }
+                Some(_) => {}
+            }
+        }
+        changes.removed = previous
+            .keys()
+            .filter(|id| !current_ids.contains(*id))
+            .map(|id| id.to_string())
+            .collect();
+        changes.removed.sort();
+        changes
+    }
+
+    fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.repriced.is_empty()
+    }
+
+    /// A one-line summary such as "OpenRouter catalog updated: 3 new models, 1 removed."
+    fn summary(&self) -> String {
+        let mut parts = Vec::new();
+        match self.added.len() {
+            0 => {}
+            1 => parts.push("1 new model".to_string()),
+            count => parts.push(format!("{count} new models")),
+        }
+        if !self.removed.is_empty() {
+            parts.push(format!("{} removed", self.removed.len()));
+        }
+        if !self.repriced.is_empty() {
+            parts.push(format!("{} repriced", self.repriced.len()));
+        }
+        format!("OpenRouter catalog updated: {}.", parts.join(", "))
+    }
+}
+/// The catalog as of the last fetch, kept on disk for when `auto_refresh_catalog` is disabled.
+#[derive(Serialize, Deserialize)]
+struct CachedCatalog {
+    api_url: String,
+    fetched_at: DateTime<Local>,
+    models: Vec<open_router::Model>,
+}
+/// The metadata of the last-used model, kept on disk so it can be used right after startup.
+#[derive(Serialize, Deserialize)]
+struct CachedWarmModel {
+    api_url: String,
+    model: open_router::Model,
+}
+fn warm_model_cache_path() -> PathBuf {
+    paths::data_dir()
+        .join("openrouter")
+        .join("last_used_model.json")
+}
+/// Reads an API key from the keyring. Keys that are empty or aren't valid UTF-8, e.g. after a
+/// partial write, are treated as missing so the user is asked to enter the key again.
+fn parse_stored_api_key(api_key: Vec<u8>) -> Result<String, AuthenticateError> {
+    let Ok(api_key) = String::from_utf8(api_key) else {
+        log::warn!("Ignoring a stored {PROVIDER_NAME} API key that isn't valid UTF-8");
+        return Err(AuthenticateError::CredentialsNotFound);
+    };
+    if api_key.is_empty() {
+        return Err(AuthenticateError::CredentialsNotFound);
+    }
+    Ok(api_key)
+}
+
+fn catalog_cache_path() -> std::path::PathBuf {
+    paths::data_dir().join("openrouter").join("models.json")
+}
+
+/// Drops items whose id repeats an earlier one, keeping the first, and logs each collision.
+/// Configs merged from several sources can list the same model twice.
+fn dedup_by_id<T>(items: Vec<T>, id: impl Fn(&T) -> &str, kind: &str) -> Vec<T> {
+    let mut seen = HashSet::default();
+    items
+        .into_iter()
+        .filter(|item| {
+            let id = id(item);
+            let is_new = seen.insert(id.to_string());
+            if !is_new {
+                log::warn!("Ignoring duplicate OpenRouter {kind} \"{id}\", keeping the first one");
+            }
+            is_new
+        })
+        .collect()
+}
+/// Finds the catalog id closest to `id`, if any is similar enough to be a likely typo.
+fn suggest_model_id<'a>(id: &str, catalog: &'a [open_router::Model]) -> Option<&'a str> {
+    catalog
+        .iter()
+        .map(|entry| (entry.id(), strsim::normalized_levenshtein(id, entry.id())))
+        .filter(|(_, similarity)| *similarity >= MIN_MODEL_ID_SIMILARITY)
+        .max_by(|(_, a), (_, b)| a.total_cmp(b))
+        .map(|(id, _)| id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::events::{EMPTY_RESPONSE_MESSAGE, strip_code_fence};
+    use super::request::{
+        MAX_METADATA_KEY_LEN, MAX_METADATA_TAGS, MAX_METADATA_VALUE_LEN, hash_user_id,
+        moderation_input,
+    };
+    use super::*;
+    use fs::FakeFs;
+    use gpui::TestAppContext;
+    use http_client::{FakeHttpClient, Response};
+    use language_model::{
+        LanguageModelRequestMessage, LanguageModelRequestTool, LanguageModelSafetyRating,
+        LanguageModelToolChoice, LanguageModelToolResult, LanguageModelToolUse, MessageContent,
+        TokenUsage,
+    };
+    use std::path::Path;
+    use std::sync::atomic::AtomicUsize;
+
+    const CATALOG_RESPONSE: &str = r#"{"data":[{"id":"openai/gpt-4o","name":"OpenAI: GPT-4o","context_length":128000,"supported_parameters":["tools"]}]}"#;
+
+    fn init_test(cx: &mut TestAppContext) {
+        cx.update(|cx| {
+            let store = SettingsStore::test(cx);
+            cx.set_global(store);
+            AllLanguageModelSettings::register(cx);
+        });
+    }
+
+    #[test]
+    fn test_into_open_router_round_trips_tool_results() {
+        let model = AvailableModel {
+            name: "openai/gpt-4o".into(),
+            display_name: None,
+            max_tokens: 128000,
+            max_output_tokens: None,
+            max_completion_tokens: None,
+            context_length_override: None,
+            only: Vec::new(),
+            default_params: ModelParameters::default(),
+            system_role: None,
+            requires_max_tokens: None,
+            post_process: None,
+        };
+        let tool_turn = |id: &str, path: &str| {
+            [
+                LanguageModelRequestMessage {
+                    role: Role::Assistant,
+                    content: vec![MessageContent::ToolUse(LanguageModelToolUse {
+                        id: id.into(),
+                        name: "read_file".into(),
+                        input: serde_json::json!({ "path": path }),
+                    })],
+                    cache: false,
+                },
+                LanguageModelRequestMessage {
+                    role: Role::User,
+                    content: vec![MessageContent::ToolResult(LanguageModelToolResult {
+                        tool_use_id: id.into(),
+                        tool_name: "read_file".into(),
+                        is_error: false,
+                        content: format!("contents of {path}").into(),
+                    })],
+                    cache: false,
+                },
+            ]
+        };
+        let mut messages = vec![LanguageModelRequestMessage {
+            role: Role::User,
+            content: vec![MessageContent::Text("Compare a.rs and b.rs".into())],
+            cache: false,
+        }];
+        messages.extend(tool_turn("call_1", "a.rs"));
+        messages.extend(tool_turn("call_2", "b.rs"));
+        let request = LanguageModelRequest {
+            thread_id: None,
+            prompt_id: None,
+            messages,
+            tools: Vec::new(),
+            stop: Vec::new(),
+            temperature: None,
+            tool_choice: None,
+        };
+
+        let request =
+            into_open_router(request, &model, None, &OpenRouterSettings::default()).unwrap();
+        let messages = serde_json::to_value(&request.messages).unwrap();
+        assert_eq!(
+            messages,
+            serde_json::json!([
+                { "role": "user", "content": "Compare a.rs and b.rs" },
+                {
+                    "role": "assistant",
+                    "content": null,
+                    "tool_calls": [{
+                        "id": "call_1",
+                        "type": "function",
+                        "function": { "name": "read_file", "arguments": "{\"path\":\"a.rs\"}" }
+                    }]
+                },
+                {
+                    "role": "tool",
+                    "tool_call_id": "call_1",
+                    "name": "read_file",
+                    "content": "contents of a.rs"
+                },
+                {
+                    "role": "assistant",
+                    "content": null,
+                    "tool_calls": [{
+                        "id": "call_2",
+                        "type": "function",
+                        "function": { "name": "read_file", "arguments": "{\"path\":\"b.rs\"}" }
+                    }]
+                },
+                {
+                    "role": "tool",
+                    "tool_call_id": "call_2",
+                    "name": "read_file",
+                    "content": "contents of b.rs"
+                },
+            ])
+        );
+    }
+
+    fn tool_result_names(model_id: &str, settings: &OpenRouterSettings) -> Vec<Option<String>> {
+        let model = AvailableModel {
+            name: model_id.into(),
+            display_name: None,
+            max_tokens: 32000,
+            max_output_tokens: None,
+            max_completion_tokens: None,
+            context_length_override: None,
+            only: Vec::new(),
+            default_params: ModelParameters::default(),
+            system_role: None,
+            requires_max_tokens: None,
+            post_process: None,
+        };
+        let request = LanguageModelRequest {
+            messages: vec![
+                LanguageModelRequestMessage {
+                    role: Role::Assistant,
+                    content: vec![MessageContent::ToolUse(LanguageModelToolUse {
+                        id: "call_1".into(),
+                        name: "list_directory".into(),
+                        input: serde_json::json!({ "path": "." }),
+                    })],
+                    cache: false,
+                },
+                LanguageModelRequestMessage {
+                    role: Role::User,
+                    content: vec![MessageContent::ToolResult(LanguageModelToolResult {
+                        tool_use_id: "call_1".into(),
+                        tool_name: "list_directory".into(),
+                        is_error: false,
+                        content: "src/".into(),
+                    })],
+                    cache: false,
+                },
+            ],
+            ..Default::default()
+        };
+
+        into_open_router(request, &model, None, settings)
+            .unwrap()
+            .messages
+            .into_iter()
+            .filter_map(|message| match message {
+                open_router::RequestMessage::Tool { name, .. } => Some(name),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_strips_message_metadata_for_strict_models() {
+        let settings = OpenRouterSettings {
+            strip_unsupported_message_fields: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            tool_result_names("mistralai/mistral-large", &settings),
+            [None]
+        );
+        assert_eq!(
+            tool_result_names("cohere/command-r-plus", &settings),
+            [None]
+        );
+        assert_eq!(
+            tool_result_names("openai/gpt-4o", &settings),
+            [Some("list_directory".to_string())]
+        );
+
+        let settings = OpenRouterSettings {
+            strip_unsupported_message_fields: false,
+            ..Default::default()
+        };
+        assert_eq!(
+            tool_result_names("mistralai/mistral-large", &settings),
+            [Some("list_directory".to_string())]
+        );
+    }
+
+    #[test]
+    fn test_system_prompt_is_merged_into_existing_system_message() {
+        let model = AvailableModel {
+            name: "openai/gpt-4o".into(),
+            display_name: None,
+            max_tokens: 32000,
+            max_output_tokens: None,
+            max_completion_tokens: None,
+            context_length_override: None,
+            only: Vec::new(),
+            default_params: ModelParameters::default(),
+            system_role: None,
+            requires_max_tokens: None,
+            post_process: None,
+        };
+        let settings = OpenRouterSettings {
+            system_prompt: Some("Be concise.".into()),
+            ..Default::default()
+        };
+        let message = |role, text: &str| LanguageModelRequestMessage {
+            role,
+            content: vec![MessageContent::Text(text.into())],
+            cache: false,
+        };
+        let system_messages = |messages: Vec<LanguageModelRequestMessage>| {
+            let request = LanguageModelRequest {
+                messages,
+                ..Default::default()
+            };
+            into_open_router(request, &model, None, &settings)
+                .unwrap()
+                .messages
+                .into_iter()
+                .filter_map(|message| match message {
+                    open_router::RequestMessage::System { content } => Some(content),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            system_messages(vec![message(Role::User, "Hi")]),
+            ["Be concise."]
+        );
+        assert_eq!(
+            system_messages(vec![
+                message(Role::System, "You are a coding assistant."),
+                message(Role::User, "Hi"),
+            ]),
+            ["Be concise.\n\nYou are a coding assistant."]
+        );
+        assert_eq!(
+            system_messages(vec![
+                message(Role::System, "Be concise."),
+                message(Role::User, "Hi"),
+            ]),
+            ["Be concise."]
+        );
+    }
+
+    #[test]
+    fn test_diagnostic_leaves_out_prompt_and_api_key() {
+        let model = AvailableModel {
+            name: "openai/gpt-4o".into(),
+            display_name: None,
+            max_tokens: 32000,
+            max_output_tokens: None,
+            max_completion_tokens: None,
+            context_length_override: None,
+            only: Vec::new(),
+            default_params: ModelParameters::default(),
+            system_role: None,
+            requires_max_tokens: None,
+            post_process: None,
+        };
+        let request = LanguageModelRequest {
+            messages: vec![LanguageModelRequestMessage {
+                role: Role::User,
+                content: vec![MessageContent::Text("my secret prompt".into())],
+                cache: false,
+            }],
+            temperature: Some(0.5),
+            ..Default::default()
+        };
+        let request =
+            into_open_router(request, &model, None, &OpenRouterSettings::default()).unwrap();
+        let error = anyhow::Error::new(open_router::OpenRouterError::from_code(
+            429,
+            "Rate limited for key sk-or-v1-secret",
+        ));
+
+        let diagnostic = CompletionFailure::new(
+            &request.model,
+            open_router::OPEN_ROUTER_API_URL,
+            diagnostic_request_params(&request),
+            Some("gen-1234".into()),
+            &error,
+            Some("sk-or-v1-secret"),
+        )
+        .diagnostic();
+
+        assert!(diagnostic.contains("Model: openai/gpt-4o"));
+        assert!(diagnostic.contains("Request ID: gen-1234"));
+        assert!(diagnostic.contains("Status: 429"));
+        assert!(diagnostic.contains("\"temperature\": 0.5"));
+        assert!(diagnostic.contains("\"message_count\": 1"));
+        assert!(!diagnostic.contains("my secret prompt"));
+        assert!(!diagnostic.contains("sk-or-v1-secret"));
+    }
+
+    #[gpui::test]
+    async fn test_failure_after_tool_call_fragment_is_not_retried(cx: &mut TestAppContext) {
+        let tool_call_fragment: open_router::ResponseStreamEvent = serde_json::from_str(
+            r#"{"created":1,"model":"openai/gpt-4o","choices":[{"index":0,"delta":{"role":"assistant","content":null,"tool_calls":[{"index":0,"id":"call_1","function":{"name":"delete_path","arguments":""}}]},"finish_reason":null}]}"#,
+        )
+        .unwrap();
+        let fragment = Arc::new(smol::lock::Mutex::new(Some(tool_call_fragment)));
+        let attempts = Arc::new(AtomicUsize::new(0));
+        let start = {
+            let attempts = attempts.clone();
+            move |_| {
+                attempts.fetch_add(1, Ordering::SeqCst);
+                let fragment = fragment.clone();
+                async move {
+                    let fragment = fragment.lock().await.take().unwrap();
+                    let events: Vec<Result<open_router::ResponseStreamEvent>> = vec![
+                        Ok(fragment),
+                        Err(
+                            open_router::OpenRouterError::Transport(anyhow!("connection reset"))
+                                .into(),
+                        ),
+                    ];
+                    Ok(futures::stream::iter(events).boxed())
+                }
+                .boxed()
+            }
+        };
+
+        let policy = RetryPolicy::new(MAX_COMPLETION_RETRIES, RateLimitBehavior::Retry, 0);
+        let events = stream_with_retries(start, policy, cx.executor())
+            .await
+            .unwrap()
+            .collect::<Vec<_>>()
+            .await;
+
+        assert_eq!(attempts.load(Ordering::SeqCst), 1);
+        assert_eq!(events.len(), 2);
+        assert!(events[0].is_ok());
+        assert!(events[1].is_err());
+    }
+
+    #[gpui::test]
+    async fn test_cancellation_ends_stream_and_releases_permit(cx: &mut TestAppContext) {
+        let limiter = RateLimiter::new(1);
+        let (events_tx, events_rx) = mpsc::unbounded::<&str>();
+        let events = limiter
+            .stream(async move { Ok(events_rx) })
+            .await
+            .unwrap()
+            .boxed();
+        let (cancel, cancellation) = futures::future::AbortHandle::new_pair();
+        let mut events = until_cancelled(
+            events,
+            Abortable::new(futures::future::pending::<()>(), cancellation),
+            "cancelled",
+        );
+
+        events_tx.unbounded_send("Hello").unwrap();
+        assert_eq!(events.next().await, Some("Hello"));
+        assert!(limiter.is_saturated());
+
+        let next = cx.executor().spawn(async move { events.next().await });
+        cx.run_until_parked();
+        cancel.abort();
+        assert_eq!(next.await, Some("cancelled"));
+        assert!(!limiter.is_saturated());
+        assert_eq!(events.next().await, None);
+        assert!(events_tx.unbounded_send("world").is_err());
+    }
+
+    #[gpui::test]
+    async fn test_rate_limit_fails_over_to_fallback(cx: &mut TestAppContext) {
+        let attempts = Arc::new(Mutex::new(Vec::new()));
+        let start = {
+            let attempts = attempts.clone();
+            move |attempt: Attempt| {
+                attempts.lock().push(attempt);
+                let result: Result<BoxStream<'static, Result<open_router::ResponseStreamEvent>>> =
+                    if attempt == Attempt::First {
+                        Err(open_router::OpenRouterError::from_code(429, "Rate limited").into())
+                    } else {
+                        Ok(futures::stream::empty().boxed())
+                    };
+                futures::future::ready(result).boxed()
+            }
+        };
+
+        let policy = RetryPolicy::new(
+            MAX_COMPLETION_RETRIES,
+            RateLimitBehavior::FailoverToFallback,
+            1,
+        );
+        stream_with_retries(start, policy, cx.executor())
+            .await
+            .unwrap();
+        assert_eq!(*attempts.lock(), [Attempt::First, Attempt::Failover]);
+
+        let mut policy = RetryPolicy::new(MAX_COMPLETION_RETRIES, RateLimitBehavior::Error, 0);
+        let rate_limited: anyhow::Error =
+            open_router::OpenRouterError::from_code(429, "Rate limited").into();
+        assert_eq!(policy.next_attempt(&rate_limited), None);
+    }
+
+    #[gpui::test]
+    async fn test_coalesce_text_events_flushes_before_other_events(cx: &mut TestAppContext) {
+        let events = futures::stream::iter([
+            Ok(LanguageModelCompletionEvent::Text("Hel".into())),
+            Ok(LanguageModelCompletionEvent::Text("lo".into())),
+            Ok(LanguageModelCompletionEvent::Stop(StopReason::ToolUse)),
+            Ok(LanguageModelCompletionEvent::Text("!".into())),
+        ])
+        .boxed();
+
+        let events = coalesce_text_events(events, Duration::from_millis(16), cx.executor())
+            .map(|event| event.unwrap())
+            .collect::<Vec<_>>()
+            .await;
+
+        assert_eq!(
+            events,
+            [
+                LanguageModelCompletionEvent::Text("Hello".into()),
+                LanguageModelCompletionEvent::Stop(StopReason::ToolUse),
+                LanguageModelCompletionEvent::Text("!".into()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_text_and_tool_calls_keep_their_order() {
+        let events = [
+            r#"{"created":1,"model":"openai/gpt-4o","choices":[{"index":0,"delta":{"role":"assistant","content":"Let me look."},"finish_reason":null}]}"#,
+            r#"{"created":1,"model":"openai/gpt-4o","choices":[{"index":0,"delta":{"content":null,"tool_calls":[{"index":0,"id":"call_1","function":{"name":"read_file","arguments":"{\"path\":"}}]},"finish_reason":null}]}"#,
+            r#"{"created":1,"model":"openai/gpt-4o","choices":[{"index":0,"delta":{"content":null,"tool_calls":[{"index":0,"function":{"arguments":"\"a.rs\"}"}}]},"finish_reason":null}]}"#,
+            r#"{"created":1,"model":"openai/gpt-4o","choices":[{"index":0,"delta":{"content":"Then I'll fix it."},"finish_reason":null}]}"#,
+            r#"{"created":1,"model":"openai/gpt-4o","choices":[{"index":0,"delta":{"content":null,"tool_calls":[{"index":1,"id":"call_2","function":{"name":"edit_file","arguments":"{}"}}]},"finish_reason":"stop"}]}"#,
+        ]
+        .map(|event| Ok(serde_json::from_str::<open_router::ResponseStreamEvent>(event).unwrap()));
+
+        let events = futures::executor::block_on(
+            map_to_language_model_completion_events(futures::stream::iter(events).boxed())
+                .map(|event| event.unwrap())
+                .collect::<Vec<_>>(),
+        );
+
+        assert_eq!(
+            events,
+            [
+                LanguageModelCompletionEvent::Text("Let me look.".into()),
+                LanguageModelCompletionEvent::ToolUseStart {
+                    id: "call_1".into(),
+                    name: "read_file".into(),
+                },
+                LanguageModelCompletionEvent::ToolUse(LanguageModelToolUse {
+                    id: "call_1".into(),
+                    name: "read_file".into(),
+                    input: serde_json::json!({ "path": "a.rs" }),
+                }),
+                LanguageModelCompletionEvent::Text("Then I'll fix it.".into()),
+                LanguageModelCompletionEvent::ToolUseStart {
+                    id: "call_2".into(),
+                    name: "edit_file".into(),
+                },
+                LanguageModelCompletionEvent::ToolUse(LanguageModelToolUse {
+                    id: "call_2".into(),
+                    name: "edit_file".into(),
+                    input: serde_json::json!({}),
+                }),
+                LanguageModelCompletionEvent::Stop(StopReason::ToolUse),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_usage_before_finish_is_partial() {
+        let events = [
+            r#"{"created":1,"model":"openai/gpt-4o","choices":[{"index":0,"delta":{"content":"Hi"},"finish_reason":null}],"usage":{"prompt_tokens":10,"completion_tokens":1,"total_tokens":11}}"#,
+            r#"{"created":1,"model":"openai/gpt-4o","choices":[{"index":0,"delta":{"content":"!"},"finish_reason":"stop"}]}"#,
+            r#"{"created":1,"model":"openai/gpt-4o","choices":[],"usage":{"prompt_tokens":10,"completion_tokens":2,"total_tokens":12}}"#,
+        ]
+        .map(|event| Ok(serde_json::from_str::<open_router::ResponseStreamEvent>(event).unwrap()));
+
+        let events = futures::executor::block_on(
+            map_to_language_model_completion_events(futures::stream::iter(events).boxed())
+                .map(|event| event.unwrap())
+                .collect::<Vec<_>>(),
+        );
+
+        let usage = |output_tokens| TokenUsage {
+            input_tokens: 10,
+            output_tokens,
+            cache_creation_input_tokens: 0,
+            cache_read_input_tokens: 0,
+        };
+        assert_eq!(
+            events,
+            [
+                LanguageModelCompletionEvent::PartialUsageUpdate(usage(1)),
+                LanguageModelCompletionEvent::Text("Hi".into()),
+                LanguageModelCompletionEvent::Text("!".into()),
+                LanguageModelCompletionEvent::Stop(StopReason::EndTurn),
+                LanguageModelCompletionEvent::UsageUpdate(usage(2)),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_empty_response_is_reported_as_an_error() {
+        let event = open_router::ResponseStreamEvent {
+            id: None,
+            created: 0,
+            model: "openai/gpt-4o".into(),
+            choices: vec![open_router::ChoiceDelta {
+                index: 0,
+                delta: open_router::ResponseMessageDelta {
+                    role: Some(open_router::Role::Assistant),
+                    content: Some(String::new()),
+                    reasoning: None,
+                    tool_calls: None,
+                },
+                finish_reason: Some("stop".into()),
+                safety_ratings: Vec::new(),
+            }],
+            usage: None,
+        };
+
+        let events = futures::executor::block_on(
+            map_to_language_model_completion_events(futures::stream::iter([Ok(event)]).boxed())
+                .collect::<Vec<_>>(),
+        );
+
+        assert_eq!(
+            events.last().unwrap().as_ref().unwrap_err().to_string(),
+            EMPTY_RESPONSE_MESSAGE
+        );
+    }
+
+    #[test]
+    fn test_request_parameters_take_precedence_over_model_defaults() {
+        let model = AvailableModel {
+            name: "openai/gpt-4o".into(),
+            display_name: None,
+            max_tokens: 128000,
+            max_output_tokens: None,
+            max_completion_tokens: None,
+            context_length_override: None,
+            only: Vec::new(),
+            default_params: ModelParameters::parse("temperature=0.2, top_p=0.9").unwrap(),
+            system_role: None,
+            requires_max_tokens: None,
+            post_process: None,
+        };
+        let request = |temperature| LanguageModelRequest {
+            thread_id: None,
+            prompt_id: None,
+            messages: Vec::new(),
+            tools: Vec::new(),
+            stop: Vec::new(),
+            temperature,
+            tool_choice: None,
+        };
+        let settings = OpenRouterSettings::default();
+
+        let defaulted = into_open_router(request(None), &model, None, &settings).unwrap();
+        assert_eq!(defaulted.temperature, Some(0.2));
+        assert_eq!(defaulted.top_p, Some(0.9));
+
+        let overridden = into_open_router(request(Some(1.0)), &model, None, &settings).unwrap();
+        assert_eq!(overridden.temperature, Some(1.0));
+        assert_eq!(overridden.top_p, Some(0.9));
+
+        assert!(ModelParameters::parse("temperature=3").is_err());
+    }
+
+    #[test]
+    fn test_pinned_model_is_only_routed_to_its_providers() {
+        let model = AvailableModel {
+            name: "meta-llama/llama-3.3-70b-instruct".into(),
+            display_name: None,
+            max_tokens: 128000,
+            max_output_tokens: None,
+            max_completion_tokens: None,
+            context_length_override: None,
+            only: vec!["together".into()],
+            default_params: ModelParameters::default(),
+            system_role: None,
+            requires_max_tokens: None,
+            post_process: None,
+        };
+
+        let request = into_open_router(
+            LanguageModelRequest::default(),
+            &model,
+            None,
+            &OpenRouterSettings::default(),
+        )
+        .unwrap();
+
+        assert_eq!(
+            serde_json::to_value(&request).unwrap()["provider"],
+            serde_json::json!({ "only": ["together"] })
+        );
+    }
+
+    #[test]
+    fn test_output_limits_are_clamped_to_max_tokens() {
+        let mut model = AvailableModel {
+            name: "openai/gpt-4o".into(),
+            display_name: None,
+            max_tokens: 16000,
+            max_output_tokens: Some(32000),
+            max_completion_tokens: Some(8000),
+            context_length_override: None,
+            only: Vec::new(),
+            default_params: ModelParameters::default(),
+            system_role: None,
+            requires_max_tokens: None,
+            post_process: None,
+        };
+
+        model.clamp_output_limits();
+
+        assert_eq!(model.max_output_tokens, Some(16000));
+        assert_eq!(model.max_completion_tokens, Some(8000));
+    }
+
+    #[test]
+    fn test_catalog_changes_between_fetches() {
+        let model = |id: &str, prompt_price: &str| open_router::Model {
+            id: id.into(),
+            name: id.into(),
+            context_length: 128000,
+            top_provider: None,
+            supported_parameters: Vec::new(),
+            architecture: None,
+            pricing: Some(open_router::Pricing {
+                prompt: prompt_price.into(),
+                completion: "0.00001".into(),
+            }),
+        };
+        let previous = [
+            model("anthropic/claude-3.7-sonnet", "0.000003"),
+            model("openai/gpt-4o", "0.000005"),
+            model("openai/gpt-4o-mini", "0.00000015"),
+        ];
+        let current = [
+            model("anthropic/claude-3.7-sonnet", "0.000003"),
+            model("openai/gpt-4.1", "0.000002"),
+            model("openai/gpt-4o", "0.0000025"),
+        ];
+
+        let changes = CatalogChanges::between(&previous, &current);
+        assert_eq!(
+            changes,
+            CatalogChanges {
+                added: vec!["openai/gpt-4.1".into()],
+                removed: vec!["openai/gpt-4o-mini".into()],
+                repriced: vec!["openai/gpt-4o".into()],
+            }
+        );
+        assert_eq!(
+            changes.summary(),
+            "OpenRouter catalog updated: 1 new model, 1 removed, 1 repriced."
+        );
+        assert!(CatalogChanges::between(&current, &current).is_empty());
+    }
+
+    #[gpui::test]
+    fn test_request_limiters_are_created_on_first_use(cx: &mut TestAppContext) {
+        init_test(cx);
+
+        let http_client = FakeHttpClient::with_404_response();
+        let provider = cx.update(|cx| {
+            OpenRouterLanguageModelProvider::new(http_client, FakeFs::new(cx.executor()), cx)
+        });
+        let model = |name: &str| AvailableModel {
+            name: name.into(),
+            display_name: None,
+            max_tokens: 128000,
+            max_output_tokens: None,
+            max_completion_tokens: None,
+            context_length_override: None,
+            only: Vec::new(),
+            default_params: ModelParameters::default(),
+            system_role: None,
+            requires_max_tokens: None,
+            post_process: None,
+        };
+        let (gpt, _claude, gpt_again) = cx.update(|cx| {
+            (
+                provider.build_language_model(model("openai/gpt-4o"), cx),
+                provider.build_language_model(model("anthropic/claude-3.7-sonnet"), cx),
+                provider.build_language_model(model("openai/gpt-4o"), cx),
+            )
+        });
+        assert!(provider.request_limiters.0.lock().is_empty());
 
-        cx.notify();
+        gpt.request_limiter();
+        gpt_again.request_limiter();
+        assert_eq!(
+            provider
+                .request_limiters
+                .0
+                .lock()
+                .keys()
+                .collect::<Vec<_>>(),
+            ["openai/gpt-4o"]
+        );
+    }
+
+    #[gpui::test]
+    async fn test_reset_api_key_cancels_pending_catalog_fetch(cx: &mut TestAppContext) {
+        init_test(cx);
+
+        let (release_response_tx, release_response_rx) = smol::channel::bounded::<()>(1);
+        let http_client = FakeHttpClient::create(move |_| {
+            let release_response_rx = release_response_rx.clone();
+            async move {
+                release_response_rx.recv().await.ok();
+                Ok(Response::builder()
+                    .status(200)
+                    .body(CATALOG_RESPONSE.into())
+                    .unwrap())
+            }
+        });
+        let provider = cx.update(|cx| {
+            OpenRouterLanguageModelProvider::new(http_client, FakeFs::new(cx.executor()), cx)
+        });
+        let state = provider.state.clone();
+
+        state.update(cx, |state, cx| {
+            state.api_key = Some("sk-or-old-key".into());
+            state.restart_fetch_catalog_task(cx);
+        });
+        cx.run_until_parked();
+        state.read_with(cx, |state, _| {
+            assert!(state.fetch_catalog_task.is_some());
+            assert!(state.catalog.is_none());
+        });
+
+        let reset = state.update(cx, |state, cx| state.reset_api_key(cx));
+        state.read_with(cx, |state, _| {
+            assert!(state.fetch_catalog_task.is_none());
+            assert!(state.catalog.is_none());
+        });
+        reset.await.unwrap();
+
+        release_response_tx.send(()).await.unwrap();
+        cx.run_until_parked();
+        state.read_with(cx, |state, _| {
+            assert!(!state.is_authenticated());
+            assert!(state.catalog.is_none());
+            assert!(state.enabled_models.is_empty());
+        });
+    }
+
+    fn tool(name: &str, input_schema: serde_json::Value) -> LanguageModelRequestTool {
+        LanguageModelRequestTool {
+            name: name.into(),
+            description: "A test tool".into(),
+            input_schema,
+        }
+    }
+
+    #[test]
+    fn test_tool_with_nested_schema_is_converted() {
+        let schema = serde_json::json!({
+            "type": "object",
+            "properties": {
+                "path": { "type": "string" },
+                "edits": {
+                    "type": "array",
+                    "items": {
+                        "type": "object",
+                        "properties": {
+                            "old_text": { "type": "string" },
+                            "new_text": { "type": "string" },
+                            "mode": { "anyOf": [{ "const": "replace" }, { "const": "insert" }] }
+                        },
+                        "required": ["old_text", "new_text"]
+                    }
+                },
+                "options": { "$ref": "#/$defs/options" }
+            },
+            "required": ["path", "edits"],
+            "$defs": {
+                "options": {
+                    "type": "object",
+                    "properties": { "dry_run": { "type": ["boolean", "null"] } },
+                    "additionalProperties": false
+                }
+            }
+        });
+
+        let definition = into_open_router_tool(tool("edit_file", schema.clone())).unwrap();
+
+        assert_eq!(
+            serde_json::to_value(&definition).unwrap(),
+            serde_json::json!({
+                "type": "function",
+                "function": {
+                    "name": "edit_file",
+                    "description": "A test tool",
+                    "parameters": schema
+                }
+            })
+        );
+    }
+
+    #[test]
+    fn test_tool_without_parameters_is_converted() {
+        let schema = serde_json::json!({ "type": "object", "properties": {} });
+        assert!(into_open_router_tool(tool("now", schema)).is_ok());
     }
 
-    fn render_api_key_editor(&self, cx: &mut Context<Self>) -> impl IntoElement {
-        let settings = ThemeSettings::get_global(cx);
-        let text_style = TextStyle {
-            color: cx.theme().colors().text,
-            font_family: settings.ui_font.family.clone(),
-            font_features: settings.ui_font.features.clone(),
-            font_fallbacks: settings.ui_font.fallbacks.clone(),
-            font_size: rems(0.875).into(),
-            font_weight: settings.ui_font.weight,
-            font_style: FontStyle::Normal,
-            line_height: relative(1.3),
-            white_space: WhiteSpace::Normal,
+    #[test]
+    fn test_malformed_tool_schemas_are_rejected() {
+        let error = |name: &str, schema: serde_json::Value| {
+            format!(
+                "{:#}",
+                into_open_router_tool(tool(name, schema)).unwrap_err()
+            )
+        };
+
+        assert!(error("bad name!", serde_json::json!({ "type": "object" })).contains("bad name!"));
+        assert!(
+            error("list", serde_json::json!({ "type": "array" }))
+                .contains("the root must be an object schema")
+        );
+        assert!(
+            error(
+                "read",
+                serde_json::json!({
+                    "type": "object",
+                    "properties": { "path": { "type": "string" } },
+                    "required": ["paths"]
+                })
+            )
+            .contains("#/required lists \"paths\"")
+        );
+        assert!(
+            error(
+                "grep",
+                serde_json::json!({
+                    "type": "object",
+                    "properties": {
+                        "globs": { "type": "array", "items": { "type": 1 } }
+                    }
+                })
+            )
+            .contains("#/properties/globs/items/type must be a string")
+        );
+        assert!(
+            error(
+                "run",
+                serde_json::json!({ "type": "object", "properties": ["command"] })
+            )
+            .contains("#/properties must be an object")
+        );
+    }
+
+    #[test]
+    fn test_invalid_tool_fails_request_conversion() {
+        let model = AvailableModel {
+            name: "openai/gpt-4o".into(),
+            display_name: None,
+            max_tokens: 128000,
+            max_output_tokens: None,
+            max_completion_tokens: None,
+            context_length_override: None,
+            only: Vec::new(),
+            default_params: ModelParameters::default(),
+            system_role: None,
+            requires_max_tokens: None,
+            post_process: None,
+        };
+        let request = LanguageModelRequest {
+            tools: vec![tool("broken", serde_json::json!("not a schema"))],
+            ..Default::default()
+        };
+
+        assert!(into_open_router(request, &model, None, &OpenRouterSettings::default()).is_err());
+    }
+
+    #[gpui::test]
+    fn test_low_credit_mode_offers_only_free_models(cx: &mut TestAppContext) {
+        init_test(cx);
+
+        let http_client = FakeHttpClient::with_404_response();
+        let provider = cx.update(|cx| {
+            OpenRouterLanguageModelProvider::new(http_client, FakeFs::new(cx.executor()), cx)
+        });
+        let catalog: Vec<open_router::Model> = serde_json::from_str(
+            r#"[
+                {"id":"openai/gpt-4o","name":"GPT-4o","context_length":128000,"pricing":{"prompt":"0.0000025","completion":"0.00001"}},
+                {"id":"meta-llama/llama-3.3-70b-instruct:free","name":"Llama 3.3 70B (free)","context_length":131072},
+                {"id":"qwen/qwen3-coder","name":"Qwen3 Coder","context_length":262144,"pricing":{"prompt":"0","completion":"0"}}
+            ]"#,
+        )
+        .unwrap();
+        let settings = OpenRouterSettings {
+            low_credit_mode: true,
+            low_credit_alert_threshold: Some(1.0),
+            ..Default::default()
+        };
+
+        provider.state.update(cx, |state, _| {
+            state.catalog = Some(catalog);
+            state.credits = Some(open_router::Credits {
+                total_credits: 10.0,
+                total_usage: 9.5,
+            });
+            assert!(state.low_credit_mode_active(&settings));
+            assert!(!state.is_free_model("openai/gpt-4o"));
+            assert!(state.is_free_model("meta-llama/llama-3.3-70b-instruct:free"));
+            assert!(state.is_free_model("qwen/qwen3-coder"));
+
+            state.credits = Some(open_router::Credits {
+                total_credits: 20.0,
+                total_usage: 9.5,
+            });
+            assert!(!state.low_credit_mode_active(&settings));
+            assert!(!state.low_credit_mode_active(&OpenRouterSettings {
+                low_credit_mode: false,
+                ..settings.clone()
+            }));
+        });
+    }
+
+    #[gpui::test]
+    fn test_preflight_rejects_prompts_larger_than_the_context_window(cx: &mut TestAppContext) {
+        init_test(cx);
+
+        let http_client = FakeHttpClient::with_404_response();
+        let provider = cx.update(|cx| {
+            OpenRouterLanguageModelProvider::new(http_client, FakeFs::new(cx.executor()), cx)
+        });
+        let model = cx.update(|cx| {
+            provider.build_language_model(
+                AvailableModel {
+                    name: "openai/gpt-4o".into(),
+                    display_name: None,
+                    max_tokens: 64,
+                    max_output_tokens: None,
+                    max_completion_tokens: None,
+                    context_length_override: None,
+                    only: Vec::new(),
+                    default_params: ModelParameters::default(),
+                    system_role: None,
+                    requires_max_tokens: None,
+                    post_process: None,
+                },
+                cx,
+            )
+        });
+        let request = |text: String| LanguageModelRequest {
+            messages: vec![LanguageModelRequestMessage {
+                role: Role::User,
+                content: vec![MessageContent::Text(text)],
+                cache: false,
+            }],
             ..Default::default()
         };
-        EditorElement::new(
-            &self.api_key_editor,
-            EditorStyle {
-                background: cx.theme().colors().editor_background,
-                local_player: cx.theme().players().local(),
-                text: text_style,
+
+        assert!(model.check_context_fits(&request("Hello".into())).is_ok());
+
+        let error = model
+            .check_context_fits(&request("word ".repeat(1000)))
+            .unwrap_err();
+        assert!(error.downcast_ref::<LanguageModelKnownError>().is_some());
+        let message = error.to_string();
+        assert!(message.contains("exceeds the context window of openai/gpt-4o"));
+        assert!(message.contains("at most 64"));
+    }
+
+    #[gpui::test]
+    fn test_telemetry_id_keeps_variant_suffix(cx: &mut TestAppContext) {
+        init_test(cx);
+
+        let http_client = FakeHttpClient::with_404_response();
+        let provider = cx.update(|cx| {
+            OpenRouterLanguageModelProvider::new(http_client, FakeFs::new(cx.executor()), cx)
+        });
+        let telemetry_id = |name: &str| {
+            cx.update(|cx| {
+                provider
+                    .create_language_model(
+                        AvailableModel {
+                            name: name.into(),
+                            display_name: None,
+                            max_tokens: 128000,
+                            max_output_tokens: None,
+                            max_completion_tokens: None,
+                            context_length_override: None,
+                            only: Vec::new(),
+                            default_params: ModelParameters::default(),
+                            system_role: None,
+                            requires_max_tokens: None,
+                            post_process: None,
+                        },
+                        cx,
+                    )
+                    .telemetry_id()
+            })
+        };
+
+        assert_eq!(
+            telemetry_id("meta-llama/llama-3.3-70b-instruct"),
+            "openrouter/meta-llama/llama-3.3-70b-instruct"
+        );
+        assert_eq!(
+            telemetry_id("meta-llama/llama-3.3-70b-instruct:free"),
+            "openrouter/meta-llama/llama-3.3-70b-instruct:free"
+        );
+        assert_eq!(
+            telemetry_id("meta-llama/llama-3.3-70b-instruct:nitro"),
+            "openrouter/meta-llama/llama-3.3-70b-instruct:nitro"
+        );
+    }
+
+    #[test]
+    fn test_dropped_stream_resumes_from_received_text() {
+        let event = |json: &str| {
+            Ok(serde_json::from_str::<open_router::ResponseStreamEvent>(json).unwrap())
+        };
+        let dropped = || -> Result<open_router::ResponseStreamEvent> {
+            Err(open_router::OpenRouterError::Transport(anyhow!("connection reset")).into())
+        };
+        let request = into_open_router(
+            LanguageModelRequest {
+                messages: vec![LanguageModelRequestMessage {
+                    role: Role::User,
+                    content: vec![MessageContent::Text("Write a story".into())],
+                    cache: false,
+                }],
                 ..Default::default()
             },
+            &AvailableModel::from(
+                &serde_json::from_str::<open_router::Model>(
+                    r#"{"id":"anthropic/claude-sonnet-4","name":"Claude Sonnet 4","context_length":200000}"#,
+                )
+                .unwrap(),
+            ),
+            None,
+            &OpenRouterSettings::default(),
         )
+        .unwrap();
+
+        let first = futures::stream::iter([
+            event(r#"{"created":1,"model":"anthropic/claude-sonnet-4","choices":[{"index":0,"delta":{"content":"Once upon"},"finish_reason":null}]}"#),
+            dropped(),
+        ])
+        .boxed();
+        let resumed_requests = Arc::new(Mutex::new(Vec::new()));
+        let restart = {
+            let resumed_requests = resumed_requests.clone();
+            move |request: open_router::Request| {
+                resumed_requests.lock().push(request);
+                let events = futures::stream::iter([event(
+                    r#"{"created":1,"model":"anthropic/claude-sonnet-4","choices":[{"index":0,"delta":{"content":" a time."},"finish_reason":"stop"}]}"#,
+                )])
+                .boxed();
+                futures::future::ready(Ok(events)).boxed()
+            }
+        };
+
+        let text = futures::executor::block_on(
+            resume_dropped_stream(first, request.clone(), MAX_STREAM_RESUMES, restart)
+                .map(|event| event.unwrap().choices[0].delta.content.clone().unwrap())
+                .collect::<String>(),
+        );
+        assert_eq!(text, "Once upon a time.");
+        assert_eq!(
+            resumed_requests.lock()[0].messages.last(),
+            Some(&open_router::RequestMessage::Assistant {
+                content: Some("Once upon".into()),
+                tool_calls: Vec::new(),
+            })
+        );
+
+        // A tool call can't be continued from a prefill, so the drop is surfaced instead.
+        let with_tool_call = futures::stream::iter([
+            event(r#"{"created":1,"model":"anthropic/claude-sonnet-4","choices":[{"index":0,"delta":{"content":null,"tool_calls":[{"index":0,"id":"call_1","function":{"name":"read_file","arguments":"{\"pa"}}]},"finish_reason":null}]}"#),
+            dropped(),
+        ])
+        .boxed();
+        let events = futures::executor::block_on(
+            resume_dropped_stream(with_tool_call, request, MAX_STREAM_RESUMES, |_| {
+                unreachable!("tool calls shouldn't be resumed")
+            })
+            .collect::<Vec<_>>(),
+        );
+        assert_eq!(events.len(), 2);
+        assert!(is_dropped_connection(events[1].as_ref().unwrap_err()));
+    }
+
+    #[test]
+    fn test_system_messages_use_the_role_the_model_expects() {
+        let roles = |name: &str, system_role: Option<SystemRole>| {
+            let model = AvailableModel {
+                name: name.into(),
+                display_name: None,
+                max_tokens: 128000,
+                max_output_tokens: None,
+                max_completion_tokens: None,
+                context_length_override: None,
+                only: Vec::new(),
+                default_params: ModelParameters::default(),
+                system_role,
+                requires_max_tokens: None,
+                post_process: None,
+            };
+            let request = LanguageModelRequest {
+                messages: vec![
+                    LanguageModelRequestMessage {
+                        role: Role::System,
+                        content: vec![MessageContent::Text("Be brief.".into())],
+                        cache: false,
+                    },
+                    LanguageModelRequestMessage {
+                        role: Role::User,
+                        content: vec![MessageContent::Text("Hi".into())],
+                        cache: false,
+                    },
+                ],
+                ..Default::default()
+            };
+            let request =
+                into_open_router(request, &model, None, &OpenRouterSettings::default()).unwrap();
+            serde_json::to_value(&request.messages)
+                .unwrap()
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|message| message["role"].as_str().unwrap().to_string())
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(roles("openai/gpt-4o", None), ["system", "user"]);
+        assert_eq!(roles("openai/o3-mini", None), ["developer", "user"]);
+        assert_eq!(
+            roles("openai/o3-mini", Some(SystemRole::System)),
+            ["system", "user"]
+        );
+        assert_eq!(
+            roles("openai/gpt-4.1", Some(SystemRole::Developer)),
+            ["developer", "user"]
+        );
+    }
+
+    #[test]
+    fn test_request_params_summary_leaves_out_prompt() {
+        let model = AvailableModel {
+            name: "openai/gpt-4o".into(),
+            display_name: None,
+            max_tokens: 128000,
+            max_output_tokens: None,
+            max_completion_tokens: None,
+            context_length_override: None,
+            only: vec!["azure".into()],
+            default_params: ModelParameters::parse("temperature=0.5").unwrap(),
+            system_role: None,
+            requires_max_tokens: None,
+            post_process: None,
+        };
+        let request = LanguageModelRequest {
+            messages: vec![LanguageModelRequestMessage {
+                role: Role::User,
+                content: vec![MessageContent::Text("secret plans".into())],
+                cache: false,
+            }],
+            ..Default::default()
+        };
+        let request =
+            into_open_router(request, &model, Some(4096), &OpenRouterSettings::default()).unwrap();
+
+        let params = diagnostic_request_params(&request);
+        let summary = request_params_summary(&params);
+        assert_eq!(
+            summary,
+            r#"openai/gpt-4o · temperature 0.5 · max_tokens 4096 · provider {"only":["azure"]}"#
+        );
+        assert!(!params.to_string().contains("secret plans"));
+    }
+
+    #[test]
+    fn test_safety_ratings_are_reported_once() {
+        let events = [
+            r#"{"created":1,"model":"google/gemini-2.5-pro","choices":[{"index":0,"delta":{"content":"Sure"},"finish_reason":null,"safety_ratings":[{"category":"HARM_CATEGORY_DANGEROUS_CONTENT","probability":"LOW"}]}]}"#,
+            r#"{"created":1,"model":"google/gemini-2.5-pro","choices":[{"index":0,"delta":{"content":"."},"finish_reason":null,"safety_ratings":[{"category":"HARM_CATEGORY_DANGEROUS_CONTENT","probability":"LOW"}]}]}"#,
+            r#"{"created":1,"model":"google/gemini-2.5-pro","choices":[{"index":0,"delta":{"content":""},"finish_reason":"stop","safetyRatings":[{"category":"HARM_CATEGORY_DANGEROUS_CONTENT","probability":"HIGH","blocked":true}]}]}"#,
+        ]
+        .map(|event| Ok(serde_json::from_str::<open_router::ResponseStreamEvent>(event).unwrap()));
+
+        let events = futures::executor::block_on(
+            map_to_language_model_completion_events(futures::stream::iter(events).boxed())
+                .map(|event| event.unwrap())
+                .collect::<Vec<_>>(),
+        );
+
+        let rating = |probability: &str, blocked| LanguageModelSafetyRating {
+            category: "HARM_CATEGORY_DANGEROUS_CONTENT".into(),
+            probability: probability.into(),
+            blocked,
+        };
+        assert_eq!(
+            events,
+            [
+                LanguageModelCompletionEvent::SafetyRatings(vec![rating("LOW", false)]),
+                LanguageModelCompletionEvent::Text("Sure".into()),
+                LanguageModelCompletionEvent::Text(".".into()),
+                LanguageModelCompletionEvent::SafetyRatings(vec![rating("HIGH", true)]),
+                LanguageModelCompletionEvent::Text("".into()),
+                LanguageModelCompletionEvent::Stop(StopReason::EndTurn),
+            ]
+        );
+    }
+
+    #[gpui::test]
+    fn test_duplicate_model_ids_produce_a_single_model(cx: &mut TestAppContext) {
+        init_test(cx);
+
+        let http_client = FakeHttpClient::with_404_response();
+        let provider = cx.update(|cx| {
+            OpenRouterLanguageModelProvider::new(http_client, FakeFs::new(cx.executor()), cx)
+        });
+        let model = |display_name: &str| AvailableModel {
+            name: "openai/gpt-4o".into(),
+            display_name: Some(display_name.into()),
+            max_tokens: 128000,
+            max_output_tokens: None,
+            max_completion_tokens: None,
+            context_length_override: None,
+            only: Vec::new(),
+            default_params: ModelParameters::default(),
+            system_role: None,
+            requires_max_tokens: None,
+            post_process: None,
+        };
+
+        let enabled_models = dedup_by_id(
+            vec![model("First"), model("Second")],
+            |model| &model.name,
+            "model",
+        );
+        assert_eq!(enabled_models, [model("First")]);
+
+        provider.state.update(cx, |state, _| {
+            state.catalog = Some(serde_json::from_str(r#"[{"id":"openai/gpt-4o","name":"GPT-4o","context_length":128000,"supported_parameters":["tools"]}]"#).unwrap());
+            state.enabled_models = vec![model("First"), model("Second")];
+        });
+        let models = cx.update(|cx| provider.provided_models(cx));
+        assert_eq!(models.len(), 1);
+        assert_eq!(models[0].id().0.as_ref(), "openai/gpt-4o");
+        assert_eq!(models[0].name().0.as_ref(), "First");
+    }
+
+    #[gpui::test]
+    async fn test_stream_transcript_starts_a_new_file_each_session(cx: &mut TestAppContext) {
+        let fs = FakeFs::new(cx.executor());
+        let path = PathBuf::from("/logs/transcript.log");
+        fs.insert_file(&path, b"previous session\n".to_vec()).await;
+        let transcript = StreamTranscript {
+            fs: fs.clone(),
+            path: path.clone(),
+            include_prompt: false,
+            started: Arc::default(),
+        };
+
+        let request: open_router::Request = serde_json::from_value(serde_json::json!({
+            "model": "openai/gpt-4o",
+            "messages": [{"role": "user", "content": "secret prompt"}],
+            "stream": true,
+        }))
+        .unwrap();
+        let mut writer = TranscriptWriter::new(transcript.clone(), &request, cx.executor());
+        writer.push_event(
+            &serde_json::from_value(serde_json::json!({
+                "id": "gen-1",
+                "created": 0,
+                "model": "openai/gpt-4o",
+                "choices": [{
+                    "index": 0,
+                    "delta": {"role": "assistant", "content": "Hello"},
+                    "finish_reason": "stop",
+                }],
+            }))
+            .unwrap(),
+        );
+        drop(writer);
+        cx.run_until_parked();
+        transcript.append("second entry\n".into()).await.unwrap();
+
+        let contents = fs.load(&path).await.unwrap();
+        assert!(contents.contains("--- response ---\nHello\n"), "{contents}");
+        assert!(contents.contains("--- finished: stop ---"), "{contents}");
+        assert!(contents.ends_with("second entry\n"), "{contents}");
+        assert!(!contents.contains("secret prompt"), "{contents}");
+        assert!(!contents.contains("previous session"), "{contents}");
+        assert_eq!(
+            fs.load(Path::new("/logs/transcript.log.old"))
+                .await
+                .unwrap(),
+            "previous session\n"
+        );
+    }
+
+    #[test]
+    fn test_stop_after_tool_calls_ends_the_response() {
+        let events = [
+            r#"{"created":1,"model":"openai/gpt-4o","choices":[{"index":0,"delta":{"role":"assistant","content":"Let me look."},"finish_reason":null}]}"#,
+            r#"{"created":1,"model":"openai/gpt-4o","choices":[{"index":0,"delta":{"content":null,"tool_calls":[{"index":0,"id":"call_1","function":{"name":"read_file","arguments":"{\"path\":"}}]},"finish_reason":null}]}"#,
+            r#"{"created":1,"model":"openai/gpt-4o","choices":[{"index":0,"delta":{"content":"","tool_calls":[{"index":0,"function":{"arguments":"\"a.rs\"}"}}]},"finish_reason":null}]}"#,
+            r#"{"created":1,"model":"openai/gpt-4o","choices":[{"index":0,"delta":{"content":"Then I'll fix it."},"finish_reason":null}]}"#,
+            r#"{"created":1,"model":"openai/gpt-4o","choices":[{"index":0,"delta":{"content":null,"tool_calls":[{"index":1,"id":"call_2","function":{"name":"edit_file","arguments":"{}"}}]},"finish_reason":"stop"}]}"#,
+        ]
+        .map(|event| Ok(serde_json::from_str::<open_router::ResponseStreamEvent>(event).unwrap()));
+
+        let events = futures::executor::block_on(
+            map_to_language_model_completion_events(end_after_tool_calls(
+                futures::stream::iter(events).boxed(),
+            ))
+            .map(|event| event.unwrap())
+            .collect::<Vec<_>>(),
+        );
+
+        assert_eq!(
+            events,
+            [
+                LanguageModelCompletionEvent::Text("Let me look.".into()),
+                LanguageModelCompletionEvent::ToolUseStart {
+                    id: "call_1".into(),
+                    name: "read_file".into(),
+                },
+                LanguageModelCompletionEvent::ToolUse(LanguageModelToolUse {
+                    id: "call_1".into(),
+                    name: "read_file".into(),
+                    input: serde_json::json!({ "path": "a.rs" }),
+                }),
+                LanguageModelCompletionEvent::Stop(StopReason::ToolUse),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_model_id_format() {
+        let id = "meta-llama/llama-3.3-70b-instruct:free";
+        assert_eq!(ModelIdFormat::Full.format(id), id);
+        assert_eq!(ModelIdFormat::Provider.format(id), "meta-llama");
+        assert_eq!(
+            ModelIdFormat::Model.format(id),
+            "llama-3.3-70b-instruct:free"
+        );
+        assert_eq!(
+            ModelIdFormat::Provider.format("openrouter-auto"),
+            "openrouter-auto"
+        );
+    }
+
+    #[test]
+    fn test_request_metadata_is_bounded() {
+        let mut tags = (0..20)
+            .map(|i| (format!("tag-{i:02}"), "value".to_string()))
+            .collect::<HashMap<_, _>>();
+        tags.insert("a".repeat(MAX_METADATA_KEY_LEN + 1), "value".into());
+        tags.insert("cost_center".into(), "v".repeat(MAX_METADATA_VALUE_LEN + 1));
+
+        let metadata = request_metadata(&tags);
+        assert_eq!(metadata.len(), MAX_METADATA_TAGS);
+        assert_eq!(metadata.keys().next().unwrap(), "tag-00");
+        assert!(!metadata.contains_key("cost_center"));
+
+        let request = open_router::Request {
+            metadata: request_metadata(&HashMap::default()),
+            ..serde_json::from_value(serde_json::json!({
+                "model": "openai/gpt-4o",
+                "messages": [],
+                "stream": true,
+            }))
+            .unwrap()
+        };
+        assert!(
+            serde_json::to_value(&request)
+                .unwrap()
+                .get("metadata")
+                .is_none()
+        );
+    }
+
+    #[gpui::test]
+    async fn test_user_cancel_stops_with_cancelled_reason(cx: &mut TestAppContext) {
+        init_test(cx);
+
+        let http_client = FakeHttpClient::with_404_response();
+        let provider = cx.update(|cx| {
+            OpenRouterLanguageModelProvider::new(http_client, FakeFs::new(cx.executor()), cx)
+        });
+        let model = cx.update(|cx| {
+            provider.build_language_model(
+                AvailableModel {
+                    name: "openai/gpt-4o".into(),
+                    display_name: None,
+                    max_tokens: 128000,
+                    max_output_tokens: None,
+                    max_completion_tokens: None,
+                    context_length_override: None,
+                    only: Vec::new(),
+                    default_params: ModelParameters::default(),
+                    system_role: None,
+                    requires_max_tokens: None,
+                    post_process: None,
+                },
+                cx,
+            )
+        });
+
+        let (cancel, cancellation) = futures::future::AbortHandle::new_pair();
+        cancel.abort();
+        let events = model
+            .stream_completion_with_cancellation(
+                LanguageModelRequest::default(),
+                cancellation,
+                &cx.to_async(),
+            )
+            .await
+            .unwrap()
+            .collect::<Vec<_>>()
+            .await;
+
+        assert_eq!(events.len(), 1);
+        assert!(matches!(
+            events[0],
+            Ok(LanguageModelCompletionEvent::Stop(StopReason::Cancelled))
+        ));
     }
 
-    fn render_editor(&self, cx: &mut Context<Self>) -> impl ui::IntoElement {
-        v_flex()
-          .size_full()
-          .on_action(cx.listener(Self::save_api_key))
-          .child(Label::new("To use Zed's assistant with OpenRouter, you need to add an API key. Follow these steps:"))
-          .child(
-              List::new()
-                  .child(InstructionListItem::new(
-                      "Create one by visiting",
-                      Some("OpenRouter's console"),
-                      Some("https://openrouter.ai/settings/keys"),
-                  ))
-                  .child(InstructionListItem::text_only(
-                      "Ensure your OpenRouter account has credits",
-                  ))
-                  .child(InstructionListItem::text_only(
-                      "Paste your API key below and hit enter to start using the assistant",
-                  )),
-          )
-          .child(
-              h_flex()
-                  .w_full()
-                  .my_2()
-                  .px_2()
-                  .py_1()
-                  .bg(cx.theme().colors().editor_background)
-                  .border_1()
-                  .border_color(cx.theme().colors().border_variant)
-                  .rounded_sm()
-                  .child(self.render_api_key_editor(cx)),
-          )
-          .child(
-              Label::new(
-                  format!("You can also assign the {OPENROUTER_API_KEY_VAR} environment variable and restart Zed."),
-              )
-              .size(LabelSize::Small).color(Color::Muted),
-          )
-          .into_any()
-    }
-
-    fn render_settings(&self, cx: &mut Context<Self>) -> impl ui::IntoElement {
-        h_flex()
-          .size_full()
-          .justify_between()
-          .child(
-            h_flex()
-              .gap_1()
-              .child(Icon::new(IconName::Check).color(Color::Success))
-              .child(Label::new(match env_var_set {
-                true => format!("API key set in {OPENROUTER_API_KEY_VAR} environment variable."),
-                false => "API key configured.".to_string(),
-              })),
-          )
-          .child(
-            Button::new("reset-key", "Reset key")
-              .icon(Some(IconName::Trash))
-              .icon_size(IconSize::Small)
-              .icon_position(IconPosition::Start)
-              .disabled(env_var_set)
-              .when(env_var_set, |this| {
-                this.tooltip(Tooltip::text(format!("To reset your API key, unset the {OPENROUTER_API_KEY_VAR} environment variable.")))
-              })
-              .on_click(cx.listener(|this, _, window, cx| this.reset_api_key(window, cx))),
-          )
-          // TODO: add model selector for OpenRouter
-          .into_any()
-    }
-
-    fn should_render_editor(&self, cx: &mut Context<Self>) -> bool {
-        !self.state.read(cx).is_authenticated()
-    }
-}
-
-impl Render for ConfigurationView {
-    fn render(
-        &mut self,
-        window: &mut ui::Window,
-        cx: &mut ui::Context<'_, Self>,
-    ) -> impl ui::IntoElement {
-        let env_var_set = self.state.read(cx).api_key_from_env;
-
-        match (self.load_credentials_task, self.should_render_editor(cx)) {
-            (None, true) => self.render_editor(cx),
-            (None, false) => self.render_settings(cx),
-            (Some(_), _) => div().child(Label::new("Loading credentials...")).into_any(),
+    #[test]
+    fn test_models_requiring_max_tokens_always_send_it() {
+        let model = |name: &str, requires_max_tokens| AvailableModel {
+            name: name.into(),
+            display_name: None,
+            max_tokens: 200000,
+            max_output_tokens: Some(16000),
+            max_completion_tokens: None,
+            context_length_override: None,
+            only: Vec::new(),
+            default_params: ModelParameters::default(),
+            system_role: None,
+            requires_max_tokens,
+        };
+        let max_tokens = |model: &AvailableModel| {
+            into_open_router(
+                LanguageModelRequest::default(),
+                model,
+                None,
+                &OpenRouterSettings::default(),
+            )
+            .unwrap()
+            .max_tokens
+        };
+
+        assert_eq!(
+            max_tokens(&model("anthropic/claude-3.7-sonnet", None)),
+            Some(16000)
+        );
+        assert_eq!(
+            max_tokens(&model("acme/strict-model", Some(true))),
+            Some(16000)
+        );
+        assert_eq!(max_tokens(&model("openai/gpt-4o", None)), None);
+        assert_eq!(
+            max_tokens(&model("anthropic/claude-3.7-sonnet", Some(false))),
+            None
+        );
+    }
+
+    #[test]
+    fn test_empty_or_garbled_stored_api_keys_count_as_missing() {
+        let key = format!("sk-or-v1-{}", "0".repeat(64));
+        let not_found = |bytes: &[u8]| {
+            matches!(
+                parse_stored_api_key(bytes.to_vec()),
+                Err(AuthenticateError::CredentialsNotFound)
+            )
+        };
+
+        assert!(not_found(b""));
+        assert!(not_found(&[0xe2, 0x82]));
+        assert_eq!(parse_stored_api_key(key.clone().into_bytes()).unwrap(), key);
+        assert_eq!(
+            parse_stored_api_key(key.as_bytes()[..20].to_vec()).unwrap(),
+            key[..20]
+        );
+        assert_eq!(
+            parse_stored_api_key(format!("{key}\n").into_bytes()).unwrap(),
+            format!("{key}\n")
+        );
+        assert_eq!(
+            parse_stored_api_key(b"gateway-key".to_vec()).unwrap(),
+            "gateway-key"
+        );
+    }
+
+    #[test]
+    fn test_multiple_choices_are_demultiplexed() {
+        let events = [
+            r#"{"created":1,"model":"openai/gpt-4o","choices":[{"index":0,"delta":{"role":"assistant","content":"Red"},"finish_reason":null},{"index":1,"delta":{"role":"assistant","content":"Bl"},"finish_reason":null}]}"#,
+            r#"{"created":1,"model":"openai/gpt-4o","choices":[{"index":1,"delta":{"content":"ue"},"finish_reason":"stop"}]}"#,
+            r#"{"created":1,"model":"openai/gpt-4o","choices":[{"index":0,"delta":{"content":"!"},"finish_reason":"stop"}],"usage":{"prompt_tokens":10,"completion_tokens":4,"total_tokens":14}}"#,
+        ]
+        .map(|event| Ok(serde_json::from_str::<open_router::ResponseStreamEvent>(event).unwrap()));
+
+        let events = futures::executor::block_on(
+            map_choices_to_language_model_completion_events(futures::stream::iter(events).boxed())
+                .map(|event| event.unwrap())
+                .collect::<Vec<_>>(),
+        );
+
+        let choice = |index| {
+            events
+                .iter()
+                .filter(|(event_index, _)| *event_index == index)
+                .map(|(_, event)| event.clone())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            choice(0),
+            [
+                LanguageModelCompletionEvent::Text("Red".into()),
+                LanguageModelCompletionEvent::UsageUpdate(TokenUsage {
+                    input_tokens: 10,
+                    output_tokens: 4,
+                    cache_creation_input_tokens: 0,
+                    cache_read_input_tokens: 0,
+                }),
+                LanguageModelCompletionEvent::Text("!".into()),
+                LanguageModelCompletionEvent::Stop(StopReason::EndTurn),
+            ]
+        );
+        assert_eq!(
+            choice(1),
+            [
+                LanguageModelCompletionEvent::Text("Bl".into()),
+                LanguageModelCompletionEvent::Text("ue".into()),
+                LanguageModelCompletionEvent::Stop(StopReason::EndTurn),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_older_available_models_deserialize_with_defaults() {
+        let mut models: Vec<AvailableModel> = serde_json::from_value(serde_json::json!([
+            {
+                "name": "openai/gpt-4o",
+                "display_name": "GPT-4o",
+                "max_tokens": 128000,
+                "max_output_tokens": 16384
+            },
+            { "name": "acme/legacy-model" }
+        ]))
+        .unwrap();
+
+        assert_eq!(models[0].context_length_override, None);
+        assert!(models[0].only.is_empty());
+        assert_eq!(models[0].default_params, ModelParameters::default());
+        assert_eq!(models[0].system_role, None);
+        assert_eq!(models[0].requires_max_tokens, None);
+        assert_eq!(models[1].max_tokens, 0);
+
+        assert!(upgrade_available_models(&mut models));
+        assert_eq!(models[0].display_name.as_deref(), Some("GPT-4o"));
+        assert_eq!(models[0].max_tokens, 128000);
+        assert_eq!(models[0].max_output_tokens, Some(16384));
+        assert_eq!(models[1].max_tokens, open_router::DEFAULT_CONTEXT_LENGTH);
+        assert!(!upgrade_available_models(&mut models));
+    }
+
+    #[gpui::test]
+    fn test_older_available_models_are_flagged_for_migration(cx: &mut TestAppContext) {
+        init_test(cx);
+        cx.update(|cx| {
+            cx.update_global::<SettingsStore, _>(|store, cx| {
+                store
+                    .set_user_settings(
+                        r#"{
+                            "language_models": {
+                                "openrouter": {
+                                    "available_models": [
+                                        { "name": "openai/gpt-4o", "max_tokens": 128000 },
+                                        { "name": "acme/legacy-model", "display_name": "Legacy" }
+                                    ]
+                                }
+                            }
+                        }"#,
+                        cx,
+                    )
+                    .unwrap();
+            });
+
+            let settings = &AllLanguageModelSettings::get_global(cx).openrouter;
+            assert!(settings.needs_setting_migration);
+            assert_eq!(settings.available_models.len(), 2);
+            assert_eq!(settings.available_models[0].max_tokens, 128000);
+            assert_eq!(
+                settings.available_models[1].display_name.as_deref(),
+                Some("Legacy")
+            );
+            assert_eq!(
+                settings.available_models[1].max_tokens,
+                open_router::DEFAULT_CONTEXT_LENGTH
+            );
+        });
+    }
+
+    #[test]
+    fn test_post_process_text_strips_fences_before_stop() {
+        let post_processor = PostProcessor::new(&PostProcess {
+            strip_code_fences: true,
+            remove_pattern: Some(r"(?m)^Here is the code:\s*".into()),
+        })
+        .unwrap();
+        let events = futures::stream::iter([
+            Ok(LanguageModelCompletionEvent::Text(
+                "Here is the code:\n```rust\n".into(),
+            )),
+            Ok(LanguageModelCompletionEvent::Text("fn main() {}\n".into())),
+            Ok(LanguageModelCompletionEvent::StatusUpdate(
+                CompletionRequestStatus::Started,
+            )),
+            Ok(LanguageModelCompletionEvent::Text("```\n".into())),
+            Ok(LanguageModelCompletionEvent::Stop(StopReason::EndTurn)),
+        ])
+        .boxed();
+
+        let events = futures::executor::block_on(
+            post_process_text(events, post_processor)
+                .map(|event| event.unwrap())
+                .collect::<Vec<_>>(),
+        );
+
+        assert_eq!(
+            events,
+            [
+                LanguageModelCompletionEvent::StatusUpdate(CompletionRequestStatus::Started),
+                LanguageModelCompletionEvent::Text("fn main() {}".into()),
+                LanguageModelCompletionEvent::Stop(StopReason::EndTurn),
+            ]
+        );
+        assert_eq!(strip_code_fence("```\na\n```\nb\n```\nc\n```"), None);
+        assert!(PostProcessor::new(&PostProcess::default()).is_none());
+    }
+
+    #[test]
+    fn test_post_process_text_keeps_text_before_tool_calls() {
+        let post_processor = PostProcessor::new(&PostProcess {
+            strip_code_fences: false,
+            remove_pattern: Some(r"(?m)^Sure!\s*".into()),
+        })
+        .unwrap();
+        let tool_use = LanguageModelToolUse {
+            id: "call_1".into(),
+            name: "read_file".into(),
+            input: serde_json::json!({ "path": "a.rs" }),
+        };
+        let events = futures::stream::iter([
+            Ok(LanguageModelCompletionEvent::Text("Sure! ".into())),
+            Ok(LanguageModelCompletionEvent::Text("Let me look.".into())),
+            Ok(LanguageModelCompletionEvent::ToolUse(tool_use.clone())),
+            Ok(LanguageModelCompletionEvent::Text("Sure! Done.".into())),
+            Ok(LanguageModelCompletionEvent::Stop(StopReason::EndTurn)),
+        ])
+        .boxed();
+
+        let events = futures::executor::block_on(
+            post_process_text(events, post_processor)
+                .map(|event| event.unwrap())
+                .collect::<Vec<_>>(),
+        );
+
+        assert_eq!(
+            events,
+            [
+                LanguageModelCompletionEvent::Text("Let me look.".into()),
+                LanguageModelCompletionEvent::ToolUse(tool_use),
+                LanguageModelCompletionEvent::Text("Done.".into()),
+                LanguageModelCompletionEvent::Stop(StopReason::EndTurn),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_report_progress_estimates_against_max_tokens() {
+        let usage = TokenUsage {
+            output_tokens: 20,
+            ..Default::default()
+        };
+        let events = futures::stream::iter([
+            Ok(LanguageModelCompletionEvent::Text("a a a a a".into())),
+            Ok(LanguageModelCompletionEvent::Text(String::new())),
+            Ok(LanguageModelCompletionEvent::UsageUpdate(usage)),
+            Ok(LanguageModelCompletionEvent::Stop(StopReason::EndTurn)),
+        ])
+        .boxed();
+
+        let events = futures::executor::block_on(
+            report_progress(events, "openai/gpt-4o".into(), 10)
+                .map(|event| event.unwrap())
+                .collect::<Vec<_>>(),
+        );
+
+        let halfway = CompletionRequestStatus::Generating {
+            output_tokens: 5,
+            max_tokens: 10,
+        };
+        let done = CompletionRequestStatus::Generating {
+            output_tokens: 10,
+            max_tokens: 10,
+        };
+        assert_eq!(
+            events,
+            [
+                LanguageModelCompletionEvent::Text("a a a a a".into()),
+                LanguageModelCompletionEvent::StatusUpdate(halfway),
+                LanguageModelCompletionEvent::Text(String::new()),
+                LanguageModelCompletionEvent::UsageUpdate(usage),
+                LanguageModelCompletionEvent::StatusUpdate(done),
+                LanguageModelCompletionEvent::Stop(StopReason::EndTurn),
+            ]
+        );
+        assert_eq!(halfway.progress(), Some(0.5));
+        assert_eq!(done.progress(), Some(1.));
+    }
+
+    #[test]
+    fn test_flagged_prompt_blocks_generation() {
+        let message = |role, text: &str| LanguageModelRequestMessage {
+            role,
+            content: vec![MessageContent::Text(text.into())],
+            cache: false,
+        };
+        let request = LanguageModelRequest {
+            thread_id: None,
+            prompt_id: None,
+            messages: vec![
+                message(Role::User, "Hello"),
+                message(Role::Assistant, "Hi!"),
+                message(Role::User, "Something questionable"),
+            ],
+            tools: Vec::new(),
+            stop: Vec::new(),
+            temperature: None,
+            tool_choice: None,
+        };
+        let model = AvailableModel {
+            name: "openai/gpt-4o".into(),
+            display_name: None,
+            max_tokens: 128000,
+            max_output_tokens: None,
+            max_completion_tokens: None,
+            context_length_override: None,
+            only: Vec::new(),
+            default_params: ModelParameters::default(),
+            system_role: None,
+            requires_max_tokens: None,
+            post_process: None,
+        };
+        let request =
+            into_open_router(request, &model, None, &OpenRouterSettings::default()).unwrap();
+        assert_eq!(moderation_input(&request), "Something questionable");
+
+        let http_client = FakeHttpClient::create(|request| async move {
+            assert_eq!(request.uri().path(), "/api/v1/moderations");
+            Ok(Response::builder()
+                .status(200)
+                .body(
+                    r#"{"results":[{"flagged":true,"categories":{"harassment":true,"violence":false}}]}"#
+                        .into(),
+                )
+                .unwrap())
+        });
+        let error = futures::executor::block_on(screen_prompt(
+            http_client.as_ref(),
+            "https://openrouter.ai/api/v1",
+            "sk-or-v1-test",
+            &request,
+        ))
+        .unwrap_err();
+
+        match error.downcast_ref::<open_router::OpenRouterError>() {
+            Some(open_router::OpenRouterError::ContentFiltered(message)) => {
+                assert_eq!(
+                    message.message,
+                    "The prompt was flagged by moderation for: harassment"
+                );
+            }
+            _ => panic!("expected a content filter error, got {error:#}"),
         }
     }
+
+    #[test]
+    fn test_credentials_scope_replaces_api_url_as_key() {
+        let mut settings = OpenRouterSettings {
+            api_url: "https://openrouter.ai/api/v1".into(),
+            ..Default::default()
+        };
+        assert_eq!(settings.credentials_key(), "https://openrouter.ai/api/v1");
+
+        settings.credentials_scope = Some("zed-openrouter".into());
+        assert_eq!(settings.credentials_key(), "zed-openrouter");
+    }
+
+    #[test]
+    fn test_default_tool_choice_applies_when_the_request_has_none() {
+        let model = AvailableModel {
+            name: "openai/gpt-4o".into(),
+            display_name: None,
+            max_tokens: 128000,
+            max_output_tokens: None,
+            max_completion_tokens: None,
+            context_length_override: None,
+            only: Vec::new(),
+            default_params: ModelParameters::default(),
+            system_role: None,
+            requires_max_tokens: None,
+            post_process: None,
+        };
+        let settings = OpenRouterSettings {
+            default_tool_choice: Some(LanguageModelToolChoice::Required),
+            ..Default::default()
+        };
+        let request = |tools: Vec<LanguageModelRequestTool>, tool_choice| LanguageModelRequest {
+            tools,
+            tool_choice,
+            ..Default::default()
+        };
+        let tool = || LanguageModelRequestTool {
+            name: "read_file".into(),
+            description: "Reads a file".into(),
+            input_schema: serde_json::json!({ "type": "object" }),
+        };
+
+        let open_router_request =
+            into_open_router(request(vec![tool()], None), &model, None, &settings).unwrap();
+        assert_eq!(
+            open_router_request.tool_choice,
+            Some(open_router::ToolChoice::Required)
+        );
+
+        let open_router_request = into_open_router(
+            request(vec![tool()], Some(LanguageModelToolChoice::None)),
+            &model,
+            None,
+            &settings,
+        )
+        .unwrap();
+        assert_eq!(
+            open_router_request.tool_choice,
+            Some(open_router::ToolChoice::None)
+        );
+
+        let open_router_request =
+            into_open_router(request(Vec::new(), None), &model, None, &settings).unwrap();
+        assert_eq!(open_router_request.tool_choice, None);
+    }
+
+    #[test]
+    fn test_gateway_without_usage_emits_no_usage_events() {
+        let events = [
+            r#"{"created":1,"model":"openai/gpt-4o","choices":[{"index":0,"delta":{"content":"Hi"},"finish_reason":null}]}"#,
+            r#"{"created":1,"model":"openai/gpt-4o","choices":[{"index":0,"delta":{"content":"!"},"finish_reason":null}],"usage":{}}"#,
+            r#"{"created":1,"model":"openai/gpt-4o","choices":[{"index":0,"delta":{},"finish_reason":"stop"}],"usage":{"prompt_tokens":0,"completion_tokens":0,"total_tokens":0}}"#,
+        ]
+        .map(|event| Ok(serde_json::from_str::<open_router::ResponseStreamEvent>(event).unwrap()));
+        let usage_unavailable = Arc::new(AtomicBool::new(false));
+
+        let events = futures::executor::block_on(
+            report_usage_telemetry(
+                "openai/gpt-4o".into(),
+                InFlightUsageRecords::default(),
+                usage_unavailable.clone(),
+                map_to_language_model_completion_events(futures::stream::iter(events).boxed()),
+                |event| event,
+            )
+            .map(|event| event.unwrap())
+            .collect::<Vec<_>>(),
+        );
+
+        assert_eq!(
+            events,
+            [
+                LanguageModelCompletionEvent::Text("Hi".into()),
+                LanguageModelCompletionEvent::Text("!".into()),
+                LanguageModelCompletionEvent::Stop(StopReason::EndTurn),
+            ]
+        );
+        assert!(usage_unavailable.load(Ordering::Relaxed));
+    }
+
+    #[gpui::test]
+    fn test_warm_model_stands_in_until_the_catalog_loads(cx: &mut TestAppContext) {
+        init_test(cx);
+        let provider = cx.update(|cx| {
+            cx.update_global::<SettingsStore, _>(|store, cx| {
+                store
+                    .set_user_settings(
+                        r#"{
+                            "language_models": {
+                                "openrouter": {
+                                    "available_models": [
+                                        { "name": "openai/gpt-4o", "max_tokens": 128000 }
+                                    ]
+                                }
+                            }
+                        }"#,
+                        cx,
+                    )
+                    .unwrap();
+            });
+            OpenRouterLanguageModelProvider::new(
+                FakeHttpClient::with_404_response(),
+                FakeFs::new(cx.executor()),
+                cx,
+            )
+        });
+        let entry = |context_length: usize| {
+            serde_json::from_value::<open_router::Model>(serde_json::json!({
+                "id": "openai/gpt-4o",
+                "name": "OpenAI: GPT-4o",
+                "context_length": context_length,
+                "supported_parameters": ["tools"],
+            }))
+            .unwrap()
+        };
+
+        provider.state.update(cx, |state, cx| {
+            state.warm_model = Some(entry(128000));
+            state.update_enabled_models(cx);
+            assert!(state.catalog.is_none());
+            assert_eq!(state.visible_models().len(), 1);
+
+            state.catalog = Some(vec![entry(64000)]);
+            state.validate_warm_model(cx);
+            assert_eq!(state.warm_model, Some(entry(64000)));
+
+            state.catalog = Some(Vec::new());
+            state.validate_warm_model(cx);
+            assert_eq!(state.warm_model, None);
+        });
+    }
+
+    fn serialized_request(settings: &OpenRouterSettings) -> serde_json::Value {
+        let model = AvailableModel {
+            name: "openai/gpt-4o".into(),
+            display_name: None,
+            max_tokens: 128000,
+            max_output_tokens: None,
+            max_completion_tokens: None,
+            context_length_override: None,
+            only: Vec::new(),
+            default_params: ModelParameters::default(),
+            system_role: None,
+            requires_max_tokens: None,
+            post_process: None,
+        };
+        let request = LanguageModelRequest {
+            messages: vec![LanguageModelRequestMessage {
+                role: Role::User,
+                content: vec![MessageContent::Text("Hello".into())],
+                cache: false,
+            }],
+            ..Default::default()
+        };
+        serde_json::to_value(into_open_router(request, &model, None, settings).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_user_is_omitted_without_user_id() {
+        let request = serialized_request(&OpenRouterSettings::default());
+        assert!(request.get("user").is_none());
+    }
+
+    #[test]
+    fn test_user_id_is_hashed_before_sending() {
+        let settings = OpenRouterSettings {
+            user_id: Some("jane@example.com".into()),
+            ..Default::default()
+        };
+        let request = serialized_request(&settings);
+        assert_eq!(
+            request["user"],
+            serde_json::json!(hash_user_id("jane@example.com"))
+        );
+        assert_eq!(request["user"].as_str().unwrap().len(), 64);
+        assert!(!request.to_string().contains("jane@example.com"));
+    }
+
+    #[gpui::test]
+    fn test_reasoning_is_only_sent_to_models_that_may_support_it(cx: &mut TestAppContext) {
+        init_test(cx);
+        let provider = cx.update(|cx| {
+            OpenRouterLanguageModelProvider::new(
+                FakeHttpClient::with_404_response(),
+                FakeFs::new(cx.executor()),
+                cx,
+            )
+        });
+        let entry = |id: &str, supported_parameters: &[&str]| {
+            serde_json::from_value::<open_router::Model>(serde_json::json!({
+                "id": id,
+                "name": id,
+                "context_length": 128000,
+                "supported_parameters": supported_parameters,
+            }))
+            .unwrap()
+        };
+        provider.state.update(cx, |state, _| {
+            state.catalog = Some(vec![
+                entry("openai/gpt-4o", &["tools"]),
+                entry("deepseek/deepseek-r1", &["tools", "reasoning"]),
+            ]);
+        });
+
+        let supports_reasoning = |name: &str, cx: &mut TestAppContext| {
+            let model = AvailableModel {
+                name: name.into(),
+                display_name: None,
+                max_tokens: 128000,
+                max_output_tokens: None,
+                max_completion_tokens: None,
+                context_length_override: None,
+                only: Vec::new(),
+                default_params: ModelParameters::default(),
+                system_role: None,
+                requires_max_tokens: None,
+                post_process: None,
+            };
+            cx.update(|cx| provider.build_language_model(model, cx).supports_reasoning)
+        };
+        assert!(!supports_reasoning("openai/gpt-4o", cx));
+        assert!(supports_reasoning("deepseek/deepseek-r1", cx));
+        assert!(supports_reasoning("acme/unlisted-model", cx));
+    }
+
+    #[gpui::test]
+    async fn test_reset_all_deletes_the_catalog_cache(cx: &mut TestAppContext) {
+        init_test(cx);
+        let fs = FakeFs::new(cx.executor());
+        let cache_path = catalog_cache_path();
+        fs.create_dir(cache_path.parent().unwrap()).await.unwrap();
+        fs.insert_file(&cache_path, CATALOG_RESPONSE.as_bytes().to_vec())
+            .await;
+        let provider = cx.update(|cx| {
+            OpenRouterLanguageModelProvider::new(
+                FakeHttpClient::with_404_response(),
+                fs.clone(),
+                cx,
+            )
+        });
+
+        provider
+            .state
+            .update(cx, |state, cx| state.reset_all(cx))
+            .await
+            .unwrap();
+        assert!(!fs.is_file(&cache_path).await);
+    }
+
+    #[gpui::test]
+    async fn test_fan_out_keeps_streaming_when_one_model_fails(cx: &mut TestAppContext) {
+        use futures::AsyncReadExt as _;
+
+        init_test(cx);
+        let http_client = FakeHttpClient::create(|request| async move {
+            let mut body = String::new();
+            request.into_body().read_to_string(&mut body).await?;
+            if body.contains("acme/broken") {
+                return Ok(Response::builder()
+                    .status(400)
+                    .body(
+                        r#"{"error":{"code":400,"message":"acme/broken is not a valid model ID"}}"#
+                            .into(),
+                    )
+                    .unwrap());
+            }
+            Ok(Response::builder()
+                .status(200)
+                .body(
+                    concat!(
+                        r#"data: {"created":1,"model":"openai/gpt-4o","choices":[{"index":0,"delta":{"role":"assistant","content":"Hello"},"finish_reason":"stop"}]}"#,
+                        "\n\ndata: [DONE]\n\n",
+                    )
+                    .into(),
+                )
+                .unwrap())
+        });
+        let provider = cx.update(|cx| {
+            OpenRouterLanguageModelProvider::new(http_client, FakeFs::new(cx.executor()), cx)
+        });
+        provider.state.update(cx, |state, _| {
+            state.api_key = Some("sk-or-test".into());
+        });
+        let model = |name: &str, cx: &mut TestAppContext| {
+            let model = AvailableModel {
+                name: name.into(),
+                display_name: None,
+                max_tokens: 128000,
+                max_output_tokens: None,
+                max_completion_tokens: None,
+                context_length_override: None,
+                only: Vec::new(),
+                default_params: ModelParameters::default(),
+                system_role: None,
+                requires_max_tokens: None,
+                post_process: None,
+            };
+            cx.update(|cx| provider.build_language_model(model, cx))
+        };
+        let models = [model("openai/gpt-4o", cx), model("acme/broken", cx)];
+        let working_id = models[0].id();
+        let broken_id = models[1].id();
+        let request = LanguageModelRequest {
+            messages: vec![LanguageModelRequestMessage {
+                role: Role::User,
+                content: vec![MessageContent::Text("Hi".into())],
+                cache: false,
+            }],
+            ..Default::default()
+        };
+
+        let events = fan_out_completion(&models, request, &cx.to_async())
+            .collect::<Vec<_>>()
+            .await;
+
+        let broken_events = events
+            .iter()
+            .filter(|(id, _)| *id == broken_id)
+            .collect::<Vec<_>>();
+        assert_eq!(broken_events.len(), 1);
+        assert!(broken_events[0].1.is_err());
+        let working_events = events
+            .into_iter()
+            .filter(|(id, _)| *id == working_id)
+            .map(|(_, event)| event.unwrap())
+            .collect::<Vec<_>>();
+        assert!(working_events.contains(&LanguageModelCompletionEvent::Text("Hello".into())));
+        assert!(working_events.contains(&LanguageModelCompletionEvent::Stop(StopReason::EndTurn)));
+    }
+
+    #[gpui::test]
+    async fn test_warm_model_is_loaded_from_the_cache(cx: &mut TestAppContext) {
+        init_test(cx);
+        cx.update(|cx| {
+            cx.update_global::<SettingsStore, _>(|store, cx| {
+                store
+                    .set_user_settings(
+                        r#"{
+                            "language_models": {
+                                "openrouter": {
+                                    "cache_last_used_model": true,
+                                    "available_models": [
+                                        { "name": "openai/gpt-4o", "max_tokens": 128000 }
+                                    ]
+                                }
+                            }
+                        }"#,
+                        cx,
+                    )
+                    .unwrap();
+            })
+        });
+        let model = serde_json::from_value::<open_router::Model>(serde_json::json!({
+            "id": "openai/gpt-4o",
+            "name": "OpenAI: GPT-4o",
+            "context_length": 128000,
+            "supported_parameters": ["tools"],
+        }))
+        .unwrap();
+        let cache = CachedWarmModel {
+            api_url: cx.update(|cx| {
+                AllLanguageModelSettings::get_global(cx)
+                    .openrouter
+                    .api_url
+                    .clone()
+            }),
+            model: model.clone(),
+        };
+        let fs = FakeFs::new(cx.executor());
+        let cache_path = warm_model_cache_path();
+        fs.create_dir(cache_path.parent().unwrap()).await.unwrap();
+        fs.insert_file(&cache_path, serde_json::to_vec(&cache).unwrap())
+            .await;
+
+        let provider = cx.update(|cx| {
+            OpenRouterLanguageModelProvider::new(FakeHttpClient::with_404_response(), fs, cx)
+        });
+        cx.run_until_parked();
+
+        provider.state.read_with(cx, |state, _| {
+            assert_eq!(state.warm_model, Some(model));
+            assert_eq!(state.visible_models().len(), 1);
+        });
+    }
 }
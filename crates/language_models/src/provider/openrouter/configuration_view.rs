@@ -0,0 +1,1601 @@
+use anyhow::Result;
+use editor::{Editor, EditorElement, EditorEvent, EditorStyle};
+use fs::Fs;
+use gpui::{
+    App, ClipboardItem, Context, Entity, FocusHandle, FontStyle, KeyDownEvent, PromptLevel, Task,
+    TextStyle, WhiteSpace,
+};
+use http_client::Url;
+use language_model::LanguageModelRegistry;
+use settings::{Settings, update_settings_file};
+use std::sync::atomic::Ordering;
+use theme::ThemeSettings;
+use ui::{
+    Disclosure, Icon, IconName, Indicator, List, SwitchWithLabel, Table, Tooltip, prelude::*,
+};
+use util::ResultExt;
+
+use crate::{AllLanguageModelSettings, ui::InstructionListItem};
+
+use super::settings::{AvailableModel, ModelParameters, ReasoningMode};
+use super::{
+    ModelProbe, PROVIDER_ID, RECOMMENDED_MODEL_IDS, State, comparison_capabilities,
+    format_price_per_million, model_capabilities, request_params_summary,
+};
+
+fn validate_api_url(api_url: &str) -> Result<(), SharedString> {
+    match Url::parse(api_url) {
+        Ok(url) if matches!(url.scheme(), "http" | "https") && url.has_host() => Ok(()),
+        Ok(_) => Err("The API URL must be an absolute http or https URL.".into()),
+        Err(error) => Err(format!("Invalid API URL: {error}.").into()),
+    }
+}
+/// Which setting of an enabled model is being edited in place.
+#[derive(Clone, Copy, PartialEq)]
+enum ModelEditField {
+    DisplayName,
+    ContextLength,
+    DefaultParams,
+}
+struct ModelEdit {
+    model_name: String,
+    field: ModelEditField,
+    editor: Entity<Editor>,
+    error: Option<SharedString>,
+}
+pub(super) struct ConfigurationView {
+    api_key_editor: Entity<Editor>,
+    api_url_editor: Entity<Editor>,
+    api_url_error: Option<SharedString>,
+    system_prompt_editor: Entity<Editor>,
+    /// Focus for the model list, which is navigated with the `menu` actions.
+    models_focus_handle: FocusHandle,
+    selected_model_ix: usize,
+    /// What has been typed while the model list is focused, used to jump to a matching model.
+    model_typeahead: String,
+    model_edit: Option<ModelEdit>,
+    /// Show the enabled models side by side in a table instead of as a list.
+    show_model_comparison: bool,
+    /// Show every parameter of the last request rather than a one-line summary.
+    request_params_expanded: bool,
+    /// Show the public catalog before an API key is added.
+    show_catalog_preview: bool,
+    state: Entity<State>,
+    load_credentials_task: Option<Task<()>>,
+}
+impl ConfigurationView {
+    pub(super) fn new(state: Entity<State>, window: &mut Window, cx: &mut Context<Self>) -> Self {
+        let api_key_editor = cx.new(|cx| {
+            let mut editor = Editor::single_line(window, cx);
+            editor.set_placeholder_text(
+                "sk-or-v1-0000000000000000000000000000000000000000000000000000000000000000",
+                cx,
+            );
+            editor
+        });
+
+        let api_url_editor = cx.new(|cx| {
+            let mut editor = Editor::single_line(window, cx);
+            editor.set_placeholder_text(open_router::OPEN_ROUTER_API_URL, cx);
+            editor.set_text(
+                AllLanguageModelSettings::get_global(cx)
+                    .openrouter
+                    .api_url
+                    .clone(),
+                window,
+                cx,
+            );
+            editor
+        });
+
+        let system_prompt_editor = cx.new(|cx| {
+            let mut editor = Editor::single_line(window, cx);
+            editor.set_placeholder_text("Be concise.", cx);
+            if let Some(system_prompt) = AllLanguageModelSettings::get_global(cx)
+                .openrouter
+                .system_prompt
+                .clone()
+            {
+                editor.set_text(system_prompt, window, cx);
+            }
+            editor
+        });
+
+        cx.subscribe(&api_url_editor, |this, editor, event, cx| {
+            if let EditorEvent::BufferEdited = event {
+                let api_url = editor.read(cx).text(cx);
+                this.api_url_error = validate_api_url(api_url.trim()).err();
+                cx.notify();
+            }
+        })
+        .detach();
+
+        cx.observe(&state, |_, _, cx| {
+            cx.notify();
+        })
+        .detach();
+
+        // The reasoning toggle depends on the default model.
+        cx.observe(&LanguageModelRegistry::global(cx), |_, _, cx| {
+            cx.notify();
+        })
+        .detach();
+
+        let load_credentials_task = Some(cx.spawn_in(window, {
+            let state = state.clone();
+            async move |this, cx| {
+                if let Some(task) = state
+                    .update(cx, |state, cx| state.authenticate(cx))
+                    .log_err()
+                {
+                    // We don't log an error, because "not signed in" is also an error.
+                    let _ = task.await;
+                }
+
+                this.update(cx, |this, cx| {
+                    this.load_credentials_task = None;
+                    cx.notify();
+                })
+                .log_err();
+            }
+        }));
+
+        Self {
+            api_key_editor,
+            api_url_editor,
+            api_url_error: None,
+            system_prompt_editor,
+            models_focus_handle: cx.focus_handle(),
+            selected_model_ix: 0,
+            model_typeahead: String::new(),
+            model_edit: None,
+            show_model_comparison: false,
+            request_params_expanded: false,
+            show_catalog_preview: false,
+            state,
+            load_credentials_task,
+        }
+    }
+
+    fn save_api_key(&mut self, _: &menu::Confirm, window: &mut Window, cx: &mut Context<Self>) {
+        let api_key = self.api_key_editor.read(cx).text(cx);
+        if api_key.is_empty() {
+            return;
+        }
+
+        let state = self.state.clone();
+        cx.spawn_in(window, async move |_, cx| {
+            state
+                .update(cx, |state, cx| state.set_api_key(api_key, cx))?
+                .await
+        })
+        .detach_and_log_err(cx);
+
+        cx.notify();
+    }
+
+    fn reset_api_key(&mut self, window: &mut Window, cx: &mut Context<Self>) {
+        self.api_key_editor
+            .update(cx, |editor, cx| editor.set_text("", window, cx));
+
+        let state = self.state.clone();
+        cx.spawn_in(window, async move |_, cx| {
+            state.update(cx, |state, cx| state.reset_api_key(cx))?.await
+        })
+        .detach_and_log_err(cx);
+
+        cx.notify();
+    }
+
+    fn reset_all_state(&mut self, window: &mut Window, cx: &mut Context<Self>) {
+        let answer = window.prompt(
+            PromptLevel::Warning,
+            "Reset OpenRouter?",
+            Some(
+                "This deletes your stored API key and clears the cached model catalog and credit \
+                balance. Your settings are kept.",
+            ),
+            &["Reset", "Cancel"],
+            cx,
+        );
+        let state = self.state.clone();
+        cx.spawn_in(window, async move |this, cx| {
+            if answer.await != Ok(0) {
+                return Ok(());
+            }
+            this.update_in(cx, |this, window, cx| {
+                this.api_key_editor
+                    .update(cx, |editor, cx| editor.set_text("", window, cx));
+            })?;
+            state.update(cx, |state, cx| state.reset_all(cx))?.await
+        })
+        .detach_and_log_err(cx);
+    }
+
+    fn save_api_url(&mut self, _: &menu::Confirm, _window: &mut Window, cx: &mut Context<Self>) {
+        let api_url = self.api_url_editor.read(cx).text(cx);
+        let api_url = api_url.trim().trim_end_matches('/').to_string();
+        if let Err(error) = validate_api_url(&api_url) {
+            self.api_url_error = Some(error);
+            cx.notify();
+            return;
+        }
+
+        if AllLanguageModelSettings::get_global(cx).openrouter.api_url == api_url {
+            return;
+        }
+
+        update_settings_file::<AllLanguageModelSettings>(
+            <dyn Fs>::global(cx),
+            cx,
+            move |settings, _| {
+                settings.openrouter.get_or_insert_default().api_url = Some(api_url);
+            },
+        );
+    }
+
+    fn save_system_prompt(
+        &mut self,
+        _: &menu::Confirm,
+        _window: &mut Window,
+        cx: &mut Context<Self>,
+    ) {
+        let system_prompt = self.system_prompt_editor.read(cx).text(cx);
+        let system_prompt = Some(system_prompt.trim().to_string())
+            .filter(|system_prompt| !system_prompt.is_empty());
+        if AllLanguageModelSettings::get_global(cx)
+            .openrouter
+            .system_prompt
+            == system_prompt
+        {
+            return;
+        }
+
+        update_settings_file::<AllLanguageModelSettings>(
+            <dyn Fs>::global(cx),
+            cx,
+            move |settings, _| {
+                settings.openrouter.get_or_insert_default().system_prompt = system_prompt;
+            },
+        );
+    }
+
+    fn select_model(&mut self, ix: usize, cx: &mut Context<Self>) {
+        let model_count = self.state.read(cx).enabled_models.len();
+        self.selected_model_ix = ix.min(model_count.saturating_sub(1));
+        self.model_typeahead.clear();
+        cx.notify();
+    }
+
+    fn select_next_model(&mut self, _: &menu::SelectNext, _: &mut Window, cx: &mut Context<Self>) {
+        self.select_model(self.selected_model_ix + 1, cx);
+    }
+
+    fn select_previous_model(
+        &mut self,
+        _: &menu::SelectPrevious,
+        _: &mut Window,
+        cx: &mut Context<Self>,
+    ) {
+        self.select_model(self.selected_model_ix.saturating_sub(1), cx);
+    }
+
+    fn select_first_model(
+        &mut self,
+        _: &menu::SelectFirst,
+        _: &mut Window,
+        cx: &mut Context<Self>,
+    ) {
+        self.select_model(0, cx);
+    }
+
+    fn select_last_model(&mut self, _: &menu::SelectLast, _: &mut Window, cx: &mut Context<Self>) {
+        self.select_model(usize::MAX, cx);
+    }
+
+    /// Moves focus from the API URL field up into the model list above it, which is otherwise
+    /// only reachable with the mouse.
+    fn focus_models_from_api_url(
+        &mut self,
+        _: &editor::actions::MoveUp,
+        window: &mut Window,
+        cx: &mut Context<Self>,
+    ) {
+        let state = self.state.read(cx);
+        let list_visible = !self.show_model_comparison
+            && !state.enabled_models.is_empty()
+            && state.is_authenticated();
+        if !list_visible {
+            cx.propagate();
+            return;
+        }
+        window.focus(&self.models_focus_handle);
+        cx.notify();
+    }
+
+    fn probe_selected_model(&mut self, _: &menu::Confirm, _: &mut Window, cx: &mut Context<Self>) {
+        let Some(model) = self
+            .state
+            .read(cx)
+            .enabled_models
+            .get(self.selected_model_ix)
+        else {
+            return;
+        };
+        let model_id = model.name.clone();
+        self.state
+            .update(cx, |state, cx| state.probe_model(model_id, cx));
+    }
+
+    fn rename_selected_model(
+        &mut self,
+        _: &menu::SecondaryConfirm,
+        window: &mut Window,
+        cx: &mut Context<Self>,
+    ) {
+        let Some(model) = self
+            .state
+            .read(cx)
+            .enabled_models
+            .get(self.selected_model_ix)
+            .cloned()
+        else {
+            return;
+        };
+        self.start_model_rename(&model, window, cx);
+    }
+
+    /// Jumps to the first model whose name contains everything typed since the selection last
+    /// moved, so a model can be found without leaving the keyboard.
+    fn type_to_select_model(
+        &mut self,
+        event: &KeyDownEvent,
+        window: &mut Window,
+        cx: &mut Context<Self>,
+    ) {
+        if !self.models_focus_handle.is_focused(window) {
+            return;
+        }
+        let modifiers = &event.keystroke.modifiers;
+        if modifiers.control || modifiers.alt || modifiers.platform || modifiers.function {
+            return;
+        }
+        let Some(key_char) = event.keystroke.key_char.as_deref() else {
+            return;
+        };
+        if key_char.trim().is_empty() {
+            return;
+        }
+
+        let query = format!("{}{}", self.model_typeahead, key_char.to_lowercase());
+        let matching_ix = self.state.read(cx).enabled_models.iter().position(|model| {
+            model.name.to_lowercase().contains(&query)
+                || model
+                    .display_name
+                    .as_ref()
+                    .is_some_and(|name| name.to_lowercase().contains(&query))
+        });
+        if let Some(ix) = matching_ix {
+            self.selected_model_ix = ix;
+            self.model_typeahead = query;
+            cx.stop_propagation();
+            cx.notify();
+        }
+    }
+
+    fn start_model_rename(
+        &mut self,
+        model: &AvailableModel,
+        window: &mut Window,
+        cx: &mut Context<Self>,
+    ) {
+        let editor = cx.new(|cx| {
+            let mut editor = Editor::single_line(window, cx);
+            editor.set_placeholder_text(model.name.clone(), cx);
+            if let Some(display_name) = model.display_name.as_ref() {
+                editor.set_text(display_name.clone(), window, cx);
+            }
+            editor
+        });
+        window.focus(&editor.focus_handle(cx));
+        self.model_edit = Some(ModelEdit {
+            model_name: model.name.clone(),
+            field: ModelEditField::DisplayName,
+            editor,
+            error: None,
+        });
+        cx.notify();
+    }
+
+    fn start_context_length_edit(
+        &mut self,
+        model: &AvailableModel,
+        window: &mut Window,
+        cx: &mut Context<Self>,
+    ) {
+        let editor = cx.new(|cx| {
+            let mut editor = Editor::single_line(window, cx);
+            editor.set_placeholder_text(model.max_tokens.to_string(), cx);
+            if let Some(context_length) = model.context_length_override {
+                editor.set_text(context_length.to_string(), window, cx);
+            }
+            editor
+        });
+        window.focus(&editor.focus_handle(cx));
+        self.model_edit = Some(ModelEdit {
+            model_name: model.name.clone(),
+            field: ModelEditField::ContextLength,
+            editor,
+            error: None,
+        });
+        cx.notify();
+    }
+
+    fn start_default_params_edit(
+        &mut self,
+        model: &AvailableModel,
+        window: &mut Window,
+        cx: &mut Context<Self>,
+    ) {
+        let editor = cx.new(|cx| {
+            let mut editor = Editor::single_line(window, cx);
+            editor.set_placeholder_text("temperature=0.7, top_p=0.9", cx);
+            if !model.default_params.is_empty() {
+                editor.set_text(model.default_params.to_string(), window, cx);
+            }
+            editor
+        });
+        window.focus(&editor.focus_handle(cx));
+        self.model_edit = Some(ModelEdit {
+            model_name: model.name.clone(),
+            field: ModelEditField::DefaultParams,
+            editor,
+            error: None,
+        });
+        cx.notify();
+    }
+
+    fn confirm_model_edit(
+        &mut self,
+        _: &menu::Confirm,
+        _window: &mut Window,
+        cx: &mut Context<Self>,
+    ) {
+        let Some(edit) = self.model_edit.as_ref() else {
+            return;
+        };
+
+        let result = match edit.field {
+            ModelEditField::DisplayName => self.save_display_name(edit, cx),
+            ModelEditField::ContextLength => self.save_context_length_override(edit, cx),
+            ModelEditField::DefaultParams => self.save_default_params(edit, cx),
+        };
+        match result {
+            Ok(()) => self.model_edit = None,
+            Err(error) => {
+                if let Some(edit) = self.model_edit.as_mut() {
+                    edit.error = Some(error);
+                }
+            }
+        }
+        cx.notify();
+    }
+
+    fn save_display_name(&self, edit: &ModelEdit, cx: &mut App) -> Result<(), SharedString> {
+        let display_name = edit.editor.read(cx).text(cx).trim().to_string();
+        let display_name = (!display_name.is_empty()).then_some(display_name);
+
+        // Aliases are matched by name in the model selector, so they must stay unique.
+        if let Some(display_name) = display_name.as_ref() {
+            let collides = self.state.read(cx).enabled_models.iter().any(|model| {
+                model.name != edit.model_name
+                    && model.display_name.as_ref().unwrap_or(&model.name) == display_name
+            });
+            if collides {
+                return Err(format!("\"{display_name}\" is already used by another model.").into());
+            }
+        }
+
+        let model_name = edit.model_name.clone();
+        update_settings_file::<AllLanguageModelSettings>(
+            <dyn Fs>::global(cx),
+            cx,
+            move |settings, _| {
+                if let Some(model) = settings
+                    .openrouter
+                    .as_mut()
+                    .and_then(|openrouter| openrouter.available_models.as_mut())
+                    .and_then(|models| models.iter_mut().find(|model| model.name == model_name))
+                {
+                    model.display_name = display_name;
+                }
+            },
+        );
+        Ok(())
+    }
+
+    /// Saves a lower context window for the model. The override can't raise the limit, so values
+    /// above the model's `max_tokens` are rejected rather than silently ignored.
+    fn save_context_length_override(
+        &self,
+        edit: &ModelEdit,
+        cx: &mut App,
+    ) -> Result<(), SharedString> {
+        let Some(max_tokens) = self
+            .state
+            .read(cx)
+            .enabled_models
+            .iter()
+            .find(|model| model.name == edit.model_name)
+            .map(|model| model.max_tokens)
+        else {
+            return Ok(());
+        };
+
+        let text = edit.editor.read(cx).text(cx);
+        let text = text.trim();
+        let context_length_override = if text.is_empty() {
+            None
+        } else {
+            match text.replace('_', "").parse::<usize>() {
+                Ok(context_length) if context_length == 0 => {
+                    return Err("The context window must be at least 1 token.".into());
+                }
+                Ok(context_length) if context_length > max_tokens => {
+                    return Err(format!(
+                        "The override can only lower the context window, which is {max_tokens} tokens."
+                    )
+                    .into());
+                }
+                Ok(context_length) => Some(context_length),
+                Err(_) => return Err("Enter a whole number of tokens.".into()),
+            }
+        };
+
+        let model_name = edit.model_name.clone();
+        update_settings_file::<AllLanguageModelSettings>(
+            <dyn Fs>::global(cx),
+            cx,
+            move |settings, _| {
+                if let Some(model) = settings
+                    .openrouter
+                    .as_mut()
+                    .and_then(|openrouter| openrouter.available_models.as_mut())
+                    .and_then(|models| models.iter_mut().find(|model| model.name == model_name))
+                {
+                    model.context_length_override = context_length_override;
+                }
+            },
+        );
+        Ok(())
+    }
+
+    fn save_default_params(&self, edit: &ModelEdit, cx: &mut App) -> Result<(), SharedString> {
+        let default_params = ModelParameters::parse(edit.editor.read(cx).text(cx).trim())?;
+
+        let model_name = edit.model_name.clone();
+        update_settings_file::<AllLanguageModelSettings>(
+            <dyn Fs>::global(cx),
+            cx,
+            move |settings, _| {
+                if let Some(model) = settings
+                    .openrouter
+                    .as_mut()
+                    .and_then(|openrouter| openrouter.available_models.as_mut())
+                    .and_then(|models| models.iter_mut().find(|model| model.name == model_name))
+                {
+                    model.default_params = default_params;
+                }
+            },
+        );
+        Ok(())
+    }
+
+    fn cancel_model_edit(
+        &mut self,
+        _: &menu::Cancel,
+        _window: &mut Window,
+        cx: &mut Context<Self>,
+    ) {
+        self.model_edit = None;
+        cx.notify();
+    }
+
+    fn reset_api_url(&mut self, window: &mut Window, cx: &mut Context<Self>) {
+        self.api_url_editor.update(cx, |editor, cx| {
+            editor.set_text(open_router::OPEN_ROUTER_API_URL, window, cx)
+        });
+        self.api_url_error = None;
+
+        update_settings_file::<AllLanguageModelSettings>(
+            <dyn Fs>::global(cx),
+            cx,
+            |settings, _| {
+                if let Some(openrouter) = settings.openrouter.as_mut() {
+                    openrouter.api_url = None;
+                }
+            },
+        );
+
+        cx.notify();
+    }
+
+    fn render_text_field(
+        &self,
+        editor: &Entity<Editor>,
+        cx: &mut Context<Self>,
+    ) -> impl IntoElement {
+        let settings = ThemeSettings::get_global(cx);
+        let text_style = TextStyle {
+            color: cx.theme().colors().text,
+            font_family: settings.ui_font.family.clone(),
+            font_features: settings.ui_font.features.clone(),
+            font_fallbacks: settings.ui_font.fallbacks.clone(),
+            font_size: rems(0.875).into(),
+            font_weight: settings.ui_font.weight,
+            font_style: FontStyle::Normal,
+            line_height: relative(1.3),
+            white_space: WhiteSpace::Normal,
+            ..Default::default()
+        };
+        EditorElement::new(
+            editor,
+            EditorStyle {
+                background: cx.theme().colors().editor_background,
+                local_player: cx.theme().players().local(),
+                text: text_style,
+                ..Default::default()
+            },
+        )
+    }
+
+    fn render_editor(&self, cx: &mut Context<Self>) -> AnyElement {
+        let api_key_revoked = self.state.read(cx).api_key_revoked;
+
+        v_flex()
+            .size_full()
+            .on_action(cx.listener(Self::save_api_key))
+            .when(api_key_revoked, |this| {
+                this.child(
+                    Label::new("Your API key was revoked on OpenRouter. Please enter a new one.")
+                        .color(Color::Error),
+                )
+            })
+            .child(Label::new("To use Zed's assistant with OpenRouter, you need to add an API key. Follow these steps:"))
+            .child(
+                List::new()
+                    .child(InstructionListItem::new(
+                        "Create one by visiting",
+                        Some("OpenRouter's console"),
+                        Some("https://openrouter.ai/settings/keys"),
+                    ))
+                    .child(InstructionListItem::text_only(
+                        "Ensure your OpenRouter account has credits",
+                    ))
+                    .child(InstructionListItem::text_only(
+                        "Paste your API key below and hit enter to start using the assistant",
+                    )),
+            )
+            .child(
+                h_flex()
+                    .w_full()
+                    .my_2()
+                    .px_2()
+                    .py_1()
+                    .bg(cx.theme().colors().editor_background)
+                    .border_1()
+                    .border_color(cx.theme().colors().border)
+                    .rounded_sm()
+                    .child(self.render_text_field(&self.api_key_editor, cx)),
+            )
+            .child(
+                Label::new(
+                    format!("You can also assign the {OPENROUTER_API_KEY_VAR} environment variable and restart Zed."),
+                )
+                .size(LabelSize::Small).color(Color::Muted),
+            )
+            .into_any()
+    }
+
+    fn render_settings(&self, cx: &mut Context<Self>) -> AnyElement {
+        let env_var_set = self.state.read(cx).api_key_from_env;
+
+        h_flex()
+            .mt_1()
+            .p_1()
+            .justify_between()
+            .rounded_md()
+            .border_1()
+            .border_color(cx.theme().colors().border)
+            .bg(cx.theme().colors().background)
+            .child(
+                h_flex()
+                    .gap_1()
+                    .child(Icon::new(IconName::Check).color(Color::Success))
+                    .child(Label::new(if env_var_set {
+                        format!("API key set in {OPENROUTER_API_KEY_VAR} environment variable.")
+                    } else {
+                        "API key configured.".to_string()
+                    })),
+            )
+            .child(
+                Button::new("reset-key", "Reset Key")
+                    .label_size(LabelSize::Small)
+                    .icon(Some(IconName::Trash))
+                    .icon_size(IconSize::Small)
+                    .icon_position(IconPosition::Start)
+                    .disabled(env_var_set)
+                    .when(env_var_set, |this| {
+                        this.tooltip(Tooltip::text(format!("To reset your API key, unset the {OPENROUTER_API_KEY_VAR} environment variable.")))
+                    })
+                    .on_click(cx.listener(|this, _, window, cx| this.reset_api_key(window, cx))),
+            )
+            .into_any()
+    }
+
+    fn render_api_url(&self, cx: &mut Context<Self>) -> AnyElement {
+        let is_default = AllLanguageModelSettings::get_global(cx).openrouter.api_url
+            == open_router::OPEN_ROUTER_API_URL;
+
+        v_flex()
+            .mt_2()
+            .gap_1()
+            .on_action(cx.listener(Self::save_api_url))
+            .on_action(cx.listener(Self::focus_models_from_api_url))
+            .child(
+                h_flex()
+                    .justify_between()
+                    .child(Label::new("API URL").size(LabelSize::Small))
+                    .child(
+                        Button::new("reset-api-url", "Reset to Default")
+                            .label_size(LabelSize::Small)
+                            .icon(Some(IconName::RotateCcw))
+                            .icon_size(IconSize::Small)
+                            .icon_position(IconPosition::Start)
+                            .disabled(is_default)
+                            .on_click(
+                                cx.listener(|this, _, window, cx| this.reset_api_url(window, cx)),
+                            ),
+                    ),
+            )
+            .child(
+                h_flex()
+                    .w_full()
+                    .px_2()
+                    .py_1()
+                    .bg(cx.theme().colors().editor_background)
+                    .border_1()
+                    .border_color(if self.api_url_error.is_some() {
+                        cx.theme().status().error_border
+                    } else {
+                        cx.theme().colors().border
+                    })
+                    .rounded_sm()
+                    .child(self.render_text_field(&self.api_url_editor, cx)),
+            )
+            .map(|this| match self.api_url_error.clone() {
+                Some(error) => {
+                    this.child(Label::new(error).size(LabelSize::Small).color(Color::Error))
+                }
+                None => this.child(
+                    Label::new(
+                        "Press enter to apply. Changing the URL requires authenticating again.",
+                    )
+                    .size(LabelSize::Small)
+                    .color(Color::Muted),
+                ),
+            })
+            .into_any()
+    }
+
+    fn render_system_prompt(&self, cx: &mut Context<Self>) -> AnyElement {
+        v_flex()
+            .mt_2()
+            .gap_1()
+            .on_action(cx.listener(Self::save_system_prompt))
+            .child(Label::new("System Prompt").size(LabelSize::Small))
+            .child(
+                h_flex()
+                    .w_full()
+                    .px_2()
+                    .py_1()
+                    .bg(cx.theme().colors().editor_background)
+                    .border_1()
+                    .border_color(cx.theme().colors().border)
+                    .rounded_sm()
+                    .child(self.render_text_field(&self.system_prompt_editor, cx)),
+            )
+            .child(
+                Label::new(
+                    "Press enter to apply. Prepended to the system message of every OpenRouter request.",
+                )
+                .size(LabelSize::Small)
+                .color(Color::Muted),
+            )
+            .into_any()
+    }
+
+    fn render_reset_all(&self, cx: &mut Context<Self>) -> AnyElement {
+        h_flex()
+            .mt_2()
+            .justify_end()
+            .child(
+                Button::new("reset-all-state", "Reset OpenRouter State…")
+                    .label_size(LabelSize::Small)
+                    .icon(Some(IconName::Trash))
+                    .icon_size(IconSize::Small)
+                    .icon_position(IconPosition::Start)
+                    .tooltip(Tooltip::text(
+                        "Delete the stored API key and clear cached models and credits",
+                    ))
+                    .on_click(cx.listener(|this, _, window, cx| this.reset_all_state(window, cx))),
+            )
+            .into_any()
+    }
+
+    fn render_health(&self, cx: &mut Context<Self>) -> AnyElement {
+        let state = self.state.read(cx);
+
+        let authenticated = if state.is_authenticated() {
+            "Authenticated"
+        } else {
+            "Not authenticated"
+        };
+        let catalog_stale = state.is_catalog_stale(cx);
+        let catalog = match (state.catalog.as_ref(), state.catalog_fetched_at) {
+            (Some(catalog), Some(fetched_at)) if catalog_stale => format!(
+                "{} models, cached on {}. Refresh to see new models",
+                catalog.len(),
+                fetched_at.format("%b %-d")
+            ),
+            (Some(catalog), Some(fetched_at)) => format!(
+                "{} models, fetched at {}",
+                catalog.len(),
+                fetched_at.format("%H:%M")
+            ),
+            _ if state.catalog_error.is_some() => "Models failed to load".to_string(),
+            _ if state.fetch_catalog_task.is_some() => "Loading models…".to_string(),
+            _ => "Models not loaded".to_string(),
+        };
+        let credits = match state.credits {
+            Some(credits)
+                if state.low_credit_mode_active(
+                    &AllLanguageModelSettings::get_global(cx).openrouter,
+                ) =>
+            {
+                format!(
+                    "${:.2} credits remaining, free models only",
+                    credits.remaining()
+                )
+            }
+            Some(credits) => format!("${:.2} credits remaining", credits.remaining()),
+            None => "Credits unknown".to_string(),
+        };
+        let usage = if state.usage_unavailable.load(Ordering::Relaxed) {
+            " · Usage unavailable"
+        } else {
+            ""
+        };
+
+        h_flex()
+            .mt_2()
+            .gap_1()
+            .child(Indicator::dot().color(
+                if state.is_authenticated() && state.catalog.is_some() && !catalog_stale {
+                    Color::Success
+                } else {
+                    Color::Warning
+                },
+            ))
+            .child(
+                Label::new(format!("{authenticated} · {catalog} · {credits}{usage}"))
+                    .size(LabelSize::Small)
+                    .color(Color::Muted),
+            )
+            .into_any()
+    }
+
+    fn render_last_failure(&self, cx: &mut Context<Self>) -> Option<AnyElement> {
+        let (error, diagnostic) = self
+            .state
+            .read(cx)
+            .last_failure
+            .lock()
+            .as_ref()
+            .map(|failure| (failure.error.clone(), failure.diagnostic()))?;
+
+        Some(
+            h_flex()
+                .mt_2()
+                .gap_2()
+                .justify_between()
+                .child(
+                    Label::new(format!("Last request failed: {error}"))
+                        .size(LabelSize::Small)
+                        .color(Color::Error)
+                        .single_line(),
+                )
+                .child(
+                    Button::new("copy-diagnostic", "Copy Diagnostic")
+                        .label_size(LabelSize::Small)
+                        .icon(Some(IconName::Copy))
+                        .icon_size(IconSize::Small)
+                        .icon_position(IconPosition::Start)
+                        .tooltip(Tooltip::text(
+                            "Copy the model, request parameters and error for a bug report. \
+                            Prompts and your API key are left out.",
+                        ))
+                        .on_click(move |_, _, cx| {
+                            cx.write_to_clipboard(ClipboardItem::new_string(diagnostic.clone()))
+                        }),
+                )
+                .into_any(),
+        )
+    }
+
+    fn render_last_request_params(&self, cx: &mut Context<Self>) -> Option<AnyElement> {
+        if !AllLanguageModelSettings::get_global(cx)
+            .openrouter
+            .show_request_params
+        {
+            return None;
+        }
+        let params = self.state.read(cx).last_request_params.lock().clone()?;
+        let is_open = self.request_params_expanded;
+
+        Some(
+            v_flex()
+                .mt_2()
+                .child(
+                    h_flex()
+                        .gap_1()
+                        .child(
+                            Disclosure::new("request-params-disclosure", is_open).on_click(
+                                cx.listener(|this, _, _, cx| {
+                                    this.request_params_expanded = !this.request_params_expanded;
+                                    cx.notify();
+                                }),
+                            ),
+                        )
+                        .child(
+                            Label::new(format!(
+                                "Last request: {}",
+                                request_params_summary(&params)
+                            ))
+                            .size(LabelSize::Small)
+                            .color(Color::Muted)
+                            .single_line(),
+                        ),
+                )
+                .when(is_open, |this| {
+                    this.child(
+                        Label::new(serde_json::to_string_pretty(&params).unwrap_or_default())
+                            .size(LabelSize::Small)
+                            .color(Color::Muted)
+                            .buffer_font(cx),
+                    )
+                })
+                .into_any(),
+        )
+    }
+
+    fn enable_recommended_models(&mut self, cx: &mut Context<Self>) {
+        let Some(catalog) = self.state.read(cx).catalog.as_ref() else {
+            return;
+        };
+        let recommended_models = catalog
+            .iter()
+            .filter(|entry| RECOMMENDED_MODEL_IDS.contains(&entry.id()))
+            .map(AvailableModel::from)
+            .collect::<Vec<_>>();
+
+        update_settings_file::<AllLanguageModelSettings>(
+            <dyn Fs>::global(cx),
+            cx,
+            move |settings, _| {
+                settings
+                    .openrouter
+                    .get_or_insert_with(Default::default)
+                    .available_models
+                    .get_or_insert_with(Vec::new)
+                    .extend(recommended_models);
+            },
+        );
+    }
+
+    fn retry_fetch_catalog(&mut self, cx: &mut Context<Self>) {
+        self.state
+            .update(cx, |state, cx| state.restart_fetch_catalog_task(cx));
+    }
+
+    fn toggle_catalog_preview(&mut self, cx: &mut Context<Self>) {
+        self.show_catalog_preview = !self.show_catalog_preview;
+        if self.show_catalog_preview {
+            self.state
+                .update(cx, |state, cx| state.fetch_catalog_preview(cx));
+        }
+        cx.notify();
+    }
+
+    /// A read-only look at the catalog for users who haven't added an API key yet.
+    fn render_catalog_preview(&self, cx: &mut Context<Self>) -> Option<AnyElement> {
+        let state = self.state.read(cx);
+        if !self.show_catalog_preview || state.is_authenticated() {
+            return None;
+        }
+
+        let content = match (&state.catalog, &state.catalog_error) {
+            (Some(catalog), _) => {
+                let rows = catalog
+                    .iter()
+                    .map(|entry| {
+                        let pricing = entry.pricing.as_ref();
+                        vec![
+                            entry.display_name().to_string(),
+                            entry.max_token_count().to_string(),
+                            format_price_per_million(
+                                pricing.map(|pricing| pricing.prompt.as_str()),
+                            ),
+                            format_price_per_million(
+                                pricing.map(|pricing| pricing.completion.as_str()),
+                            ),
+                            comparison_capabilities(Some(entry)),
+                        ]
+                    })
+                    .collect::<Vec<_>>();
+                Table::new(vec![
+                    "Model",
+                    "Context",
+                    "Input $/M",
+                    "Output $/M",
+                    "Capabilities",
+                ])
+                .rows(rows)
+                .striped()
+                .into_any_element()
+            }
+            (None, Some(error)) => Label::new(format!("The model catalog failed to load: {error}"))
+                .size(LabelSize::Small)
+                .color(Color::Error)
+                .into_any_element(),
+            (None, None) => Label::new("Loading models…")
+                .size(LabelSize::Small)
+                .color(Color::Muted)
+                .into_any_element(),
+        };
+
+        Some(
+            v_flex()
+                .mt_2()
+                .gap_1()
+                .child(
+                    Label::new("Read-only preview. Add an API key above to enable models.")
+                        .size(LabelSize::Small)
+                        .color(Color::Muted),
+                )
+                .child(content)
+                .into_any(),
+        )
+    }
+
+    /// Explains why no models are listed, so an empty model picker is never a dead end.
+    fn render_models_empty_state(&self, cx: &mut Context<Self>) -> Option<AnyElement> {
+        let state = self.state.read(cx);
+        let authenticated = state.is_authenticated();
+        let catalog_loaded = state.catalog.is_some();
+        let catalog_error = state.catalog_error.clone();
+        let any_models_visible = !state.visible_models().is_empty();
+
+        let (message, color, action) = if !authenticated {
+            (
+                "Add your API key to start using OpenRouter models.".into(),
+                Color::Muted,
+                Some(
+                    Button::new(
+                        "toggle-catalog-preview",
+                        if self.show_catalog_preview {
+                            "Hide Available Models"
+                        } else {
+                            "Preview Available Models"
+                        },
+                    )
+                    .icon(IconName::ListTree)
+                    .icon_size(IconSize::Small)
+                    .icon_position(IconPosition::Start)
+                    .on_click(cx.listener(|this, _, _, cx| this.toggle_catalog_preview(cx))),
+                ),
+            )
+        } else if let Some(error) = catalog_error.filter(|_| !catalog_loaded) {
+            (
+                format!("The OpenRouter model catalog failed to load: {error}"),
+                Color::Error,
+                Some(
+                    Button::new("retry-fetch-catalog", "Retry")
+                        .icon(IconName::RotateCw)
+                        .icon_size(IconSize::Small)
+                        .icon_position(IconPosition::Start)
+                        .on_click(cx.listener(|this, _, _, cx| this.retry_fetch_catalog(cx))),
+                ),
+            )
+        } else if catalog_loaded && !any_models_visible {
+            (
+                "No models are enabled, so none can be selected in the assistant.".into(),
+                Color::Muted,
+                Some(
+                    Button::new("enable-recommended-models", "Enable Recommended Models")
+                        .icon(IconName::Check)
+                        .icon_size(IconSize::Small)
+                        .icon_position(IconPosition::Start)
+                        .on_click(cx.listener(|this, _, _, cx| this.enable_recommended_models(cx))),
+                ),
+            )
+        } else {
+            return None;
+        };
+
+        Some(
+            v_flex()
+                .mt_2()
+                .gap_1()
+                .child(Label::new(message).size(LabelSize::Small).color(color))
+                .children(action)
+                .into_any(),
+        )
+    }
+
+    fn render_model_probe(ix: usize, probe: Option<&ModelProbe>) -> Option<AnyElement> {
+        Some(match probe? {
+            ModelProbe::Pending => Label::new("Checking…")
+                .size(LabelSize::Small)
+                .color(Color::Muted)
+                .into_any_element(),
+            ModelProbe::Available => div()
+                .id(("model-probe", ix))
+                .child(
+                    Icon::new(IconName::Check)
+                        .size(IconSize::Small)
+                        .color(Color::Success),
+                )
+                .tooltip(Tooltip::text("Available with your key and routing"))
+                .into_any_element(),
+            ModelProbe::Unavailable(error) => div()
+                .id(("model-probe", ix))
+                .child(
+                    Icon::new(IconName::XCircle)
+                        .size(IconSize::Small)
+                        .color(Color::Error),
+                )
+                .tooltip(Tooltip::text(format!("Unavailable: {error}")))
+                .into_any_element(),
+        })
+    }
+
+    fn render_models(&self, window: &mut Window, cx: &mut Context<Self>) -> Option<AnyElement> {
+        let enabled_models = self.state.read(cx).enabled_models.clone();
+        // A catalog fetched for the preview fills in the enabled models, but they can't be used
+        // or edited until a key is added.
+        if enabled_models.is_empty() || !self.state.read(cx).is_authenticated() {
+            return self.render_models_empty_state(cx);
+        }
+        let model_probes = self.state.read(cx).model_probes.clone();
+        let list_focused = self.models_focus_handle.is_focused(window);
+        let selected_model_ix = self.selected_model_ix.min(enabled_models.len() - 1);
+
+        let rows = enabled_models.iter().enumerate().map(|(ix, model)| {
+            let edit = self
+                .model_edit
+                .as_ref()
+                .filter(|edit| edit.model_name == model.name);
+
+            if let Some(edit) = edit {
+                let title = match edit.field {
+                    ModelEditField::DisplayName => format!("Display name for {}", model.name),
+                    ModelEditField::ContextLength => format!(
+                        "Context window for {} in tokens, at most {}. Leave empty to use the full window.",
+                        model.name, model.max_tokens
+                    ),
+                    ModelEditField::DefaultParams => format!(
+                        "Default parameters for {}, used when a request doesn't set its own. \
+                        Leave empty to use the model's defaults.",
+                        model.name
+                    ),
+                };
+                return v_flex()
+                    .gap_0p5()
+                    .on_action(cx.listener(Self::confirm_model_edit))
+                    .on_action(cx.listener(Self::cancel_model_edit))
+                    .child(Label::new(title).size(LabelSize::Small).color(Color::Muted))
+                    .child(
+                        h_flex()
+                            .w_full()
+                            .px_2()
+                            .py_1()
+                            .bg(cx.theme().colors().editor_background)
+                            .border_1()
+                            .border_color(cx.theme().colors().border)
+                            .rounded_sm()
+                            .child(self.render_text_field(&edit.editor, cx)),
+                    )
+                    .children(
+                        edit.error.clone().map(|error| {
+                            Label::new(error).size(LabelSize::Small).color(Color::Error)
+                        }),
+                    )
+                    .into_any_element();
+            }
+
+            let model = model.clone();
+            let selected = list_focused && ix == selected_model_ix;
+            let capabilities = model_capabilities(self.state.read(cx).catalog_entry(&model.name));
+            h_flex()
+                .id(("model", ix))
+                .justify_between()
+                .px_1()
+                .rounded_sm()
+                .when(selected, |this| {
+                    this.bg(cx.theme().colors().element_selected)
+                        .border_1()
+                        .border_color(cx.theme().colors().border_focused)
+                })
+                .child(
+                    h_flex()
+                        .gap_1()
+                        .child(Label::new(
+                            model
+                                .display_name
+                                .clone()
+                                .unwrap_or_else(|| model.name.clone()),
+                        ))
+                        .when(model.display_name.is_some(), |this| {
+                            this.child(
+                                Label::new(model.name.clone())
+                                    .size(LabelSize::Small)
+                                    .color(Color::Muted),
+                            )
+                        })
+                        .child(
+                            Label::new(capabilities)
+                                .size(LabelSize::Small)
+                                .color(Color::Muted),
+                        )
+                        .when(model.context_length_override.is_some(), |this| {
+                            this.child(
+                                Label::new(format!("limited to {} tokens", model.context_length()))
+                                    .size(LabelSize::Small)
+                                    .color(Color::Muted),
+                            )
+                        })
+                        .when(!model.default_params.is_empty(), |this| {
+                            this.child(
+                                Label::new(model.default_params.to_string())
+                                    .size(LabelSize::Small)
+                                    .color(Color::Muted),
+                            )
+                        }),
+                )
+                .child(
+                    h_flex()
+                        .gap_1()
+                        .children(Self::render_model_probe(ix, model_probes.get(&model.name)))
+                        .child(
+                            IconButton::new(("probe-model", ix), IconName::Play)
+                                .icon_size(IconSize::Small)
+                                .tooltip(Tooltip::text(format!(
+                                    "Check Availability of {} (sends a 1-token request)",
+                                    model.name
+                                )))
+                                .on_click(cx.listener({
+                                    let model_id = model.name.clone();
+                                    move |this, _, _, cx| {
+                                        let model_id = model_id.clone();
+                                        this.state.update(cx, |state, cx| {
+                                            state.probe_model(model_id, cx)
+                                        });
+                                    }
+                                })),
+                        )
+                        .child(
+                            IconButton::new(("context-length", ix), IconName::Sliders)
+                                .icon_size(IconSize::Small)
+                                .tooltip(Tooltip::text(format!(
+                                    "Limit the context window of {}",
+                                    model.name
+                                )))
+                                .on_click(cx.listener({
+                                    let model = model.clone();
+                                    move |this, _, window, cx| {
+                                        this.start_context_length_edit(&model, window, cx)
+                                    }
+                                })),
+                        )
+                        .child(
+                            IconButton::new(("default-params", ix), IconName::SlidersVertical)
+                                .icon_size(IconSize::Small)
+                                .tooltip(Tooltip::text(format!(
+                                    "Set default parameters for {}",
+                                    model.name
+                                )))
+                                .on_click(cx.listener({
+                                    let model = model.clone();
+                                    move |this, _, window, cx| {
+                                        this.start_default_params_edit(&model, window, cx)
+                                    }
+                                })),
+                        )
+                        .child(
+                            IconButton::new(("rename-model", ix), IconName::Pencil)
+                                .icon_size(IconSize::Small)
+                                .tooltip(Tooltip::text(format!("Rename {}", model.name)))
+                                .on_click(cx.listener(move |this, _, window, cx| {
+                                    this.start_model_rename(&model, window, cx)
+                                })),
+                        ),
+                )
+                .into_any_element()
+        });
+
+        let header = h_flex()
+            .justify_between()
+            .child(Label::new("Models").size(LabelSize::Small))
+            .child(
+                Button::new(
+                    "toggle-model-comparison",
+                    if self.show_model_comparison {
+                        "Show List"
+                    } else {
+                        "Compare"
+                    },
+                )
+                .label_size(LabelSize::Small)
+                .tooltip(Tooltip::text(
+                    "Compare context length, output limit, pricing and capabilities side by side",
+                ))
+                .on_click(cx.listener(|this, _, _, cx| {
+                    this.show_model_comparison = !this.show_model_comparison;
+                    cx.notify();
+                })),
+            );
+        if self.show_model_comparison {
+            return Some(
+                v_flex()
+                    .mt_2()
+                    .gap_1()
+                    .child(header)
+                    .child(self.render_model_comparison(&enabled_models, cx))
+                    .into_any(),
+            );
+        }
+
+        Some(
+            v_flex()
+                .mt_2()
+                .gap_1()
+                .child(header)
+                .child(
+                    v_flex()
+                        .id("openrouter-models")
+                        .track_focus(&self.models_focus_handle)
+                        .key_context("OpenRouterModels menu")
+                        .on_action(cx.listener(Self::select_next_model))
+                        .on_action(cx.listener(Self::select_previous_model))
+                        .on_action(cx.listener(Self::select_first_model))
+                        .on_action(cx.listener(Self::select_last_model))
+                        .on_action(cx.listener(Self::probe_selected_model))
+                        .on_action(cx.listener(Self::rename_selected_model))
+                        .on_key_down(cx.listener(Self::type_to_select_model))
+                        .gap_1()
+                        .children(rows),
+                )
+                .when(list_focused, |this| {
+                    this.child(
+                        Label::new(
+                            "Up and down to move, type to jump, enter to check availability \
+                            (sends a 1-token request), ctrl-enter to rename.",
+                        )
+                        .size(LabelSize::Small)
+                        .color(Color::Muted),
+                    )
+                })
+                .into_any(),
+        )
+    }
+
+    fn render_model_comparison(&self, models: &[AvailableModel], cx: &App) -> Table {
+        let state = self.state.read(cx);
+        let rows = models
+            .iter()
+            .map(|model| {
+                let entry = state.catalog_entry(&model.name);
+                let pricing = entry.and_then(|entry| entry.pricing.as_ref());
+                vec![
+                    model
+                        .display_name
+                        .clone()
+                        .unwrap_or_else(|| model.name.clone()),
+                    model.context_length().to_string(),
+                    entry
+                        .and_then(|entry| entry.max_output_tokens())
+                        .map_or_else(|| "—".to_string(), |tokens| tokens.to_string()),
+                    format_price_per_million(pricing.map(|pricing| pricing.prompt.as_str())),
+                    format_price_per_million(pricing.map(|pricing| pricing.completion.as_str())),
+                    comparison_capabilities(entry),
+                ]
+            })
+            .collect::<Vec<_>>();
+
+        Table::new(vec![
+            "Model",
+            "Context",
+            "Max Output",
+            "Input $/M",
+            "Output $/M",
+            "Capabilities",
+        ])
+        .rows(rows)
+        .striped()
+        .width(relative(1.))
+    }
+
+    /// Whether the default model is an OpenRouter model the catalog says can't reason, in which
+    /// case the reasoning toggle has no effect on it.
+    fn default_model_lacks_reasoning(&self, cx: &App) -> Option<SharedString> {
+        let default_model = LanguageModelRegistry::read_global(cx).default_model()?;
+        if default_model.provider.id().0.as_ref() != PROVIDER_ID {
+            return None;
+        }
+        let model_id = default_model.model.id().0.to_string();
+        let settings = &AllLanguageModelSettings::get_global(cx).openrouter;
+        let catalog_id = settings
+            .model_aliases
+            .iter()
+            .find(|alias| alias.name == model_id)
+            .and_then(|alias| alias.models.first().cloned())
+            .unwrap_or_else(|| model_id.clone());
+        let entry = self.state.read(cx).catalog_entry(&catalog_id)?;
+        (!entry.supports_reasoning()).then(|| default_model.model.name().0)
+    }
+
+    fn render_show_reasoning(&self, cx: &mut Context<Self>) -> AnyElement {
+        let reasoning = AllLanguageModelSettings::get_global(cx)
+            .openrouter
+            .reasoning;
+        let unsupported_model = self.default_model_lacks_reasoning(cx);
+
+        v_flex()
+            .mt_2()
+            .gap_0p5()
+            .child(
+                SwitchWithLabel::new(
+                    "openrouter-show-reasoning",
+                    Label::new("Show Reasoning"),
+                    (reasoning == ReasoningMode::Returned).into(),
+                    |toggle_state, _window, cx| {
+                        let reasoning = if toggle_state == &ToggleState::Selected {
+                            ReasoningMode::Returned
+                        } else {
+                            ReasoningMode::Excluded
+                        };
+                        update_settings_file::<AllLanguageModelSettings>(
+                            <dyn Fs>::global(cx),
+                            cx,
+                            move |settings, _| {
+                                settings.openrouter.get_or_insert_default().reasoning =
+                                    Some(reasoning);
+                            },
+                        );
+                    },
+                )
+                .disabled(unsupported_model.is_some()),
+            )
+            .child(
+                Label::new(match unsupported_model {
+                    Some(model_name) => {
+                        format!("{model_name}, the default model, doesn't support reasoning.")
+                    }
+                    None if reasoning == ReasoningMode::Disabled => {
+                        "Reasoning is disabled in your settings. Turn this on to have reasoning \
+                        models reason and show their thought process."
+                            .to_string()
+                    }
+                    None => "Reasoning models show their thought process alongside the answer. \
+                        When off, they still reason but only the answer is returned."
+                        .to_string(),
+                })
+                .size(LabelSize::Small)
+                .color(Color::Muted),
+            )
+            .into_any()
+    }
+
+    fn render_show_all_models(&self, cx: &mut Context<Self>) -> AnyElement {
+        let state = self.state.clone();
+        let show_all_models = self.state.read(cx).show_all_models;
+
+        v_flex()
+            .mt_2()
+            .gap_0p5()
+            .child(SwitchWithLabel::new(
+                "openrouter-show-all-models",
+                Label::new("Show All Models"),
+                show_all_models,
+                move |toggle_state, _window, cx| {
+                    state.update(cx, |state, cx| {
+                        state.set_show_all_models(toggle_state == &ToggleState::Selected, cx)
+                    });
+                },
+            ))
+            .child(
+                Label::new(if show_all_models {
+                    "Every catalog model is offered for this session, including ones without tool support."
+                } else {
+                    "Only enabled models that support tools are offered, so they all work with the agent."
+                })
+                .size(LabelSize::Small)
+                .color(Color::Muted),
+            )
+            .into_any()
+    }
+
+    fn render_model_id_suggestions(&self, cx: &mut Context<Self>) -> Option<AnyElement> {
+        let suggestions = &self.state.read(cx).model_id_suggestions;
+        if suggestions.is_empty() {
+            return None;
+        }
+
+        Some(
+            v_flex()
+                .mt_2()
+                .gap_0p5()
+                .children(suggestions.iter().map(|(id, suggestion)| {
+                    h_flex()
+                        .gap_1()
+                        .child(
+                            Icon::new(IconName::Warning)
+                                .size(IconSize::XSmall)
+                                .color(Color::Warning),
+                        )
+                        .child(
+                            Label::new(format!(
+                                "Model \"{id}\" was not found. Did you mean \"{suggestion}\"?"
+                            ))
+                            .size(LabelSize::Small)
+                            .color(Color::Muted),
+                        )
+                }))
+                .into_any(),
+        )
+    }
+
+    fn should_render_editor(&self, cx: &mut Context<Self>) -> bool {
+        !self.state.read(cx).is_authenticated()
+    }
+}
+impl Render for ConfigurationView {
+    fn render(&mut self, window: &mut Window, cx: &mut Context<Self>) -> impl IntoElement {
+        if self.load_credentials_task.is_some() {
+            div().child(Label::new("Loading credentials...")).into_any()
+        } else {
+            let content = if self.should_render_editor(cx) {
+                self.render_editor(cx)
+            } else {
+                self.render_settings(cx)
+            };
+
+            v_flex()
+                .size_full()
+                .child(content)
+                .child(self.render_health(cx))
+                .children(self.render_last_failure(cx))
+                .children(self.render_last_request_params(cx))
+                .children(self.render_models(window, cx))
+                .children(self.render_catalog_preview(cx))
+                .child(self.render_show_all_models(cx))
+                .child(self.render_show_reasoning(cx))
+                .children(self.render_model_id_suggestions(cx))
+                .child(self.render_api_url(cx))
+                .child(self.render_system_prompt(cx))
+                .child(self.render_reset_all(cx))
+                .into_any()
+        }
+    }
+}
@@ -0,0 +1,628 @@
+use anyhow::{Result, anyhow};
+use collections::{BTreeMap, HashMap};
+use futures::{FutureExt, StreamExt, channel::mpsc, future::Either, stream::BoxStream};
+use gpui::BackgroundExecutor;
+use language_model::{
+    CompletionRequestStatus, LanguageModelCompletionEvent, LanguageModelSafetyRating,
+    LanguageModelToolUse, StopReason, TokenUsage,
+};
+use parking_lot::Mutex;
+use regex::Regex;
+use std::str::FromStr as _;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::Duration;
+
+use super::settings::PostProcess;
+
+/// A compiled [`PostProcess`].
+#[derive(Clone, Debug)]
+pub(super) struct PostProcessor {
+    strip_code_fences: bool,
+    remove_pattern: Option<Regex>,
+}
+impl PostProcessor {
+    /// Compiles `config`, returning `None` when it doesn't change replies. An invalid
+    /// `remove_pattern` is logged and ignored.
+    pub(super) fn new(config: &PostProcess) -> Option<Self> {
+        let remove_pattern = config
+            .remove_pattern
+            .as_deref()
+            .and_then(|pattern| match Regex::new(pattern) {
+                Ok(regex) => Some(regex),
+                Err(error) => {
+                    log::warn!("Ignoring invalid OpenRouter post_process pattern: {error}");
+                    None
+                }
+            });
+        if !config.strip_code_fences && remove_pattern.is_none() {
+            return None;
+        }
+        Some(Self {
+            strip_code_fences: config.strip_code_fences,
+            remove_pattern,
+        })
+    }
+
+    fn apply(&self, text: &str) -> String {
+        let text = match &self.remove_pattern {
+            Some(pattern) => pattern.replace_all(text, ""),
+            None => text.into(),
+        };
+        if self.strip_code_fences {
+            if let Some(code) = strip_code_fence(&text) {
+                return code.to_string();
+            }
+        }
+        text.into_owned()
+    }
+}
+/// Returns the code inside `text` when all of it is one fenced code block.
+pub(super) fn strip_code_fence(text: &str) -> Option<&str> {
+    let (_, code) = text.trim().strip_prefix("```")?.split_once('\n')?;
+    let code = code.strip_suffix("```")?;
+    if code.contains("\n```") {
+        return None;
+    }
+    Some(code.strip_suffix('\n').unwrap_or(code))
+}
+
+/// Ends `events` once the model produces text or reasoning after starting tool calls, finishing
+/// the response with the tool calls received so far. Text streamed in the same delta as a tool
+/// call is dropped. Ending the stream closes the connection, so the model stops generating.
+pub(super) fn end_after_tool_calls(
+    events: BoxStream<'static, Result<open_router::ResponseStreamEvent>>,
+) -> BoxStream<'static, Result<open_router::ResponseStreamEvent>> {
+    futures::stream::unfold(
+        (Some(events), false),
+        |(events, mut tool_calls_started)| async move {
+            let mut events = events?;
+            let mut event = match events.next().await? {
+                Ok(event) => event,
+                Err(error) => return Some((Err(error), (Some(events), tool_calls_started))),
+            };
+            let mut ended = false;
+            for choice in &mut event.choices {
+                let has_tool_calls = choice
+                    .delta
+                    .tool_calls
+                    .as_ref()
+                    .is_some_and(|tool_calls| !tool_calls.is_empty());
+                let had_tool_calls = tool_calls_started;
+                tool_calls_started |= has_tool_calls;
+                if !tool_calls_started {
+                    continue;
+                }
+                let has_text = choice
+                    .delta
+                    .content
+                    .as_ref()
+                    .is_some_and(|content| !content.is_empty())
+                    || choice
+                        .delta
+                        .reasoning
+                        .as_ref()
+                        .is_some_and(|reasoning| !reasoning.is_empty());
+                choice.delta.content = None;
+                choice.delta.reasoning = None;
+                if had_tool_calls && has_text && !has_tool_calls && choice.finish_reason.is_none() {
+                    choice.finish_reason = Some("tool_calls".into());
+                    ended = true;
+                }
+            }
+            if ended {
+                log::debug!("Ending OpenRouter response after its tool calls");
+                return Some((Ok(event), (None, tool_calls_started)));
+            }
+            Some((Ok(event), (Some(events), tool_calls_started)))
+        },
+    )
+    .boxed()
+}
+
+/// Follows each piece of generated output with a `Generating` status estimating how much of
+/// `max_tokens` the response has used. Estimates are only sent when they move by at least a
+/// percent, and output usage reported by OpenRouter replaces the estimate once it arrives.
+pub(super) fn report_progress(
+    events: BoxStream<'static, Result<LanguageModelCompletionEvent>>,
+    model_id: String,
+    max_tokens: u32,
+) -> BoxStream<'static, Result<LanguageModelCompletionEvent>> {
+    let mut output_tokens = 0u32;
+    let mut reported_percent = 0;
+    events
+        .flat_map(move |event| {
+            let generated = match &event {
+                Ok(LanguageModelCompletionEvent::Text(text))
+                | Ok(LanguageModelCompletionEvent::Thinking { text, .. }) => {
+                    open_router::estimate_tokens(&model_id, text)
+                }
+                Ok(LanguageModelCompletionEvent::ToolUse(tool_use)) => {
+                    open_router::estimate_tokens(&model_id, &tool_use.input.to_string())
+                }
+                Ok(
+                    LanguageModelCompletionEvent::UsageUpdate(usage)
+                    | LanguageModelCompletionEvent::PartialUsageUpdate(usage),
+                ) => {
+                    output_tokens = usage.output_tokens;
+                    0
+                }
+                _ => return futures::stream::iter(vec![event]),
+            };
+            output_tokens =
+                output_tokens.saturating_add(u32::try_from(generated).unwrap_or(u32::MAX));
+            let status = CompletionRequestStatus::Generating {
+                output_tokens: output_tokens.min(max_tokens),
+                max_tokens,
+            };
+            let percent = (status.progress().unwrap_or(0.) * 100.) as u32;
+            if percent <= reported_percent {
+                return futures::stream::iter(vec![event]);
+            }
+            reported_percent = percent;
+            futures::stream::iter(vec![
+                event,
+                Ok(LanguageModelCompletionEvent::StatusUpdate(status)),
+            ])
+        })
+        .boxed()
+}
+/// Holds back text events until the next event that is part of the reply, such as a tool call or
+/// the stop, then emits the held text as one event cleaned up by `post_processor`, right before
+/// that event. Status, usage and safety events carry no reply content, so they pass through as
+/// they arrive without splitting the text.
+pub(super) fn post_process_text(
+    events: BoxStream<'static, Result<LanguageModelCompletionEvent>>,
+    post_processor: PostProcessor,
+) -> BoxStream<'static, Result<LanguageModelCompletionEvent>> {
+    futures::stream::unfold(Some((events, String::new(), None)), move |state| {
+        let post_processor = post_processor.clone();
+        async move {
+            let (mut events, mut text, queued) = state?;
+            if let Some(event) = queued {
+                return Some((event, Some((events, text, None))));
+            }
+
+            loop {
+                let event = match events.next().await {
+                    Some(Ok(LanguageModelCompletionEvent::Text(chunk))) => {
+                        text.push_str(&chunk);
+                        continue;
+                    }
+                    Some(
+                        event @ Ok(
+                            LanguageModelCompletionEvent::StatusUpdate(_)
+                            | LanguageModelCompletionEvent::UsageUpdate(_)
+                            | LanguageModelCompletionEvent::PartialUsageUpdate(_)
+                            | LanguageModelCompletionEvent::SafetyRatings(_),
+                        ),
+                    ) => return Some((event, Some((events, text, None)))),
+                    Some(event) => Some(event),
+                    None => None,
+                };
+                let processed = post_processor.apply(&std::mem::take(&mut text));
+                return match event {
+                    Some(event) if processed.is_empty() => {
+                        Some((event, Some((events, text, None))))
+                    }
+                    Some(event) => Some((
+                        Ok(LanguageModelCompletionEvent::Text(processed)),
+                        Some((events, text, Some(event))),
+                    )),
+                    None if processed.is_empty() => None,
+                    None => Some((Ok(LanguageModelCompletionEvent::Text(processed)), None)),
+                };
+            }
+        }
+    })
+    .boxed()
+}
+/// Merges text events that arrive within `window` of the first one into a single event, so fast
+/// models cause fewer UI updates. Any other event, and the end of the stream, flushes the
+/// buffered text first.
+pub(super) fn coalesce_text_events(
+    events: BoxStream<'static, Result<LanguageModelCompletionEvent>>,
+    window: Duration,
+    executor: BackgroundExecutor,
+) -> BoxStream<'static, Result<LanguageModelCompletionEvent>> {
+    futures::stream::unfold(Some((events, None)), move |state| {
+        let executor = executor.clone();
+        async move {
+            let (mut events, queued) = state?;
+            if let Some(event) = queued {
+                return Some((event, Some((events, None))));
+            }
+
+            let mut text = match events.next().await? {
+                Ok(LanguageModelCompletionEvent::Text(text)) => text,
+                event => return Some((event, Some((events, None)))),
+            };
+            let mut flush = executor.timer(window);
+            loop {
+                match futures::future::select(events.next(), &mut flush).await {
+                    futures::future::Either::Left((
+                        Some(Ok(LanguageModelCompletionEvent::Text(more))),
+                        _,
+                    )) => text.push_str(&more),
+                    futures::future::Either::Left((Some(event), _)) => {
+                        return Some((
+                            Ok(LanguageModelCompletionEvent::Text(text)),
+                            Some((events, Some(event))),
+                        ));
+                    }
+                    futures::future::Either::Left((None, _)) => {
+                        return Some((Ok(LanguageModelCompletionEvent::Text(text)), None));
+                    }
+                    futures::future::Either::Right(_) => {
+                        return Some((
+                            Ok(LanguageModelCompletionEvent::Text(text)),
+                            Some((events, None)),
+                        ));
+                    }
+                }
+            }
+        }
+    })
+    .boxed()
+}
+
+/// The latest usage of a completion that hasn't finished streaming.
+pub(super) struct InFlightUsage {
+    pub(super) telemetry_id: String,
+    pub(super) usage: TokenUsage,
+}
+pub(super) type InFlightUsageRecords = Arc<Mutex<HashMap<usize, InFlightUsage>>>;
+/// Forgets a completion's in-flight usage once its stream is dropped.
+struct InFlightUsageGuard {
+    id: usize,
+    records: InFlightUsageRecords,
+    stopped: bool,
+}
+impl Drop for InFlightUsageGuard {
+    fn drop(&mut self) {
+        self.records.lock().remove(&self.id);
+    }
+}
+/// Reports which model served a completion and how many tokens it used once the stream ends.
+/// Completions that stop without reporting usage mark it unavailable in `usage_unavailable`
+/// instead, so it isn't shown as zero.
+///
+/// Only this metadata is sent, never request or response content, and `telemetry::event!` drops
+/// it unless the user has opted into telemetry.
+pub(super) fn report_usage_telemetry<T: Send + 'static>(
+    telemetry_id: String,
+    in_flight_usage: InFlightUsageRecords,
+    usage_unavailable: Arc<AtomicBool>,
+    events: BoxStream<'static, Result<T>>,
+    completion_event: fn(&T) -> &LanguageModelCompletionEvent,
+) -> BoxStream<'static, Result<T>> {
+    static NEXT_COMPLETION_ID: AtomicUsize = AtomicUsize::new(0);
+    let guard = InFlightUsageGuard {
+        id: NEXT_COMPLETION_ID.fetch_add(1, Ordering::Relaxed),
+        records: in_flight_usage,
+        stopped: false,
+    };
+
+    futures::stream::unfold((events, guard), move |(mut events, mut guard)| {
+        let telemetry_id = telemetry_id.clone();
+        let usage_unavailable = usage_unavailable.clone();
+        async move {
+            match events.next().await {
+                Some(event) => {
+                    let completion = event.as_ref().ok().map(completion_event);
+                    guard.stopped |=
+                        matches!(completion, Some(LanguageModelCompletionEvent::Stop(_)));
+                    if let Some(
+                        LanguageModelCompletionEvent::UsageUpdate(usage)
+                        | LanguageModelCompletionEvent::PartialUsageUpdate(usage),
+                    ) = completion
+                    {
+                        guard.records.lock().insert(
+                            guard.id,
+                            InFlightUsage {
+                                telemetry_id,
+                                usage: *usage,
+                            },
+                        );
+                    }
+                    Some((event, (events, guard)))
+                }
+                None => {
+                    let record = guard.records.lock().remove(&guard.id);
+                    if guard.stopped {
+                        usage_unavailable.store(record.is_none(), Ordering::Relaxed);
+                    }
+                    if let Some(record) = record {
+                        telemetry::event!(
+                            "OpenRouter Completion Finished",
+                            model = record.telemetry_id,
+                            input_tokens = record.usage.input_tokens,
+                            output_tokens = record.usage.output_tokens,
+                        );
+                    }
+                    None
+                }
+            }
+        }
+    })
+    .boxed()
+}
+
+pub(super) const EMPTY_RESPONSE_MESSAGE: &str =
+    "The model returned an empty response. Try sending your message again.";
+
+pub fn map_to_language_model_completion_events(
+    events: BoxStream<'static, Result<open_router::ResponseStreamEvent>>,
+) -> impl futures::Stream<Item = Result<LanguageModelCompletionEvent>> {
+    struct State {
+        events: BoxStream<'static, Result<open_router::ResponseStreamEvent>>,
+        tool_calls_by_index: BTreeMap<usize, RawToolCall>,
+        /// Whether the model has produced any text, reasoning or tool calls so far.
+        produced_output: bool,
+        /// Whether a finish reason has arrived, after which reported usage is final.
+        finished: bool,
+        /// The safety ratings last reported, so repeats on later chunks aren't emitted again.
+        safety_ratings: Vec<open_router::SafetyRating>,
+    }
+
+    futures::stream::unfold(
+        State {
+            events,
+            tool_calls_by_index: BTreeMap::default(),
+            produced_output: false,
+            finished: false,
+            safety_ratings: Vec::new(),
+        },
+        |mut state| async move {
+            if let Some(event) = state.events.next().await {
+                match event {
+                    Ok(event) => {
+                        let mut events = Vec::new();
+                        state.finished |= event
+                            .choices
+                            .first()
+                            .is_some_and(|choice| choice.finish_reason.is_some());
+                        if let Some(usage) = event.usage.as_ref() {
+                            let usage = TokenUsage {
+                                input_tokens: usage.prompt_tokens,
+                                output_tokens: usage.completion_tokens,
+                                cache_creation_input_tokens: 0,
+                                cache_read_input_tokens: 0,
+                            };
+                            // Usage reported mid-stream is a running total for live feedback.
+                            events.push(Ok(if state.finished {
+                                LanguageModelCompletionEvent::UsageUpdate(usage)
+                            } else {
+                                LanguageModelCompletionEvent::PartialUsageUpdate(usage)
+                            }));
+                        }
+
+                        let Some(choice) = event.choices.first() else {
+                            if events.is_empty() {
+                                events.push(Err(anyhow!("Response contained no choices")));
+                            }
+                            return Some((events, state));
+                        };
+
+                        if !choice.safety_ratings.is_empty()
+                            && choice.safety_ratings != state.safety_ratings
+                        {
+                            state.safety_ratings = choice.safety_ratings.clone();
+                            events.push(Ok(LanguageModelCompletionEvent::SafetyRatings(
+                                choice
+                                    .safety_ratings
+                                    .iter()
+                                    .map(|rating| LanguageModelSafetyRating {
+                                        category: rating.category.clone(),
+                                        probability: rating.probability.clone(),
+                                        blocked: rating.blocked,
+                                    })
+                                    .collect(),
+                            )));
+                        }
+
+                        if let Some(reasoning) = choice.delta.reasoning.clone() {
+                            state.produced_output |= !reasoning.is_empty();
+                            events.push(Ok(LanguageModelCompletionEvent::Thinking {
+                                text: reasoning,
+                                signature: None,
+                            }));
+                        }
+
+                        if let Some(content) = choice.delta.content.clone() {
+                            state.produced_output |= !content.is_empty();
+                            // Text after a tool call means the model has finished writing it, so
+                            // emit the call first to keep the turn in order.
+                            if !content.is_empty() {
+                                events.extend(flush_tool_calls(&mut state.tool_calls_by_index));
+                            }
+                            events.push(Ok(LanguageModelCompletionEvent::Text(content)));
+                        }
+
+                        if let Some(tool_calls) = choice.delta.tool_calls.as_ref() {
+                            for tool_call in tool_calls {
+                                state.produced_output = true;
+                                let entry = state
+                                    .tool_calls_by_index
+                                    .entry(tool_call.index)
+                                    .or_default();
+
+                                if let Some(tool_id) = tool_call.id.clone() {
+                                    entry.id = tool_id;
+                                }
+
+                                if let Some(function) = tool_call.function.as_ref() {
+                                    if let Some(name) = function.name.clone() {
+                                        entry.name = name;
+                                    }
+
+                                    if let Some(arguments) = function.arguments.clone() {
+                                        entry.arguments.push_str(&arguments);
+                                    }
+                                }
+
+                                // Announce the call as soon as it's identifiable, so the UI can
+                                // show which tool is being called while its input streams in.
+                                if !entry.started && !entry.id.is_empty() && !entry.name.is_empty()
+                                {
+                                    entry.started = true;
+                                    events.push(Ok(LanguageModelCompletionEvent::ToolUseStart {
+                                        id: entry.id.clone().into(),
+                                        name: entry.name.as_str().into(),
+                                    }));
+                                }
+                            }
+                        }
+
+                        // Some models finish with "stop" even when the turn ends in a tool call.
+                        let finish_reason = match choice.finish_reason.as_deref() {
+                            Some("stop") if !state.tool_calls_by_index.is_empty() => {
+                                Some("tool_calls")
+                            }
+                            finish_reason => finish_reason,
+                        };
+                        match finish_reason {
+                            // Some models occasionally finish a turn without saying anything,
+                            // which would otherwise show up as a blank message.
+                            Some("stop") if !state.produced_output => {
+                                events.push(Err(anyhow!(EMPTY_RESPONSE_MESSAGE)));
+                            }
+                            Some("stop") => {
+                                events.push(Ok(LanguageModelCompletionEvent::Stop(
+                                    StopReason::EndTurn,
+                                )));
+                            }
+                            Some("length") => {
+                                events.push(Ok(LanguageModelCompletionEvent::Stop(
+                                    StopReason::MaxTokens,
+                                )));
+                            }
+                            Some("tool_calls") => {
+                                events.extend(flush_tool_calls(&mut state.tool_calls_by_index));
+
+                                events.push(Ok(LanguageModelCompletionEvent::Stop(
+                                    StopReason::ToolUse,
+                                )));
+                            }
+                            Some(stop_reason) => {
+                                log::error!("Unexpected OpenRouter stop_reason: {stop_reason:?}");
+                                events.push(Ok(LanguageModelCompletionEvent::Stop(
+                                    StopReason::EndTurn,
+                                )));
+                            }
+                            None => {}
+                        }
+
+                        Some((events, state))
+                    }
+                    Err(err) => Some((vec![Err(err)], state)),
+                }
+            } else {
+                None
+            }
+        },
+    )
+    .flat_map(futures::stream::iter)
+}
+/// Maps a response carrying several choices, tagging each event with the index of its choice.
+/// Every choice is mapped on its own, as by [`map_to_language_model_completion_events`], so text
+/// and tool calls from different choices never mix. Usage covers the whole response and is
+/// reported with choice 0.
+pub fn map_choices_to_language_model_completion_events(
+    events: BoxStream<'static, Result<open_router::ResponseStreamEvent>>,
+) -> impl futures::Stream<Item = Result<(u32, LanguageModelCompletionEvent)>> {
+    type ChoiceEvents = BoxStream<'static, Result<LanguageModelCompletionEvent>>;
+
+    struct Choice {
+        tx: mpsc::UnboundedSender<Result<open_router::ResponseStreamEvent>>,
+        events: ChoiceEvents,
+    }
+
+    impl Choice {
+        fn new() -> Self {
+            let (tx, rx) = mpsc::unbounded();
+            Self {
+                tx,
+                events: map_to_language_model_completion_events(rx.boxed()).boxed(),
+            }
+        }
+
+        /// Takes the events mapped so far. The mapping only waits on the channel, so everything
+        /// sent to it has been mapped once it stops being ready.
+        fn drain(&mut self, index: u32) -> Vec<Result<(u32, LanguageModelCompletionEvent)>> {
+            let mut events = Vec::new();
+            while let Some(Some(event)) = self.events.next().now_or_never() {
+                events.push(event.map(|event| (index, event)));
+            }
+            events
+        }
+    }
+
+    futures::stream::unfold(
+        (Some(events), BTreeMap::<u32, Choice>::default()),
+        |(events, mut choices)| async move {
+            let mut events = events?;
+            match events.next().await {
+                Some(Ok(mut event)) => {
+                    let mut usage = event.usage.take();
+                    let mut split = std::mem::take(&mut event.choices)
+                        .into_iter()
+                        .map(|choice| (choice.index, vec![choice]))
+                        .collect::<Vec<_>>();
+                    if split.is_empty() {
+                        split.push((0, Vec::new()));
+                    }
+                    let mut mapped = Vec::new();
+                    for (index, choice) in split {
+                        let choice_event = open_router::ResponseStreamEvent {
+                            id: event.id.clone(),
+                            created: event.created,
+                            model: event.model.clone(),
+                            choices: choice,
+                            usage: if index == 0 { usage.take() } else { None },
+                        };
+                        let choice = choices.entry(index).or_insert_with(Choice::new);
+                        choice.tx.unbounded_send(Ok(choice_event)).ok();
+                        mapped.extend(choice.drain(index));
+                    }
+                    Some((mapped, (Some(events), choices)))
+                }
+                Some(Err(error)) => Some((vec![Err(error)], (Some(events), choices))),
+                None => {
+                    let mut mapped = Vec::new();
+                    for (index, mut choice) in std::mem::take(&mut choices) {
+                        choice.tx.close_channel();
+                        mapped.extend(choice.drain(index));
+                    }
+                    Some((mapped, (None, choices)))
+                }
+            }
+        },
+    )
+    .flat_map(futures::stream::iter)
+}
+#[derive(Default)]
+struct RawToolCall {
+    id: String,
+    name: String,
+    arguments: String,
+    /// Whether `ToolUseStart` has been emitted for this call.
+    started: bool,
+}
+/// Emits the tool calls accumulated so far, in the order the model started them.
+fn flush_tool_calls(
+    tool_calls_by_index: &mut BTreeMap<usize, RawToolCall>,
+) -> Vec<Result<LanguageModelCompletionEvent>> {
+    std::mem::take(tool_calls_by_index)
+        .into_values()
+        .map(|tool_call| {
+            Ok(LanguageModelCompletionEvent::ToolUse(
+                LanguageModelToolUse {
+                    id: tool_call.id.into(),
+                    name: tool_call.name.as_str().into(),
+                    input: serde_json::Value::from_str(&tool_call.arguments)?,
+                },
+            ))
+        })
+        .collect()
+}
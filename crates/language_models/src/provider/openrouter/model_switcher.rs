@@ -0,0 +1,355 @@
+use fuzzy::{StringMatch, StringMatchCandidate, match_strings};
+use gpui::{
+    App, Context, DismissEvent, Entity, EventEmitter, FocusHandle, Focusable, Task, WeakEntity,
+    Window, actions,
+};
+use language_model::{
+    LanguageModelId, LanguageModelProviderId, LanguageModelRegistry, SelectedModel,
+};
+use picker::{Picker, PickerDelegate};
+use std::sync::Arc;
+use ui::{HighlightedLabel, ListItem, ListItemSpacing, Tooltip, prelude::*};
+use util::ResultExt;
+use workspace::{ModalView, Toast, Workspace, notifications::NotificationId};
+
+use super::{AvailableModel, PROVIDER_ID, State};
+
+actions!(open_router, [SwitchModel]);
+
+/// Registers the [`SwitchModel`] action on every workspace, offering the models cached in `state`.
+pub(super) fn register(state: WeakEntity<State>, cx: &mut App) {
+    cx.observe_new(move |workspace: &mut Workspace, _, _| {
+        let state = state.clone();
+        workspace.register_action(move |workspace, _: &SwitchModel, window, cx| {
+            let Some(state) = state.upgrade() else {
+                return;
+            };
+            if !state.read(cx).is_authenticated() {
+                struct OpenRouterNotAuthenticated;
+                workspace.show_toast(
+                    Toast::new(
+                        NotificationId::unique::<OpenRouterNotAuthenticated>(),
+                        "Add an OpenRouter API key in the Agent settings to switch models.",
+                    )
+                    .autohide(),
+                    cx,
+                );
+                return;
+            }
+            workspace.toggle_modal(window, cx, |window, cx| {
+                ModelSwitcher::new(state, window, cx)
+            });
+        });
+    })
+    .detach();
+}
+
+pub struct ModelSwitcher {
+    picker: Entity<Picker<ModelSwitcherDelegate>>,
+}
+
+impl ModelSwitcher {
+    fn new(state: Entity<State>, window: &mut Window, cx: &mut Context<Self>) -> Self {
+        let state = state.read(cx);
+        let models = state
+            .visible_models()
+            .into_iter()
+            .map(|model| SwitcherEntry {
+                label: label(&model),
+                recommended: state.is_recommended(&model.name),
+                keywords: state
+                    .catalog_entry(&model.name)
+                    .map(capability_keywords)
+                    .unwrap_or_default(),
+                id: model.name,
+                chain: Vec::new(),
+            })
+            .chain(state.alias_models(cx).into_iter().map(|(alias, model)| {
+                SwitcherEntry {
+                    label: label(&model),
+                    recommended: false,
+                    keywords: state
+                        .catalog_entry(&model.name)
+                        .map(capability_keywords)
+                        .unwrap_or_default(),
+                    id: alias.name,
+                    chain: alias.models,
+                }
+            }))
+            .collect();
+        let delegate = ModelSwitcherDelegate::new(cx.entity().downgrade(), models, cx);
+        let picker = cx.new(|cx| Picker::uniform_list(delegate, window, cx));
+        Self { picker }
+    }
+}
+
+impl Render for ModelSwitcher {
+    fn render(&mut self, _window: &mut Window, _cx: &mut Context<Self>) -> impl IntoElement {
+        v_flex().w(rems(34.)).child(self.picker.clone())
+    }
+}
+
+impl Focusable for ModelSwitcher {
+    fn focus_handle(&self, cx: &App) -> FocusHandle {
+        self.picker.focus_handle(cx)
+    }
+}
+
+impl EventEmitter<DismissEvent> for ModelSwitcher {}
+impl ModalView for ModelSwitcher {}
+
+/// A model offered by the switcher.
+struct SwitcherEntry {
+    /// The id the model is selected by.
+    id: String,
+    label: String,
+    /// Whether the model carries the "Recommended" badge.
+    recommended: bool,
+    /// Words describing what the model can do, so it can be searched for by capability.
+    keywords: Vec<&'static str>,
+    /// For aliases, the models OpenRouter tries in order.
+    chain: Vec<String>,
+}
+
+/// The capability words a model can be found by, e.g. "vision" for models that accept images.
+fn capability_keywords(model: &open_router::Model) -> Vec<&'static str> {
+    let mut keywords = Vec::new();
+    if model.supports_tools() {
+        keywords.push("tools");
+    }
+    if model.supports_reasoning() {
+        keywords.extend(["reasoning", "thinking"]);
+    }
+    for modality in model.input_modalities() {
+        match modality {
+            open_router::Modality::Image => keywords.extend(["vision", "image"]),
+            open_router::Modality::File => keywords.extend(["file", "pdf"]),
+            open_router::Modality::Audio => keywords.push("audio"),
+            open_router::Modality::Text | open_router::Modality::Other => {}
+        }
+    }
+    if model
+        .output_modalities()
+        .contains(&open_router::Modality::Image)
+    {
+        keywords.push("image generation");
+    }
+    if model.is_free() {
+        keywords.push("free");
+    }
+    keywords
+}
+
+/// Whether every word of `query` is part of the entry's label or one of its capability
+/// keywords, ignoring case, so that e.g. "vis claude" finds Claude models that accept images.
+fn matches_keywords(search_text: &str, query: &str) -> bool {
+    let query = query.to_lowercase();
+    let mut words = query.split_whitespace().peekable();
+    words.peek().is_some() && words.all(|word| search_text.contains(word))
+}
+
+fn label(model: &AvailableModel) -> String {
+    match &model.display_name {
+        Some(display_name) => format!("{display_name} ({})", model.name),
+        None => model.name.clone(),
+    }
+}
+
+pub struct ModelSwitcherDelegate {
+    model_switcher: WeakEntity<ModelSwitcher>,
+    models: Vec<SwitcherEntry>,
+    candidates: Vec<StringMatchCandidate>,
+    /// Each model's lowercased label and capability keywords, searched alongside the fuzzy match.
+    search_texts: Arc<[String]>,
+    matches: Vec<StringMatch>,
+    selected_index: usize,
+}
+
+impl ModelSwitcherDelegate {
+    fn new(
+        model_switcher: WeakEntity<ModelSwitcher>,
+        mut models: Vec<SwitcherEntry>,
+        cx: &App,
+    ) -> Self {
+        // List recommended models first to guide users who haven't settled on one yet.
+        models.sort_by(|a, b| {
+            b.recommended
+                .cmp(&a.recommended)
+                .then_with(|| a.id.cmp(&b.id))
+        });
+        let candidates = models
+            .iter()
+            .enumerate()
+            .map(|(candidate_id, model)| StringMatchCandidate::new(candidate_id, &model.label))
+            .collect::<Vec<_>>();
+        let search_texts = models
+            .iter()
+            .map(|model| {
+                let mut text = model.label.to_lowercase();
+                for keyword in &model.keywords {
+                    text.push(' ');
+                    text.push_str(keyword);
+                }
+                text
+            })
+            .collect();
+
+        // Start on the active model, so confirming straight away is a no-op.
+        let active_model_id = LanguageModelRegistry::read_global(cx)
+            .default_model()
+            .filter(|configured| configured.provider.id().0.as_ref() == PROVIDER_ID)
+            .map(|configured| configured.model.id());
+        let selected_index = active_model_id
+            .and_then(|id| models.iter().position(|model| model.id == id.0.as_ref()))
+            .unwrap_or(0);
+
+        Self {
+            model_switcher,
+            models,
+            candidates,
+            search_texts,
+            matches: Vec::new(),
+            selected_index,
+        }
+    }
+}
+
+impl PickerDelegate for ModelSwitcherDelegate {
+    type ListItem = ListItem;
+
+    fn placeholder_text(&self, _window: &mut Window, _cx: &mut App) -> Arc<str> {
+        "Switch to an OpenRouter model…".into()
+    }
+
+    fn match_count(&self) -> usize {
+        self.matches.len()
+    }
+
+    fn confirm(&mut self, _: bool, window: &mut Window, cx: &mut Context<Picker<Self>>) {
+        if let Some(mat) = self.matches.get(self.selected_index) {
+            let model = SelectedModel {
+                provider: LanguageModelProviderId::from(PROVIDER_ID.to_string()),
+                model: LanguageModelId::from(self.models[mat.candidate_id].id.clone()),
+            };
+            LanguageModelRegistry::global(cx).update(cx, |registry, cx| {
+                registry.select_default_model(Some(&model), cx);
+            });
+        }
+        self.dismissed(window, cx);
+    }
+
+    fn dismissed(&mut self, _: &mut Window, cx: &mut Context<Picker<Self>>) {
+        self.model_switcher
+            .update(cx, |_, cx| cx.emit(DismissEvent))
+            .log_err();
+    }
+
+    fn selected_index(&self) -> usize {
+        self.selected_index
+    }
+
+    fn set_selected_index(
+        &mut self,
+        ix: usize,
+        _window: &mut Window,
+        _: &mut Context<Picker<Self>>,
+    ) {
+        self.selected_index = ix;
+    }
+
+    fn update_matches(
+        &mut self,
+        query: String,
+        window: &mut Window,
+        cx: &mut Context<Picker<Self>>,
+    ) -> Task<()> {
+        let background = cx.background_executor().clone();
+        let candidates = self.candidates.clone();
+        let search_texts = self.search_texts.clone();
+        cx.spawn_in(window, async move |this, cx| {
+            let matches = if query.is_empty() {
+                candidates
+                    .into_iter()
+                    .enumerate()
+                    .map(|(index, candidate)| StringMatch {
+                        candidate_id: index,
+                        string: candidate.string,
+                        positions: Vec::new(),
+                        score: 0.0,
+                    })
+                    .collect()
+            } else {
+                let mut matches = match_strings(
+                    &candidates,
+                    &query,
+                    false,
+                    100,
+                    &Default::default(),
+                    background,
+                )
+                .await;
+                // Follow the fuzzy matches with models that only match by capability.
+                let matched = matches
+                    .iter()
+                    .map(|mat| mat.candidate_id)
+                    .collect::<Vec<_>>();
+                matches.extend(
+                    candidates
+                        .into_iter()
+                        .filter(|candidate| {
+                            !matched.contains(&candidate.id)
+                                && matches_keywords(&search_texts[candidate.id], &query)
+                        })
+                        .map(|candidate| StringMatch {
+                            candidate_id: candidate.id,
+                            string: candidate.string,
+                            positions: Vec::new(),
+                            score: 0.0,
+                        }),
+                );
+                matches
+            };
+
+            this.update(cx, |this, cx| {
+                let delegate = &mut this.delegate;
+                delegate.matches = matches;
+                delegate.selected_index = delegate
+                    .selected_index
+                    .min(delegate.matches.len().saturating_sub(1));
+                cx.notify();
+            })
+            .log_err();
+        })
+    }
+
+    fn render_match(
+        &self,
+        ix: usize,
+        selected: bool,
+        _: &mut Window,
+        _: &mut Context<Picker<Self>>,
+    ) -> Option<Self::ListItem> {
+        let mat = &self.matches[ix];
+        let model = &self.models[mat.candidate_id];
+        Some(
+            ListItem::new(ix)
+                .inset(true)
+                .spacing(ListItemSpacing::Sparse)
+                .toggle_state(selected)
+                .child(HighlightedLabel::new(
+                    mat.string.clone(),
+                    mat.positions.clone(),
+                ))
+                .when(model.recommended, |this| {
+                    this.end_slot(
+                        Label::new("Recommended")
+                            .size(LabelSize::XSmall)
+                            .color(Color::Accent),
+                    )
+                })
+                .when(!model.chain.is_empty(), |this| {
+                    this.tooltip(Tooltip::text(format!("Tries {}", model.chain.join(" → "))))
+                }),
+        )
+    }
+}
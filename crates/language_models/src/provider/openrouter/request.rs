@@ -0,0 +1,524 @@
+use anyhow::{Context as _, Result, anyhow};
+use collections::{BTreeMap, HashMap};
+use http_client::HttpClient;
+use language_model::{
+    LanguageModelRequest, LanguageModelRequestTool, LanguageModelToolChoice, MessageContent, Role,
+};
+use sha2::{Digest, Sha256};
+
+use super::settings::{AvailableModel, OpenRouterSettings, SystemRole};
+
+/// Model families whose OpenRouter providers continue a trailing assistant message.
+const PREFILL_MODEL_PREFIXES: &[&str] = &["anthropic/", "deepseek/", "mistralai/"];
+/// Models that ignore a separate system role and expect instructions in the first user message.
+const NO_SYSTEM_ROLE_MODEL_PREFIXES: &[&str] = &[
+    "google/gemma-",
+    "meta-llama/llama-2-",
+    "mistralai/mistral-7b-instruct-v0.1",
+    "mistralai/mixtral-8x7b-instruct",
+];
+
+/// Models whose upstreams reject message fields beyond the role, content and tool calls.
+const STRICT_MESSAGE_MODEL_PREFIXES: &[&str] = &["cohere/", "mistralai/"];
+
+/// The longest function name the upstream providers accept.
+const MAX_TOOL_NAME_LEN: usize = 64;
+
+/// Limits on `request_metadata`, matching what OpenRouter passes through.
+pub(super) const MAX_METADATA_TAGS: usize = 16;
+pub(super) const MAX_METADATA_KEY_LEN: usize = 64;
+pub(super) const MAX_METADATA_VALUE_LEN: usize = 512;
+
+/// Appends `text` to the request's trailing assistant prefill, adding one if there isn't one.
+pub(super) fn with_assistant_prefill(
+    mut request: open_router::Request,
+    text: &str,
+) -> open_router::Request {
+    if text.is_empty() {
+        return request;
+    }
+    match request.messages.last_mut() {
+        Some(open_router::RequestMessage::Assistant {
+            content: Some(content),
+            tool_calls,
+        }) if tool_calls.is_empty() => content.push_str(text),
+        _ => request
+            .messages
+            .push(open_router::RequestMessage::Assistant {
+                content: Some(text.to_string()),
+                tool_calls: Vec::new(),
+            }),
+    }
+    request
+}
+
+/// The input a request adds since the model last replied, which earlier requests haven't
+/// screened yet.
+pub(super) fn moderation_input(request: &open_router::Request) -> String {
+    let new_messages = request
+        .messages
+        .iter()
+        .rposition(|message| matches!(message, open_router::RequestMessage::Assistant { .. }))
+        .map_or(&request.messages[..], |ix| &request.messages[ix + 1..]);
+    new_messages
+        .iter()
+        .filter_map(|message| match message {
+            open_router::RequestMessage::User { content }
+            | open_router::RequestMessage::Tool { content, .. } => Some(content.as_str()),
+            _ => None,
+        })
+        .collect::<Vec<_>>()
+        .join("\n\n")
+}
+/// Checks the request's new input with OpenRouter's moderation endpoint, failing with
+/// [`open_router::OpenRouterError::ContentFiltered`] when it's flagged.
+pub(super) async fn screen_prompt(
+    http_client: &dyn HttpClient,
+    api_url: &str,
+    api_key: &str,
+    request: &open_router::Request,
+) -> Result<()> {
+    let input = moderation_input(request);
+    if input.trim().is_empty() {
+        return Ok(());
+    }
+    let result = open_router::moderate(http_client, api_url, api_key, &input)
+        .await
+        .context("Failed to screen the prompt with OpenRouter moderation")?;
+    if !result.flagged {
+        return Ok(());
+    }
+    let categories = result.flagged_categories().collect::<Vec<_>>();
+    let reason = if categories.is_empty() {
+        "The prompt was flagged by moderation".to_string()
+    } else {
+        format!(
+            "The prompt was flagged by moderation for: {}",
+            categories.join(", ")
+        )
+    };
+    Err(
+        open_router::OpenRouterError::ContentFiltered(open_router::ErrorMessage {
+            message: reason,
+            metadata: Some(open_router::ErrorMetadata {
+                reasons: categories.into_iter().map(str::to_string).collect(),
+                ..Default::default()
+            }),
+        })
+        .into(),
+    )
+}
+pub(super) fn supports_assistant_prefill(model_id: &str) -> bool {
+    PREFILL_MODEL_PREFIXES
+        .iter()
+        .any(|prefix| model_id.starts_with(prefix))
+}
+/// A trailing assistant message asks the model to continue a partial response. Models that
+/// can't do that would start a fresh reply instead, so the partial response is handed to them
+/// as context in a user message.
+fn fold_assistant_prefill(messages: &mut Vec<open_router::RequestMessage>, model_id: &str) {
+    let Some(open_router::RequestMessage::Assistant {
+        content: Some(_),
+        tool_calls,
+    }) = messages.last()
+    else {
+        return;
+    };
+    if !tool_calls.is_empty() {
+        return;
+    }
+
+    let Some(open_router::RequestMessage::Assistant {
+        content: Some(prefill),
+        ..
+    }) = messages.pop()
+    else {
+        return;
+    };
+    log::warn!("{model_id} doesn't support assistant prefill, folding it into the prompt");
+    messages.push(open_router::RequestMessage::User {
+        content: format!(
+            "Continue your previous response exactly where it left off, without repeating it:\n\n{prefill}"
+        ),
+    });
+}
+pub(super) fn estimate_request_tokens(model_id: &str, request: &LanguageModelRequest) -> usize {
+    /// Tokens spent on each message's role and delimiters.
+    const MESSAGE_OVERHEAD_TOKENS: usize = 4;
+
+    request
+        .messages
+        .iter()
+        .map(|message| {
+            MESSAGE_OVERHEAD_TOKENS
+                + open_router::estimate_tokens(model_id, &message.string_contents())
+        })
+        .sum()
+}
+fn accepts_message_metadata(model_id: &str) -> bool {
+    !STRICT_MESSAGE_MODEL_PREFIXES
+        .iter()
+        .any(|prefix| model_id.starts_with(prefix))
+}
+/// Removes optional message fields that strict upstreams reject with a 400, keeping everything
+/// the model needs to follow the conversation.
+fn strip_message_metadata(messages: &mut [open_router::RequestMessage]) {
+    for message in messages {
+        if let open_router::RequestMessage::Tool { name, .. } = message {
+            *name = None;
+        }
+    }
+}
+/// Resends system messages under the `developer` role.
+fn use_developer_role(messages: &mut [open_router::RequestMessage]) {
+    for message in messages {
+        if let open_router::RequestMessage::System { content } = message {
+            *message = open_router::RequestMessage::Developer {
+                content: std::mem::take(content),
+            };
+        }
+    }
+}
+fn supports_system_role(model_id: &str) -> bool {
+    !NO_SYSTEM_ROLE_MODEL_PREFIXES
+        .iter()
+        .any(|prefix| model_id.starts_with(prefix))
+}
+
+/// Prepends the configured `system_prompt` to the request's first system message, or adds one
+/// if the request has none. Requests that already carry the prompt are left alone.
+fn inject_system_prompt(messages: &mut Vec<open_router::RequestMessage>, system_prompt: &str) {
+    match messages
+        .iter_mut()
+        .find(|message| matches!(message, open_router::RequestMessage::System { .. }))
+    {
+        Some(open_router::RequestMessage::System { content }) => {
+            if !content.contains(system_prompt) {
+                *content = format!("{system_prompt}\n\n{content}");
+            }
+        }
+        _ => messages.insert(
+            0,
+            open_router::RequestMessage::System {
+                content: system_prompt.to_string(),
+            },
+        ),
+    }
+}
+/// Moves system messages into the first user message, for models without a system role.
+fn fold_system_prompt(messages: &mut Vec<open_router::RequestMessage>) {
+    let mut system_prompt = Vec::new();
+    messages.retain_mut(|message| match message {
+        open_router::RequestMessage::System { content } => {
+            system_prompt.push(std::mem::take(content));
+            false
+        }
+        _ => true,
+    });
+    if system_prompt.is_empty() {
+        return;
+    }
+
+    let system_prompt = system_prompt.join("\n\n");
+    match messages
+        .iter_mut()
+        .find(|message| matches!(message, open_router::RequestMessage::User { .. }))
+    {
+        Some(open_router::RequestMessage::User { content }) => {
+            *content = format!("{system_prompt}\n\n{content}");
+        }
+        _ => messages.insert(
+            0,
+            open_router::RequestMessage::User {
+                content: system_prompt,
+            },
+        ),
+    }
+}
+
+/// The routing constraints for a request, or `None` when OpenRouter may route freely.
+pub(super) fn provider_preferences(
+    settings: &OpenRouterSettings,
+    only: &[String],
+) -> Option<open_router::ProviderPreferences> {
+    let preferences = open_router::ProviderPreferences {
+        max_price: settings.max_price.map(Into::into),
+        only: only.to_vec(),
+    };
+    (preferences != open_router::ProviderPreferences::default()).then_some(preferences)
+}
+
+pub fn into_open_router(
+    request: LanguageModelRequest,
+    model: &AvailableModel,
+    max_output_tokens: Option<u32>,
+    settings: &OpenRouterSettings,
+) -> Result<open_router::Request> {
+    let tools = request
+        .tools
+        .into_iter()
+        .map(into_open_router_tool)
+        .collect::<Result<Vec<_>>>()?;
+
+    let mut messages = Vec::new();
+    for message in request.messages {
+        for content in message.content {
+            match content {
+                MessageContent::Text(text) | MessageContent::Thinking { text, .. } => messages
+                    .push(match message.role {
+                        Role::User => open_router::RequestMessage::User { content: text },
+                        Role::Assistant => open_router::RequestMessage::Assistant {
+                            content: Some(text),
+                            tool_calls: Vec::new(),
+                        },
+                        Role::System => open_router::RequestMessage::System { content: text },
+                    }),
+                MessageContent::RedactedThinking(_) => {}
+                MessageContent::Image(_) => {}
+                MessageContent::ToolUse(tool_use) => {
+                    let tool_call = open_router::ToolCall {
+                        id: tool_use.id.to_string(),
+                        content: open_router::ToolCallContent::Function {
+                            function: open_router::FunctionContent {
+                                name: tool_use.name.to_string(),
+                                arguments: serde_json::to_string(&tool_use.input)
+                                    .unwrap_or_default(),
+                            },
+                        },
+                    };
+
+                    // Only attach to an assistant message from the same turn, otherwise the
+                    // call would be spliced into an earlier turn ahead of its predecessor's
+                    // tool results.
+                    if let Some(open_router::RequestMessage::Assistant { tool_calls, .. }) =
+                        messages.last_mut()
+                    {
+                        tool_calls.push(tool_call);
+                    } else {
+                        messages.push(open_router::RequestMessage::Assistant {
+                            content: None,
+                            tool_calls: vec![tool_call],
+                        });
+                    }
+                }
+                MessageContent::ToolResult(tool_result) => {
+                    messages.push(open_router::RequestMessage::Tool {
+                        content: tool_result.content.to_string(),
+                        tool_call_id: tool_result.tool_use_id.to_string(),
+                        name: Some(tool_result.tool_name.to_string()),
+                    });
+                }
+            }
+        }
+    }
+
+    if let Some(system_prompt) = settings
+        .system_prompt
+        .as_deref()
+        .map(str::trim)
+        .filter(|system_prompt| !system_prompt.is_empty())
+    {
+        inject_system_prompt(&mut messages, system_prompt);
+    }
+    if !supports_assistant_prefill(&model.name) {
+        fold_assistant_prefill(&mut messages, &model.name);
+    }
+    if !supports_system_role(&model.name) {
+        fold_system_prompt(&mut messages);
+    }
+    if settings.strip_unsupported_message_fields && !accepts_message_metadata(&model.name) {
+        strip_message_metadata(&mut messages);
+    }
+    if model.system_role() == SystemRole::Developer {
+        use_developer_role(&mut messages);
+    }
+
+    // A tool choice without tools is rejected by some upstreams.
+    let tool_choice = request
+        .tool_choice
+        .or(settings.default_tool_choice)
+        .filter(|_| !tools.is_empty())
+        .map(|tool_choice| match tool_choice {
+            LanguageModelToolChoice::Auto => open_router::ToolChoice::Auto,
+            LanguageModelToolChoice::Required => open_router::ToolChoice::Required,
+            LanguageModelToolChoice::None => open_router::ToolChoice::None,
+        });
+
+    Ok(open_router::Request {
+        model: model.name.clone(),
+        models: Vec::new(),
+        messages,
+        stream: settings.streaming,
+        // Models that require an output limit get their own when the caller doesn't pass one.
+        max_tokens: max_output_tokens.or_else(|| {
+            model.requires_max_tokens().then(|| {
+                model
+                    .max_output_tokens
+                    .unwrap_or(settings.default_max_output_tokens)
+            })
+        }),
+        stop: request.stop,
+        temperature: request.temperature.or(model.default_params.temperature),
+        top_p: model.default_params.top_p,
+        tools,
+        tool_choice,
+        user: settings.user_id.as_deref().map(hash_user_id),
+        reasoning: Some(settings.reasoning.into()),
+        provider: provider_preferences(settings, &model.only),
+        preset: settings.preset.clone(),
+        metadata: request_metadata(&settings.request_metadata),
+        n: None,
+    })
+}
+/// The `request_metadata` tags to send, in key order, leaving out any beyond OpenRouter's limits.
+pub(super) fn request_metadata(tags: &HashMap<String, String>) -> BTreeMap<String, String> {
+    tags.iter()
+        .filter(|(key, value)| {
+            !key.is_empty()
+                && key.chars().count() <= MAX_METADATA_KEY_LEN
+                && value.chars().count() <= MAX_METADATA_VALUE_LEN
+        })
+        .map(|(key, value)| (key.clone(), value.clone()))
+        .collect::<BTreeMap<_, _>>()
+        .into_iter()
+        .take(MAX_METADATA_TAGS)
+        .collect()
+}
+/// Converts a Zed tool into OpenRouter's function tool format, rejecting schemas that the
+/// upstream providers would refuse so the error surfaces before the request is sent.
+pub fn into_open_router_tool(
+    tool: LanguageModelRequestTool,
+) -> Result<open_router::ToolDefinition> {
+    let name_is_valid = !tool.name.is_empty()
+        && tool.name.len() <= MAX_TOOL_NAME_LEN
+        && tool
+            .name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+    if !name_is_valid {
+        return Err(anyhow!(
+            "Tool name {:?} is invalid: it must be 1-{MAX_TOOL_NAME_LEN} characters of letters, digits, '_' or '-'",
+            tool.name
+        ));
+    }
+
+    match tool.input_schema.get("type") {
+        Some(serde_json::Value::String(ty)) if ty == "object" => {}
+        _ => {
+            return Err(anyhow!(
+                "Tool {:?} has an invalid input schema: the root must be an object schema with \"type\": \"object\"",
+                tool.name
+            ));
+        }
+    }
+    validate_tool_schema(&tool.input_schema, "#")
+        .with_context(|| format!("Tool {:?} has an invalid input schema", tool.name))?;
+
+    Ok(open_router::ToolDefinition::Function {
+        function: open_router::FunctionDefinition {
+            name: tool.name,
+            description: Some(tool.description),
+            parameters: Some(tool.input_schema),
+        },
+    })
+}
+/// Walks a JSON schema, checking the keywords that nest other schemas. `path` is a JSON
+/// pointer to `schema`, used to locate the problem in error messages.
+fn validate_tool_schema(schema: &serde_json::Value, path: &str) -> Result<()> {
+    let object = match schema {
+        serde_json::Value::Bool(_) => return Ok(()),
+        serde_json::Value::Object(object) => object,
+        _ => return Err(anyhow!("{path} must be a schema object")),
+    };
+
+    if let Some(ty) = object.get("type") {
+        let type_is_valid = match ty {
+            serde_json::Value::String(_) => true,
+            serde_json::Value::Array(types) => types.iter().all(|ty| ty.is_string()),
+            _ => false,
+        };
+        if !type_is_valid {
+            return Err(anyhow!(
+                "{path}/type must be a string or an array of strings"
+            ));
+        }
+    }
+
+    let properties = match object.get("properties") {
+        None => None,
+        Some(serde_json::Value::Object(properties)) => {
+            for (name, property) in properties {
+                validate_tool_schema(property, &format!("{path}/properties/{name}"))?;
+            }
+            Some(properties)
+        }
+        Some(_) => return Err(anyhow!("{path}/properties must be an object")),
+    };
+
+    if let Some(required) = object.get("required") {
+        let serde_json::Value::Array(required) = required else {
+            return Err(anyhow!(
+                "{path}/required must be an array of property names"
+            ));
+        };
+        for name in required {
+            let Some(name) = name.as_str() else {
+                return Err(anyhow!(
+                    "{path}/required must be an array of property names"
+                ));
+            };
+            if !properties.is_some_and(|properties| properties.contains_key(name)) {
+                return Err(anyhow!(
+                    "{path}/required lists {name:?}, which is not one of its properties"
+                ));
+            }
+        }
+    }
+
+    match object.get("items") {
+        None => {}
+        Some(serde_json::Value::Array(items)) => {
+            for (ix, item) in items.iter().enumerate() {
+                validate_tool_schema(item, &format!("{path}/items/{ix}"))?;
+            }
+        }
+        Some(items) => validate_tool_schema(items, &format!("{path}/items"))?,
+    }
+
+    for keyword in ["anyOf", "oneOf", "allOf"] {
+        match object.get(keyword) {
+            None => {}
+            Some(serde_json::Value::Array(schemas)) => {
+                for (ix, schema) in schemas.iter().enumerate() {
+                    validate_tool_schema(schema, &format!("{path}/{keyword}/{ix}"))?;
+                }
+            }
+            Some(_) => return Err(anyhow!("{path}/{keyword} must be an array of schemas")),
+        }
+    }
+
+    for keyword in ["$defs", "definitions"] {
+        match object.get(keyword) {
+            None => {}
+            Some(serde_json::Value::Object(definitions)) => {
+                for (name, definition) in definitions {
+                    validate_tool_schema(definition, &format!("{path}/{keyword}/{name}"))?;
+                }
+            }
+            Some(_) => return Err(anyhow!("{path}/{keyword} must be an object")),
+        }
+    }
+
+    if let Some(additional) = object.get("additionalProperties") {
+        validate_tool_schema(additional, &format!("{path}/additionalProperties"))?;
+    }
+
+    Ok(())
+}
+/// Hashes the configured user identifier so that OpenRouter never sees the raw value (which is
+/// often an email address).
+pub(super) fn hash_user_id(user_id: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(user_id.as_bytes());
+    hex::encode(hasher.finalize())
+}
@@ -0,0 +1,376 @@
+use anyhow::Result;
+use collections::HashMap;
+use gpui::SharedString;
+use language_model::LanguageModelToolChoice;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// Models that expect system instructions under the `developer` role.
+const DEVELOPER_ROLE_MODEL_PREFIXES: &[&str] =
+    &["openai/o1", "openai/o3", "openai/o4", "openai/gpt-5"];
+/// Models whose upstreams reject requests that don't set `max_tokens`.
+const MAX_TOKENS_REQUIRED_MODEL_PREFIXES: &[&str] = &["anthropic/"];
+
+#[derive(Default, Clone, Debug, PartialEq)]
+pub struct OpenRouterSettings {
+    pub api_url: String,
+    pub credentials_scope: Option<String>,
+    pub available_models: Vec<AvailableModel>,
+    pub needs_setting_migration: bool,
+    pub user_id: Option<String>,
+    pub reasoning: ReasoningMode,
+    pub on_rate_limit: RateLimitBehavior,
+    pub log_model_ids: ModelIdFormat,
+    pub fallback_models: Vec<String>,
+    pub default_max_output_tokens: u32,
+    pub omit_max_tokens: bool,
+    pub compress_requests_above_bytes: Option<usize>,
+    pub max_sse_line_bytes: Option<usize>,
+    pub max_price: Option<MaxPrice>,
+    pub catalog_refresh_interval_hours: Option<u64>,
+    pub auto_refresh_catalog: bool,
+    pub cache_last_used_model: bool,
+    pub validate_api_key: bool,
+    pub dry_run: bool,
+    pub resume_dropped_streams: bool,
+    pub stop_after_tool_calls: bool,
+    pub show_request_params: bool,
+    pub notify_catalog_changes: bool,
+    pub auto_enable_new_models: bool,
+    pub low_credit_alerts: bool,
+    pub low_credit_alert_threshold: Option<f64>,
+    pub low_credit_mode: bool,
+    pub streaming: bool,
+    pub keep_alive: bool,
+    pub strip_unsupported_message_fields: bool,
+    pub idempotency_keys: bool,
+    pub inter_token_timeout_seconds: Option<u64>,
+    pub request_timeout_seconds: Option<u64>,
+    pub system_prompt: Option<String>,
+    pub coalesce_window_ms: Option<u64>,
+    pub post_process: PostProcess,
+    pub report_progress: bool,
+    pub moderate_prompts: bool,
+    pub default_tool_choice: Option<LanguageModelToolChoice>,
+    pub model_aliases: Vec<ModelAlias>,
+    pub preset: Option<String>,
+    pub request_metadata: HashMap<String, String>,
+    pub min_reserved_output_tokens: u32,
+    pub preflight_context_check: bool,
+    pub stream_transcript: bool,
+    pub stream_transcript_path: Option<PathBuf>,
+    pub stream_transcript_include_prompt: bool,
+}
+/// Limits on how long a completion may take.
+#[derive(Clone, Copy, Debug, Default)]
+pub(super) struct CompletionTimeouts {
+    /// The longest gap allowed between streamed events.
+    pub(super) inter_token: Option<Duration>,
+    /// The longest the whole request may take, from sending it to the last event.
+    pub(super) total: Option<Duration>,
+}
+impl OpenRouterSettings {
+    /// The key the API key is stored under in the credentials provider.
+    pub(super) fn credentials_key(&self) -> &str {
+        self.credentials_scope.as_deref().unwrap_or(&self.api_url)
+    }
+
+    pub(super) fn timeouts(&self) -> CompletionTimeouts {
+        CompletionTimeouts {
+            inter_token: self.inter_token_timeout_seconds.map(Duration::from_secs),
+            total: self.request_timeout_seconds.map(Duration::from_secs),
+        }
+    }
+
+    pub(super) fn request_options(&self) -> open_router::RequestOptions {
+        open_router::RequestOptions {
+            keep_alive: self.keep_alive,
+            idempotency_key: self.idempotency_keys,
+            compress_above: self.compress_requests_above_bytes,
+            max_sse_line_len: self
+                .max_sse_line_bytes
+                .unwrap_or(open_router::MAX_SSE_LINE_LEN),
+        }
+    }
+}
+/// The highest price, in USD per million tokens, a provider may charge to serve a request.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct MaxPrice {
+    /// The maximum price per million prompt tokens.
+    pub prompt: Option<f64>,
+    /// The maximum price per million completion tokens.
+    pub completion: Option<f64>,
+}
+impl From<MaxPrice> for open_router::MaxPrice {
+    fn from(max_price: MaxPrice) -> Self {
+        open_router::MaxPrice {
+            prompt: max_price.prompt,
+            completion: max_price.completion,
+        }
+    }
+}
+
+/// Controls how reasoning models are asked to reason.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ReasoningMode {
+    /// Ask the model not to reason.
+    Disabled,
+    /// Reason and return the reasoning tokens so they are shown alongside the response.
+    #[default]
+    Returned,
+    /// Reason without returning the reasoning tokens.
+    Excluded,
+}
+/// What to do when OpenRouter rate-limits a request before the model has responded.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum RateLimitBehavior {
+    /// Retry the same model after a short delay.
+    #[default]
+    Retry,
+    /// Send the request to the next of the configured `fallback_models` instead.
+    FailoverToFallback,
+    /// Fail right away.
+    Error,
+}
+/// How much of a model id, such as `openai/gpt-4o`, to show in logs and telemetry.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ModelIdFormat {
+    /// The whole id, e.g. `openai/gpt-4o`.
+    #[default]
+    Full,
+    /// Only the provider prefix, e.g. `openai`.
+    Provider,
+    /// Only the model name, e.g. `gpt-4o`.
+    Model,
+}
+impl ModelIdFormat {
+    pub fn format(self, id: &str) -> &str {
+        let Some((provider, model)) = id.split_once('/') else {
+            return id;
+        };
+        match self {
+            ModelIdFormat::Full => id,
+            ModelIdFormat::Provider => provider,
+            ModelIdFormat::Model => model,
+        }
+    }
+}
+impl From<ReasoningMode> for open_router::Reasoning {
+    fn from(mode: ReasoningMode) -> Self {
+        match mode {
+            ReasoningMode::Disabled => open_router::Reasoning {
+                enabled: Some(false),
+                exclude: false,
+            },
+            ReasoningMode::Returned => open_router::Reasoning {
+                enabled: None,
+                exclude: false,
+            },
+            ReasoningMode::Excluded => open_router::Reasoning {
+                enabled: None,
+                exclude: true,
+            },
+        }
+    }
+}
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct AvailableModel {
+    pub name: String,
+    pub display_name: Option<String>,
+    /// The model's context window. Entries without one, such as those written by older versions
+    /// of Zed, are migrated to a default of 4096 tokens.
+    #[serde(default)]
+    pub max_tokens: usize,
+    pub max_output_tokens: Option<u32>,
+    pub max_completion_tokens: Option<u32>,
+    /// A smaller context window to use instead of `max_tokens`, for a safety margin or a gateway
+    /// that accepts less than OpenRouter advertises. It can only lower the limit: values above
+    /// `max_tokens` are ignored.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub context_length_override: Option<usize>,
+    /// Upstream providers to pin this model to, by OpenRouter slug, such as `["together"]`.
+    /// Requests fail instead of being routed elsewhere when none of them is available.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub only: Vec<String>,
+    /// Sampling parameters sent whenever this model is used, unless the request sets its own.
+    #[serde(default, skip_serializing_if = "ModelParameters::is_empty")]
+    pub default_params: ModelParameters,
+    /// The role system instructions are sent under. Defaults to `developer` for models known to
+    /// expect it, such as OpenAI's reasoning models, and `system` otherwise.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub system_role: Option<SystemRole>,
+    /// Whether requests to this model must include `max_tokens`, which is then sent even when
+    /// `omit_max_tokens` is enabled. Defaults to true for models whose upstreams reject requests
+    /// without it, such as Anthropic's.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub requires_max_tokens: Option<bool>,
+    /// Cleanup applied to this model's replies, replacing the global `post_process` setting.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub post_process: Option<PostProcess>,
+}
+/// The role a model expects system instructions under.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum SystemRole {
+    System,
+    Developer,
+}
+/// Sampling parameters tuned for a particular model.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct ModelParameters {
+    /// Between 0 and 2. Lower values make responses more focused and deterministic.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f32>,
+    /// Between 0 and 1. Limits sampling to the most likely tokens whose probabilities add up to
+    /// this value.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f32>,
+}
+impl ModelParameters {
+    pub fn is_empty(&self) -> bool {
+        self.temperature.is_none() && self.top_p.is_none()
+    }
+
+    /// Parses the `temperature=0.7, top_p=0.9` form shown in the configuration view. Either
+    /// parameter may be left out.
+    pub(super) fn parse(text: &str) -> Result<Self, SharedString> {
+        let mut params = Self::default();
+        for assignment in text.split([',', ' ']).filter(|part| !part.is_empty()) {
+            let Some((name, value)) = assignment.split_once('=') else {
+                return Err(format!("Expected name=value, found \"{assignment}\".").into());
+            };
+            let value = value
+                .parse::<f32>()
+                .map_err(|_| SharedString::from(format!("\"{value}\" is not a number.")))?;
+            match name {
+                "temperature" if (0.0..=2.0).contains(&value) => params.temperature = Some(value),
+                "temperature" => return Err("The temperature must be between 0 and 2.".into()),
+                "top_p" if (0.0..=1.0).contains(&value) => params.top_p = Some(value),
+                "top_p" => return Err("top_p must be between 0 and 1.".into()),
+                _ => {
+                    return Err(
+                        format!("Unknown parameter \"{name}\". Use temperature or top_p.").into(),
+                    );
+                }
+            }
+        }
+        Ok(params)
+    }
+}
+impl std::fmt::Display for ModelParameters {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let params = [("temperature", self.temperature), ("top_p", self.top_p)]
+            .into_iter()
+            .filter_map(|(name, value)| Some(format!("{name}={}", value?)))
+            .collect::<Vec<_>>();
+        write!(f, "{}", params.join(", "))
+    }
+}
+/// Cleanup applied to a reply's text once it's complete.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct PostProcess {
+    /// Whether to unwrap replies that consist of a single markdown code fence, keeping only the
+    /// code inside it.
+    #[serde(default)]
+    pub strip_code_fences: bool,
+    /// A regular expression whose matches are removed from replies, e.g. to drop an echoed
+    /// prompt. Applied before `strip_code_fences`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub remove_pattern: Option<String>,
+}
+
+/// Fills in fields that `available_models` entries written by older versions of Zed lack,
+/// leaving everything else as the user set it. Returns whether any entry changed.
+pub(crate) fn upgrade_available_models(models: &mut [AvailableModel]) -> bool {
+    let mut upgraded = false;
+    for model in models {
+        if model.max_tokens == 0 {
+            model.max_tokens = open_router::DEFAULT_CONTEXT_LENGTH;
+            upgraded = true;
+        }
+    }
+    upgraded
+}
+impl AvailableModel {
+    /// Lowers `max_output_tokens` and `max_completion_tokens` to `max_tokens` where they exceed
+    /// it, since OpenRouter rejects such requests. Each adjustment is logged.
+    pub(crate) fn clamp_output_limits(&mut self) {
+        let max_tokens = u32::try_from(self.max_tokens).unwrap_or(u32::MAX);
+        for (setting, limit) in [
+            ("max_output_tokens", &mut self.max_output_tokens),
+            ("max_completion_tokens", &mut self.max_completion_tokens),
+        ] {
+            if let Some(tokens) = limit.filter(|tokens| *tokens > max_tokens) {
+                log::warn!(
+                    "OpenRouter model \"{}\" has {setting} of {tokens}, more than its max_tokens \
+                    of {max_tokens}. Using {max_tokens} instead.",
+                    self.name
+                );
+                *limit = Some(max_tokens);
+            }
+        }
+    }
+
+    /// Whether requests must include `max_tokens`, honoring `requires_max_tokens`.
+    pub fn requires_max_tokens(&self) -> bool {
+        self.requires_max_tokens.unwrap_or_else(|| {
+            MAX_TOKENS_REQUIRED_MODEL_PREFIXES
+                .iter()
+                .any(|prefix| self.name.starts_with(prefix))
+        })
+    }
+
+    /// The role to send system instructions under, honoring `system_role`.
+    pub fn system_role(&self) -> SystemRole {
+        self.system_role.unwrap_or_else(|| {
+            if DEVELOPER_ROLE_MODEL_PREFIXES
+                .iter()
+                .any(|prefix| self.name.starts_with(prefix))
+            {
+                SystemRole::Developer
+            } else {
+                SystemRole::System
+            }
+        })
+    }
+
+    /// The context window to budget requests against, honoring `context_length_override`.
+    pub fn context_length(&self) -> usize {
+        self.context_length_override
+            .map_or(self.max_tokens, |context_length| {
+                context_length.min(self.max_tokens)
+            })
+    }
+}
+/// A named model selection that OpenRouter resolves to the first available model of a chain.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct ModelAlias {
+    /// The id the alias is selected by, such as "best-coding".
+    pub name: String,
+    /// The name shown in model pickers. Defaults to `name`.
+    pub display_name: Option<String>,
+    /// Model ids to try in order. The first one determines the context window and capabilities.
+    pub models: Vec<String>,
+}
+impl From<&open_router::Model> for AvailableModel {
+    fn from(model: &open_router::Model) -> Self {
+        Self {
+            name: model.id().to_string(),
+            display_name: Some(model.display_name().to_string()),
+            max_tokens: model.max_token_count(),
+            max_output_tokens: model.max_output_tokens(),
+            max_completion_tokens: None,
+            context_length_override: None,
+            only: Vec::new(),
+            default_params: ModelParameters::default(),
+            system_role: None,
+            requires_max_tokens: None,
+            post_process: None,
+        }
+    }
+}
@@ -0,0 +1,149 @@
+use anyhow::{Result, anyhow};
+use chrono::Local;
+use collections::BTreeMap;
+use fs::Fs;
+use gpui::BackgroundExecutor;
+use parking_lot::Mutex;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use util::ResultExt;
+
+/// The size the stream transcript may grow to before it's rotated within a session.
+const MAX_TRANSCRIPT_BYTES: usize = 4 * 1024 * 1024;
+
+pub(super) fn default_transcript_path() -> PathBuf {
+    paths::logs_dir().join("openrouter_transcript.log")
+}
+/// Where the stream transcript is written, for reviewing model output offline.
+#[derive(Clone)]
+pub(super) struct StreamTranscript {
+    pub(super) fs: Arc<dyn Fs>,
+    pub(super) path: PathBuf,
+    pub(super) include_prompt: bool,
+    pub(super) started: Arc<futures::lock::Mutex<bool>>,
+}
+impl StreamTranscript {
+    /// Appends `entry` to the transcript. The first entry of a session starts a new file, as does
+    /// one that would take the file past [`MAX_TRANSCRIPT_BYTES`]; the previous file is kept with
+    /// an `.old` suffix.
+    pub(super) async fn append(&self, entry: String) -> Result<()> {
+        let mut started = self.started.lock().await;
+        if let Some(dir) = self.path.parent() {
+            self.fs.create_dir(dir).await?;
+        }
+        let len = self
+            .fs
+            .metadata(&self.path)
+            .await?
+            .map_or(0, |metadata| metadata.len as usize);
+        let rotate = len > 0 && (!*started || len + entry.len() > MAX_TRANSCRIPT_BYTES);
+        if rotate {
+            let mut old_path = self.path.clone().into_os_string();
+            old_path.push(".old");
+            self.fs
+                .rename(
+                    &self.path,
+                    Path::new(&old_path),
+                    fs::RenameOptions {
+                        overwrite: true,
+                        ignore_if_exists: false,
+                    },
+                )
+                .await?;
+        }
+        *started = true;
+        self.fs.append(&self.path, entry.as_bytes()).await
+    }
+}
+/// Collects one completion's streamed output, appending it to the transcript once the stream
+/// ends or is dropped.
+pub(super) struct TranscriptWriter {
+    transcript: StreamTranscript,
+    executor: BackgroundExecutor,
+    header: String,
+    reasoning: String,
+    content: String,
+    tool_calls: BTreeMap<usize, (String, String)>,
+    finish_reason: Option<String>,
+    error: Option<String>,
+}
+impl TranscriptWriter {
+    pub(super) fn new(
+        transcript: StreamTranscript,
+        request: &open_router::Request,
+        executor: BackgroundExecutor,
+    ) -> Self {
+        let mut header = format!("=== {} {} ===\n", Local::now().to_rfc3339(), request.model);
+        if transcript.include_prompt {
+            let prompt = serde_json::to_string_pretty(&request.messages).unwrap_or_default();
+            header.push_str(&format!("--- prompt ---\n{prompt}\n"));
+        }
+        Self {
+            transcript,
+            executor,
+            header,
+            reasoning: String::new(),
+            content: String::new(),
+            tool_calls: BTreeMap::default(),
+            finish_reason: None,
+            error: None,
+        }
+    }
+
+    pub(super) fn push_event(&mut self, event: &open_router::ResponseStreamEvent) {
+        for choice in &event.choices {
+            if let Some(reasoning) = &choice.delta.reasoning {
+                self.reasoning.push_str(reasoning);
+            }
+            if let Some(content) = &choice.delta.content {
+                self.content.push_str(content);
+            }
+            for tool_call in choice.delta.tool_calls.iter().flatten() {
+                let (name, arguments) = self.tool_calls.entry(tool_call.index).or_default();
+                if let Some(function) = &tool_call.function {
+                    if let Some(fragment) = &function.name {
+                        name.push_str(fragment);
+                    }
+                    if let Some(fragment) = &function.arguments {
+                        arguments.push_str(fragment);
+                    }
+                }
+            }
+            if let Some(finish_reason) = &choice.finish_reason {
+                self.finish_reason = Some(finish_reason.clone());
+            }
+        }
+    }
+
+    pub(super) fn push_error(&mut self, error: &anyhow::Error) {
+        self.error = Some(format!("{error:#}"));
+    }
+
+    fn render(&self) -> String {
+        let mut entry = self.header.clone();
+        if !self.reasoning.is_empty() {
+            entry.push_str(&format!("--- reasoning ---\n{}\n", self.reasoning));
+        }
+        entry.push_str(&format!("--- response ---\n{}\n", self.content));
+        for (name, arguments) in self.tool_calls.values() {
+            entry.push_str(&format!("--- tool call: {name} ---\n{arguments}\n"));
+        }
+        if let Some(error) = &self.error {
+            entry.push_str(&format!("--- error ---\n{error}\n"));
+        } else {
+            let finish_reason = self.finish_reason.as_deref().unwrap_or("cancelled");
+            entry.push_str(&format!("--- finished: {finish_reason} ---\n"));
+        }
+        entry.push('\n');
+        entry
+    }
+}
+impl Drop for TranscriptWriter {
+    fn drop(&mut self) {
+        let entry = self.render();
+        let transcript = self.transcript.clone();
+        self.executor
+            .spawn(async move { transcript.append(entry).await.log_err() })
+            .detach();
+    }
+}
@@ -1,12 +1,13 @@
-use std::sync::Arc;
+use std::{path::PathBuf, sync::Arc};
 
 use anyhow::Result;
+use collections::HashMap;
 use gpui::App;
-use language_model::LanguageModelCacheConfiguration;
+use language_model::{GlobalRateLimiter, LanguageModelCacheConfiguration, LanguageModelToolChoice};
 use project::Fs;
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
-use settings::{Settings, SettingsSources, update_settings_file};
+use settings::{Settings, SettingsSources, SettingsStore, update_settings_file};
 
 use crate::provider::{
     self,
@@ -27,6 +28,18 @@ use crate::provider::{
 pub fn init(fs: Arc<dyn Fs>, cx: &mut App) {
     AllLanguageModelSettings::register(cx);
 
+    GlobalRateLimiter::set_limit(
+        AllLanguageModelSettings::get_global(cx).max_concurrent_requests,
+        cx,
+    );
+    cx.observe_global::<SettingsStore>(|cx| {
+        GlobalRateLimiter::set_limit(
+            AllLanguageModelSettings::get_global(cx).max_concurrent_requests,
+            cx,
+        );
+    })
+    .detach();
+
     if AllLanguageModelSettings::get_global(cx)
         .openai
         .needs_setting_migration
@@ -41,6 +54,21 @@ pub fn init(fs: Arc<dyn Fs>, cx: &mut App) {
         });
     }
 
+    if AllLanguageModelSettings::get_global(cx)
+        .openrouter
+        .needs_setting_migration
+    {
+        update_settings_file::<AllLanguageModelSettings>(fs.clone(), cx, move |setting, _| {
+            if let Some(models) = setting
+                .openrouter
+                .as_mut()
+                .and_then(|settings| settings.available_models.as_mut())
+            {
+                provider::openrouter::upgrade_available_models(models);
+            }
+        });
+    }
+
     if AllLanguageModelSettings::get_global(cx)
         .anthropic
         .needs_setting_migration
@@ -69,10 +97,16 @@ pub struct AllLanguageModelSettings {
     pub deepseek: DeepSeekSettings,
     pub mistral: MistralSettings,
     pub openrouter: OpenRouterSettings,
+    pub max_concurrent_requests: Option<usize>,
 }
 
 #[derive(Default, Clone, Debug, Serialize, Deserialize, PartialEq, JsonSchema)]
 pub struct AllLanguageModelSettingsContent {
+    /// The most completion requests to run at once across every provider that honors it, in
+    /// addition to each provider's own limit. Unlimited when unset or 0.
+    ///
+    /// Default: none
+    pub max_concurrent_requests: Option<usize>,
     pub anthropic: Option<AnthropicSettingsContent>,
     pub bedrock: Option<AmazonBedrockSettingsContent>,
     pub ollama: Option<OllamaSettingsContent>,
@@ -200,7 +234,241 @@ pub struct MistralSettingsContent {
 #[derive(Default, Clone, Debug, Serialize, Deserialize, PartialEq, JsonSchema)]
 pub struct OpenRouterSettingsContent {
     pub api_url: Option<String>,
+    /// The key the API key is stored under in the system keychain, to keep it apart from other
+    /// tools' entries for the same URL. Changing it means entering the API key again.
+    ///
+    /// Default: the value of `api_url`
+    pub credentials_scope: Option<String>,
     pub available_models: Option<Vec<provider::openrouter::AvailableModel>>,
+    /// An identifier for the end user (e.g. an email address), sent to OpenRouter as a hash for
+    /// abuse tracking. Omitted from requests when unset.
+    pub user_id: Option<String>,
+    /// Whether reasoning models should reason, and whether their reasoning is returned.
+    ///
+    /// Default: returned
+    pub reasoning: Option<provider::openrouter::ReasoningMode>,
+    /// What to do when OpenRouter rate-limits a request before the model has responded:
+    /// `retry` the same model, `failover_to_fallback` to try the next of `fallback_models`, or
+    /// `error` to fail right away.
+    ///
+    /// Default: retry
+    pub on_rate_limit: Option<provider::openrouter::RateLimitBehavior>,
+    /// How model ids appear in request log lines and telemetry: the `full` id, such as
+    /// `openai/gpt-4o`, only its `provider` prefix, or only its `model` name. Requests always
+    /// use the full id.
+    ///
+    /// Default: full
+    pub log_model_ids: Option<provider::openrouter::ModelIdFormat>,
+    /// Models to fail over to, in order, when `on_rate_limit` is `failover_to_fallback`.
+    ///
+    /// Default: []
+    pub fallback_models: Option<Vec<String>>,
+    /// The output token limit used for models that don't configure `max_output_tokens`.
+    /// Clamped to the model's own limit.
+    ///
+    /// Default: 8192
+    pub default_max_output_tokens: Option<u32>,
+    /// Whether to leave `max_tokens` out of requests, so models may reply up to their own limit.
+    /// Models that require it, such as Anthropic's or those with `requires_max_tokens` set, are
+    /// still sent their `max_output_tokens`.
+    ///
+    /// Default: false
+    pub omit_max_tokens: Option<bool>,
+    /// Gzip request bodies larger than this many bytes, which can speed up sending large
+    /// prompts over a slow connection. Requests are resent uncompressed if the server doesn't
+    /// accept compressed bodies. Leave unset to never compress.
+    ///
+    /// Default: none
+    pub compress_requests_above_bytes: Option<usize>,
+    /// The longest line, in bytes, accepted in a streamed response before the response fails.
+    /// Raise it for models that send very large tool-call arguments or reasoning in one event.
+    ///
+    /// Default: 67108864 (64 MiB)
+    pub max_sse_line_bytes: Option<usize>,
+    /// Only route requests to providers charging at most this much, in USD per million tokens.
+    /// Requests fail when no provider qualifies.
+    pub max_price: Option<provider::openrouter::MaxPrice>,
+    /// How often, in hours, to refresh the model catalog in the background.
+    /// Background refresh is disabled when unset.
+    pub catalog_refresh_interval_hours: Option<u64>,
+    /// Whether to check the API key with OpenRouter when authenticating. A revoked key is then
+    /// deleted and the configuration view asks for a new one, rather than every request failing.
+    /// This costs a request at startup.
+    ///
+    /// Default: false
+    pub validate_api_key: Option<bool>,
+    /// Log each request instead of sending it, and reply with a fixed response. Intended for
+    /// debugging prompts without spending credits.
+    ///
+    /// Default: false
+    pub dry_run: Option<bool>,
+    /// Whether to reconnect when a streaming response is cut off, asking the model to continue
+    /// from the text received so far. Only models that support assistant prefill are resumed,
+    /// and a resumed response may not match what the original would have said.
+    ///
+    /// Default: false
+    pub resume_dropped_streams: Option<bool>,
+    /// Whether to stop reading a response once the model moves on from its tool calls, so it
+    /// can't ramble after issuing them. Text that arrives alongside or after a tool call is
+    /// dropped, and the response ends with the tool calls received so far.
+    ///
+    /// Parallel tool calls are kept as long as the model streams them back to back, which is
+    /// how they're usually sent; any calls that would follow text are lost. Leave this off for
+    /// models that explain their tool calls in the same response.
+    ///
+    /// Default: false
+    pub stop_after_tool_calls: Option<bool>,
+    /// Whether to show what the last completion request was sent with in the configuration
+    /// view: the model, sampling parameters, output limit and routing. Prompts and the API key
+    /// are never shown.
+    ///
+    /// Default: false
+    pub show_request_params: Option<bool>,
+    /// Whether to fetch the model catalog automatically. When disabled, the catalog cached from
+    /// the last fetch is used until it's refreshed from the configuration view.
+    ///
+    /// Default: true
+    pub auto_refresh_catalog: Option<bool>,
+    /// Whether to keep the last-used model's catalog entry on disk and load it at startup, so
+    /// the model can be used before the catalog finishes loading. The entry is checked against
+    /// the catalog once it loads.
+    ///
+    /// Default: true
+    pub cache_last_used_model: Option<bool>,
+    /// Whether to notify when a background catalog refresh finds models that were added, removed
+    /// or repriced since the previous fetch.
+    ///
+    /// Default: false
+    pub notify_catalog_changes: Option<bool>,
+    /// Whether models that a catalog refresh finds newly added are enabled automatically, by
+    /// adding them to `available_models`. When disabled, new models stay off until enabled.
+    ///
+    /// Default: false
+    pub auto_enable_new_models: Option<bool>,
+    /// Whether to notify when the account balance drops below `low_credit_alert_threshold`.
+    ///
+    /// Default: false
+    pub low_credit_alerts: Option<bool>,
+    /// The balance, in USD, below which a low-credit alert is shown and low-credit mode
+    /// takes effect.
+    pub low_credit_alert_threshold: Option<f64>,
+    /// Whether to offer only free models while the balance is below
+    /// `low_credit_alert_threshold`. Paid models return once the balance recovers.
+    ///
+    /// Default: false
+    pub low_credit_mode: Option<bool>,
+    /// Whether to stream responses as they're generated. When disabled, each response is
+    /// requested in full and delivered at once, which can help behind buffering proxies.
+    ///
+    /// Default: true
+    pub streaming: Option<bool>,
+    /// Whether to keep connections to OpenRouter open between requests, so that frequent small
+    /// requests skip the TLS handshake. Every request shares one pool of connections per API URL.
+    /// Turning this off only affects HTTP/1.1 connections, since HTTP/2 connections are always
+    /// shared by concurrent requests.
+    ///
+    /// Default: true
+    pub keep_alive: Option<bool>,
+    /// Whether to drop optional message fields, such as tool names on tool results, for models
+    /// whose upstreams are known to reject them.
+    ///
+    /// Default: true
+    pub strip_unsupported_message_fields: Option<bool>,
+    /// Whether to send an `Idempotency-Key` header derived from each request's contents, so a
+    /// caching proxy in front of OpenRouter can serve repeated identical requests.
+    ///
+    /// Default: false
+    pub idempotency_keys: Option<bool>,
+    /// How many seconds a streaming response may go without sending anything before it's treated
+    /// as stalled and ended with an error. Leave unset to wait indefinitely.
+    ///
+    /// Default: none
+    pub inter_token_timeout_seconds: Option<u64>,
+    /// How many seconds a whole request may take, from sending it to receiving the last token.
+    /// This should be longer than `inter_token_timeout_seconds`. Leave unset for no limit.
+    ///
+    /// Default: none
+    pub request_timeout_seconds: Option<u64>,
+    /// An instruction prepended to the system message of every OpenRouter request, such as
+    /// "Be concise.". Models without a system role receive it at the start of the first user
+    /// message instead.
+    ///
+    /// Default: none
+    pub system_prompt: Option<String>,
+    /// How many milliseconds of streamed text to batch into a single event, reducing UI updates
+    /// for very fast models. Leave unset to emit every delta as it arrives.
+    ///
+    /// Default: none
+    pub coalesce_window_ms: Option<u64>,
+    /// Cleanup applied to each reply before its text is emitted, such as removing a markdown
+    /// fence around the whole reply. Models can override it with their own `post_process`.
+    /// Text is held back until the reply ends while this is enabled.
+    ///
+    /// Default: none
+    pub post_process: Option<provider::openrouter::PostProcess>,
+    /// Whether to send progress estimates while a response streams, comparing the tokens
+    /// generated so far to the request's `max_tokens`. The estimate is approximate, and responses
+    /// often finish well before reaching the cap.
+    ///
+    /// Default: false
+    pub report_progress: Option<bool>,
+    /// Whether to screen each new prompt with OpenRouter's moderation endpoint before sending
+    /// it, refusing to generate a response when it's flagged. This adds a round trip to every
+    /// request.
+    ///
+    /// Default: false
+    pub moderate_prompts: Option<bool>,
+    /// Whether models must (`required`), may (`auto`) or must not (`none`) call a tool when a
+    /// request offers tools without choosing. Requests that choose override it.
+    ///
+    /// Default: none, leaving it to the model
+    pub default_tool_choice: Option<LanguageModelToolChoice>,
+    /// Named selections that resolve to the first available model of an ordered chain, shown in
+    /// model pickers alongside the regular models. For example:
+    /// `{ "name": "best-coding", "models": ["anthropic/claude-3.7-sonnet", "openai/gpt-4.1"] }`
+    ///
+    /// Default: []
+    pub model_aliases: Option<Vec<provider::openrouter::ModelAlias>>,
+    /// The slug of a preset created on OpenRouter, so parameters can be managed centrally there.
+    /// OpenRouter applies the preset first; parameters Zed sends, such as the temperature,
+    /// max tokens, reasoning and provider preferences configured here, override the preset's.
+    ///
+    /// Default: none
+    pub preset: Option<String>,
+    /// Tags attached to every request, such as a cost center or project, which OpenRouter passes
+    /// through for your own analytics. They're sent as-is, so don't put anything sensitive here.
+    /// At most 16 tags are sent; tags with keys over 64 characters or values over 512 characters
+    /// are left out.
+    ///
+    /// Default: {}
+    pub request_metadata: Option<HashMap<String, String>>,
+    /// The least room, in tokens, to leave for the reply when deciding how much of the
+    /// conversation fits in a model's context window. The reply's own output limit is reserved
+    /// when it is larger.
+    ///
+    /// Default: 0
+    pub min_reserved_output_tokens: Option<u32>,
+    /// Whether to estimate each prompt's size before sending it, and fail right away when it
+    /// can't fit in the model's context window. Disable to leave the check to the server.
+    ///
+    /// Default: true
+    pub preflight_context_check: Option<bool>,
+    /// Whether to append the raw streamed output of every completion to a transcript file, so
+    /// runs can be reviewed later. The file is started afresh each session, and the previous one
+    /// is kept alongside it with an `.old` suffix.
+    ///
+    /// Default: false
+    pub stream_transcript: Option<bool>,
+    /// Where to write the stream transcript. Defaults to `openrouter_transcript.log` in Zed's
+    /// logs directory.
+    ///
+    /// Default: none
+    pub stream_transcript_path: Option<PathBuf>,
+    /// Whether the stream transcript includes the messages sent with each request. When
+    /// disabled, only the model's output is recorded.
+    ///
+    /// Default: false
+    pub stream_transcript_include_prompt: Option<bool>,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq, JsonSchema)]
@@ -297,6 +565,11 @@ impl settings::Settings for AllLanguageModelSettings {
         let mut settings = AllLanguageModelSettings::default();
 
         for value in sources.defaults_and_customizations() {
+            merge(
+                &mut settings.max_concurrent_requests,
+                value.max_concurrent_requests.map(Some),
+            );
+
             // Anthropic
             let (anthropic, upgraded) = match value.anthropic.clone().map(|s| s.upgrade()) {
                 Some((content, upgraded)) => (Some(content), upgraded),
@@ -426,9 +699,236 @@ impl settings::Settings for AllLanguageModelSettings {
                 openrouter.as_ref().and_then(|s| s.api_url.clone()),
             );
             merge(
-                &mut settings.openrouter.available_models,
-                openrouter.as_ref().and_then(|s| s.available_models.clone()),
+                &mut settings.openrouter.credentials_scope,
+                openrouter
+                    .as_ref()
+                    .and_then(|s| s.credentials_scope.clone())
+                    .map(Some),
+            );
+            let mut available_models = openrouter.as_ref().and_then(|s| s.available_models.clone());
+            if let Some(models) = available_models.as_mut() {
+                if provider::openrouter::upgrade_available_models(models) {
+                    settings.openrouter.needs_setting_migration = true;
+                }
+            }
+            merge(&mut settings.openrouter.available_models, available_models);
+            merge(
+                &mut settings.openrouter.user_id,
+                openrouter
+                    .as_ref()
+                    .and_then(|s| s.user_id.clone())
+                    .map(Some),
+            );
+            merge(
+                &mut settings.openrouter.reasoning,
+                openrouter.as_ref().and_then(|s| s.reasoning),
+            );
+            merge(
+                &mut settings.openrouter.on_rate_limit,
+                openrouter.as_ref().and_then(|s| s.on_rate_limit),
+            );
+            merge(
+                &mut settings.openrouter.log_model_ids,
+                openrouter.as_ref().and_then(|s| s.log_model_ids),
+            );
+            merge(
+                &mut settings.openrouter.fallback_models,
+                openrouter.as_ref().and_then(|s| s.fallback_models.clone()),
+            );
+            merge(
+                &mut settings.openrouter.default_max_output_tokens,
+                openrouter
+                    .as_ref()
+                    .and_then(|s| s.default_max_output_tokens),
+            );
+            merge(
+                &mut settings.openrouter.compress_requests_above_bytes,
+                openrouter
+                    .as_ref()
+                    .and_then(|s| s.compress_requests_above_bytes)
+                    .map(Some),
+            );
+            merge(
+                &mut settings.openrouter.max_sse_line_bytes,
+                openrouter
+                    .as_ref()
+                    .and_then(|s| s.max_sse_line_bytes)
+                    .map(Some),
+            );
+            merge(
+                &mut settings.openrouter.omit_max_tokens,
+                openrouter.as_ref().and_then(|s| s.omit_max_tokens),
+            );
+            merge(
+                &mut settings.openrouter.max_price,
+                openrouter.as_ref().and_then(|s| s.max_price).map(Some),
+            );
+            merge(
+                &mut settings.openrouter.catalog_refresh_interval_hours,
+                openrouter
+                    .as_ref()
+                    .and_then(|s| s.catalog_refresh_interval_hours)
+                    .map(Some),
+            );
+            merge(
+                &mut settings.openrouter.validate_api_key,
+                openrouter.as_ref().and_then(|s| s.validate_api_key),
+            );
+            merge(
+                &mut settings.openrouter.dry_run,
+                openrouter.as_ref().and_then(|s| s.dry_run),
+            );
+            merge(
+                &mut settings.openrouter.resume_dropped_streams,
+                openrouter.as_ref().and_then(|s| s.resume_dropped_streams),
+            );
+            merge(
+                &mut settings.openrouter.stop_after_tool_calls,
+                openrouter.as_ref().and_then(|s| s.stop_after_tool_calls),
+            );
+            merge(
+                &mut settings.openrouter.show_request_params,
+                openrouter.as_ref().and_then(|s| s.show_request_params),
+            );
+            merge(
+                &mut settings.openrouter.auto_refresh_catalog,
+                openrouter.as_ref().and_then(|s| s.auto_refresh_catalog),
+            );
+            merge(
+                &mut settings.openrouter.cache_last_used_model,
+                openrouter.as_ref().and_then(|s| s.cache_last_used_model),
+            );
+            merge(
+                &mut settings.openrouter.notify_catalog_changes,
+                openrouter.as_ref().and_then(|s| s.notify_catalog_changes),
+            );
+            merge(
+                &mut settings.openrouter.auto_enable_new_models,
+                openrouter.as_ref().and_then(|s| s.auto_enable_new_models),
             );
+            merge(
+                &mut settings.openrouter.low_credit_alerts,
+                openrouter.as_ref().and_then(|s| s.low_credit_alerts),
+            );
+            merge(
+                &mut settings.openrouter.low_credit_alert_threshold,
+                openrouter
+                    .as_ref()
+                    .and_then(|s| s.low_credit_alert_threshold)
+                    .map(Some),
+            );
+            merge(
+                &mut settings.openrouter.low_credit_mode,
+                openrouter.as_ref().and_then(|s| s.low_credit_mode),
+            );
+            merge(
+                &mut settings.openrouter.streaming,
+                openrouter.as_ref().and_then(|s| s.streaming),
+            );
+            merge(
+                &mut settings.openrouter.keep_alive,
+                openrouter.as_ref().and_then(|s| s.keep_alive),
+            );
+            merge(
+                &mut settings.openrouter.strip_unsupported_message_fields,
+                openrouter
+                    .as_ref()
+                    .and_then(|s| s.strip_unsupported_message_fields),
+            );
+            merge(
+                &mut settings.openrouter.idempotency_keys,
+                openrouter.as_ref().and_then(|s| s.idempotency_keys),
+            );
+            merge(
+                &mut settings.openrouter.inter_token_timeout_seconds,
+                openrouter
+                    .as_ref()
+                    .and_then(|s| s.inter_token_timeout_seconds)
+                    .map(Some),
+            );
+            merge(
+                &mut settings.openrouter.request_timeout_seconds,
+                openrouter
+                    .as_ref()
+                    .and_then(|s| s.request_timeout_seconds)
+                    .map(Some),
+            );
+            merge(
+                &mut settings.openrouter.system_prompt,
+                openrouter
+                    .as_ref()
+                    .and_then(|s| s.system_prompt.clone())
+                    .map(Some),
+            );
+            merge(
+                &mut settings.openrouter.preset,
+                openrouter.as_ref().and_then(|s| s.preset.clone()).map(Some),
+            );
+            merge(
+                &mut settings.openrouter.request_metadata,
+                openrouter.as_ref().and_then(|s| s.request_metadata.clone()),
+            );
+            merge(
+                &mut settings.openrouter.min_reserved_output_tokens,
+                openrouter
+                    .as_ref()
+                    .and_then(|s| s.min_reserved_output_tokens),
+            );
+            merge(
+                &mut settings.openrouter.preflight_context_check,
+                openrouter.as_ref().and_then(|s| s.preflight_context_check),
+            );
+            merge(
+                &mut settings.openrouter.stream_transcript,
+                openrouter.as_ref().and_then(|s| s.stream_transcript),
+            );
+            merge(
+                &mut settings.openrouter.stream_transcript_path,
+                openrouter
+                    .as_ref()
+                    .and_then(|s| s.stream_transcript_path.clone())
+                    .map(Some),
+            );
+            merge(
+                &mut settings.openrouter.stream_transcript_include_prompt,
+                openrouter
+                    .as_ref()
+                    .and_then(|s| s.stream_transcript_include_prompt),
+            );
+            merge(
+                &mut settings.openrouter.model_aliases,
+                openrouter.as_ref().and_then(|s| s.model_aliases.clone()),
+            );
+            merge(
+                &mut settings.openrouter.coalesce_window_ms,
+                openrouter
+                    .as_ref()
+                    .and_then(|s| s.coalesce_window_ms)
+                    .map(Some),
+            );
+            merge(
+                &mut settings.openrouter.post_process,
+                openrouter.as_ref().and_then(|s| s.post_process.clone()),
+            );
+            merge(
+                &mut settings.openrouter.report_progress,
+                openrouter.as_ref().and_then(|s| s.report_progress),
+            );
+            merge(
+                &mut settings.openrouter.moderate_prompts,
+                openrouter.as_ref().and_then(|s| s.moderate_prompts),
+            );
+            merge(
+                &mut settings.openrouter.default_tool_choice,
+                openrouter
+                    .as_ref()
+                    .and_then(|s| s.default_tool_choice)
+                    .map(Some),
+            );
+        }
+
+        for model in &mut settings.openrouter.available_models {
+            model.clamp_output_limits();
         }
 
         Ok(settings)
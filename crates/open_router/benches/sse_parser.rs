@@ -0,0 +1,100 @@
+use criterion::{BenchmarkId, Criterion, Throughput, criterion_group, criterion_main};
+use futures::{StreamExt, TryStreamExt};
+use open_router::{MAX_SSE_LINE_LEN, parse_sse_stream};
+
+/// Wraps a single SSE event payload in its `data:` framing.
+fn event(json: String) -> String {
+    format!("data: {json}\n\n")
+}
+
+fn text_payload(events: usize) -> String {
+    let mut payload = String::new();
+    for ix in 0..events {
+        payload.push_str(&event(format!(
+            r#"{{"id":"gen-1","created":1,"model":"openai/gpt-4o","choices":[{{"index":0,"delta":{{"content":"token {ix} of the reply, "}},"finish_reason":null}}]}}"#
+        )));
+    }
+    payload.push_str("data: [DONE]\n\n");
+    payload
+}
+
+fn reasoning_payload(events: usize) -> String {
+    let mut payload = String::new();
+    for ix in 0..events {
+        payload.push_str(&event(format!(
+            r#"{{"id":"gen-1","created":1,"model":"anthropic/claude-sonnet-4","choices":[{{"index":0,"delta":{{"reasoning":"step {ix}: consider the next edit. "}},"finish_reason":null}}]}}"#
+        )));
+    }
+    payload.push_str("data: [DONE]\n\n");
+    payload
+}
+
+fn tool_call_payload(events: usize) -> String {
+    let mut payload = event(
+        r#"{"id":"gen-1","created":1,"model":"openai/gpt-4o","choices":[{"index":0,"delta":{"tool_calls":[{"index":0,"id":"call_1","function":{"name":"edit_file","arguments":""}}]},"finish_reason":null}]}"#
+            .into(),
+    );
+    for ix in 0..events {
+        payload.push_str(&event(format!(
+            r#"{{"id":"gen-1","created":1,"model":"openai/gpt-4o","choices":[{{"index":0,"delta":{{"tool_calls":[{{"index":0,"function":{{"arguments":"{{\"line_{ix}\": \"fn main() {{}}\", "}}}}]}},"finish_reason":null}}]}}"#
+        )));
+    }
+    payload.push_str("data: [DONE]\n\n");
+    payload
+}
+
+/// A few events carrying a large chunk each, like a whole file returned in one delta.
+fn large_chunk_payload(events: usize, chunk_len: usize) -> String {
+    let content = "x".repeat(chunk_len);
+    let mut payload = String::new();
+    for _ in 0..events {
+        payload.push_str(&event(format!(
+            r#"{{"id":"gen-1","created":1,"model":"openai/gpt-4o","choices":[{{"index":0,"delta":{{"content":"{content}"}},"finish_reason":null}}]}}"#
+        )));
+    }
+    payload.push_str("data: [DONE]\n\n");
+    payload
+}
+
+/// Parses `payload` as if it arrived from the network in pieces of `chunk_len` bytes, so events
+/// span reads the way they do over a real connection.
+fn parse(payload: &str, chunk_len: usize) -> usize {
+    let chunks = payload
+        .as_bytes()
+        .chunks(chunk_len)
+        .map(|chunk| Ok::<_, std::io::Error>(chunk.to_vec()))
+        .collect::<Vec<_>>();
+    let reader = futures::stream::iter(chunks).into_async_read();
+    futures::executor::block_on(
+        parse_sse_stream(reader, MAX_SSE_LINE_LEN)
+            .map(|event| event.expect("fixture events should parse"))
+            .count(),
+    )
+}
+
+fn sse_parser_benchmarks(c: &mut Criterion) {
+    let payloads = [
+        ("text", text_payload(2000)),
+        ("reasoning", reasoning_payload(2000)),
+        ("tool_call", tool_call_payload(2000)),
+        ("large_chunks", large_chunk_payload(8, 256 * 1024)),
+    ];
+
+    let mut group = c.benchmark_group("parse_sse_stream");
+    for (name, payload) in &payloads {
+        group.throughput(Throughput::Bytes(payload.len() as u64));
+        // Small reads exercise buffering lines split across chunks; large ones the common case
+        // of several whole events per read.
+        for chunk_len in [16, 1024, 64 * 1024] {
+            group.bench_with_input(
+                BenchmarkId::new(*name, chunk_len),
+                &chunk_len,
+                |b, &chunk_len| b.iter(|| parse(payload, chunk_len)),
+            );
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, sse_parser_benchmarks);
+criterion_main!(benches);
@@ -0,0 +1,1912 @@
+use anyhow::{Context as _, Result, anyhow};
+use async_compression::futures::bufread::GzipEncoder;
+use futures::{
+    AsyncBufRead, AsyncBufReadExt, AsyncReadExt, StreamExt, io::BufReader, stream::BoxStream,
+};
+use http_client::{AsyncBody, HttpClient, Method, Request as HttpRequest, StatusCode};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use thiserror::Error;
+
+pub const OPEN_ROUTER_API_URL: &str = "https://openrouter.ai/api/v1";
+/// The longest event stream line accepted by [`stream_completion`] unless
+/// [`RequestOptions::max_sse_line_len`] says otherwise. Single events carrying large tool-call
+/// arguments or reasoning can run to several megabytes, so this only guards against a stream that
+/// never terminates its line.
+pub const MAX_SSE_LINE_LEN: usize = 64 * 1024 * 1024;
+/// The context length assumed for catalog entries that don't list one. Small enough that any
+/// model can serve it.
+pub const DEFAULT_CONTEXT_LENGTH: usize = 4096;
+
+fn is_none_or_empty<T: AsRef<[U]>, U>(opt: &Option<T>) -> bool {
+    opt.as_ref().is_none_or(|v| v.as_ref().is_empty())
+}
+
+/// Deserializes `null` as the type's default, for catalog fields that are sometimes sent empty.
+fn null_as_default<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: serde::Deserializer<'de>,
+    T: Default + Deserialize<'de>,
+{
+    Ok(Option::<T>::deserialize(deserializer)?.unwrap_or_default())
+}
+
+fn deserialize_context_length<'de, D>(deserializer: D) -> Result<usize, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    Ok(Option::<usize>::deserialize(deserializer)?
+        .filter(|context_length| *context_length > 0)
+        .unwrap_or(DEFAULT_CONTEXT_LENGTH))
+}
+
+fn default_context_length() -> usize {
+    DEFAULT_CONTEXT_LENGTH
+}
+
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    User,
+    Assistant,
+    System,
+    Tool,
+}
+
+impl TryFrom<String> for Role {
+    type Error = anyhow::Error;
+
+    fn try_from(value: String) -> Result<Self> {
+        match value.as_str() {
+            "user" => Ok(Self::User),
+            "assistant" => Ok(Self::Assistant),
+            "system" => Ok(Self::System),
+            "tool" => Ok(Self::Tool),
+            _ => Err(anyhow!("invalid role '{value}'")),
+        }
+    }
+}
+
+impl From<Role> for String {
+    fn from(val: Role) -> Self {
+        match val {
+            Role::User => "user".to_owned(),
+            Role::Assistant => "assistant".to_owned(),
+            Role::System => "system".to_owned(),
+            Role::Tool => "tool".to_owned(),
+        }
+    }
+}
+
+/// A model listed in OpenRouter's public model catalog.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct Model {
+    pub id: String,
+    #[serde(default, deserialize_with = "null_as_default")]
+    pub name: String,
+    /// [`DEFAULT_CONTEXT_LENGTH`] when the catalog doesn't list one.
+    #[serde(
+        default = "default_context_length",
+        deserialize_with = "deserialize_context_length"
+    )]
+    pub context_length: usize,
+    #[serde(default)]
+    pub top_provider: Option<TopProvider>,
+    #[serde(default, deserialize_with = "null_as_default")]
+    pub supported_parameters: Vec<String>,
+    #[serde(default)]
+    pub architecture: Option<Architecture>,
+    #[serde(default)]
+    pub pricing: Option<Pricing>,
+}
+
+impl Model {
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    pub fn display_name(&self) -> &str {
+        if self.name.is_empty() {
+            &self.id
+        } else {
+            &self.name
+        }
+    }
+
+    pub fn max_token_count(&self) -> usize {
+        self.context_length
+    }
+
+    pub fn max_output_tokens(&self) -> Option<u32> {
+        self.top_provider
+            .as_ref()
+            .and_then(|provider| provider.max_completion_tokens)
+    }
+
+    pub fn supports_tools(&self) -> bool {
+        self.supported_parameters
+            .iter()
+            .any(|parameter| parameter == "tools")
+    }
+
+    /// Whether the model can return several alternative completions for one request.
+    pub fn supports_multiple_choices(&self) -> bool {
+        self.supported_parameters
+            .iter()
+            .any(|parameter| parameter == "n")
+    }
+
+    /// Whether requests to this model cost nothing, either because it's a `:free` variant or
+    /// because the catalog prices both prompt and completion at zero.
+    pub fn is_free(&self) -> bool {
+        if self.id.ends_with(":free") {
+            return true;
+        }
+        let is_zero = |price: &str| price.parse::<f64>().is_ok_and(|price| price == 0.);
+        self.pricing
+            .as_ref()
+            .is_some_and(|pricing| is_zero(&pricing.prompt) && is_zero(&pricing.completion))
+    }
+
+    pub fn supports_reasoning(&self) -> bool {
+        self.supported_parameters
+            .iter()
+            .any(|parameter| parameter == "reasoning" || parameter == "include_reasoning")
+    }
+
+    /// The kinds of input the model accepts, assuming text only when the catalog doesn't say.
+    pub fn input_modalities(&self) -> Vec<Modality> {
+        self.architecture
+            .as_ref()
+            .map(|architecture| architecture.input_modalities.clone())
+            .filter(|modalities| !modalities.is_empty())
+            .unwrap_or_else(|| vec![Modality::Text])
+    }
+
+    /// The kinds of output the model produces, assuming text only when the catalog doesn't say.
+    pub fn output_modalities(&self) -> Vec<Modality> {
+        self.architecture
+            .as_ref()
+            .map(|architecture| architecture.output_modalities.clone())
+            .filter(|modalities| !modalities.is_empty())
+            .unwrap_or_else(|| vec![Modality::Text])
+    }
+}
+
+/// Prices in USD per token, as decimal strings.
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq)]
+pub struct Pricing {
+    #[serde(default, deserialize_with = "null_as_default")]
+    pub prompt: String,
+    #[serde(default, deserialize_with = "null_as_default")]
+    pub completion: String,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq)]
+pub struct Architecture {
+    #[serde(default)]
+    pub input_modalities: Vec<Modality>,
+    #[serde(default)]
+    pub output_modalities: Vec<Modality>,
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Modality {
+    Text,
+    Image,
+    File,
+    Audio,
+    #[serde(other)]
+    Other,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct TopProvider {
+    #[serde(default)]
+    pub context_length: Option<usize>,
+    #[serde(default)]
+    pub max_completion_tokens: Option<u32>,
+    #[serde(default)]
+    pub is_moderated: bool,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ListModelsResponse {
+    pub data: Vec<Value>,
+}
+
+/// Parses a `/models` response. Entries that can't be read even with defaults for their
+/// optional fields, such as ones without an id, are skipped rather than failing the catalog.
+pub fn parse_models(body: &str) -> Result<Vec<Model>> {
+    let response: ListModelsResponse =
+        serde_json::from_str(body).context("Unable to parse OpenRouter models response")?;
+    Ok(response
+        .data
+        .into_iter()
+        .filter_map(|entry| match serde_json::from_value::<Model>(entry) {
+            Ok(model) => Some(model),
+            Err(error) => {
+                log::warn!("Skipping malformed OpenRouter catalog entry: {error}");
+                None
+            }
+        })
+        .collect())
+}
+
+pub async fn list_models(client: &dyn HttpClient, api_url: &str) -> Result<Vec<Model>> {
+    let uri = format!("{api_url}/models");
+    let request_builder = HttpRequest::builder()
+        .method(Method::GET)
+        .uri(uri)
+        .header("Accept", "application/json");
+
+    let request = request_builder.body(AsyncBody::default())?;
+    let mut response = client.send(request).await?;
+
+    let mut body = String::new();
+    response.body_mut().read_to_string(&mut body).await?;
+
+    if response.status().is_success() {
+        parse_models(&body)
+    } else {
+        Err(OpenRouterError::from_response(response.status(), &body).into())
+    }
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq)]
+pub struct Credits {
+    pub total_credits: f64,
+    pub total_usage: f64,
+}
+
+impl Credits {
+    pub fn remaining(&self) -> f64 {
+        self.total_credits - self.total_usage
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct CreditsResponse {
+    data: Credits,
+}
+
+pub async fn get_credits(client: &dyn HttpClient, api_url: &str, api_key: &str) -> Result<Credits> {
+    let uri = format!("{api_url}/credits");
+    let request_builder = HttpRequest::builder()
+        .method(Method::GET)
+        .uri(uri)
+        .header("Accept", "application/json")
+        .header("Authorization", format!("Bearer {}", api_key));
+
+    let request = request_builder.body(AsyncBody::default())?;
+    let mut response = client.send(request).await?;
+
+    let mut body = String::new();
+    response.body_mut().read_to_string(&mut body).await?;
+
+    if response.status().is_success() {
+        let response: CreditsResponse =
+            serde_json::from_str(&body).context("Unable to parse OpenRouter credits response")?;
+        Ok(response.data)
+    } else {
+        Err(OpenRouterError::from_response(response.status(), &body).into())
+    }
+}
+
+/// What OpenRouter reports about the API key a request was made with.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct KeyInfo {
+    #[serde(default)]
+    pub label: Option<String>,
+    /// The spending limit set on the key, in USD, if any.
+    #[serde(default)]
+    pub limit: Option<f64>,
+    #[serde(default, deserialize_with = "null_as_default")]
+    pub usage: f64,
+    #[serde(default, deserialize_with = "null_as_default")]
+    pub is_free_tier: bool,
+}
+
+#[derive(Deserialize, Debug)]
+struct KeyInfoResponse {
+    data: KeyInfo,
+}
+
+/// Looks up the API key itself, which fails with [`OpenRouterError::Unauthorized`] once the key
+/// has been revoked.
+pub async fn get_key_info(
+    client: &dyn HttpClient,
+    api_url: &str,
+    api_key: &str,
+) -> Result<KeyInfo> {
+    let uri = format!("{api_url}/auth/key");
+    let request_builder = HttpRequest::builder()
+        .method(Method::GET)
+        .uri(uri)
+        .header("Accept", "application/json")
+        .header("Authorization", format!("Bearer {}", api_key));
+
+    let request = request_builder.body(AsyncBody::default())?;
+    let mut response = client.send(request).await?;
+
+    let mut body = String::new();
+    response.body_mut().read_to_string(&mut body).await?;
+
+    if response.status().is_success() {
+        let response: KeyInfoResponse =
+            serde_json::from_str(&body).context("Unable to parse OpenRouter key response")?;
+        Ok(response.data)
+    } else {
+        Err(OpenRouterError::from_response(response.status(), &body).into())
+    }
+}
+
+#[derive(Serialize, Debug)]
+struct ModerationRequest<'a> {
+    input: &'a str,
+}
+
+#[derive(Deserialize, Debug)]
+struct ModerationResponse {
+    #[serde(default)]
+    results: Vec<ModerationResult>,
+}
+
+/// How the moderation endpoint rated a piece of input.
+#[derive(Clone, Debug, Default, PartialEq, Deserialize)]
+pub struct ModerationResult {
+    #[serde(default)]
+    pub flagged: bool,
+    /// Each moderation category, and whether the input falls under it.
+    #[serde(default)]
+    pub categories: BTreeMap<String, bool>,
+}
+
+impl ModerationResult {
+    /// The categories the input was flagged for.
+    pub fn flagged_categories(&self) -> impl Iterator<Item = &str> {
+        self.categories
+            .iter()
+            .filter(|(_, flagged)| **flagged)
+            .map(|(category, _)| category.as_str())
+    }
+}
+
+/// Screens `input` with OpenRouter's OpenAI-compatible moderation endpoint, without running a
+/// completion. When the input is split into several results, they're combined into one that's
+/// flagged if any of them is.
+pub async fn moderate(
+    client: &dyn HttpClient,
+    api_url: &str,
+    api_key: &str,
+    input: &str,
+) -> Result<ModerationResult> {
+    let uri = format!("{api_url}/moderations");
+    let request_builder = HttpRequest::builder()
+        .method(Method::POST)
+        .uri(uri)
+        .header("Content-Type", "application/json")
+        .header("Authorization", format!("Bearer {}", api_key));
+
+    let request = request_builder.body(AsyncBody::from(serde_json::to_string(
+        &ModerationRequest { input },
+    )?))?;
+    let mut response = client.send(request).await?;
+
+    let mut body = String::new();
+    response.body_mut().read_to_string(&mut body).await?;
+
+    if response.status().is_success() {
+        let response: ModerationResponse = serde_json::from_str(&body)
+            .context("Unable to parse OpenRouter moderation response")?;
+        let mut combined = ModerationResult::default();
+        for result in response.results {
+            combined.flagged |= result.flagged;
+            for (category, flagged) in result.categories {
+                *combined.categories.entry(category).or_default() |= flagged;
+            }
+        }
+        Ok(combined)
+    } else {
+        Err(OpenRouterError::from_response(response.status(), &body).into())
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Request {
+    pub model: String,
+    /// Models to fall back to, in order, if `model` is unavailable or fails. When set, `model`
+    /// should be the first entry.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub models: Vec<String>,
+    pub messages: Vec<RequestMessage>,
+    pub stream: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_tokens: Option<u32>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub stop: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f32>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tools: Vec<ToolDefinition>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tool_choice: Option<ToolChoice>,
+    /// A stable identifier for the end-user, which OpenRouter uses for abuse detection.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reasoning: Option<Reasoning>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub provider: Option<ProviderPreferences>,
+    /// The slug of a preset configured on OpenRouter. The preset's parameters apply first, and
+    /// any parameter also set in this request overrides the preset's value.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub preset: Option<String>,
+    /// Tags OpenRouter passes through for the caller's own analytics.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub metadata: BTreeMap<String, String>,
+    /// How many alternative completions to generate. Each is streamed as its own choice.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub n: Option<u32>,
+}
+
+/// Constraints on which upstream providers OpenRouter may route a request to.
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq)]
+pub struct ProviderPreferences {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_price: Option<MaxPrice>,
+    /// Only route to these providers, by slug. Requests fail when none of them is available.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub only: Vec<String>,
+}
+
+/// The highest price, in USD per million tokens, a provider may charge to serve a request.
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize, PartialEq)]
+pub struct MaxPrice {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub prompt: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub completion: Option<f64>,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq)]
+pub struct Reasoning {
+    /// Whether the model should reason before answering. Defaults to the model's own behavior.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub enabled: Option<bool>,
+    /// Run reasoning without returning the reasoning tokens in the response.
+    #[serde(default)]
+    pub exclude: bool,
+}
+
+#[derive(Clone, Deserialize, Serialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ToolDefinition {
+    Function { function: FunctionDefinition },
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct FunctionDefinition {
+    pub name: String,
+    pub description: Option<String>,
+    pub parameters: Option<Value>,
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug, Eq, PartialEq)]
+#[serde(tag = "role", rename_all = "lowercase")]
+pub enum RequestMessage {
+    Assistant {
+        content: Option<String>,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        tool_calls: Vec<ToolCall>,
+    },
+    User {
+        content: String,
+    },
+    System {
+        content: String,
+    },
+    /// System instructions for models that expect them under the `developer` role.
+    Developer {
+        content: String,
+    },
+    Tool {
+        content: String,
+        tool_call_id: String,
+        /// The name of the tool that produced this result.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        name: Option<String>,
+    },
+}
+
+/// Whether the model must, may or must not call one of the request's tools.
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, Eq, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ToolChoice {
+    Auto,
+    Required,
+    None,
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug, Eq, PartialEq)]
+pub struct ToolCall {
+    pub id: String,
+    #[serde(flatten)]
+    pub content: ToolCallContent,
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug, Eq, PartialEq)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum ToolCallContent {
+    Function { function: FunctionContent },
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug, Eq, PartialEq)]
+pub struct FunctionContent {
+    pub name: String,
+    pub arguments: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Eq, PartialEq)]
+pub struct ResponseMessageDelta {
+    pub role: Option<Role>,
+    pub content: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reasoning: Option<String>,
+    #[serde(default, skip_serializing_if = "is_none_or_empty")]
+    pub tool_calls: Option<Vec<ToolCallChunk>>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Eq, PartialEq)]
+pub struct ToolCallChunk {
+    pub index: usize,
+    pub id: Option<String>,
+    pub function: Option<FunctionChunk>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Eq, PartialEq)]
+pub struct FunctionChunk {
+    pub name: Option<String>,
+    pub arguments: Option<String>,
+}
+
+/// Deserializes a usage block, treating one without token counts, or with only zeros, as
+/// missing. Some OpenRouter-compatible gateways send such placeholders instead of leaving it out.
+fn deserialize_usage<'de, D>(deserializer: D) -> Result<Option<Usage>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    struct PartialUsage {
+        prompt_tokens: Option<u32>,
+        completion_tokens: Option<u32>,
+        total_tokens: Option<u32>,
+    }
+
+    let Some(usage) = Option::<PartialUsage>::deserialize(deserializer)? else {
+        return Ok(None);
+    };
+    let prompt_tokens = usage.prompt_tokens.unwrap_or(0);
+    let completion_tokens = usage.completion_tokens.unwrap_or(0);
+    if prompt_tokens == 0 && completion_tokens == 0 {
+        return Ok(None);
+    }
+    Ok(Some(Usage {
+        prompt_tokens,
+        completion_tokens,
+        total_tokens: usage
+            .total_tokens
+            .unwrap_or(prompt_tokens.saturating_add(completion_tokens)),
+    }))
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Usage {
+    pub prompt_tokens: u32,
+    pub completion_tokens: u32,
+    pub total_tokens: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ChoiceDelta {
+    pub index: u32,
+    pub delta: ResponseMessageDelta,
+    pub finish_reason: Option<String>,
+    #[serde(
+        default,
+        alias = "safetyRatings",
+        skip_serializing_if = "Vec::is_empty"
+    )]
+    pub safety_ratings: Vec<SafetyRating>,
+}
+
+/// How an upstream, such as Google's, rated a response against one of its safety categories.
+/// Only some upstreams report these.
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct SafetyRating {
+    pub category: String,
+    #[serde(default)]
+    pub probability: String,
+    /// Whether this category caused the response to be blocked.
+    #[serde(default)]
+    pub blocked: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ApiError {
+    #[serde(default)]
+    pub code: Option<u16>,
+    pub message: String,
+    #[serde(default)]
+    pub metadata: Option<ErrorMetadata>,
+}
+
+/// Provider-specific details OpenRouter attaches to some errors.
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq)]
+pub struct ErrorMetadata {
+    /// The upstream provider that produced the error.
+    #[serde(default)]
+    pub provider_name: Option<String>,
+    /// The upstream provider's raw error.
+    #[serde(default)]
+    pub raw: Option<Value>,
+    /// Why the input was flagged, for moderation errors.
+    #[serde(default)]
+    pub reasons: Vec<String>,
+    /// The part of the input that was flagged, for moderation errors.
+    #[serde(default)]
+    pub flagged_input: Option<String>,
+    #[serde(default)]
+    pub model_slug: Option<String>,
+}
+
+impl ErrorMetadata {
+    const MAX_LOGGED_RAW_LEN: usize = 500;
+
+    /// Describes the metadata for logs. The flagged input is left out, and the raw upstream error
+    /// truncated, since either may echo the user's request.
+    pub fn redacted(&self) -> String {
+        let mut parts = Vec::new();
+        if let Some(provider_name) = &self.provider_name {
+            parts.push(format!("provider: {provider_name}"));
+        }
+        if let Some(model_slug) = &self.model_slug {
+            parts.push(format!("model: {model_slug}"));
+        }
+        if !self.reasons.is_empty() {
+            parts.push(format!("reasons: {}", self.reasons.join(", ")));
+        }
+        if let Some(flagged_input) = &self.flagged_input {
+            parts.push(format!(
+                "flagged input: <{} chars redacted>",
+                flagged_input.chars().count()
+            ));
+        }
+        if let Some(raw) = &self.raw {
+            let raw = raw.to_string();
+            let truncated = raw
+                .char_indices()
+                .nth(Self::MAX_LOGGED_RAW_LEN)
+                .map_or(raw.as_str(), |(ix, _)| &raw[..ix]);
+            if truncated.len() < raw.len() {
+                parts.push(format!("raw: {truncated}…"));
+            } else {
+                parts.push(format!("raw: {raw}"));
+            }
+        }
+        parts.join("; ")
+    }
+}
+
+/// An error message from OpenRouter, along with any provider-specific details it came with.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ErrorMessage {
+    pub message: String,
+    pub metadata: Option<ErrorMetadata>,
+}
+
+impl std::fmt::Display for ErrorMessage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.message)?;
+        let provider_name = self
+            .metadata
+            .as_ref()
+            .and_then(|metadata| metadata.provider_name.as_ref());
+        if let Some(provider_name) = provider_name {
+            write!(f, " (from {provider_name})")?;
+        }
+        Ok(())
+    }
+}
+
+impl From<String> for ErrorMessage {
+    fn from(message: String) -> Self {
+        Self {
+            message,
+            metadata: None,
+        }
+    }
+}
+
+impl From<&str> for ErrorMessage {
+    fn from(message: &str) -> Self {
+        message.to_string().into()
+    }
+}
+
+impl PartialEq<str> for ErrorMessage {
+    fn eq(&self, other: &str) -> bool {
+        self.message == other
+    }
+}
+
+/// An error returned by OpenRouter, classified so callers can react to specific failures.
+///
+/// <https://openrouter.ai/docs/api-reference/errors>
+#[derive(Error, Debug)]
+pub enum OpenRouterError {
+    #[error("OpenRouter rejected the API key: {0}")]
+    Unauthorized(ErrorMessage),
+    #[error("OpenRouter rate limit exceeded: {0}")]
+    RateLimited(ErrorMessage),
+    /// A rate limit that interrupted a response partway. The events received before it are
+    /// valid, so the completion can be continued from them.
+    #[error("OpenRouter rate limit exceeded partway through the response: {0}")]
+    RateLimitedMidStream(ErrorMessage),
+    #[error("insufficient OpenRouter credits: {0}")]
+    InsufficientCredits(ErrorMessage),
+    #[error("invalid OpenRouter model: {0}")]
+    InvalidModel(ErrorMessage),
+    #[error("OpenRouter flagged the input for moderation: {0}")]
+    ContentFiltered(ErrorMessage),
+    #[error("no OpenRouter provider is available: {0}")]
+    NoProviderAvailable(ErrorMessage),
+    #[error("OpenRouter API error ({code}): {message}")]
+    Api { code: u16, message: ErrorMessage },
+    #[error("OpenRouter sent an event stream line longer than {max_len} bytes")]
+    LineTooLong { max_len: usize },
+    #[error("OpenRouter request failed: {0}")]
+    Transport(#[from] anyhow::Error),
+}
+
+impl OpenRouterError {
+    pub fn from_code(code: u16, message: impl Into<ErrorMessage>) -> Self {
+        let message = message.into();
+        match code {
+            401 => Self::Unauthorized(message),
+            402 => Self::InsufficientCredits(message),
+            403 => Self::ContentFiltered(message),
+            429 => Self::RateLimited(message),
+            400 if message.message.contains("not a valid model") => Self::InvalidModel(message),
+            502 | 503 => Self::NoProviderAvailable(message),
+            _ => Self::Api { code, message },
+        }
+    }
+
+    /// The HTTP status code OpenRouter answered with, if the request got a response.
+    pub fn status_code(&self) -> Option<u16> {
+        match self {
+            Self::Unauthorized(_) => Some(401),
+            Self::InsufficientCredits(_) => Some(402),
+            Self::ContentFiltered(_) => Some(403),
+            Self::RateLimited(_) | Self::RateLimitedMidStream(_) => Some(429),
+            Self::InvalidModel(_) => Some(400),
+            Self::NoProviderAvailable(_) => Some(503),
+            Self::Api { code, .. } => Some(*code),
+            Self::LineTooLong { .. } | Self::Transport(_) => None,
+        }
+    }
+
+    /// The provider-specific details OpenRouter sent with this error, if any.
+    pub fn metadata(&self) -> Option<&ErrorMetadata> {
+        match self {
+            Self::Unauthorized(message)
+            | Self::RateLimited(message)
+            | Self::RateLimitedMidStream(message)
+            | Self::InsufficientCredits(message)
+            | Self::InvalidModel(message)
+            | Self::ContentFiltered(message)
+            | Self::NoProviderAvailable(message)
+            | Self::Api { message, .. } => message.metadata.as_ref(),
+            Self::LineTooLong { .. } | Self::Transport(_) => None,
+        }
+    }
+
+    fn from_response(status: StatusCode, body: &str) -> Self {
+        #[derive(Deserialize)]
+        struct OpenRouterResponse {
+            error: ApiError,
+        }
+
+        match serde_json::from_str::<OpenRouterResponse>(body) {
+            Ok(response) if !response.error.message.is_empty() => {
+                let code = response.error.code.unwrap_or(status.as_u16());
+                Self::from_code(code, ErrorMessage::from(response.error))
+            }
+            _ => Self::from_code(status.as_u16(), body.to_string()),
+        }
+    }
+}
+
+impl From<ApiError> for ErrorMessage {
+    fn from(error: ApiError) -> Self {
+        if let Some(metadata) = &error.metadata {
+            log::warn!(
+                "OpenRouter error \"{}\" details: {}",
+                error.message,
+                metadata.redacted()
+            );
+        }
+        Self {
+            message: error.message,
+            metadata: error.metadata,
+        }
+    }
+}
+
+impl From<ApiError> for OpenRouterError {
+    fn from(error: ApiError) -> Self {
+        match error.code {
+            Some(code) => Self::from_code(code, ErrorMessage::from(error)),
+            None => Self::Transport(anyhow!(error.message)),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(untagged)]
+pub enum ResponseStreamResult {
+    Ok(ResponseStreamEvent),
+    Err { error: ApiError },
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Response {
+    /// The generation id, which OpenRouter support asks for when investigating a request.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    pub created: u64,
+    pub model: String,
+    pub choices: Vec<Choice>,
+    #[serde(default, deserialize_with = "deserialize_usage")]
+    pub usage: Option<Usage>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Choice {
+    pub index: u32,
+    pub message: ResponseMessage,
+    pub finish_reason: Option<String>,
+    #[serde(
+        default,
+        alias = "safetyRatings",
+        skip_serializing_if = "Vec::is_empty"
+    )]
+    pub safety_ratings: Vec<SafetyRating>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ResponseMessage {
+    pub role: Option<Role>,
+    pub content: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reasoning: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tool_calls: Vec<ToolCall>,
+}
+
+/// A complete response is equivalent to a stream that delivered everything in one event.
+impl From<Response> for ResponseStreamEvent {
+    fn from(response: Response) -> Self {
+        Self {
+            id: response.id,
+            created: response.created,
+            model: response.model,
+            choices: response
+                .choices
+                .into_iter()
+                .map(|choice| ChoiceDelta {
+                    index: choice.index,
+                    delta: ResponseMessageDelta {
+                        role: choice.message.role,
+                        content: choice.message.content,
+                        reasoning: choice.message.reasoning,
+                        tool_calls: Some(
+                            choice
+                                .message
+                                .tool_calls
+                                .into_iter()
+                                .enumerate()
+                                .map(|(index, tool_call)| {
+                                    let ToolCallContent::Function { function } = tool_call.content;
+                                    ToolCallChunk {
+                                        index,
+                                        id: Some(tool_call.id),
+                                        function: Some(FunctionChunk {
+                                            name: Some(function.name),
+                                            arguments: Some(function.arguments),
+                                        }),
+                                    }
+                                })
+                                .collect(),
+                        ),
+                    },
+                    finish_reason: choice.finish_reason,
+                    safety_ratings: choice.safety_ratings,
+                })
+                .collect(),
+            usage: response.usage,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ResponseStreamEvent {
+    /// The generation id, the same for every event of a response.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    pub created: u64,
+    pub model: String,
+    pub choices: Vec<ChoiceDelta>,
+    #[serde(default, deserialize_with = "deserialize_usage")]
+    pub usage: Option<Usage>,
+}
+
+/// Parses a single line of an OpenRouter event stream.
+///
+/// Returns `None` for lines that carry no event: blank separators, the `[DONE]` sentinel, and
+/// comments (lines starting with `:`), which OpenRouter and intermediate proxies send as
+/// keep-alives.
+pub fn parse_sse_line(line: &str) -> Option<Result<ResponseStreamEvent>> {
+    if line.starts_with(':') {
+        return None;
+    }
+
+    let data = line.strip_prefix("data:")?.trim_start();
+    if data == "[DONE]" {
+        return None;
+    }
+
+    match serde_json::from_str(data) {
+        Ok(ResponseStreamResult::Ok(response)) => Some(Ok(response)),
+        Ok(ResponseStreamResult::Err { error }) => Some(Err(OpenRouterError::from(error).into())),
+        Err(error) => Some(Err(anyhow!(error))),
+    }
+}
+
+/// Parses an OpenRouter event stream into typed events, independently of how it's transported.
+///
+/// Lines may be arbitrarily long up to `max_line_len` bytes. A longer line ends the stream with
+/// [`OpenRouterError::LineTooLong`] rather than being truncated.
+pub fn parse_sse_stream<R>(
+    reader: R,
+    max_line_len: usize,
+) -> BoxStream<'static, Result<ResponseStreamEvent>>
+where
+    R: AsyncBufRead + Send + Unpin + 'static,
+{
+    futures::stream::unfold(Some((reader, false)), move |state| async move {
+        let (mut reader, received_events) = state?;
+        loop {
+            match read_sse_line(&mut reader, max_line_len).await {
+                Ok(Some(line)) => match parse_sse_line(&line) {
+                    Some(Ok(event)) => return Some((Ok(event), Some((reader, true)))),
+                    Some(Err(error)) if received_events => {
+                        return Some((
+                            Err(mark_mid_stream_rate_limit(error)),
+                            Some((reader, received_events)),
+                        ));
+                    }
+                    Some(Err(error)) => return Some((Err(error), Some((reader, received_events)))),
+                    None => {}
+                },
+                Ok(None) => return None,
+                Err(error) => return Some((Err(error), None)),
+            }
+        }
+    })
+    .boxed()
+}
+
+/// Turns a rate limit received after other events into
+/// [`OpenRouterError::RateLimitedMidStream`], so callers know the response so far is usable.
+fn mark_mid_stream_rate_limit(error: anyhow::Error) -> anyhow::Error {
+    match error.downcast::<OpenRouterError>() {
+        Ok(OpenRouterError::RateLimited(message)) => {
+            OpenRouterError::RateLimitedMidStream(message).into()
+        }
+        Ok(error) => error.into(),
+        Err(error) => error,
+    }
+}
+
+/// Reads the next line without its terminator, or `None` at the end of the stream.
+async fn read_sse_line<R>(reader: &mut R, max_line_len: usize) -> Result<Option<String>>
+where
+    R: AsyncBufRead + Unpin,
+{
+    let mut line = Vec::new();
+    // Leave room for the newline, so that a line of exactly `max_line_len` bytes is accepted.
+    (&mut *reader)
+        .take(max_line_len as u64 + 1)
+        .read_until(b'\n', &mut line)
+        .await
+        .map_err(|error| OpenRouterError::Transport(anyhow!(error)))?;
+    if line.is_empty() {
+        return Ok(None);
+    }
+
+    if line.last() == Some(&b'\n') {
+        line.pop();
+        if line.last() == Some(&b'\r') {
+            line.pop();
+        }
+    } else if line.len() > max_line_len {
+        return Err(OpenRouterError::LineTooLong {
+            max_len: max_line_len,
+        }
+        .into());
+    }
+
+    // Decode only whole lines, so a multi-byte character split across network chunks has been
+    // reassembled by the time it's converted.
+    let line =
+        String::from_utf8(line).map_err(|error| OpenRouterError::Transport(anyhow!(error)))?;
+    Ok(Some(line))
+}
+
+/// How a completion request is sent, as opposed to what it asks for.
+#[derive(Clone, Copy, Debug)]
+pub struct RequestOptions {
+    /// Leave the connection open for the client to reuse. When false, the server is asked to close
+    /// it once the response is complete. This only applies to HTTP/1.1: HTTP/2 connections are
+    /// shared by concurrent requests and stay open either way.
+    pub keep_alive: bool,
+    /// Send an `Idempotency-Key` header derived from the request body, so a caching proxy can
+    /// recognize identical requests.
+    pub idempotency_key: bool,
+    /// Gzip request bodies larger than this many bytes, sending them with a
+    /// `Content-Encoding: gzip` header. A server that rejects the encoding is sent the plain body
+    /// instead.
+    pub compress_above: Option<usize>,
+    /// The longest event stream line, in bytes, accepted before a streamed response fails with
+    /// [`OpenRouterError::LineTooLong`].
+    pub max_sse_line_len: usize,
+}
+
+impl Default for RequestOptions {
+    fn default() -> Self {
+        Self {
+            keep_alive: true,
+            idempotency_key: false,
+            compress_above: None,
+            max_sse_line_len: MAX_SSE_LINE_LEN,
+        }
+    }
+}
+
+/// A key that's identical for identical request bodies and differs when any parameter does.
+pub fn idempotency_key(body: &str) -> String {
+    hex::encode(Sha256::digest(body.as_bytes()))
+}
+
+/// Sends a chat completion request, returning the response if OpenRouter accepted it.
+async fn send_chat_completion(
+    client: &dyn HttpClient,
+    api_url: &str,
+    api_key: &str,
+    request: &Request,
+    options: RequestOptions,
+) -> Result<http_client::Response<AsyncBody>> {
+    let has_max_price = request
+        .provider
+        .as_ref()
+        .is_some_and(|provider| provider.max_price.is_some());
+    let body = serde_json::to_string(request)?;
+    let request_builder = || {
+        let mut request_builder = HttpRequest::builder()
+            .method(Method::POST)
+            .uri(format!("{api_url}/chat/completions"))
+            .header("Content-Type", "application/json")
+            .header("Authorization", format!("Bearer {}", api_key))
+            .header("HTTP-Referer", "https://zed.dev")
+            .header("X-Title", "Zed Editor");
+        if !options.keep_alive {
+            request_builder = request_builder.header("Connection", "close");
+        }
+        if options.idempotency_key {
+            request_builder = request_builder.header("Idempotency-Key", idempotency_key(&body));
+        }
+        request_builder
+    };
+
+    let compress = options
+        .compress_above
+        .is_some_and(|threshold| body.len() > threshold);
+    let mut response = if compress {
+        let mut compressed = Vec::new();
+        GzipEncoder::new(body.as_bytes())
+            .read_to_end(&mut compressed)
+            .await?;
+        let request = request_builder()
+            .header("Content-Encoding", "gzip")
+            .body(AsyncBody::from(compressed))?;
+        client.send(request).await?
+    } else {
+        let request = request_builder().body(AsyncBody::from(body.clone()))?;
+        client.send(request).await?
+    };
+    if compress && response.status() == StatusCode::UNSUPPORTED_MEDIA_TYPE {
+        log::warn!("OpenRouter rejected a gzip-compressed request, sending it uncompressed");
+        // A connection is only reused once its response has been read in full.
+        response.body_mut().read_to_end(&mut Vec::new()).await.ok();
+        let request = request_builder().body(AsyncBody::from(body))?;
+        response = client.send(request).await?;
+    }
+    if response.status() == StatusCode::NOT_FOUND && has_max_price {
+        response.body_mut().read_to_end(&mut Vec::new()).await.ok();
+        Err(OpenRouterError::NoProviderAvailable(
+            "no provider can serve this model within the configured max_price".into(),
+        )
+        .into())
+    } else if response.status().is_success() {
+        Ok(response)
+    } else {
+        let mut body = String::new();
+        response.body_mut().read_to_string(&mut body).await?;
+        Err(OpenRouterError::from_response(response.status(), &body).into())
+    }
+}
+
+pub async fn stream_completion(
+    client: &dyn HttpClient,
+    api_url: &str,
+    api_key: &str,
+    request: Request,
+    options: RequestOptions,
+) -> Result<BoxStream<'static, Result<ResponseStreamEvent>>> {
+    let max_sse_line_len = options.max_sse_line_len;
+    let response = send_chat_completion(client, api_url, api_key, &request, options).await?;
+    Ok(parse_sse_stream(
+        BufReader::new(response.into_body()),
+        max_sse_line_len,
+    ))
+}
+
+/// Requests a completion without streaming, waiting for the whole response.
+pub async fn complete(
+    client: &dyn HttpClient,
+    api_url: &str,
+    api_key: &str,
+    request: Request,
+    options: RequestOptions,
+) -> Result<Response> {
+    let mut response = send_chat_completion(client, api_url, api_key, &request, options).await?;
+    let mut body = String::new();
+    response.body_mut().read_to_string(&mut body).await?;
+    serde_json::from_str(&body).context("Unable to parse OpenRouter completion response")
+}
+
+/// The tokenizer family a model most likely uses, inferred from its vendor prefix.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TokenizerFamily {
+    Gpt,
+    Claude,
+    Llama,
+    Other,
+}
+
+impl TokenizerFamily {
+    pub fn for_model(model_id: &str) -> Self {
+        let vendor = model_id
+            .split_once('/')
+            .map_or(model_id, |(vendor, _)| vendor);
+        match vendor {
+            "openai" => Self::Gpt,
+            "anthropic" => Self::Claude,
+            "meta-llama" => Self::Llama,
+            _ => Self::Other,
+        }
+    }
+
+    /// The average number of letters a single token covers within a word. Common words are a
+    /// single token, so this is well above the per-character average.
+    fn chars_per_word_token(self) -> f32 {
+        match self {
+            Self::Gpt => 8.0,
+            Self::Claude => 7.0,
+            Self::Llama => 7.5,
+            Self::Other => 7.0,
+        }
+    }
+}
+
+/// Approximates how many tokens `text` encodes to for the given model, without a vocabulary.
+///
+/// Mirrors how BPE tokenizers split text: a word or symbol absorbs its leading space, digits
+/// are grouped in threes, runs of symbols merge in pairs, other whitespace runs are a single
+/// token, and non-ASCII characters are about one token each.
+pub fn estimate_tokens(model_id: &str, text: &str) -> usize {
+    let chars_per_token = TokenizerFamily::for_model(model_id).chars_per_word_token();
+    let is_symbol = |c: &char| c.is_ascii_punctuation();
+    let mut tokens = 0;
+    let mut chars = text.chars().peekable();
+    while let Some(char) = chars.next() {
+        if char.is_ascii_alphabetic() {
+            let mut len = 1_usize;
+            while chars.next_if(|c| c.is_ascii_alphabetic()).is_some() {
+                len += 1;
+            }
+            tokens += ((len as f32 / chars_per_token).round() as usize).max(1);
+        } else if char.is_ascii_digit() {
+            let mut len = 1_usize;
+            while chars.next_if(|c| c.is_ascii_digit()).is_some() {
+                len += 1;
+            }
+            tokens += len.div_ceil(3);
+        } else if char == ' ' && chars.peek().is_some_and(|c| !c.is_whitespace()) {
+            continue;
+        } else if char.is_whitespace() {
+            while chars.next_if(|c| c.is_whitespace()).is_some() {}
+            tokens += 1;
+        } else if is_symbol(&char) {
+            let mut len = 1_usize;
+            while chars.next_if(is_symbol).is_some() {
+                len += 1;
+            }
+            tokens += len.div_ceil(2);
+        } else {
+            tokens += 1;
+        }
+    }
+    tokens
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http_client::FakeHttpClient;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex};
+
+    const COMPLETION_RESPONSE: &str = r#"{"created":1,"model":"openai/gpt-4o","choices":[{"index":0,"message":{"role":"assistant","content":"Hello"},"finish_reason":"stop"}]}"#;
+
+    fn assert_estimate_within(model_id: &str, text: &str, expected: usize, tolerance: f32) {
+        let estimate = estimate_tokens(model_id, text);
+        let difference = (estimate as f32 - expected as f32).abs();
+        assert!(
+            difference <= expected as f32 * tolerance,
+            "estimated {estimate} tokens for {text:?}, expected {expected} ± {:.0}%",
+            tolerance * 100.0
+        );
+    }
+
+    #[test]
+    fn test_catalog_tolerates_missing_fields() {
+        let models = parse_models(
+            r#"{"data":[
+                {"id":"openai/gpt-4o","name":"OpenAI: GPT-4o","context_length":128000,"pricing":{"prompt":"0.0000025","completion":"0.00001"}},
+                {"id":"acme/new-model","name":null,"context_length":null,"supported_parameters":null,"pricing":{"prompt":null}},
+                {"name":"No id"},
+                {"id":"anthropic/claude-sonnet-4","name":"Anthropic: Claude Sonnet 4","context_length":200000}
+            ]}"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            models.iter().map(Model::id).collect::<Vec<_>>(),
+            [
+                "openai/gpt-4o",
+                "acme/new-model",
+                "anthropic/claude-sonnet-4"
+            ]
+        );
+        assert_eq!(models[0].max_token_count(), 128000);
+
+        let partial = &models[1];
+        assert_eq!(partial.display_name(), "acme/new-model");
+        assert_eq!(partial.max_token_count(), DEFAULT_CONTEXT_LENGTH);
+        assert!(partial.supported_parameters.is_empty());
+        assert_eq!(partial.pricing.as_ref().unwrap().prompt, "");
+        assert!(!partial.is_free());
+
+        assert_eq!(models[2].max_token_count(), 200000);
+        assert!(models[2].pricing.is_none());
+    }
+
+    #[test]
+    fn test_estimate_tokens_matches_known_counts() {
+        assert_eq!(estimate_tokens("openai/gpt-4o", ""), 0);
+        assert_eq!(estimate_tokens("openai/gpt-4o", "Hello, world!"), 4);
+        assert_eq!(
+            estimate_tokens(
+                "openai/gpt-4o",
+                "The quick brown fox jumps over the lazy dog."
+            ),
+            10
+        );
+    }
+
+    #[test]
+    fn test_estimate_tokens_within_tolerance() {
+        let prose = "OpenRouter provides a unified API that gives you access to hundreds \
+            of AI models through a single endpoint, while automatically handling fallbacks \
+            and selecting the most cost-effective options.";
+        assert_estimate_within("openai/gpt-4o", prose, 33, 0.2);
+        assert_estimate_within("anthropic/claude-3.5-sonnet", prose, 35, 0.25);
+        assert_estimate_within("meta-llama/llama-3.1-70b-instruct", prose, 34, 0.25);
+
+        let code = "fn main() {\n    let total = 1234 + 56;\n    println!(\"{total}\");\n}\n";
+        assert_estimate_within("openai/gpt-4o", code, 24, 0.3);
+    }
+
+    fn parse_fixture(fixture: &'static [u8]) -> Vec<Result<ResponseStreamEvent>> {
+        futures::executor::block_on(parse_sse_stream(fixture, MAX_SSE_LINE_LEN).collect())
+    }
+
+    fn long_content_event(content_len: usize) -> (String, Vec<u8>) {
+        let content = "x".repeat(content_len);
+        let event = format!(
+            r#"data: {{"created":1,"model":"openai/gpt-4o","choices":[{{"index":0,"delta":{{"role":"assistant","content":"{content}"}},"finish_reason":null}}]}}"#
+        );
+        (content, format!("{event}\n\ndata: [DONE]\n\n").into_bytes())
+    }
+
+    /// A client that answers every request with a completion, recording the value of `header`.
+    fn recording_client(
+        header: &'static str,
+    ) -> (
+        Arc<http_client::HttpClientWithUrl>,
+        Arc<Mutex<Vec<Option<String>>>>,
+    ) {
+        let values = Arc::new(Mutex::new(Vec::new()));
+        let client = FakeHttpClient::create({
+            let values = values.clone();
+            move |request| {
+                values.lock().unwrap().push(
+                    request
+                        .headers()
+                        .get(header)
+                        .map(|value| value.to_str().unwrap().to_string()),
+                );
+                async move {
+                    Ok(http_client::Response::builder()
+                        .status(200)
+                        .body(COMPLETION_RESPONSE.into())
+                        .unwrap())
+                }
+            }
+        });
+        (client, values)
+    }
+
+    fn request(temperature: Option<f32>) -> Request {
+        Request {
+            model: "openai/gpt-4o".into(),
+            models: Vec::new(),
+            messages: vec![RequestMessage::User {
+                content: "Hi".into(),
+            }],
+            stream: false,
+            max_tokens: None,
+            stop: Vec::new(),
+            temperature,
+            top_p: None,
+            tools: Vec::new(),
+            user: None,
+            reasoning: None,
+            provider: None,
+            preset: None,
+            metadata: BTreeMap::new(),
+            n: None,
+            tool_choice: None,
+        }
+    }
+
+    #[test]
+    fn test_keep_alive_leaves_connection_open() {
+        let (client, connection_headers) = recording_client("Connection");
+        let close = RequestOptions {
+            keep_alive: false,
+            ..Default::default()
+        };
+
+        futures::executor::block_on(async {
+            for options in [RequestOptions::default(), RequestOptions::default(), close] {
+                complete(
+                    client.as_ref(),
+                    OPEN_ROUTER_API_URL,
+                    "key",
+                    request(None),
+                    options,
+                )
+                .await
+                .unwrap();
+            }
+        });
+
+        assert_eq!(
+            *connection_headers.lock().unwrap(),
+            [None, None, Some("close".to_string())]
+        );
+    }
+
+    /// Serves streamed completions over HTTP/1.1 on localhost, counting the connections clients
+    /// open. Gzipped requests are rejected, as by a server that doesn't accept compressed bodies.
+    fn completion_server() -> (String, Arc<AtomicUsize>) {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let api_url = format!("http://{}", listener.local_addr().unwrap());
+        let connections = Arc::new(AtomicUsize::new(0));
+        std::thread::spawn({
+            let connections = connections.clone();
+            move || {
+                for stream in listener.incoming() {
+                    let Ok(stream) = stream else {
+                        return;
+                    };
+                    connections.fetch_add(1, Ordering::SeqCst);
+                    std::thread::spawn(move || serve_completions(stream));
+                }
+            }
+        });
+        (api_url, connections)
+    }
+
+    fn serve_completions(stream: std::net::TcpStream) {
+        use std::io::{BufRead as _, Read as _, Write as _};
+
+        let mut reader = std::io::BufReader::new(stream.try_clone().unwrap());
+        let mut writer = stream;
+        loop {
+            let mut request_line = String::new();
+            if reader.read_line(&mut request_line).unwrap_or(0) == 0 {
+                return;
+            }
+            let mut content_length = 0;
+            let mut close = false;
+            let mut gzip = false;
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                let Some((name, value)) = line.trim_end().split_once(':') else {
+                    break;
+                };
+                let value = value.trim();
+                match name.to_ascii_lowercase().as_str() {
+                    "content-length" => content_length = value.parse().unwrap(),
+                    "connection" => close = value.eq_ignore_ascii_case("close"),
+                    "content-encoding" => gzip = value.eq_ignore_ascii_case("gzip"),
+                    _ => {}
+                }
+            }
+            reader.read_exact(&mut vec![0; content_length]).unwrap();
+
+            let (status, body) = if gzip {
+                (
+                    "415 Unsupported Media Type",
+                    r#"{"error":{"code":415,"message":"Unsupported encoding"}}"#.to_string(),
+                )
+            } else {
+                (
+                    "200 OK",
+                    String::from_utf8(long_content_event(16).1).unwrap(),
+                )
+            };
+            let connection = if close { "Connection: close\r\n" } else { "" };
+            write!(
+                writer,
+                "HTTP/1.1 {status}\r\nContent-Type: text/event-stream\r\n\
+                Content-Length: {}\r\n{connection}\r\n{body}",
+                body.len()
+            )
+            .unwrap();
+            if close {
+                return;
+            }
+        }
+    }
+
+    #[test]
+    fn test_keep_alive_reuses_connections() {
+        let client = reqwest_client::ReqwestClient::from(
+            reqwest::Client::builder().no_proxy().build().unwrap(),
+        );
+        let send = |api_url: &str, options: RequestOptions| {
+            let mut large_request = request(None);
+            large_request.messages = vec![RequestMessage::User {
+                content: "x".repeat(2048),
+            }];
+            futures::executor::block_on(async {
+                let events = stream_completion(&client, api_url, "key", large_request, options)
+                    .await
+                    .unwrap()
+                    .collect::<Vec<_>>()
+                    .await;
+                assert_eq!(events.len(), 1);
+                assert!(events[0].is_ok());
+            });
+            // Connections return to the pool on the client's runtime shortly after their
+            // response ends.
+            std::thread::sleep(std::time::Duration::from_millis(50));
+        };
+
+        let (api_url, connections) = completion_server();
+        for _ in 0..3 {
+            send(&api_url, RequestOptions::default());
+        }
+        // A request the server rejects for being compressed is resent on the same connection.
+        send(
+            &api_url,
+            RequestOptions {
+                compress_above: Some(1024),
+                ..Default::default()
+            },
+        );
+        assert_eq!(connections.load(Ordering::SeqCst), 1);
+
+        let (api_url, connections) = completion_server();
+        for _ in 0..3 {
+            send(
+                &api_url,
+                RequestOptions {
+                    keep_alive: false,
+                    ..Default::default()
+                },
+            );
+        }
+        assert_eq!(connections.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn test_idempotency_key_is_stable_for_identical_requests() {
+        let (client, idempotency_keys) = recording_client("Idempotency-Key");
+        let options = RequestOptions {
+            idempotency_key: true,
+            ..Default::default()
+        };
+
+        futures::executor::block_on(async {
+            for temperature in [None, None, Some(0.5)] {
+                complete(
+                    client.as_ref(),
+                    OPEN_ROUTER_API_URL,
+                    "key",
+                    request(temperature),
+                    options,
+                )
+                .await
+                .unwrap();
+            }
+            complete(
+                client.as_ref(),
+                OPEN_ROUTER_API_URL,
+                "key",
+                request(None),
+                RequestOptions::default(),
+            )
+            .await
+            .unwrap();
+        });
+
+        let idempotency_keys = idempotency_keys.lock().unwrap();
+        assert!(idempotency_keys[0].is_some());
+        assert_eq!(idempotency_keys[0], idempotency_keys[1]);
+        assert_ne!(idempotency_keys[0], idempotency_keys[2]);
+        assert_eq!(idempotency_keys[3], None);
+    }
+
+    #[test]
+    fn test_parse_sse_stream_long_line() {
+        let (content, stream) = long_content_event(4 * 1024 * 1024);
+        let line_len = stream.iter().position(|byte| *byte == b'\n').unwrap();
+
+        let events: Vec<_> = futures::executor::block_on(
+            parse_sse_stream(futures::io::Cursor::new(stream), line_len).collect(),
+        );
+        assert_eq!(events.len(), 1);
+        let event = events.into_iter().next().unwrap().unwrap();
+        assert_eq!(
+            event.choices[0].delta.content.as_deref(),
+            Some(content.as_str())
+        );
+    }
+
+    #[test]
+    fn test_parse_sse_stream_multi_byte_character_split_across_chunks() {
+        use futures::TryStreamExt as _;
+
+        let content = "Привет, 世界 👋";
+        let stream = format!(
+            "data: {{\"created\":1,\"model\":\"openai/gpt-4o\",\"choices\":[{{\"index\":0,\"delta\":{{\"content\":\"{content}\"}}}}]}}\n\n"
+        )
+        .into_bytes();
+        // Split inside the four-byte emoji.
+        let split_at = stream
+            .windows("👋".len())
+            .position(|window| window == "👋".as_bytes())
+            .unwrap()
+            + 2;
+        let chunks = vec![
+            Ok::<_, std::io::Error>(stream[..split_at].to_vec()),
+            Ok(stream[split_at..].to_vec()),
+        ];
+
+        let events: Vec<_> = futures::executor::block_on(
+            parse_sse_stream(
+                futures::stream::iter(chunks).into_async_read(),
+                MAX_SSE_LINE_LEN,
+            )
+            .collect(),
+        );
+        assert_eq!(events.len(), 1);
+        let event = events.into_iter().next().unwrap().unwrap();
+        assert_eq!(event.choices[0].delta.content.as_deref(), Some(content));
+    }
+
+    #[test]
+    fn test_parse_sse_stream_rate_limit_after_text() {
+        let stream = concat!(
+            "data: {\"created\":1,\"model\":\"openai/gpt-4o\",\"choices\":[{\"index\":0,\"delta\":{\"content\":\"Hello\"}}]}\n\n",
+            "data: {\"error\":{\"code\":429,\"message\":\"Rate limit exceeded\"}}\n\n",
+        );
+
+        let events: Vec<_> = futures::executor::block_on(
+            parse_sse_stream(futures::io::Cursor::new(stream), MAX_SSE_LINE_LEN).collect(),
+        );
+        assert_eq!(events.len(), 2);
+        let mut events = events.into_iter();
+        let event = events.next().unwrap().unwrap();
+        assert_eq!(event.choices[0].delta.content.as_deref(), Some("Hello"));
+        let error = events.next().unwrap().unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<OpenRouterError>(),
+            Some(OpenRouterError::RateLimitedMidStream(message)) if message == "Rate limit exceeded"
+        ));
+    }
+
+    #[test]
+    fn test_parse_sse_stream_line_too_long() {
+        let (_, stream) = long_content_event(4 * 1024 * 1024);
+        let line_len = stream.iter().position(|byte| *byte == b'\n').unwrap();
+
+        let events: Vec<_> = futures::executor::block_on(
+            parse_sse_stream(futures::io::Cursor::new(stream), line_len - 1).collect(),
+        );
+        assert_eq!(events.len(), 1);
+        let error = events.into_iter().next().unwrap().unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<OpenRouterError>(),
+            Some(OpenRouterError::LineTooLong { .. })
+        ));
+    }
+
+    #[test]
+    fn test_stream_completion_honors_max_sse_line_len() {
+        let (_, stream) = long_content_event(1024);
+        let client = FakeHttpClient::create(move |_| {
+            let stream = stream.clone();
+            async move {
+                Ok(http_client::Response::builder()
+                    .status(200)
+                    .body(stream.into())
+                    .unwrap())
+            }
+        });
+        let options = RequestOptions {
+            max_sse_line_len: 512,
+            ..Default::default()
+        };
+
+        let events: Vec<_> = futures::executor::block_on(async {
+            stream_completion(
+                client.as_ref(),
+                OPEN_ROUTER_API_URL,
+                "sk-or-test",
+                request(None),
+                options,
+            )
+            .await
+            .unwrap()
+            .collect()
+            .await
+        });
+        assert_eq!(events.len(), 1);
+        let error = events.into_iter().next().unwrap().unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<OpenRouterError>(),
+            Some(OpenRouterError::LineTooLong { max_len: 512 })
+        ));
+    }
+
+    #[test]
+    fn test_parse_sse_stream_text_with_usage() {
+        let events = parse_fixture(include_bytes!("../test_data/text_with_usage.sse"))
+            .into_iter()
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+
+        assert_eq!(events.len(), 4);
+        assert!(events.iter().all(|event| event.model == "openai/gpt-4o"));
+        let content = events
+            .iter()
+            .filter_map(|event| event.choices.first()?.delta.content.as_deref())
+            .collect::<String>();
+        assert_eq!(content, "Hello there!");
+        assert_eq!(events[2].choices[0].finish_reason.as_deref(), Some("stop"));
+
+        let usage = events[3].usage.as_ref().unwrap();
+        assert_eq!(usage.prompt_tokens, 12);
+        assert_eq!(usage.completion_tokens, 3);
+        assert_eq!(usage.total_tokens, 15);
+    }
+
+    #[test]
+    fn test_parse_sse_stream_tool_call() {
+        let events = parse_fixture(include_bytes!("../test_data/tool_call.sse"))
+            .into_iter()
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+
+        assert_eq!(events.len(), 5);
+        assert_eq!(
+            events[0].choices[0].delta.content.as_deref(),
+            Some("Let me check.")
+        );
+
+        let chunks = events
+            .iter()
+            .flat_map(|event| event.choices[0].delta.tool_calls.iter().flatten())
+            .collect::<Vec<_>>();
+        assert_eq!(chunks.len(), 3);
+        assert!(chunks.iter().all(|chunk| chunk.index == 0));
+        assert_eq!(chunks[0].id.as_deref(), Some("toolu_01"));
+        assert_eq!(
+            chunks[0].function.as_ref().unwrap().name.as_deref(),
+            Some("read_file")
+        );
+        let arguments = chunks
+            .iter()
+            .filter_map(|chunk| chunk.function.as_ref()?.arguments.as_deref())
+            .collect::<String>();
+        assert_eq!(arguments, r#"{"path": "src/main.rs"}"#);
+
+        assert_eq!(
+            events[4].choices[0].finish_reason.as_deref(),
+            Some("tool_calls")
+        );
+        assert_eq!(events[4].usage.as_ref().unwrap().total_tokens, 261);
+    }
+
+    #[test]
+    fn test_parse_sse_stream_reasoning() {
+        let events = parse_fixture(include_bytes!("../test_data/reasoning.sse"))
+            .into_iter()
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+
+        let reasoning = events
+            .iter()
+            .filter_map(|event| event.choices.first()?.delta.reasoning.as_deref())
+            .collect::<String>();
+        assert_eq!(reasoning, "The user wants a greeting.");
+        assert_eq!(events[2].choices[0].delta.content.as_deref(), Some("Hi!"));
+    }
+
+    #[test]
+    fn test_parse_sse_stream_error() {
+        let events = parse_fixture(include_bytes!("../test_data/error.sse"));
+
+        assert_eq!(events.len(), 2);
+        assert!(events[0].is_ok());
+        let error = events[1].as_ref().unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<OpenRouterError>(),
+            Some(OpenRouterError::NoProviderAvailable(message)) if message == "Provider returned error"
+        ));
+    }
+
+    #[test]
+    fn test_error_metadata_is_preserved() {
+        let line = r#"data: {"error":{"code":403,"message":"Input was flagged","metadata":{"provider_name":"Anthropic","reasons":["violence"],"flagged_input":"some user text","model_slug":"anthropic/claude-3.7-sonnet"}}}"#;
+        let error = parse_sse_line(line).unwrap().unwrap_err();
+        let error = error.downcast_ref::<OpenRouterError>().unwrap();
+
+        assert!(matches!(error, OpenRouterError::ContentFiltered(_)));
+        assert_eq!(
+            error.to_string(),
+            "OpenRouter flagged the input for moderation: Input was flagged (from Anthropic)"
+        );
+        let metadata = error.metadata().unwrap();
+        assert_eq!(metadata.reasons, ["violence"]);
+        assert!(!metadata.redacted().contains("some user text"));
+    }
+
+    #[test]
+    fn test_parse_sse_line_skips_keep_alive_comments() {
+        let lines = [
+            ": OPENROUTER PROCESSING",
+            r#"data: {"created":1,"model":"openai/gpt-4o","choices":[{"index":0,"delta":{"role":"assistant","content":"Hello"},"finish_reason":null}]}"#,
+            "",
+            ":",
+            ": OPENROUTER PROCESSING",
+            r#"data: {"created":1,"model":"openai/gpt-4o","choices":[{"index":0,"delta":{"content":" world"},"finish_reason":"stop"}]}"#,
+            ": keep-alive",
+            "data: [DONE]",
+        ];
+
+        let events = lines
+            .iter()
+            .filter_map(|line| parse_sse_line(line))
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+
+        assert_eq!(events.len(), 2);
+        let content = events
+            .iter()
+            .filter_map(|event| event.choices.first()?.delta.content.as_deref())
+            .collect::<String>();
+        assert_eq!(content, "Hello world");
+    }
+
+    #[test]
+    fn test_large_bodies_are_compressed_with_fallback() {
+        let content_encodings = Arc::new(Mutex::new(Vec::new()));
+        let client = FakeHttpClient::create({
+            let content_encodings = content_encodings.clone();
+            move |request| {
+                let content_encoding = request
+                    .headers()
+                    .get("Content-Encoding")
+                    .map(|value| value.to_str().unwrap().to_string());
+                let status = if content_encoding.is_some() { 415 } else { 200 };
+                content_encodings.lock().unwrap().push(content_encoding);
+                async move {
+                    Ok(http_client::Response::builder()
+                        .status(status)
+                        .body(COMPLETION_RESPONSE.into())
+                        .unwrap())
+                }
+            }
+        });
+        let options = RequestOptions {
+            compress_above: Some(1024),
+            ..Default::default()
+        };
+        let mut large_request = request(None);
+        large_request.messages = vec![RequestMessage::User {
+            content: "fn main() {}\n".repeat(200),
+        }];
+
+        futures::executor::block_on(async {
+            complete(
+                client.as_ref(),
+                OPEN_ROUTER_API_URL,
+                "key",
+                request(None),
+                options,
+            )
+            .await
+            .unwrap();
+            complete(
+                client.as_ref(),
+                OPEN_ROUTER_API_URL,
+                "key",
+                large_request,
+                options,
+            )
+            .await
+            .unwrap();
+        });
+
+        assert_eq!(
+            *content_encodings.lock().unwrap(),
+            [None, Some("gzip".to_string()), None]
+        );
+    }
+}
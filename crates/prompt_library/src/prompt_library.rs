@@ -934,6 +934,7 @@ impl PromptLibrary {
                                     tools: Vec::new(),
                                     stop: Vec::new(),
                                     temperature: None,
+                                    tool_choice: None,
                                 },
                                 cx,
                             )
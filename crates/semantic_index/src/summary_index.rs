@@ -567,6 +567,7 @@ impl SummaryIndex {
             tools: Vec::new(),
             stop: Vec::new(),
             temperature: None,
+            tool_choice: None,
         };
 
         let code_len = code.len();